@@ -0,0 +1,106 @@
+// Microbenchmarks of the basic operations, run with: cargo bench --bench micro
+#[macro_use]
+extern crate criterion;
+extern crate kvs;
+extern crate rand;
+extern crate tempfile;
+
+use criterion::Criterion;
+use kvs::{KVSOptions, KVS};
+use kvs::format::{compare_split_key, shared_prefix_len};
+use rand::{thread_rng, Rng};
+use std::cmp::Ordering;
+use tempfile::TempDir;
+
+const RECORD_COUNT: u64 = 100_000;
+const MEM_COUNT: usize = 10_000;
+
+/// A directory for a store, removed when dropped, so it has to outlive the store
+fn gen_dir() -> TempDir {
+    tempfile::Builder::new().prefix("kvs_bench_").tempdir().unwrap()
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("KEY_{:010}", i).into_bytes()
+}
+
+/// Creates a store with RECORD_COUNT records, flushed to SSTables, returning it with its directory
+fn filled_store(cache_size: usize) -> (TempDir, KVS) {
+    let dir = gen_dir();
+    let mut options = KVSOptions::new(&dir.path().to_path_buf());
+    options.mem_count(MEM_COUNT).cache_size(cache_size);
+    let kvs = options.create().unwrap();
+
+    for i in 0..RECORD_COUNT {
+        kvs.put(key(i), vec![0xAB; 100]);
+    }
+
+    (dir, kvs)
+}
+
+fn append(c: &mut Criterion) {
+    let dir = gen_dir();
+    let mut options = KVSOptions::new(&dir.path().to_path_buf());
+    options.mem_count(MEM_COUNT);
+    let kvs = options.create().unwrap();
+    let mut i = 0;
+
+    c.bench_function("append", move |b| b.iter(|| {
+        i += 1;
+        kvs.put(key(i), vec![0xAB; 100]);
+    }));
+}
+
+fn get_hot(c: &mut Criterion) {
+    let (_dir, kvs) = filled_store(100_000);
+
+    // a few keys read over and over, so they're always in the cache
+    c.bench_function("get_hot", move |b| b.iter(|| {
+        kvs.get(&key(thread_rng().gen_range(0, 100))).unwrap()
+    }));
+}
+
+fn get_cold(c: &mut Criterion) {
+    let (_dir, kvs) = filled_store(1);
+
+    // keys from all over, with a cache too small to help
+    c.bench_function("get_cold", move |b| b.iter(|| {
+        kvs.get(&key(thread_rng().gen_range(0, RECORD_COUNT))).unwrap()
+    }));
+}
+
+fn scan(c: &mut Criterion) {
+    let (_dir, kvs) = filled_store(100_000);
+
+    c.bench_function("scan_100", move |b| b.iter(|| {
+        let start = thread_rng().gen_range(0, RECORD_COUNT - 100);
+
+        kvs.range(&key(start), &key(start + 100)).count()
+    }));
+}
+
+/// Sorted keys with a long shared prefix, like the small records of a compaction
+fn sorted_keys() -> Vec<Vec<u8>> {
+    (0..1_000).map(|i| format!("tenant_0042/events/{:010}", i).into_bytes()).collect()
+}
+
+fn prefix_len(c: &mut Criterion) {
+    let keys = sorted_keys();
+
+    // run against each key written to an SSTable, run with --features simd to compare
+    c.bench_function("shared_prefix_len_1000", move |b| b.iter(|| {
+        keys.windows(2).map(|w| shared_prefix_len(&w[0], &w[1])).sum::<usize>()
+    }));
+}
+
+fn compare_keys(c: &mut Criterion) {
+    let keys = sorted_keys();
+
+    // how a lookup compares a key to a record, whose key is split into the group's prefix and its own suffix
+    c.bench_function("compare_split_key_1000", move |b| b.iter(|| {
+        keys.windows(2).filter(|w| compare_split_key(&w[0][..20], &w[0][20..], &w[1]) == Ordering::Less).count()
+    }));
+}
+
+criterion_group!(benches, append, get_hot, get_cold, scan, prefix_len, compare_keys);
+criterion_main!(benches);
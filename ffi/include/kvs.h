@@ -0,0 +1,73 @@
+/*
+ * The C API of KVS, built by the kvs-ffi crate as libkvs_ffi
+ *
+ * Ownership:
+ * - A kvs_t is created by kvs_open, and freed by kvs_close. It can be used by many threads at once.
+ * - A kvs_iterator_t is created by kvs_iter or kvs_range, and freed by kvs_iter_free. It reads the
+ *   store as it was when created. Use it from one thread, and free it before closing its store.
+ * - Keys and values passed in are copied, so the caller keeps them. A NULL pointer is fine for a length of 0.
+ * - Keys and values returned are owned by the caller, who frees each with kvs_free and its length.
+ *
+ * Errors:
+ * Every function returning an int returns one of the KVS_* codes. The message of the last error
+ * on the thread is returned by kvs_last_error. After KVS_PANIC, the store can't be trusted,
+ * and should only be closed.
+ */
+
+#ifndef KVS_H
+#define KVS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KVS_OK               0
+#define KVS_NOT_FOUND        1 /* no value for the key, or the end of an iterator */
+#define KVS_INVALID_ARGUMENT 2 /* a NULL pointer, a path that isn't UTF-8, or bad options */
+#define KVS_CORRUPTION       3 /* a file of the store doesn't decode */
+#define KVS_IO_ERROR         4 /* any other error from the file system */
+#define KVS_PANIC            5 /* the store panicked */
+
+typedef struct kvs_t kvs_t;
+typedef struct kvs_iterator_t kvs_iterator_t;
+
+/* Opens the store in the directory; when there isn't one and create is non-zero, creates one with the default options */
+int kvs_open(const char *path, int create, kvs_t **db);
+
+/* Flushes and closes the store; db is freed, even when an error is returned. NULL is ignored. */
+int kvs_close(kvs_t *db);
+
+/* Gets the value of the key, or returns KVS_NOT_FOUND */
+int kvs_get(const kvs_t *db, const uint8_t *key, size_t key_len, uint8_t **value, size_t *value_len);
+
+int kvs_put(const kvs_t *db, const uint8_t *key, size_t key_len, const uint8_t *value, size_t value_len);
+
+/* Deleting a key that isn't there isn't an error */
+int kvs_delete(const kvs_t *db, const uint8_t *key, size_t key_len);
+
+/* Frees a key or value returned by the store. NULL is ignored. */
+void kvs_free(uint8_t *bytes, size_t len);
+
+/* Iterates over all the key/value pairs, in key order */
+int kvs_iter(const kvs_t *db, kvs_iterator_t **iter);
+
+/* Iterates over the key/value pairs with keys in [start, end), in key order */
+int kvs_range(const kvs_t *db, const uint8_t *start, size_t start_len, const uint8_t *end, size_t end_len, kvs_iterator_t **iter);
+
+/* Moves to the next pair, or returns KVS_NOT_FOUND at the end */
+int kvs_iter_next(kvs_iterator_t *iter, uint8_t **key, size_t *key_len, uint8_t **value, size_t *value_len);
+
+/* Frees the iterator. NULL is ignored. */
+void kvs_iter_free(kvs_iterator_t *iter);
+
+/* The message of the last error on this thread, or NULL; valid until the next call on this thread */
+const char *kvs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
@@ -0,0 +1,360 @@
+//! A C API for KVS, so it can be embedded from other languages
+//! The functions and their ownership rules are declared in `include/kvs.h`.
+
+extern crate kvs;
+extern crate libc;
+
+#[cfg(test)] extern crate tempfile;
+
+use kvs::{KVS, KVSOptions};
+use kvs::kvs::Iter;
+
+use libc::{c_char, c_int, size_t};
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io::{Error as IOError, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+pub const KVS_OK: c_int = 0;
+pub const KVS_NOT_FOUND: c_int = 1;         // no value for the key, or the end of an iterator
+pub const KVS_INVALID_ARGUMENT: c_int = 2;  // a null pointer, a path that isn't UTF-8, or bad options
+pub const KVS_CORRUPTION: c_int = 3;        // a file of the store doesn't decode
+pub const KVS_IO_ERROR: c_int = 4;          // any other error from the file system
+pub const KVS_PANIC: c_int = 5;             // the store panicked; it should be closed
+
+/// A store opened with `kvs_open`
+pub struct Store(KVS);
+
+/// An iterator created with `kvs_iter` or `kvs_range`
+pub struct StoreIter(Iter);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(msg: String) {
+    // an interior NUL would end the message early anyway
+    let msg = CString::new(msg.replace('\0', " ")).expect("NULs were replaced");
+
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn io_error_code(e: IOError) -> c_int {
+    let code = match e.kind() {
+        ErrorKind::InvalidInput => KVS_INVALID_ARGUMENT,
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => KVS_CORRUPTION,
+        _ => KVS_IO_ERROR
+    };
+
+    set_last_error(e.to_string());
+
+    code
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload.downcast_ref::<&str>().map_or("Unknown panic".to_string(), |msg| msg.to_string())
+    }
+}
+
+/// Runs the function, turning a panic into `KVS_PANIC`, as unwinding into C is undefined
+fn guard<F: FnOnce() -> c_int>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(code) => code,
+        Err(payload) => {
+            set_last_error(panic_message(payload));
+            KVS_PANIC
+        }
+    }
+}
+
+fn invalid(msg: &str) -> c_int {
+    set_last_error(msg.to_string());
+    KVS_INVALID_ARGUMENT
+}
+
+/// Copies the bytes of the caller, which may be a null pointer when the length is 0
+unsafe fn to_vec(bytes: *const u8, len: size_t) -> Option<Vec<u8>> {
+    if len == 0 {
+        Some(Vec::new())
+    } else if bytes.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(bytes, len).to_vec())
+    }
+}
+
+/// Hands the bytes to the caller, who frees them with `kvs_free`
+unsafe fn give(bytes: Vec<u8>, out: *mut *mut u8, out_len: *mut size_t) {
+    let bytes = bytes.into_boxed_slice();
+
+    *out_len = bytes.len();
+    *out = Box::into_raw(bytes) as *mut u8;
+}
+
+/// Opens the store in the directory, creating it with the default options if there isn't one and `create` is set
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char, create: c_int, db: *mut *mut Store) -> c_int {
+    if path.is_null() || db.is_null() {
+        return invalid("kvs_open: path and db must not be NULL");
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return invalid("kvs_open: the path is not UTF-8")
+    };
+
+    guard(|| {
+        let store = match KVS::open(&path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound && create != 0 => KVSOptions::new(&path).create(),
+            store => store
+        };
+
+        match store {
+            Ok(store) => {
+                *db = Box::into_raw(Box::new(Store(store)));
+                KVS_OK
+            },
+            Err(e) => io_error_code(e)
+        }
+    })
+}
+
+/// Flushes and closes the store, and frees the handle, even when closing fails
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(db: *mut Store) -> c_int {
+    if db.is_null() {
+        return KVS_OK;
+    }
+
+    let db = Box::from_raw(db);
+
+    guard(|| match db.0.close(true) {
+        Ok(()) => KVS_OK,
+        Err(e) => io_error_code(e)
+    })
+}
+
+/// Gets the value of the key; the value is freed with `kvs_free`
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(db: *const Store, key: *const u8, key_len: size_t, value: *mut *mut u8, value_len: *mut size_t) -> c_int {
+    let key = match to_vec(key, key_len) {
+        Some(key) => key,
+        None => return invalid("kvs_get: key must not be NULL")
+    };
+
+    if db.is_null() || value.is_null() || value_len.is_null() {
+        return invalid("kvs_get: db, value, and value_len must not be NULL");
+    }
+
+    guard(|| match (*db).0.get(&key) {
+        Some(v) => { give(v, value, value_len); KVS_OK },
+        None => KVS_NOT_FOUND
+    })
+}
+
+/// Sets the value of the key; the key and value are copied
+#[no_mangle]
+pub unsafe extern "C" fn kvs_put(db: *const Store, key: *const u8, key_len: size_t, value: *const u8, value_len: size_t) -> c_int {
+    let (key, value) = match (to_vec(key, key_len), to_vec(value, value_len)) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return invalid("kvs_put: key and value must not be NULL")
+    };
+
+    if db.is_null() {
+        return invalid("kvs_put: db must not be NULL");
+    }
+
+    guard(|| { (*db).0.put(key, value); KVS_OK })
+}
+
+/// Deletes the key; deleting a key that isn't there isn't an error
+#[no_mangle]
+pub unsafe extern "C" fn kvs_delete(db: *const Store, key: *const u8, key_len: size_t) -> c_int {
+    let key = match to_vec(key, key_len) {
+        Some(key) => key,
+        None => return invalid("kvs_delete: key must not be NULL")
+    };
+
+    if db.is_null() {
+        return invalid("kvs_delete: db must not be NULL");
+    }
+
+    guard(|| { (*db).0.delete(&key); KVS_OK })
+}
+
+/// Frees a key or value returned by the store
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free(bytes: *mut u8, len: size_t) {
+    if !bytes.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(bytes, len) as *mut [u8]));
+    }
+}
+
+/// Creates an iterator over all the key/value pairs, in key order
+#[no_mangle]
+pub unsafe extern "C" fn kvs_iter(db: *const Store, iter: *mut *mut StoreIter) -> c_int {
+    if db.is_null() || iter.is_null() {
+        return invalid("kvs_iter: db and iter must not be NULL");
+    }
+
+    guard(|| {
+        *iter = Box::into_raw(Box::new(StoreIter((*db).0.iter())));
+        KVS_OK
+    })
+}
+
+/// Creates an iterator over the key/value pairs with keys in [start, end), in key order
+#[no_mangle]
+pub unsafe extern "C" fn kvs_range(db: *const Store, start: *const u8, start_len: size_t, end: *const u8, end_len: size_t, iter: *mut *mut StoreIter) -> c_int {
+    let (start, end) = match (to_vec(start, start_len), to_vec(end, end_len)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return invalid("kvs_range: start and end must not be NULL")
+    };
+
+    if db.is_null() || iter.is_null() {
+        return invalid("kvs_range: db and iter must not be NULL");
+    }
+
+    guard(|| {
+        *iter = Box::into_raw(Box::new(StoreIter((*db).0.range(&start, &end))));
+        KVS_OK
+    })
+}
+
+/// Moves to the next pair; the key and value are freed with `kvs_free`
+/// Returns `KVS_NOT_FOUND` at the end.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_iter_next(iter: *mut StoreIter, key: *mut *mut u8, key_len: *mut size_t, value: *mut *mut u8, value_len: *mut size_t) -> c_int {
+    if iter.is_null() || key.is_null() || key_len.is_null() || value.is_null() || value_len.is_null() {
+        return invalid("kvs_iter_next: iter, key, key_len, value, and value_len must not be NULL");
+    }
+
+    guard(|| match (*iter).0.next() {
+        Some( (k, v) ) => {
+            give(k, key, key_len);
+            give(v, value, value_len);
+            KVS_OK
+        },
+        None => KVS_NOT_FOUND
+    })
+}
+
+/// Frees the iterator
+#[no_mangle]
+pub unsafe extern "C" fn kvs_iter_free(iter: *mut StoreIter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+/// The message of the last error on this thread, or NULL; it's valid until the next call on this thread
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+    use std::slice;
+    use tempfile;
+
+    unsafe fn take(bytes: *mut u8, len: size_t) -> Vec<u8> {
+        let ret = slice::from_raw_parts(bytes, len).to_vec();
+
+        kvs_free(bytes, len);
+        ret
+    }
+
+    unsafe fn last_error() -> String {
+        CStr::from_ptr(kvs_last_error()).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn open_put_get() {
+        let db_dir = tempfile::Builder::new().prefix("kvs_").tempdir().unwrap();
+        let path = CString::new(db_dir.path().to_str().unwrap()).unwrap();
+        let mut db = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(KVS_IO_ERROR, kvs_open(path.as_ptr(), 0, &mut db));
+            assert!(!last_error().is_empty());
+
+            assert_eq!(KVS_OK, kvs_open(path.as_ptr(), 1, &mut db));
+
+            for i in 0..10 {
+                let key = format!("KEY_{}", i);
+                let value = format!("VALUE_{}", i);
+
+                assert_eq!(KVS_OK, kvs_put(db, key.as_ptr(), key.len(), value.as_ptr(), value.len()));
+            }
+
+            assert_eq!(KVS_OK, kvs_delete(db, b"KEY_3".as_ptr(), 5));
+            assert_eq!(KVS_OK, kvs_put(db, b"EMPTY".as_ptr(), 5, ptr::null(), 0));
+
+            let (mut value, mut value_len) = (ptr::null_mut(), 0);
+
+            assert_eq!(KVS_OK, kvs_get(db, b"KEY_5".as_ptr(), 5, &mut value, &mut value_len));
+            assert_eq!(b"VALUE_5".to_vec(), take(value, value_len));
+            assert_eq!(KVS_NOT_FOUND, kvs_get(db, b"KEY_3".as_ptr(), 5, &mut value, &mut value_len));
+            assert_eq!(KVS_INVALID_ARGUMENT, kvs_get(db, ptr::null(), 5, &mut value, &mut value_len));
+
+            assert_eq!(KVS_OK, kvs_close(db));
+
+            // reopened, the data is still there
+            assert_eq!(KVS_OK, kvs_open(path.as_ptr(), 0, &mut db));
+            assert_eq!(KVS_OK, kvs_get(db, b"EMPTY".as_ptr(), 5, &mut value, &mut value_len));
+            assert_eq!(Vec::<u8>::new(), take(value, value_len));
+            assert_eq!(KVS_OK, kvs_close(db));
+        }
+    }
+
+    #[test]
+    fn iterate() {
+        let db_dir = tempfile::Builder::new().prefix("kvs_").tempdir().unwrap();
+        let path = CString::new(db_dir.path().to_str().unwrap()).unwrap();
+        let mut db = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(KVS_OK, kvs_open(path.as_ptr(), 1, &mut db));
+
+            for i in 0..10 {
+                let key = format!("KEY_{}", i);
+
+                assert_eq!(KVS_OK, kvs_put(db, key.as_ptr(), key.len(), b"VALUE".as_ptr(), 5));
+            }
+
+            let mut iter = ptr::null_mut();
+            let (mut key, mut key_len, mut value, mut value_len) = (ptr::null_mut(), 0, ptr::null_mut(), 0);
+            let mut keys = vec![];
+
+            assert_eq!(KVS_OK, kvs_range(db, b"KEY_2".as_ptr(), 5, b"KEY_5".as_ptr(), 5, &mut iter));
+
+            while kvs_iter_next(iter, &mut key, &mut key_len, &mut value, &mut value_len) == KVS_OK {
+                keys.push(take(key, key_len));
+                assert_eq!(b"VALUE".to_vec(), take(value, value_len));
+            }
+
+            kvs_iter_free(iter);
+
+            assert_eq!(vec![b"KEY_2".to_vec(), b"KEY_3".to_vec(), b"KEY_4".to_vec()], keys);
+
+            assert_eq!(KVS_OK, kvs_iter(db, &mut iter));
+            assert_eq!(KVS_OK, kvs_iter_next(iter, &mut key, &mut key_len, &mut value, &mut value_len));
+            assert_eq!(b"KEY_0".to_vec(), take(key, key_len));
+            kvs_free(value, value_len);
+            kvs_iter_free(iter);
+
+            assert_eq!(KVS_OK, kvs_close(db));
+        }
+    }
+}
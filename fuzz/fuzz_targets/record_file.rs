@@ -0,0 +1,36 @@
+//
+// Feeds arbitrary bytes to the RecordFile and SSTable record decoders, which must return errors, not panic
+// Run with `cargo fuzz run record_file` from the root of the repository.
+//
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate kvs;
+
+use kvs::format::{records, decode_record, decode_record_key, parse_sstable_header, sstable_header};
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the alignment, the rest is the file
+    let (alignment, file) = match data.split_first() {
+        Some((&alignment, file)) => (alignment as u64 % 3 * 512, file),
+        None => return
+    };
+
+    let header = match parse_sstable_header(file) {
+        Ok(codec) => sstable_header(codec),
+        Err(_) => return
+    };
+
+    let mut group_key = vec![];
+
+    if let Ok(recs) = records(file, &header, alignment) {
+        for (_, rec) in recs.filter_map(|rec| rec.ok()) {
+            let _ = decode_record_key(rec, &group_key, true);
+
+            // the records after the first of a group are compressed against its key
+            if let Ok(rec) = decode_record(rec, &group_key, true) {
+                group_key = rec.key().to_vec();
+            }
+        }
+    }
+});
@@ -0,0 +1,22 @@
+//
+// Writes arbitrary bytes to an SSTable file and salvages it, which must return an error, not panic
+// Run with `cargo fuzz run sstable` from the root of the repository.
+//
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate kvs;
+extern crate tempfile;
+
+use kvs::SSTable;
+
+use std::fs;
+
+fuzz_target!(|data: &[u8]| {
+    let dir = tempfile::tempdir().unwrap();
+    let (src, dst) = (dir.path().join("000001.sst"), dir.path().join("000002.sst"));
+
+    fs::write(&src, data).unwrap();
+
+    let _ = SSTable::salvage(&src, &dst);
+});
@@ -0,0 +1,125 @@
+//
+// Tokens, and the key prefixes each can read and write, for the servers
+// Read from a TOML file, with a table for each token:
+//
+// [[tokens]]
+// name = "tenant-a"         # shown in the logs, never the token itself
+// token = "a-long-secret"
+// read = ["a/", "shared/"]  # key prefixes; "" is every key
+// write = ["a/"]            # writing a key doesn't imply reading it
+// stats = false             # GET /stats, which shows the smallest and largest keys of each level
+//
+
+use std::fs::File;
+use std::io::{Error as IOError, ErrorKind, Read};
+use std::path::PathBuf;
+
+use toml;
+
+/// The tokens a server accepts, see `HttpServer::acl`
+#[derive(Debug, Clone)]
+pub struct Acl {
+    principals: Vec<Principal>
+}
+
+/// What a token is allowed to do
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Principal {
+    pub name: String,
+    token: String,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+    #[serde(default)]
+    pub stats: bool
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    tokens: Vec<Principal>
+}
+
+/// Compares in time that only depends on the lengths, so a token can't be guessed a byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Acl {
+    /// Reads the tokens from a TOML file, see the top of this module for the format
+    pub fn from_toml(path: &PathBuf) -> Result<Acl, IOError> {
+        let mut contents = String::new();
+
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        Acl::from_str(&contents).map_err(|e| IOError::new(e.kind(), format!("Error reading the ACL in {}: {}", path.display(), e)))
+    }
+
+    fn from_str(contents: &str) -> Result<Acl, IOError> {
+        let file: AclFile = toml::from_str(contents).map_err(|e| IOError::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        for (i, principal) in file.tokens.iter().enumerate() {
+            if principal.token.is_empty() {
+                return Err(IOError::new(ErrorKind::InvalidInput, format!("The token of {} is empty", principal.name)));
+            }
+
+            if file.tokens[..i].iter().any(|p| p.token == principal.token) {
+                return Err(IOError::new(ErrorKind::InvalidInput, format!("The token of {} is used twice", principal.name)));
+            }
+        }
+
+        Ok(Acl { principals: file.tokens })
+    }
+
+    /// The principal with the token, or None when the token isn't known
+    pub fn authenticate(&self, token: &str) -> Option<&Principal> {
+        // every token is compared, so the time taken doesn't show which one matched
+        self.principals.iter().fold(None, |found, p| {
+            if constant_time_eq(p.token.as_bytes(), token.as_bytes()) { Some(p) } else { found }
+        })
+    }
+}
+
+impl Principal {
+    pub fn can_read(&self, key: &[u8]) -> bool {
+        self.read.iter().any(|p| key.starts_with(p.as_bytes()))
+    }
+
+    pub fn can_write(&self, key: &[u8]) -> bool {
+        self.write.iter().any(|p| key.starts_with(p.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use acl::Acl;
+
+    #[test]
+    fn acl() {
+        let acl = Acl::from_str("[[tokens]]\nname = \"a\"\ntoken = \"secret-a\"\nread = [\"a/\", \"shared/\"]\nwrite = [\"a/\"]\n\n\
+                                 [[tokens]]\nname = \"admin\"\ntoken = \"secret-admin\"\nread = [\"\"]\nstats = true\n").unwrap();
+
+        let a = acl.authenticate("secret-a").unwrap();
+
+        assert_eq!("a", a.name);
+        assert!(a.can_read(b"a/1") && a.can_read(b"shared/1") && !a.can_read(b"b/1") && !a.can_read(b"a"));
+        assert!(a.can_write(b"a/1") && !a.can_write(b"shared/1"));
+        assert!(!a.stats);
+
+        let admin = acl.authenticate("secret-admin").unwrap();
+
+        assert!(admin.can_read(b"") && admin.can_read(b"b/1") && !admin.can_write(b"b/1"));
+        assert!(admin.stats);
+
+        assert!(acl.authenticate("secret").is_none());
+        assert!(acl.authenticate("").is_none());
+
+        // empty and repeated tokens, and unknown fields, are errors
+        assert!(Acl::from_str("[[tokens]]\nname = \"a\"\ntoken = \"\"\n").is_err());
+        assert!(Acl::from_str("[[tokens]]\nname = \"a\"\ntoken = \"t\"\n[[tokens]]\nname = \"b\"\ntoken = \"t\"\n").is_err());
+        assert!(Acl::from_str("[[tokens]]\nname = \"a\"\ntoken = \"t\"\nadmin = true\n").is_err());
+    }
+}
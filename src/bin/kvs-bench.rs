@@ -0,0 +1,293 @@
+//! A db_bench style benchmark of a whole store
+//!
+//! kvs-bench --db=/tmp/kvs-bench --benchmarks=fillseq,readrandom --num=1000000 --value_size=100
+
+extern crate kvs;
+
+use kvs::{KVSOptions, KVS, WriteOptions};
+use std::env;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+
+const USAGE: &str = "Usage: kvs-bench [--db=PATH] [--benchmarks=fillseq,fillrandom,readrandom,readwhilewriting]
+                 [--num=N] [--reads=N] [--value_size=BYTES] [--mem_count=N] [--sync] [--seed=N] [--threads=N]";
+
+struct Config {
+    db: PathBuf,
+    benchmarks: Vec<String>,
+    num: u64,          // the number of records written
+    reads: u64,        // the number of reads, defaults to num
+    value_size: usize,
+    mem_count: usize,
+    sync: bool,        // sync every write
+    seed: u64,
+    threads: u64       // the threads running each benchmark, splitting the work between them
+}
+
+impl Config {
+    fn parse<I>(args: I) -> Result<Config, String> where I: Iterator<Item=String> {
+        let mut config = Config {
+            db: env::temp_dir().join("kvs-bench"),
+            benchmarks: vec!["fillseq", "fillrandom", "readrandom", "readwhilewriting"].into_iter().map(String::from).collect(),
+            num: 1_000_000,
+            reads: 0,
+            value_size: 100,
+            mem_count: 100_000,
+            sync: false,
+            seed: 301,
+            threads: 1
+        };
+        let mut reads = None;
+
+        for arg in args {
+            let (name, value) = match arg.find('=') {
+                Some(i) => (arg[..i].to_string(), arg[i+1..].to_string()),
+                None => (arg.clone(), String::new())
+            };
+
+            let number = || value.parse::<u64>().map_err(|e| format!("Bad value for {}: {}", name, e));
+
+            match name.as_str() {
+                "--db" => config.db = PathBuf::from(&value),
+                "--benchmarks" => config.benchmarks = value.split(',').map(String::from).collect(),
+                "--num" => config.num = number()?,
+                "--reads" => reads = Some(number()?),
+                "--value_size" => config.value_size = number()? as usize,
+                "--mem_count" => config.mem_count = number()? as usize,
+                "--sync" => config.sync = true,
+                "--seed" => config.seed = number()?,
+                "--threads" => config.threads = number()?.max(1),
+                _ => return Err(format!("Unknown argument: {}", arg))
+            }
+        }
+
+        config.reads = reads.unwrap_or(config.num);
+
+        Ok(config)
+    }
+}
+
+/// A small xorshift generator, so runs with the same seed read and write the same keys
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn uniform(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// The latencies of all the operations of a benchmark
+struct Stats {
+    name: String,
+    start: Instant,
+    latencies: Vec<u64>, // micros
+    bytes: u64,
+    found: u64
+}
+
+impl Stats {
+    fn new(name: &str) -> Stats {
+        Stats { name: name.to_string(), start: Instant::now(), latencies: vec![], bytes: 0, found: 0 }
+    }
+
+    fn record(&mut self, op_start: Instant, bytes: usize) {
+        let elapsed = op_start.elapsed();
+
+        self.latencies.push(elapsed.as_secs() * 1_000_000 + elapsed.subsec_nanos() as u64 / 1_000);
+        self.bytes += bytes as u64;
+    }
+
+    /// Adds the operations of another thread running the same benchmark
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        self.bytes += other.bytes;
+        self.found += other.found;
+    }
+
+    fn report(mut self) {
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let ops = self.latencies.len() as f64;
+
+        self.latencies.sort();
+
+        let percentile = |p: f64| self.latencies.get(((self.latencies.len() as f64 - 1.0) * p) as usize).cloned().unwrap_or(0);
+
+        println!("{:<18}: {:>10.3} micros/op; {:>10.0} ops/sec; {:>8.1} MB/s; p50 {} p99 {} p99.9 {} max {} micros{}",
+                 self.name,
+                 secs * 1e6 / ops,
+                 ops / secs,
+                 self.bytes as f64 / 1_048_576.0 / secs,
+                 percentile(0.5), percentile(0.99), percentile(0.999), percentile(1.0),
+                 if self.found > 0 { format!(" ({} of {} found)", self.found, self.latencies.len()) } else { String::new() });
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("{:016}", i).into_bytes()
+}
+
+/// Removes the database, and creates a new one
+fn fresh_store(config: &Config) -> KVS {
+    if config.db.exists() {
+        fs::remove_dir_all(&config.db).expect("Error removing the old database");
+    }
+
+    fs::create_dir_all(&config.db).expect("Error creating the database directory");
+
+    let mut options = KVSOptions::new(&config.db);
+    options.mem_count(config.mem_count);
+
+    options.create().expect("Error creating the database")
+}
+
+/// Runs the benchmark on `config.threads` threads, each with its own range of the `ops` and its own seed
+fn run_threads<F>(kvs: &Arc<KVS>, config: &Arc<Config>, name: &str, ops: u64, f: F) -> Stats
+    where F: Fn(&KVS, &Config, &mut Stats, &mut Random, Range<u64>) + Send + Sync + 'static
+{
+    let f = Arc::new(f);
+    let mut stats = Stats::new(name);
+
+    let threads = (0..config.threads).map(|t| {
+        let (kvs, config, f) = (kvs.clone(), config.clone(), f.clone());
+        let range = (ops * t / config.threads)..(ops * (t + 1) / config.threads);
+        let name = name.to_string();
+
+        thread::spawn(move || {
+            let mut stats = Stats::new(&name);
+            let mut random = Random::new(config.seed + t * 7919);
+
+            f(&kvs, &config, &mut stats, &mut random, range);
+
+            stats
+        })
+    }).collect::<Vec<_>>();
+
+    for thread in threads {
+        stats.merge(thread.join().expect("Benchmark thread panicked"));
+    }
+
+    stats
+}
+
+fn fill(kvs: &KVS, config: &Config, stats: &mut Stats, random: &mut Random, ops: Range<u64>, sequential: bool) {
+    let mut write_options = WriteOptions::new();
+    write_options.sync(config.sync);
+
+    for i in ops {
+        let k = if sequential { key(i) } else { key(random.uniform(config.num)) };
+        let value = random.bytes(config.value_size);
+        let size = k.len() + value.len();
+        let op_start = Instant::now();
+
+        kvs.put_with_options(k, value, &write_options);
+        stats.record(op_start, size);
+    }
+}
+
+fn read_random(kvs: &KVS, config: &Config, stats: &mut Stats, random: &mut Random, ops: Range<u64>) {
+    for _ in ops {
+        let k = key(random.uniform(config.num));
+        let op_start = Instant::now();
+        let value = kvs.get(&k);
+
+        stats.found += value.is_some() as u64;
+        stats.record(op_start, k.len() + value.map_or(0, |v| v.len()));
+    }
+}
+
+/// Reads, with one more thread writing until the reads are done; only the reads are measured
+fn read_while_writing(kvs: &Arc<KVS>, config: &Arc<Config>, random: &mut Random) -> Stats {
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (kvs, config, done) = (kvs.clone(), config.clone(), done.clone());
+        let mut random = Random::new(random.next());
+
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                kvs.put(key(random.uniform(config.num)), random.bytes(config.value_size));
+            }
+        })
+    };
+
+    let stats = run_threads(kvs, config, "readwhilewriting", config.reads, read_random);
+
+    done.store(true, Ordering::Relaxed);
+    writer.join().expect("Writer thread panicked");
+
+    stats
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
+    println!("Keys: 16 bytes; Values: {} bytes; Entries: {}; Database: {}", config.value_size, config.num, config.db.display());
+
+    let config = Arc::new(config);
+    let mut random = Random::new(config.seed);
+    let mut kvs: Option<Arc<KVS>> = None;
+
+    for name in config.benchmarks.iter() {
+        let stats = match name.as_str() {
+            "fillseq" | "fillrandom" => {
+                drop(kvs.take()); // so the old store is flushed before it's removed
+
+                let store = Arc::new(fresh_store(&config));
+                let sequential = name == "fillseq";
+
+                // sequential fills write each thread's range of keys
+                let stats = run_threads(&store, &config, name, config.num, move |kvs, config, stats, random, ops| {
+                    fill(kvs, config, stats, random, ops, sequential)
+                });
+
+                kvs = Some(store);
+                stats
+            },
+            "readrandom" | "readwhilewriting" => {
+                let store = match kvs {
+                    Some(ref store) => store,
+                    None => { eprintln!("{} needs a fill benchmark before it", name); process::exit(1); }
+                };
+
+                if name == "readrandom" {
+                    run_threads(store, &config, name, config.reads, read_random)
+                } else {
+                    read_while_writing(store, &config, &mut random)
+                }
+            },
+            _ => {
+                eprintln!("Unknown benchmark: {}\n{}", name, USAGE);
+                process::exit(1);
+            }
+        };
+
+        stats.report();
+    }
+}
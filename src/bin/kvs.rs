@@ -0,0 +1,163 @@
+//! Commands for looking at a store from the shell
+//!
+//! kvs stats --db=/var/lib/kvs [--json]
+//! kvs repair --table=/var/lib/kvs/000012.sst --out=000012.salvaged.sst
+//! kvs serve --db=/var/lib/kvs [--addr=127.0.0.1:8080] [--acl=acl.toml]   (with the `http` feature)
+//!     [--tls-cert=cert.pem --tls-key=key.pem [--tls-client-ca=ca.pem]]      (with the `tls` feature)
+
+extern crate kvs;
+
+use kvs::{KVS, SSTable};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "Usage: kvs stats --db=PATH [--json]\n       kvs repair --table=PATH --out=PATH\n       kvs serve --db=PATH [--addr=ADDR] [--acl=PATH] [--tls-cert=PATH --tls-key=PATH [--tls-client-ca=PATH]]";
+
+struct Config {
+    command: String,
+    db: Option<PathBuf>,
+    json: bool,         // print JSON for scripts, instead of a table
+    table: Option<PathBuf>, // the damaged SSTable `repair` reads
+    out: Option<PathBuf>,   // the new SSTable `repair` writes
+    addr: String,       // where `serve` listens
+    acl: Option<PathBuf>, // the tokens `serve` accepts, see kvs::acl
+    tls_cert: Option<PathBuf>,      // PEM files for `serve` to use HTTPS, see kvs::tls
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>
+}
+
+impl Config {
+    fn parse<I>(mut args: I) -> Result<Config, String> where I: Iterator<Item=String> {
+        let mut config = Config {
+            command: args.next().ok_or("No command given")?,
+            db: None,
+            json: false,
+            table: None,
+            out: None,
+            addr: "127.0.0.1:8080".to_string(),
+            acl: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None
+        };
+
+        for arg in args {
+            let (name, value) = match arg.find('=') {
+                Some(i) => (arg[..i].to_string(), arg[i+1..].to_string()),
+                None => (arg.clone(), String::new())
+            };
+
+            match name.as_str() {
+                "--db" => config.db = Some(PathBuf::from(&value)),
+                "--json" => config.json = true,
+                "--table" => config.table = Some(PathBuf::from(&value)),
+                "--out" => config.out = Some(PathBuf::from(&value)),
+                "--addr" => config.addr = value,
+                "--acl" => config.acl = Some(PathBuf::from(&value)),
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(&value)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(&value)),
+                "--tls-client-ca" => config.tls_client_ca = Some(PathBuf::from(&value)),
+                _ => return Err(format!("Unknown argument: {}", arg))
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn stats(config: &Config) -> Result<(), String> {
+    let db = config.db.as_ref().ok_or("--db is required")?;
+    let kvs = KVS::open(db).map_err(|e| format!("Error opening {}: {}", db.display(), e))?;
+    let stats = kvs.stats();
+
+    if config.json {
+        println!("{}", stats.to_json());
+    } else {
+        println!("{}", stats);
+    }
+
+    // nothing was written, so there's nothing to flush
+    kvs.close(false).map_err(|e| format!("Error closing {}: {}", db.display(), e))
+}
+
+/// Copies what can be read from a damaged SSTable to a new one, to put in its place with the store closed
+fn repair(config: &Config) -> Result<(), String> {
+    let table = config.table.as_ref().ok_or("--table is required")?;
+    let out = config.out.as_ref().ok_or("--out is required")?;
+    let report = SSTable::salvage(table, out).map_err(|e| format!("Error salvaging {}: {}", table.display(), e))?;
+
+    println!("{}: {}", out.display(), report);
+
+    Ok( () )
+}
+
+#[cfg(feature = "http")]
+fn serve(config: &Config) -> Result<(), String> {
+    use kvs::acl::Acl;
+    use kvs::http::HttpServer;
+    use std::sync::Arc;
+
+    let db = config.db.as_ref().ok_or("--db is required")?;
+    let kvs = KVS::open(db).map_err(|e| format!("Error opening {}: {}", db.display(), e))?;
+    let mut server = HttpServer::bind(Arc::new(kvs), &config.addr).map_err(|e| format!("Error listening on {}: {}", config.addr, e))?;
+
+    if let Some(ref path) = config.acl {
+        server.acl(Acl::from_toml(path).map_err(|e| e.to_string())?);
+    }
+
+    tls(config, &mut server)?;
+
+    let scheme = if config.tls_cert.is_some() { "https" } else { "http" };
+
+    eprintln!("Serving {} on {}://{}", db.display(), scheme, server.local_addr().map_err(|e| e.to_string())?);
+
+    server.serve().map_err(|e| format!("Error serving {}: {}", db.display(), e))
+}
+
+#[cfg(feature = "tls")]
+fn tls(config: &Config, server: &mut kvs::http::HttpServer) -> Result<(), String> {
+    use kvs::tls::TlsConfig;
+
+    match (&config.tls_cert, &config.tls_key) {
+        (&Some(ref cert), &Some(ref key)) => {
+            server.tls(TlsConfig::from_pem(cert, key, config.tls_client_ca.as_ref()).map_err(|e| e.to_string())?);
+            Ok( () )
+        },
+        (&None, &None) if config.tls_client_ca.is_none() => Ok( () ),
+        _ => Err("--tls-cert and --tls-key must be given together, and --tls-client-ca needs them".to_string())
+    }
+}
+
+#[cfg(all(feature = "http", not(feature = "tls")))]
+fn tls(config: &Config, _server: &mut kvs::http::HttpServer) -> Result<(), String> {
+    if config.tls_cert.is_some() || config.tls_key.is_some() || config.tls_client_ca.is_some() {
+        return Err("kvs was built without the tls feature".to_string());
+    }
+
+    Ok( () )
+}
+
+#[cfg(not(feature = "http"))]
+fn serve(_config: &Config) -> Result<(), String> {
+    Err("kvs was built without the http feature".to_string())
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => { eprintln!("{}\n{}", e, USAGE); process::exit(2); }
+    };
+
+    let result = match config.command.as_str() {
+        "stats" => stats(&config),
+        "repair" => repair(&config),
+        "serve" => serve(&config),
+        _ => { eprintln!("Unknown command: {}\n{}", config.command, USAGE); process::exit(2); }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
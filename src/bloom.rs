@@ -0,0 +1,120 @@
+//
+// Bloom filters for the keys in a partition of an SSTable
+// A negative answer means the key is definitely not in the partition, so its indices are never read.
+//
+
+use std::cmp;
+use std::io::{Error as IOError, ErrorKind};
+
+/// The number of bits used for each key, giving a false positive rate of about 1%
+pub const BITS_PER_KEY: usize = 10;
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u8
+}
+
+/// Hashes a key with 64-bit FNV-1a, followed by a finalizer to mix the bits
+pub fn hash_key(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for b in key {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+
+    hash
+}
+
+impl BloomFilter {
+    /// Creates a filter from the hashes of the keys, using `hash_key`
+    pub fn new(hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        // the optimal number of hashes is bits_per_key * ln(2)
+        let hash_count = cmp::max(1, cmp::min(30, (bits_per_key as f64 * 0.69) as u8));
+        let bit_count = cmp::max(64, hashes.len() * bits_per_key);
+        let mut bits = vec![0x00; (bit_count + 7) / 8];
+        let bit_count = bits.len() * 8;
+
+        for hash in hashes {
+            for bit in BloomFilter::bit_positions(*hash, hash_count, bit_count) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        BloomFilter { bits, hash_count }
+    }
+
+    /// The size of the serialized filter of that many keys
+    pub fn serialized_len(key_count: usize, bits_per_key: usize) -> usize {
+        1 + (cmp::max(64, key_count * bits_per_key) + 7) / 8
+    }
+
+    /// Computes the bits for a hash using double hashing: h1 + i * h2
+    fn bit_positions(hash: u64, hash_count: u8, bit_count: usize) -> Vec<usize> {
+        let h1 = hash & 0xFFFFFFFF;
+        let h2 = hash >> 32;
+
+        (0..hash_count as u64).map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count as u64) as usize).collect()
+    }
+
+    /// Returns false if the key is definitely not in the filter
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        BloomFilter::bit_positions(hash_key(key), self.hash_count, self.bits.len() * 8).into_iter().all(|bit| {
+            self.bits[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// The bytes the filter takes in memory
+    pub fn size(&self) -> usize {
+        self.bits.len() + 1
+    }
+
+    /// Serializes the filter as the number of hashes, followed by the bits
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.bits.len() + 1);
+
+        ret.push(self.hash_count);
+        ret.extend_from_slice(&self.bits);
+
+        ret
+    }
+
+    pub fn deserialize(mut buff: Vec<u8>) -> Result<BloomFilter, IOError> {
+        if buff.len() < 2 {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Bloom filter is too short: {}", buff.len())));
+        }
+
+        let bits = buff.split_off(1);
+
+        Ok(BloomFilter { bits, hash_count: buff[0] })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bloom::{hash_key, BloomFilter, BITS_PER_KEY};
+
+    #[test]
+    fn no_false_negatives() {
+        let keys = (0..10_000).map(|i| format!("KEY_{}", i).into_bytes()).collect::<Vec<_>>();
+        let hashes = keys.iter().map(|k| hash_key(k)).collect::<Vec<_>>();
+        let serialized = BloomFilter::new(&hashes, BITS_PER_KEY).serialize();
+
+        assert_eq!(BloomFilter::serialized_len(keys.len(), BITS_PER_KEY), serialized.len());
+
+        let filter = BloomFilter::deserialize(serialized).unwrap();
+
+        for key in keys.iter() {
+            assert!(filter.may_contain(key));
+        }
+
+        // about 1% false positives, so 5% is plenty of room
+        let false_positives = (0..10_000).filter(|i| filter.may_contain(format!("OTHER_{}", i).as_bytes())).count();
+
+        assert!(false_positives < 500, "Too many false positives: {}", false_positives);
+    }
+}
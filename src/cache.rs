@@ -0,0 +1,294 @@
+//
+// The record caches of the SSTables, by the offset of the record, under one of the policies picked with
+// KVSOptions::cache_policy.
+// LRU keeps the most recently read records, so a scan through a table pushes out the records that are
+// read over and over. TinyLFU puts new records in a small LRU window, and only lets one into the main
+// cache when it's been read more often than the record it would push out, going by the counts of a
+// count-min sketch. The main cache is a segmented LRU: records read again while on probation are
+// protected, and only go back to probation when pushed out by others that are.
+//
+
+use lru_cache::LruCache;
+
+use std::sync::Arc;
+
+use bloom::hash_key;
+use meta_cache::MetaCache;
+use value_log::ValueLog;
+
+/// The cache policies, see `KVSOptions::cache_policy`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePolicyKind {
+    Lru,     // the most recently read records
+    TinyLfu  // the most often read records, with a window for new ones
+}
+
+/// The size and policy of the record caches, the metadata cache, and the value log, see `SSTable::open`
+#[derive(Clone)]
+pub struct CacheOptions {
+    pub size: usize,            // records per SSTable
+    pub policy: CachePolicyKind,
+    pub meta: Option<Arc<MetaCache>>, // None reads the filters and indexes through the record cache
+    pub pin_meta: bool,         // keep the filter and index block of every partition in the metadata cache
+    pub value_log: Option<Arc<ValueLog>> // where the values the records point to are read from
+}
+
+/// Keeps records by their offset in a file, up to a number of them
+pub trait CachePolicy: Send {
+    /// Gets a record, counting the read; borrowed, so the reader can copy it where it likes
+    fn get(&mut self, key: u64) -> Option<&[u8]>;
+
+    /// Offers a record, replacing any with the same key; the policy may not keep it
+    fn insert(&mut self, key: u64, value: Vec<u8>);
+
+    fn clear(&mut self);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub fn new_cache(kind: CachePolicyKind, size: usize) -> Box<CachePolicy> {
+    match kind {
+        CachePolicyKind::Lru => Box::new(Lru(LruCache::new(size))),
+        CachePolicyKind::TinyLfu => Box::new(TinyLfu::new(size))
+    }
+}
+
+pub struct Lru(LruCache<u64, Vec<u8>>);
+
+impl CachePolicy for Lru {
+    fn get(&mut self, key: u64) -> Option<&[u8]> {
+        self.0.get_mut(&key).map(|v| v.as_slice())
+    }
+
+    fn insert(&mut self, key: u64, value: Vec<u8>) {
+        self.0.insert(key, value);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+const SKETCH_ROWS: usize = 4;
+const MAX_COUNT: u8 = 15;
+const SAMPLE_FACTOR: usize = 10; // the counts are halved after this many reads per record of the cache
+
+/// Approximate counts of recent reads, so one-off reads of a scan don't displace often read records
+struct FrequencySketch {
+    counters: Vec<u8>, // SKETCH_ROWS rows of width, zeroed by the OS until used
+    width: usize,      // a power of 2
+    reads: usize,
+    sample_size: usize
+}
+
+impl FrequencySketch {
+    fn new(size: usize) -> FrequencySketch {
+        let width = size.next_power_of_two().max(16);
+
+        FrequencySketch { counters: vec![0; SKETCH_ROWS * width], width: width, reads: 0, sample_size: size.max(1) * SAMPLE_FACTOR }
+    }
+
+    fn indexes(&self, key: u64) -> Vec<usize> {
+        let hash = hash_key(&key.to_le_bytes());
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as u32 as usize);
+
+        (0..SKETCH_ROWS).map(|row| row * self.width + (h1.wrapping_add(row.wrapping_mul(h2)) & (self.width - 1))).collect()
+    }
+
+    fn increment(&mut self, key: u64) {
+        for i in self.indexes(key) {
+            self.counters[i] = (self.counters[i] + 1).min(MAX_COUNT);
+        }
+
+        self.reads += 1;
+
+        // halved, so records that were read often a while ago don't stay forever
+        if self.reads >= self.sample_size {
+            for c in self.counters.iter_mut() {
+                *c /= 2;
+            }
+
+            self.reads /= 2;
+        }
+    }
+
+    fn frequency(&self, key: u64) -> u8 {
+        self.indexes(key).into_iter().map(|i| self.counters[i]).min().unwrap_or(0)
+    }
+}
+
+/// The segments are unbounded LRUs, trimmed by `TinyLfu`
+pub struct TinyLfu {
+    window: LruCache<u64, Vec<u8>>,
+    probation: LruCache<u64, Vec<u8>>,
+    protected: LruCache<u64, Vec<u8>>,
+    window_size: usize,    // 1% of the cache
+    main_size: usize,      // probation and protected
+    protected_size: usize, // 80% of the main cache
+    sketch: FrequencySketch
+}
+
+impl TinyLfu {
+    pub fn new(size: usize) -> TinyLfu {
+        let window_size = (size / 100).max(1);
+        let main_size = size.saturating_sub(window_size);
+
+        TinyLfu {
+            window: LruCache::new(usize::max_value()),
+            probation: LruCache::new(usize::max_value()),
+            protected: LruCache::new(usize::max_value()),
+            window_size: window_size,
+            main_size: main_size,
+            protected_size: main_size * 8 / 10,
+            sketch: FrequencySketch::new(size)
+        }
+    }
+
+    /// Moves the oldest record of the window to the main cache, if it's read more often than the record it replaces
+    fn admit(&mut self) {
+        let (candidate, value) = match self.window.remove_lru() {
+            Some(entry) => entry,
+            None => return
+        };
+
+        if self.probation.len() + self.protected.len() < self.main_size {
+            self.probation.insert(candidate, value);
+            return;
+        }
+
+        // the main cache is full, so probation has records, as protected is smaller
+        let victim = match self.probation.iter().next() {
+            Some((&victim, _)) => victim,
+            None => return
+        };
+
+        if self.sketch.frequency(candidate) > self.sketch.frequency(victim) {
+            self.probation.remove(&victim);
+            self.probation.insert(candidate, value);
+        }
+    }
+}
+
+impl CachePolicy for TinyLfu {
+    fn get(&mut self, key: u64) -> Option<&[u8]> {
+        self.sketch.increment(key);
+
+        if self.window.contains_key(&key) {
+            return self.window.get_mut(&key).map(|v| v.as_slice());
+        }
+
+        if !self.protected.contains_key(&key) {
+            let value = self.probation.remove(&key)?;
+
+            self.protected.insert(key, value);
+
+            if self.protected.len() > self.protected_size {
+                if let Some((demoted, v)) = self.protected.remove_lru() {
+                    self.probation.insert(demoted, v);
+                }
+            }
+        }
+
+        // with no room in protected, the record is demoted again
+        match self.protected.get_mut(&key) {
+            Some(value) => Some(value.as_slice()),
+            None => self.probation.get_mut(&key).map(|v| v.as_slice())
+        }
+    }
+
+    fn insert(&mut self, key: u64, value: Vec<u8>) {
+        // a cached record is replaced where it is
+        if self.protected.contains_key(&key) {
+            self.protected.insert(key, value);
+        } else if self.probation.contains_key(&key) {
+            self.probation.insert(key, value);
+        } else {
+            self.window.insert(key, value);
+
+            if self.window.len() > self.window_size {
+                self.admit();
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cache::{CachePolicyKind, new_cache};
+
+    #[test]
+    fn policies() {
+        for &kind in [CachePolicyKind::Lru, CachePolicyKind::TinyLfu].iter() {
+            let mut cache = new_cache(kind, 10);
+
+            cache.insert(1, vec![1]);
+            cache.insert(1, vec![2]);
+
+            assert_eq!(Some(&[2u8][..]), cache.get(1), "{:?}", kind);
+            assert_eq!(None, cache.get(2));
+
+            for key in 0..100 {
+                cache.insert(key, vec![key as u8]);
+                cache.get(key);
+            }
+
+            assert!(cache.len() <= 10, "{:?}: {}", kind, cache.len());
+            assert!(!cache.is_empty());
+
+            cache.clear();
+
+            assert_eq!(0, cache.len());
+            assert!(cache.is_empty());
+            assert_eq!(None, cache.get(99));
+        }
+    }
+
+    #[test]
+    fn scan_resistance() {
+        // the hit rate of a cache of 100 records, reading 50 records over and over, mixed with a scan
+        let hit_rate = |kind: CachePolicyKind| {
+            let mut cache = new_cache(kind, 100);
+            let mut hits = 0;
+            let mut next_scan = 1_000;
+
+            for i in 0..10_000 {
+                for key in vec![i % 50, next_scan, next_scan + 1] {
+                    if cache.get(key).is_some() {
+                        hits += 1;
+                    } else {
+                        cache.insert(key, vec![0; 8]);
+                    }
+                }
+
+                next_scan += 2;
+            }
+
+            hits as f64 / 30_000.0
+        };
+
+        let (lru, tiny_lfu) = (hit_rate(CachePolicyKind::Lru), hit_rate(CachePolicyKind::TinyLfu));
+
+        // LRU holds less than the 149 records read between reads of a record, so it never hits
+        assert_eq!(0.0, lru);
+        assert!(tiny_lfu > 0.3, "{}", tiny_lfu);
+    }
+}
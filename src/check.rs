@@ -0,0 +1,149 @@
+//
+// The report of KVS::check, a look through the whole store for what a crash or bug could leave behind
+// The manifest's files must be there, and numbered below the next number it hands out; the SSTables
+// after the current one must not overlap; and the WALs must number their records one after another,
+// from the oldest retained WAL to the active one, with the manifest's WALs those of the mem_tables.
+//
+
+use std::fmt;
+use std::path::PathBuf;
+
+use record_file::buf2string;
+
+/// Something found by `KVS::check` that the store shouldn't get into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    MissingFile(PathBuf),                                 // a file the manifest references isn't there
+    BadFile { path: PathBuf, error: String },             // an SSTable that can't be opened, or a WAL without a record count
+    FileNumber { path: PathBuf, next_file_number: u64 },  // a file numbered past what the manifest handed out
+    TableId { path: PathBuf, id: u64 },                   // the id saved in an SSTable isn't its file number
+    Overlap { first: PathBuf, second: PathBuf, key: Vec<u8> }, // a key in the ranges of two SSTables after the current one
+    SeqGap { path: PathBuf, expected: u64, found: Option<u64> }, // a WAL's first sequence number doesn't follow the WAL before it
+    WalMismatch { manifest: Vec<u64>, mem_tables: Vec<u64> }     // the WALs of the manifest, oldest first, aren't those of the mem_tables
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Inconsistency::MissingFile(ref path) => write!(f, "{:?} is in the manifest, but missing", path),
+            Inconsistency::BadFile { ref path, ref error } => write!(f, "{:?} can't be read: {}", path, error),
+            Inconsistency::FileNumber { ref path, next_file_number } => write!(f, "{:?} is numbered at or past the next file number {}", path, next_file_number),
+            Inconsistency::TableId { ref path, id } => write!(f, "{:?} has the id {}", path, id),
+            Inconsistency::Overlap { ref first, ref second, ref key } => write!(f, "{:?} and {:?} both have the key {}", first, second, buf2string(key)),
+            Inconsistency::SeqGap { ref path, expected, found: Some(found) } => write!(f, "{:?} starts at sequence number {}, not {}", path, found, expected),
+            Inconsistency::SeqGap { ref path, expected, found: None } => write!(f, "{:?} has no first sequence number, it should be {}", path, expected),
+            Inconsistency::WalMismatch { ref manifest, ref mem_tables } => write!(f, "The manifest has the WALs {:?}, but the mem_tables {:?}", manifest, mem_tables)
+        }
+    }
+}
+
+/// What `KVS::check` looked at, and found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub tables: usize,              // the SSTables checked, with the current one
+    pub wals: usize,                // the WALs checked, retained, immutable, and active
+    pub unreferenced: Vec<PathBuf>, // numbered files the manifest doesn't reference, left to be removed; not a problem
+    pub problems: Vec<Inconsistency>
+}
+
+impl CheckReport {
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Checked {} SSTables and {} WALs: {} problems, {} unreferenced files", self.tables, self.wals, self.problems.len(), self.unreferenced.len())?;
+
+        for problem in self.problems.iter() {
+            write!(f, "\n  {}", problem)?;
+        }
+
+        Ok( () )
+    }
+}
+
+/// The path of an SSTable, and its [smallest, largest] keys; empty tables have no range
+pub type TableRange = (PathBuf, Option<(Vec<u8>, Vec<u8>)>);
+
+/// Finds the overlapping ranges of SSTables, given in key order
+pub fn check_overlaps(tables: &[TableRange]) -> Vec<Inconsistency> {
+    let mut problems = vec![];
+    let mut prev: Option<(&PathBuf, &Vec<u8>)> = None; // the table with the largest key so far
+
+    for &(ref path, ref range) in tables.iter() {
+        let (smallest, largest) = match *range {
+            Some((ref smallest, ref largest)) => (smallest, largest),
+            None => continue
+        };
+
+        match prev {
+            Some((prev_path, prev_largest)) if smallest <= prev_largest => {
+                problems.push(Inconsistency::Overlap { first: prev_path.clone(), second: path.clone(), key: smallest.clone() });
+
+                if largest > prev_largest {
+                    prev = Some((path, largest));
+                }
+            },
+            _ => prev = Some((path, largest))
+        }
+    }
+
+    problems
+}
+
+/// Finds the WALs whose first sequence numbers don't follow the WAL before them
+/// * wals - the paths, first sequence numbers, and record counts of the WALs, oldest first
+pub fn check_seqs(wals: &[(PathBuf, Option<u64>, u64)]) -> Vec<Inconsistency> {
+    let mut problems = vec![];
+    let mut expected = None;
+
+    for &(ref path, first_seq, record_count) in wals.iter() {
+        match (first_seq, expected) {
+            (Some(found), Some(expected)) if found != expected => problems.push(Inconsistency::SeqGap { path: path.clone(), expected: expected, found: Some(found) }),
+            (None, Some(expected)) => problems.push(Inconsistency::SeqGap { path: path.clone(), expected: expected, found: None }),
+            _ => ()
+        }
+
+        // the next WAL follows on from where this one was meant to start
+        expected = first_seq.or(expected).map(|first_seq| first_seq + record_count);
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use check::{check_overlaps, check_seqs, Inconsistency};
+    use std::path::PathBuf;
+
+    #[test]
+    fn overlaps() {
+        let table = |name: &str, range: Option<(&str, &str)>| (PathBuf::from(name), range.map(|(s, l)| (s.as_bytes().to_vec(), l.as_bytes().to_vec())));
+
+        assert!(check_overlaps(&[table("1", Some(("A", "C"))), table("2", None), table("3", Some(("D", "F")))]).is_empty());
+
+        // a table inside another overlaps it, and so does the next one
+        let problems = check_overlaps(&[table("1", Some(("A", "M"))), table("2", Some(("B", "C"))), table("3", Some(("M", "Z")))]);
+
+        assert_eq!(vec![
+            Inconsistency::Overlap { first: PathBuf::from("1"), second: PathBuf::from("2"), key: b"B".to_vec() },
+            Inconsistency::Overlap { first: PathBuf::from("1"), second: PathBuf::from("3"), key: b"M".to_vec() }
+        ], problems);
+    }
+
+    #[test]
+    fn seqs() {
+        let wal = |name: &str, first_seq: Option<u64>, count: u64| (PathBuf::from(name), first_seq, count);
+
+        assert!(check_seqs(&[wal("1", Some(0), 10), wal("2", Some(10), 0), wal("3", Some(10), 5)]).is_empty());
+
+        let problems = check_seqs(&[wal("1", Some(0), 10), wal("2", None, 5), wal("3", Some(15), 5), wal("4", Some(25), 1)]);
+
+        assert_eq!(vec![
+            Inconsistency::SeqGap { path: PathBuf::from("2"), expected: 10, found: None },
+            Inconsistency::SeqGap { path: PathBuf::from("4"), expected: 20, found: Some(25) }
+        ], problems);
+    }
+}
@@ -0,0 +1,507 @@
+//
+// A client for a set of HTTP servers, see the http module, with the keys sharded across them
+// Each key goes to a server picked with consistent hashing: every server gets a number of virtual
+// nodes on a ring of hashes, and a key goes to the first node at or after its hash. Adding or removing
+// a server only moves the keys of its nodes. Connections are kept open, and pooled for each server.
+// Built with the `http` feature.
+//
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use itertools::kmerge;
+use serde_json;
+
+use bloom::hash_key;
+use http::Encoding;
+
+const DEFAULT_VIRTUAL_NODES: usize = 64;
+const DEFAULT_MAX_IDLE: usize = 8;
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+const SCAN_PAGE: usize = 1_000;
+const MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Hashes keys, and the names of the virtual nodes, onto the ring; every client of a set of servers must use the same one
+pub trait ShardHasher: Send + Sync {
+    fn hash(&self, bytes: &[u8]) -> u64;
+}
+
+/// The hash of the bloom filters, 64-bit FNV-1a with the bits mixed at the end, so similar keys land far apart
+pub struct DefaultShardHasher;
+
+impl ShardHasher for DefaultShardHasher {
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        hash_key(bytes)
+    }
+}
+
+/// The options of a `ShardedClient`
+#[derive(Clone)]
+pub struct ClientOptions {
+    virtual_nodes: usize,
+    hasher: Arc<ShardHasher>,
+    token: Option<String>,
+    max_idle: usize,
+    timeout_ms: u64
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions::new()
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> ClientOptions {
+        ClientOptions {
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            hasher: Arc::new(DefaultShardHasher),
+            token: None,
+            max_idle: DEFAULT_MAX_IDLE,
+            timeout_ms: DEFAULT_TIMEOUT_MS
+        }
+    }
+
+    /// Sets the number of nodes on the ring for each server; more spreads the keys more evenly
+    ///
+    /// Default: 64
+    pub fn virtual_nodes(&mut self, count: usize) -> &mut ClientOptions {
+        self.virtual_nodes = count; self
+    }
+
+    /// Sets the hash used to place keys and nodes on the ring
+    ///
+    /// Default: `DefaultShardHasher`
+    pub fn hasher(&mut self, hasher: Arc<ShardHasher>) -> &mut ClientOptions {
+        self.hasher = hasher; self
+    }
+
+    /// Sets the token sent with every request, for servers with an ACL, see `HttpServer::acl`
+    ///
+    /// Default: none
+    pub fn token(&mut self, token: &str) -> &mut ClientOptions {
+        self.token = Some(token.to_string()); self
+    }
+
+    /// Sets the most connections kept open to each server, when they're not in use
+    ///
+    /// Default: 8
+    pub fn max_idle_connections(&mut self, count: usize) -> &mut ClientOptions {
+        self.max_idle = count; self
+    }
+
+    /// Sets how long to wait for a server to connect, or to answer, before giving up with an error
+    ///
+    /// Default: 30 seconds
+    pub fn timeout(&mut self, timeout: Duration) -> &mut ClientOptions {
+        self.timeout_ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000; self
+    }
+
+    /// Creates a client for the servers, given as "host:port"; the order doesn't matter
+    ///
+    /// # Panics
+    /// If there are no servers, or no virtual nodes.
+    pub fn connect(&self, servers: &[&str]) -> ShardedClient {
+        assert!(!servers.is_empty(), "A ShardedClient needs at least one server");
+        assert!(self.virtual_nodes > 0, "A ShardedClient needs at least one virtual node per server");
+
+        let mut ring = BTreeMap::new();
+
+        for (i, server) in servers.iter().enumerate() {
+            for node in 0..self.virtual_nodes {
+                ring.insert(self.hasher.hash(format!("{}#{}", server, node).as_bytes()), i);
+            }
+        }
+
+        ShardedClient {
+            shards: servers.iter().map(|s| Shard { addr: s.to_string(), idle: Mutex::new(vec![]) }).collect(),
+            ring: ring,
+            options: self.clone()
+        }
+    }
+}
+
+struct Shard {
+    addr: String,
+    idle: Mutex<Vec<BufReader<TcpStream>>> // connections kept open, for the next request
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    key: String,
+    value: String
+}
+
+#[derive(Deserialize)]
+struct ScanPage {
+    entries: Vec<KeyValue>,
+    cursor: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Counter {
+    count: i64
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, IOError> {
+    Encoding::Hex.decode(s.as_bytes()).ok_or_else(|| IOError::new(ErrorKind::InvalidData, format!("Not hex: {}", s)))
+}
+
+fn parse<'a, T: ::serde::Deserialize<'a>>(body: &'a [u8]) -> Result<T, IOError> {
+    serde_json::from_slice(body).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("Bad response: {}", e)))
+}
+
+/// Reads a response, returning its status, its body, and whether the server keeps the connection open
+fn read_response<R: Read>(stream: &mut BufReader<R>) -> Result<(u16, Vec<u8>, bool), IOError> {
+    let invalid = |msg: &str| IOError::new(ErrorKind::InvalidData, msg.to_string());
+    let mut line = String::new();
+
+    if stream.read_line(&mut line)? == 0 {
+        return Err(IOError::new(ErrorKind::UnexpectedEof, "The server closed the connection"));
+    }
+
+    let status = match line.split_whitespace().nth(1).map(|s| s.parse::<u16>()) {
+        Some(Ok(status)) => status,
+        _ => return Err(invalid("Bad status line"))
+    };
+
+    let mut content_length = 0;
+    let mut keep_alive = true;
+
+    loop {
+        line.clear();
+
+        if stream.read_line(&mut line)? == 0 {
+            return Err(IOError::new(ErrorKind::UnexpectedEof, "The server closed the connection"));
+        }
+
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(i) = header.find(':') {
+            let value = header[i + 1..].trim();
+
+            if header[..i].eq_ignore_ascii_case("content-length") {
+                content_length = value.parse::<usize>().map_err(|_| invalid("Bad Content-Length"))?;
+            } else if header[..i].eq_ignore_ascii_case("connection") {
+                keep_alive = !value.eq_ignore_ascii_case("close");
+            }
+        }
+    }
+
+    if content_length > MAX_RESPONSE_BYTES {
+        return Err(invalid("The response is too large"));
+    }
+
+    let mut body = vec![0; content_length];
+
+    stream.read_exact(&mut body)?;
+
+    Ok( (status, body, keep_alive) )
+}
+
+/// The error of a response that isn't a success, with the server's message
+fn status_error(addr: &str, status: u16, body: &[u8]) -> IOError {
+    let kind = match status {
+        400 => ErrorKind::InvalidInput,
+        401 | 403 => ErrorKind::PermissionDenied,
+        404 => ErrorKind::NotFound,
+        _ => ErrorKind::Other
+    };
+
+    let msg = parse::<ErrorBody>(body).map(|e| e.error).unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+
+    IOError::new(kind, format!("{} answered {}: {}", addr, status, msg))
+}
+
+impl Shard {
+    /// Sends a request, on an idle connection if there is one, returning the status and body of the response
+    fn request(&self, options: &ClientOptions, method: &str, target: &str, body: &[u8]) -> Result<(u16, Vec<u8>), IOError> {
+        let idle = self.idle.lock().unwrap().pop();
+
+        // an idle connection may have been closed by the server, so a failure on one is tried again on a new one
+        if let Some(conn) = idle {
+            if let Ok(response) = self.request_on(conn, options, method, target, body) {
+                return Ok(response);
+            }
+        }
+
+        let timeout = Duration::from_millis(options.timeout_ms);
+        let addr = self.addr.as_str().to_socket_addrs()?.next()
+            .ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("No address for {}", self.addr)))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        self.request_on(BufReader::new(stream), options, method, target, body)
+    }
+
+    fn request_on(&self, mut conn: BufReader<TcpStream>, options: &ClientOptions, method: &str, target: &str, body: &[u8]) -> Result<(u16, Vec<u8>), IOError> {
+        let auth = options.token.as_ref().map_or(String::new(), |t| format!("Authorization: Bearer {}\r\n", t));
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n", method, target, self.addr, auth, body.len()).into_bytes();
+
+        request.extend_from_slice(body);
+        conn.get_mut().write_all(&request)?;
+
+        let (status, body, keep_alive) = read_response(&mut conn)?;
+
+        if keep_alive {
+            let mut idle = self.idle.lock().unwrap();
+
+            if idle.len() < options.max_idle {
+                idle.push(conn);
+            }
+        }
+
+        Ok( (status, body) )
+    }
+
+    fn get(&self, options: &ClientOptions, key: &[u8]) -> Result<Option<Vec<u8>>, IOError> {
+        match self.request(options, "GET", &format!("/keys/{}?encoding=hex", to_hex(key)), b"")? {
+            (200, body) => Ok(Some(from_hex(&parse::<KeyValue>(&body)?.value)?)),
+            (404, _) => Ok(None),
+            (status, body) => Err(status_error(&self.addr, status, &body))
+        }
+    }
+
+    /// Reads up to `limit` of the keys with the prefix, in key order
+    fn scan(&self, options: &ClientOptions, prefix: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>, IOError> {
+        let mut entries = Vec::new();
+        let mut cursor = None;
+
+        while entries.len() < limit {
+            let mut target = format!("/scan?encoding=hex&prefix={}&limit={}", to_hex(prefix), SCAN_PAGE.min(limit - entries.len()));
+
+            if let Some(ref cursor) = cursor {
+                target.push_str(&format!("&cursor={}", cursor));
+            }
+
+            let page = match self.request(options, "GET", &target, b"")? {
+                (200, body) => parse::<ScanPage>(&body)?,
+                (status, body) => return Err(status_error(&self.addr, status, &body))
+            };
+
+            for entry in page.entries {
+                entries.push( (from_hex(&entry.key)?, from_hex(&entry.value)?) );
+            }
+
+            cursor = match page.cursor {
+                Some(cursor) => Some(cursor),
+                None => break
+            };
+        }
+
+        Ok(entries)
+    }
+}
+
+/// A client for a set of servers, each holding the keys that hash to it; see `ClientOptions::connect`
+///
+/// It can be shared by many threads.
+pub struct ShardedClient {
+    shards: Vec<Shard>,
+    ring: BTreeMap<u64, usize>, // the hashes of the virtual nodes, to the index of their server
+    options: ClientOptions
+}
+
+impl ShardedClient {
+    /// Creates a client with the default options
+    pub fn connect(servers: &[&str]) -> ShardedClient {
+        ClientOptions::new().connect(servers)
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let hash = self.options.hasher.hash(key);
+
+        // the first node at or after the hash, wrapping around to the first node
+        *self.ring.range(hash..).next().or_else(|| self.ring.iter().next()).expect("The ring has nodes").1
+    }
+
+    /// The server holding the key
+    pub fn server_for(&self, key: &[u8]) -> &str {
+        &self.shards[self.shard_index(key)].addr
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, IOError> {
+        self.shards[self.shard_index(key)].get(&self.options, key)
+    }
+
+    /// Puts a key/value pair; a server's quota being exceeded is an error of kind `Other`
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), IOError> {
+        let shard = &self.shards[self.shard_index(key)];
+
+        match shard.request(&self.options, "PUT", &format!("/keys/{}?encoding=hex", to_hex(key)), value)? {
+            (204, _) => Ok( () ),
+            (status, body) => Err(status_error(&shard.addr, status, &body))
+        }
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), IOError> {
+        let shard = &self.shards[self.shard_index(key)];
+
+        match shard.request(&self.options, "DELETE", &format!("/keys/{}?encoding=hex", to_hex(key)), b"")? {
+            (204, _) => Ok( () ),
+            (status, body) => Err(status_error(&shard.addr, status, &body))
+        }
+    }
+
+    /// Adds the delta to the counter at the key, returning its new value, see `KVS::increment`
+    pub fn increment(&self, key: &[u8], delta: i64) -> Result<i64, IOError> {
+        let shard = &self.shards[self.shard_index(key)];
+
+        match shard.request(&self.options, "POST", &format!("/counters/{}?encoding=hex&delta={}", to_hex(key), delta), b"")? {
+            (200, body) => Ok(parse::<Counter>(&body)?.count),
+            (status, body) => Err(status_error(&shard.addr, status, &body))
+        }
+    }
+
+    /// Gets the values of the keys, in the order of the keys, asking the servers at the same time
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, IOError> {
+        let mut by_shard = vec![vec![]; self.shards.len()];
+
+        for (i, key) in keys.iter().enumerate() {
+            by_shard[self.shard_index(key)].push(i);
+        }
+
+        let mut values = vec![None; keys.len()];
+
+        let results = thread::scope(|scope| {
+            let handles = by_shard.iter().enumerate().filter(|&(_, indexes)| !indexes.is_empty()).map(|(shard, indexes)| {
+                scope.spawn(move || {
+                    indexes.iter().map(|&i| self.shards[shard].get(&self.options, &keys[i]).map(|v| (i, v))).collect::<Result<Vec<_>, _>>()
+                })
+            }).collect::<Vec<_>>();
+
+            handles.into_iter().map(|h| h.join().expect("A get_many thread panicked")).collect::<Vec<_>>()
+        });
+
+        for result in results {
+            for (i, value) in result? {
+                values[i] = value;
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Returns up to `limit` of the key/value pairs with keys starting with the prefix, in key order
+    ///
+    /// Every server is asked at the same time, for up to `limit` pairs each, which are merged. Unlike
+    /// `KVS::prefix_page`, the servers aren't read at a single point in time.
+    pub fn scan(&self, prefix: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>, IOError> {
+        let results = thread::scope(|scope| {
+            let handles = self.shards.iter().map(|shard| scope.spawn(move || shard.scan(&self.options, prefix, limit))).collect::<Vec<_>>();
+
+            handles.into_iter().map(|h| h.join().expect("A scan thread panicked")).collect::<Vec<_>>()
+        });
+
+        let entries = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+
+        Ok(kmerge(entries).take(limit).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::{ClientOptions, DefaultShardHasher, ShardHasher, ShardedClient};
+    use http::HttpServer;
+    use kvs::{KVS, KVSOptions};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+    use test_path::{gen_dir, TestPath};
+
+    /// Starts a server on a new store, returning the store, the server's address, and the store's directory
+    fn start_server() -> (Arc<KVS>, String, TestPath) {
+        let db_dir = gen_dir();
+        let kvs = Arc::new(KVSOptions::new(&db_dir).create().unwrap());
+        let server = HttpServer::bind(kvs.clone(), "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        thread::spawn(move || server.serve());
+
+        (kvs, addr, db_dir)
+    }
+
+    #[test]
+    fn ring() {
+        let servers = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+        let client = ClientOptions::new().virtual_nodes(100).connect(&servers);
+        let keys = (0..3000).map(|i| format!("key_{}", i).into_bytes()).collect::<Vec<_>>();
+
+        let mut counts = HashMap::new();
+
+        for key in keys.iter() {
+            *counts.entry(client.server_for(key).to_string()).or_insert(0) += 1;
+        }
+
+        // spread about evenly
+        assert_eq!(3, counts.len());
+        assert!(counts.values().all(|&c| c > 600), "{:?}", counts);
+
+        // adding a server only moves keys to it
+        let bigger = ClientOptions::new().virtual_nodes(100).connect(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"]);
+
+        for key in keys.iter() {
+            assert!(bigger.server_for(key) == client.server_for(key) || bigger.server_for(key) == "10.0.0.4:80");
+        }
+
+        assert_ne!(DefaultShardHasher.hash(b"a"), DefaultShardHasher.hash(b"b"));
+    }
+
+    #[test]
+    fn sharded() {
+        let servers = (0..3).map(|_| start_server()).collect::<Vec<_>>();
+        let addrs = servers.iter().map(|&(_, ref addr, _)| addr.as_str()).collect::<Vec<_>>();
+        let client = ShardedClient::connect(&addrs);
+
+        for i in 0..100u8 {
+            client.put(&[b'k', i], &[i]).unwrap();
+        }
+
+        // each key is only on its server
+        for &(ref kvs, ref addr, _) in servers.iter() {
+            for i in 0..100u8 {
+                assert_eq!(client.server_for(&[b'k', i]) == addr.as_str(), kvs.get(&vec![b'k', i]).is_some());
+            }
+        }
+
+        assert_eq!(Some(vec![7]), client.get(&[b'k', 7]).unwrap());
+        assert_eq!(None, client.get(b"missing").unwrap());
+
+        client.delete(&[b'k', 7]).unwrap();
+
+        let keys = vec![vec![b'k', 6], vec![b'k', 7], vec![b'k', 8], b"missing".to_vec()];
+
+        assert_eq!(vec![Some(vec![6]), None, Some(vec![8]), None], client.get_many(&keys).unwrap());
+
+        // merged from every server, in key order
+        let scanned = client.scan(b"k", 10).unwrap();
+
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6, 8, 9, 10], scanned.iter().map(|&(_, ref v)| v[0]).collect::<Vec<_>>());
+        assert_eq!(99, client.scan(b"k", 1000).unwrap().len());
+        assert!(client.scan(b"x", 10).unwrap().is_empty());
+
+        assert_eq!(2, client.increment(b"hits", 2).unwrap());
+        assert_eq!(-1, client.increment(b"hits", -3).unwrap());
+        assert!(client.increment(&[b'k', 8], 1).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//
+// Where a store gets the time from, for the timestamps of the records it writes and for expiring them
+// Without a clock set with `KVSOptions::clock`, records are timestamped by the system clock when they're made.
+// With one, the store stamps each record as it's written, so replicas and tests can decide what the time is.
+//
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use kvs::get_timestamp;
+
+/// A source of timestamps, in ms since the epoch; set with `KVSOptions::clock`
+pub trait Clock: Send + Sync {
+    /// The current time, records with a TTL before it are expired
+    fn now(&self) -> u64;
+
+    /// The timestamp of a record being written, the current time unless the clock has to do better
+    fn timestamp(&self) -> u64 {
+        self.now()
+    }
+
+    /// Sees a timestamp from elsewhere, like a change from another replica, which only a logical clock uses
+    fn update(&self, _ts: u64) { }
+}
+
+/// The clock set with the options, if there is one
+#[derive(Clone)]
+pub struct ClockSlot(pub Option<Arc<Clock>>);
+
+impl Debug for ClockSlot {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "Clock({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
+
+/// The system clock, or the virtual time of `testkit::set_clock`
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        get_timestamp()
+    }
+}
+
+/// A clock that only moves when it's told to
+pub struct ManualClock {
+    ts: AtomicU64
+}
+
+impl ManualClock {
+    pub fn new(ts: u64) -> ManualClock {
+        ManualClock { ts: AtomicU64::new(ts) }
+    }
+
+    pub fn set(&self, ts: u64) {
+        self.ts.store(ts, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.ts.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.ts.load(Ordering::SeqCst)
+    }
+}
+
+/// A hybrid logical clock, following a physical clock but never going back, or giving a timestamp twice
+///
+/// Each timestamp is the physical time, or one past the last timestamp given or seen with `update`,
+/// whichever is later. Replicas that pass the timestamps they receive to `update` order their writes
+/// after the ones they've seen, even when their physical clocks are behind. More than one timestamp a ms
+/// runs the clock ahead of the physical time, until the physical time catches up.
+pub struct HybridLogicalClock {
+    physical: Arc<Clock>,
+    last: Mutex<u64> // the latest timestamp given, or seen
+}
+
+impl Default for HybridLogicalClock {
+    fn default() -> HybridLogicalClock {
+        HybridLogicalClock::new()
+    }
+}
+
+impl HybridLogicalClock {
+    /// A clock following the system clock
+    pub fn new() -> HybridLogicalClock {
+        HybridLogicalClock::with_physical(Arc::new(SystemClock))
+    }
+
+    pub fn with_physical(physical: Arc<Clock>) -> HybridLogicalClock {
+        HybridLogicalClock { physical: physical, last: Mutex::new(0) }
+    }
+}
+
+impl Clock for HybridLogicalClock {
+    fn now(&self) -> u64 {
+        self.physical.now().max(*self.last.lock().unwrap())
+    }
+
+    fn timestamp(&self) -> u64 {
+        let mut last = self.last.lock().unwrap();
+
+        *last = self.physical.now().max(*last + 1);
+        *last
+    }
+
+    /// Moves the clock up to the timestamp, so the timestamps given after are later
+    fn update(&self, ts: u64) {
+        let mut last = self.last.lock().unwrap();
+
+        *last = (*last).max(ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::{Clock, HybridLogicalClock, ManualClock};
+    use std::sync::Arc;
+
+    #[test]
+    fn hybrid_logical_clock() {
+        let physical = Arc::new(ManualClock::new(1_000));
+        let clock = HybridLogicalClock::with_physical(physical.clone());
+
+        // follows the physical clock, but never gives the same timestamp twice
+        assert_eq!(1_000, clock.timestamp());
+        assert_eq!(1_001, clock.timestamp());
+        assert_eq!(1_002, clock.timestamp());
+        assert_eq!(1_002, clock.now());
+
+        physical.advance(10);
+
+        assert_eq!(1_010, clock.now());
+        assert_eq!(1_010, clock.timestamp());
+
+        // a timestamp from a clock that's ahead moves it forward, one that's behind doesn't move it back
+        clock.update(5_000);
+        clock.update(2_000);
+
+        assert_eq!(5_001, clock.timestamp());
+
+        physical.set(6_000);
+
+        assert_eq!(6_000, clock.timestamp());
+    }
+}
@@ -0,0 +1,91 @@
+//
+// The serde formats the store's metadata can be written in
+// The format of an SSTable is in its header, so a table written with another format is found when it's opened.
+//
+
+use bincode;
+use rmps;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor;
+
+use std::fmt::Display;
+use std::io::{Error as IOError, ErrorKind};
+
+/// The codecs, see `KVSOptions::codec`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecKind {
+    MsgPack, // compact, and self-describing
+    Bincode, // the fastest, but fields can only be added to the end of a struct
+    Cbor     // self-describing, and readable by many other tools
+}
+
+/// Serializes values to bytes, and back
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, IOError>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, IOError>;
+}
+
+impl CodecKind {
+    /// The byte identifying the codec in file headers; MsgPack is 0, as tables from before codecs used it
+    pub fn id(&self) -> u8 {
+        match *self {
+            CodecKind::MsgPack => 0,
+            CodecKind::Bincode => 1,
+            CodecKind::Cbor => 2
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<CodecKind> {
+        match id {
+            0 => Some(CodecKind::MsgPack),
+            1 => Some(CodecKind::Bincode),
+            2 => Some(CodecKind::Cbor),
+            _ => None
+        }
+    }
+}
+
+fn invalid_data<E: Display>(codec: CodecKind, e: E) -> IOError {
+    IOError::new(ErrorKind::InvalidData, format!("{:?} error: {}", codec, e))
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, IOError> {
+        match *self {
+            CodecKind::MsgPack => rmps::encode::to_vec(value).map_err(|e| invalid_data(*self, e)),
+            CodecKind::Bincode => bincode::serialize(value).map_err(|e| invalid_data(*self, e)),
+            CodecKind::Cbor => serde_cbor::to_vec(value).map_err(|e| invalid_data(*self, e))
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, IOError> {
+        match *self {
+            CodecKind::MsgPack => rmps::decode::from_slice(bytes).map_err(|e| invalid_data(*self, e)),
+            CodecKind::Bincode => bincode::deserialize(bytes).map_err(|e| invalid_data(*self, e)),
+            CodecKind::Cbor => serde_cbor::from_slice(bytes).map_err(|e| invalid_data(*self, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::{Codec, CodecKind};
+    use record::Record;
+
+    #[test]
+    fn round_trip() {
+        let recs = vec![Record::new(b"KEY".to_vec(), Some(b"VALUE".to_vec())), Record::new_range_delete(b"A".to_vec(), b"B".to_vec(), 1234)];
+
+        for &codec in [CodecKind::MsgPack, CodecKind::Bincode, CodecKind::Cbor].iter() {
+            assert_eq!(Some(codec), CodecKind::from_id(codec.id()));
+
+            let decoded :Vec<Record> = codec.decode(&codec.encode(&recs).unwrap()).unwrap();
+
+            assert_eq!(format!("{:?}", recs), format!("{:?}", decoded), "{:?}", codec);
+            assert!(codec.decode::<Vec<Record>>(&[0xFF; 3]).is_err(), "{:?}", codec);
+        }
+    }
+}
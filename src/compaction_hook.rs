@@ -0,0 +1,138 @@
+//
+// Lets embedders rewrite runs of records as they're compacted, e.g. to roll up old time series points
+//
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::iter::Peekable;
+use std::sync::Arc;
+
+use record::Record;
+
+/// Rewrites runs of records during compactions; register with `KVSOptions::compaction_hook`
+///
+/// Compactions pass every live record to `run_of`, in key order, and the records next to each other
+/// in the same run to `rewrite`. It's called from the background thread, with the tables locked.
+pub trait CompactionHook: Send + Sync {
+    /// The run the key belongs to at the time, in ms since the epoch; keys in no run are left alone
+    fn run_of(&self, key: &[u8], now: u64) -> Option<Vec<u8>>;
+
+    /// Returns the key/value pairs to write in place of a run, in key order, or None to keep it
+    ///
+    /// The keys must sort after the keys before the run, and before the keys after it.
+    /// The new records get the newest created timestamp of the run, and never expire.
+    fn rewrite(&self, run: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// The hook registered with the options, if there is one
+#[derive(Clone)]
+pub struct CompactionHookSlot(pub Option<Arc<CompactionHook>>);
+
+impl Debug for CompactionHookSlot {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "CompactionHook({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
+
+/// Passes the records of a compaction through the hook, if there is one
+pub struct Rewrite<I> where I: Iterator<Item=Record> {
+    hook: Option<Arc<CompactionHook>>,
+    records: Peekable<I>,
+    now: u64,
+    pending: VecDeque<Record> // rewritten, or kept, records of the last run
+}
+
+impl<I> Rewrite<I> where I: Iterator<Item=Record> {
+    pub fn new(hook: Option<Arc<CompactionHook>>, records: I, now: u64) -> Rewrite<I> {
+        Rewrite { hook: hook, records: records.peekable(), now: now, pending: VecDeque::new() }
+    }
+}
+
+impl<I> Iterator for Rewrite<I> where I: Iterator<Item=Record> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let hook = match self.hook {
+            Some(ref hook) => hook.clone(),
+            None => return self.records.next()
+        };
+
+        let now = self.now;
+
+        loop {
+            if let Some(rec) = self.pending.pop_front() {
+                return Some(rec);
+            }
+
+            let first = self.records.next()?;
+
+            let run = match hook.run_of(&first.key(), now) {
+                Some(run) => run,
+                None => return Some(first)
+            };
+
+            let mut records = vec![first];
+
+            while self.records.peek().map_or(false, |rec| hook.run_of(&rec.key(), now).as_ref() == Some(&run)) {
+                records.push(self.records.next().unwrap());
+            }
+
+            let pairs = records.iter().map(|rec| (rec.key().to_vec(), rec.value().to_vec())).collect::<Vec<_>>();
+
+            match hook.rewrite(&pairs) {
+                None => self.pending.extend(records),
+                Some(pairs) => {
+                    let created = records.iter().map(|rec| rec.created()).max().unwrap();
+
+                    for (key, value) in pairs {
+                        let mut rec = Record::new(key, Some(value));
+
+                        rec.set_created(created);
+                        self.pending.push_back(rec);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compaction_hook::{CompactionHook, Rewrite};
+    use record::Record;
+    use std::sync::Arc;
+
+    /// Joins the values of the keys with the same first byte, if there's more than one
+    struct Join;
+
+    impl CompactionHook for Join {
+        fn run_of(&self, key: &[u8], _now: u64) -> Option<Vec<u8>> {
+            if key[0] == b'x' { None } else { Some(key[..1].to_vec()) }
+        }
+
+        fn rewrite(&self, run: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+            if run.len() == 1 {
+                return None;
+            }
+
+            Some(vec![ (run[0].0.clone(), run.iter().flat_map(|p| p.1.clone()).collect()) ])
+        }
+    }
+
+    #[test]
+    fn rewrite_runs() {
+        let records = ["a1", "a2", "b1", "c1", "c2", "c3", "x1", "x2"].iter().map(|k| Record::new(k.as_bytes().to_vec(), Some(k[1..].as_bytes().to_vec())));
+
+        let rewritten = Rewrite::new(Some(Arc::new(Join)), records.clone(), 0).map(|rec| rec.into_parts()).collect::<Vec<_>>();
+
+        assert_eq!(vec![
+            (b"a1".to_vec(), Some(b"12".to_vec())),
+            (b"b1".to_vec(), Some(b"1".to_vec())),
+            (b"c1".to_vec(), Some(b"123".to_vec())),
+            (b"x1".to_vec(), Some(b"1".to_vec())),
+            (b"x2".to_vec(), Some(b"2".to_vec()))
+        ], rewritten);
+
+        assert_eq!(8, Rewrite::new(None, records, 0).count());
+    }
+}
@@ -0,0 +1,109 @@
+//
+// Per-SSTable value compression using a trained zstd dictionary
+// Small values don't compress well on their own, but a dictionary trained on a sample
+// of the values going into an SSTable captures what they have in common.
+//
+
+use byteorder::{ByteOrder, LE};
+use zstd::block::{Compressor, Decompressor};
+use zstd::dict::from_samples;
+
+use std::io::{Error as IOError, ErrorKind};
+use std::sync::Mutex;
+
+use record::Record;
+
+use U32_SIZE;
+
+/// zstd's default compression level
+const COMPRESSION_LEVEL: i32 = 0;
+
+/// Trains a dictionary from the values of the sampled records
+/// Returns None if there is nothing to train on, or zstd could not build a dictionary
+pub fn train_dictionary(samples: &[Record], max_size: usize) -> Option<Vec<u8>> {
+    let values = samples.iter().filter(|r| !r.is_delete()).map(|r| r.value()).collect::<Vec<_>>();
+
+    if values.is_empty() {
+        return None;
+    }
+
+    match from_samples(&values, max_size) {
+        Ok(dict) => Some(dict),
+        Err(e) => {
+            debug!("Unable to train dictionary from {} samples: {}", values.len(), e);
+            None
+        }
+    }
+}
+
+pub struct ValueCompressor {
+    compressor: Compressor
+}
+
+impl ValueCompressor {
+    pub fn new(dictionary: &[u8]) -> ValueCompressor {
+        ValueCompressor { compressor: Compressor::with_dict(dictionary.to_vec()) }
+    }
+
+    /// Compresses a value, prefixing it with the uncompressed length
+    pub fn compress(&mut self, value: &[u8]) -> Result<Vec<u8>, IOError> {
+        let compressed = self.compressor.compress(value, COMPRESSION_LEVEL)?;
+        let mut ret = vec![0x00; U32_SIZE];
+
+        LE::write_u32(&mut ret, value.len() as u32);
+        ret.extend_from_slice(&compressed);
+
+        Ok(ret)
+    }
+}
+
+pub struct ValueDecompressor {
+    decompressor: Mutex<Decompressor>
+}
+
+impl ValueDecompressor {
+    pub fn new(dictionary: &[u8]) -> ValueDecompressor {
+        ValueDecompressor { decompressor: Mutex::new(Decompressor::with_dict(dictionary.to_vec())) }
+    }
+
+    /// Decompresses a value produced by `ValueCompressor::compress`
+    pub fn decompress(&self, value: &[u8]) -> Result<Vec<u8>, IOError> {
+        if value.len() < U32_SIZE {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Compressed value too short: {}", value.len())));
+        }
+
+        let len = LE::read_u32(&value[..U32_SIZE]) as usize;
+
+        self.decompressor.lock().unwrap().decompress(&value[U32_SIZE..], len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compression::{train_dictionary, ValueCompressor, ValueDecompressor};
+    use record::Record;
+
+    #[test]
+    fn round_trip() {
+        let samples = (0..1000).map(|i| {
+            Record::new(format!("KEY_{}", i).into_bytes(), Some(format!("{{\"id\": {}, \"name\": \"user_{}\", \"active\": true}}", i, i).into_bytes()))
+        }).collect::<Vec<_>>();
+
+        let dict = train_dictionary(&samples, 4096).expect("Error training dictionary");
+        let mut compressor = ValueCompressor::new(&dict);
+        let decompressor = ValueDecompressor::new(&dict);
+
+        for rec in samples.iter() {
+            let compressed = compressor.compress(&rec.value()).unwrap();
+
+            assert_eq!(rec.value(), decompressor.decompress(&compressed).unwrap());
+        }
+    }
+
+    #[test]
+    fn no_samples() {
+        let samples = vec![Record::new(b"KEY".to_vec(), None)];
+
+        assert!(train_dictionary(&samples, 4096).is_none());
+    }
+}
@@ -0,0 +1,46 @@
+//
+// Lets embedders settle the conflicts between a store's own writes and the concurrent ones of another replica
+// Without a resolver, `KVS::apply_changes` goes by last-write-wins, and a change that loses is dropped.
+//
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+/// A change from another replica to a key this store wrote at the same time or later, by their timestamps
+///
+/// The other replica can't have seen the version here when it wrote the change, as its clock would have
+/// put the change after it, see `HybridLogicalClock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaConflict {
+    pub key: Vec<u8>,
+    pub local: Option<Vec<u8>>,  // the value here, None when it's deleted
+    pub local_ts: u64,
+    pub remote: Option<Vec<u8>>, // the value of the change, None for a delete
+    pub remote_ts: u64
+}
+
+/// How a conflict is settled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    KeepLocal,  // drop the change, like last-write-wins
+    TakeRemote, // write the change's value over the one here
+    Write(Vec<(Vec<u8>, Option<Vec<u8>>)>) // the puts and deletes to write instead, like a merged value, or both under sibling keys
+}
+
+/// Settles the conflicts of `KVS::apply_changes`; set with `KVSOptions::conflict_resolver`
+///
+/// It's called with the WAL locked, so it mustn't use the store. What it writes is given a timestamp after
+/// both versions, so it reaches the other replicas as a change that's newer than either.
+pub trait ConflictResolver: Send + Sync {
+    fn resolve(&self, conflict: &ReplicaConflict) -> Resolution;
+}
+
+/// The resolver set with the options, if there is one
+#[derive(Clone)]
+pub struct ConflictResolverSlot(pub Option<Arc<ConflictResolver>>);
+
+impl Debug for ConflictResolverSlot {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "ConflictResolver({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
@@ -0,0 +1,89 @@
+//
+// Hooks for embedders to find out about the work the store does on their behalf
+//
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Callbacks for flushes, compactions, write stalls, and low disk space; register with `KVSOptions::event_listener`
+///
+/// All the methods do nothing by default. They're called on the thread doing the write that
+/// caused the event, so they should return quickly, and can be called from many threads.
+pub trait EventListener: Send + Sync {
+    /// Called after the mem_table is written to a new current SSTable
+    fn on_flush_completed(&self, _info: &FlushInfo) { }
+
+    /// Called after the SSTables are merged and rewritten
+    fn on_compaction_completed(&self, _stats: &CompactionStats) { }
+
+    /// Called when a write has to wait for a flush or compaction before it returns
+    fn on_write_stall(&self, _stall: WriteStall) { }
+
+    /// Called when the free disk space goes under `KVSOptions::min_free_space`, and when it's back over it
+    fn on_disk_space(&self, _space: &DiskSpace) { }
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushInfo {
+    pub file_path: PathBuf,  // the new current SSTable
+    pub record_count: u64,   // records in the new current SSTable
+    pub padding_bytes: u64,  // written to align the records, see `KVSOptions::record_alignment`
+    pub duration: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactionStats {
+    pub input_tables: usize,   // SSTables merged, including the current SSTable
+    pub dropped_tables: usize, // SSTables removed by range deletes without being read
+    pub moved_tables: usize,   // SSTables kept as they were, as no other's keys overlapped theirs
+    pub output_tables: usize,
+    pub input_records: u64,    // records read, from the mem_table and SSTables
+    pub output_records: u64,   // records written, after removing the old, deleted, and expired ones
+    pub padding_bytes: u64,    // written to align the records of the new SSTables
+    pub duration: Duration
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSpace {
+    pub free_bytes: u64,     // for unprivileged users, on the store's file system
+    pub min_free_bytes: u64,
+    pub low: bool            // under the minimum, so compactions are paused, and `KVS::try_write` rejects puts
+}
+
+/// What a stalled write is waiting on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStall {
+    Flush,
+    Compaction
+}
+
+/// The listeners registered with the options
+#[derive(Clone)]
+pub struct EventListeners {
+    listeners: Vec<Arc<EventListener>>
+}
+
+impl EventListeners {
+    pub fn new() -> EventListeners {
+        EventListeners { listeners: vec![] }
+    }
+
+    pub fn add(&mut self, listener: Arc<EventListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Calls the function with every listener, in the order they were added
+    pub fn notify<F>(&self, f: F) where F: Fn(&EventListener) {
+        for listener in self.listeners.iter() {
+            f(listener.as_ref());
+        }
+    }
+}
+
+impl Debug for EventListeners {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "EventListeners({})", self.listeners.len())
+    }
+}
@@ -0,0 +1,114 @@
+//
+// Lets embedders run the flushes and compactions of stores on threads they control
+// Without an executor each store has a background thread of its own. With one, the work is handed
+// to it as jobs that run until there's nothing left to flush, so stores can share a few threads.
+//
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Error as IOError;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+/// A job for an executor
+pub type Job = Box<FnOnce() + Send>;
+
+/// Runs the background work of stores; set with `KVSOptions::executor`
+pub trait Executor: Send + Sync {
+    /// Runs the job on another thread; a job takes as long as the flushes and compaction it does
+    fn execute(&self, job: Job);
+}
+
+/// The executor set with the options, if there is one
+#[derive(Clone)]
+pub struct ExecutorSlot(pub Option<Arc<Executor>>);
+
+impl Debug for ExecutorSlot {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "Executor({})", if self.0.is_some() { "set" } else { "none" })
+    }
+}
+
+/// An executor with a fixed number of threads, running the jobs in the order they're given
+pub struct ThreadPool {
+    sender: Mutex<Option<Sender<Job>>>,
+    threads: Vec<JoinHandle<()>>
+}
+
+impl ThreadPool {
+    pub fn new(thread_count: usize) -> Result<ThreadPool, IOError> {
+        assert_ne!(thread_count, 0);
+
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut threads = Vec::with_capacity(thread_count);
+
+        for i in 0..thread_count {
+            let receiver = receiver.clone();
+
+            threads.push(thread::Builder::new().name(format!("kvs-pool-{}", i)).spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+
+                    match job {
+                        // a job that panics doesn't take the thread with it
+                        Ok(job) => if panic::catch_unwind(AssertUnwindSafe(job)).is_err() { warn!("A job panicked on the thread pool") },
+                        Err(_) => break // the pool was dropped
+                    }
+                }
+            })?);
+        }
+
+        Ok(ThreadPool { sender: Mutex::new(Some(sender)), threads: threads })
+    }
+}
+
+impl Executor for ThreadPool {
+    fn execute(&self, job: Job) {
+        self.sender.lock().unwrap().as_ref().expect("The thread pool was dropped").send(job).expect("The thread pool's threads are gone");
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Waits for the jobs already given to finish
+    fn drop(&mut self) {
+        self.sender.lock().unwrap_or_else(PoisonError::into_inner).take();
+
+        let current = thread::current().id();
+
+        // a job can hold the last reference to the pool, through a store's options
+        for handle in self.threads.drain(..) {
+            if handle.thread().id() != current {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::{Executor, ThreadPool};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn thread_pool() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        {
+            let pool = ThreadPool::new(3).unwrap();
+
+            pool.execute(Box::new(|| panic!("A panicking job")));
+
+            for _ in 0..100 {
+                let count = count.clone();
+
+                pool.execute(Box::new(move || { count.fetch_add(1, Ordering::SeqCst); }));
+            }
+        }
+
+        // dropping the pool finished the jobs
+        assert_eq!(100, count.load(Ordering::SeqCst));
+    }
+}
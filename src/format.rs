@@ -0,0 +1,532 @@
+//
+// The encodings of RecordFiles and SSTables, as functions on byte slices
+// Nothing here touches a file, so the formats can be read from any buffer, e.g. a file read into memory,
+// and fuzzed on their own; record_file and sstable do the reading and writing on top.
+//
+
+use byteorder::{ByteOrder, WriteBytesExt, LE};
+use crc32fast::hash as crc32;
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IOError, ErrorKind};
+
+use record::{Record, RecordRef};
+use codec::CodecKind;
+
+use U32_SIZE;
+use U64_SIZE;
+
+/// The record count of a file that was created, but never had its count written out
+pub const BAD_COUNT: u32 = 0xFFFFFFFF;
+
+pub const SSTABLE_HEADER: &[u8; 8] = b"DATA\x08\x00\x00\x00"; // the 6th byte is the codec of the SSTableInfo
+
+/// What's wrong with bytes that don't decode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    BadHeader,                                      // the bytes don't start with the expected header
+    BadCount,                                       // the file's record count was never written out
+    UnknownCodec(u8),                               // the SSTable header names a codec this version doesn't have
+    Truncated { offset: u64, len: u64, end: u64 },  // the len bytes at the offset run past the end
+    BadChecksum,                                    // a record doesn't match its CRC
+    BadRecord(String)                               // a record with lengths that don't add up
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::BadHeader => write!(f, "Invalid file header"),
+            FormatError::BadCount => write!(f, "Bad record file; record_count == BAD_COUNT"),
+            FormatError::UnknownCodec(id) => write!(f, "Unknown codec {} in the header", id),
+            FormatError::Truncated { offset, len, end } => write!(f, "{} bytes at {} are past the end: {}", len, offset, end),
+            FormatError::BadChecksum => write!(f, "Record does not match its checksum"),
+            FormatError::BadRecord(ref reason) => write!(f, "Bad record: {}", reason)
+        }
+    }
+}
+
+impl Error for FormatError { }
+
+impl From<FormatError> for IOError {
+    fn from(e: FormatError) -> IOError {
+        let kind = match e {
+            FormatError::Truncated { .. } => ErrorKind::UnexpectedEof,
+            _ => ErrorKind::InvalidData
+        };
+
+        IOError::new(kind, e)
+    }
+}
+
+/// The length of a RecordFile's header, record count, and offset of its last record
+pub fn file_prefix_len(header: &[u8]) -> usize {
+    header.len() + U32_SIZE + U64_SIZE
+}
+
+/// Encodes the start of a RecordFile: the header, the record count, and the offset of the last record
+pub fn encode_file_prefix(header: &[u8], record_count: u32, last_record: u64) -> Vec<u8> {
+    let mut buff = Vec::with_capacity(file_prefix_len(header));
+
+    buff.extend_from_slice(header);
+    buff.write_u32::<LE>(record_count).expect("Error writing to a Vec");
+    buff.write_u64::<LE>(last_record).expect("Error writing to a Vec");
+
+    buff
+}
+
+/// Decodes the start of a RecordFile with the header, returning the record count and the offset of the last record
+pub fn decode_file_prefix(bytes: &[u8], header: &[u8]) -> Result<(u32, u64), FormatError> {
+    let prefix_len = file_prefix_len(header);
+
+    if bytes.len() < prefix_len {
+        return Err(FormatError::Truncated { offset: 0, len: prefix_len as u64, end: bytes.len() as u64 });
+    }
+
+    if &bytes[..header.len()] != header {
+        return Err(FormatError::BadHeader);
+    }
+
+    let record_count = LE::read_u32(&bytes[header.len()..]);
+
+    if record_count == BAD_COUNT {
+        return Err(FormatError::BadCount);
+    }
+
+    Ok( (record_count, LE::read_u64(&bytes[header.len() + U32_SIZE..])) )
+}
+
+/// Where a record of the size goes when the file ends at the offset, so it doesn't cross a block boundary
+///
+/// The bytes up to there are padding, zeros. With no alignment, or a record too large for what's left
+/// of a block, it starts at the offset.
+pub fn aligned_offset(offset: u64, alignment: u64, rec_size: usize) -> u64 {
+    let in_block = if alignment == 0 { 0 } else { offset % alignment };
+
+    if in_block == 0 || in_block + (U32_SIZE + rec_size) as u64 <= alignment {
+        offset
+    } else {
+        offset - in_block + alignment
+    }
+}
+
+/// Returns the offset of the record at, or after the padding at, the offset
+///
+/// Padding is too short for a size, or starts with a size of 0; `read_size` reads the size at the offset,
+/// and is only called when that's what tells them apart.
+pub fn skip_padding<F, E>(offset: u64, alignment: u64, read_size: F) -> Result<u64, E>
+    where F: FnOnce(u64) -> Result<Option<u32>, E>
+{
+    let in_block = if alignment == 0 { 0 } else { offset % alignment };
+
+    if in_block == 0 {
+        return Ok(offset);
+    }
+
+    let next_block = offset - in_block + alignment;
+
+    if next_block - offset < U32_SIZE as u64 {
+        return Ok(next_block);
+    }
+
+    // None at the end of the file
+    Ok(match read_size(offset)? {
+        Some(0) => next_block,
+        _ => offset
+    })
+}
+
+/// Borrows the record at the offset of a file's bytes, after its size
+pub fn record_at(file: &[u8], offset: u64) -> Result<&[u8], FormatError> {
+    let start = offset.saturating_add(U32_SIZE as u64); // the offset can come from a damaged file
+
+    if start > file.len() as u64 {
+        return Err(FormatError::Truncated { offset: offset, len: U32_SIZE as u64, end: file.len() as u64 });
+    }
+
+    let rec_size = LE::read_u32(&file[offset as usize..]) as u64;
+
+    if start + rec_size > file.len() as u64 {
+        return Err(FormatError::Truncated { offset: start, len: rec_size, end: file.len() as u64 });
+    }
+
+    Ok(&file[start as usize..(start + rec_size) as usize])
+}
+
+/// Iterates over the offsets and records of a RecordFile's bytes, like `RecordFile::iter_with_offsets`
+pub struct Records<'a> {
+    file: &'a [u8],
+    alignment: u64,
+    cur_offset: u64,
+    ends_at: u64
+}
+
+/// Iterates over the records of a whole RecordFile read into memory
+/// * alignment - the block size the records were aligned to, 0 for none
+pub fn records<'a>(file: &'a [u8], header: &[u8], alignment: u64) -> Result<Records<'a>, FormatError> {
+    let (record_count, last_record) = decode_file_prefix(file, header)?;
+    let first_offset = file_prefix_len(header) as u64;
+
+    let ends_at = if record_count == 0 { first_offset } else {
+        last_record + (U32_SIZE + record_at(file, last_record)?.len()) as u64
+    };
+
+    Ok(Records { file: file, alignment: alignment, cur_offset: first_offset, ends_at: ends_at })
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<(u64, &'a [u8]), FormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cur_offset >= self.ends_at {
+            return None;
+        }
+
+        let file = self.file;
+
+        let rec = skip_padding(self.cur_offset, self.alignment, |offset| {
+            Ok(if offset + U32_SIZE as u64 > file.len() as u64 { None } else { Some(LE::read_u32(&file[offset as usize..])) })
+        }).and_then(|offset| record_at(file, offset).map(|rec| (offset, rec)));
+
+        match rec {
+            Ok((offset, rec)) => self.cur_offset = offset + (U32_SIZE + rec.len()) as u64,
+            Err(_) => self.cur_offset = self.ends_at // the rest of the file can't be found
+        }
+
+        Some(rec)
+    }
+}
+
+/// The header of an SSTable written with the codec
+pub fn sstable_header(codec: CodecKind) -> [u8; 8] {
+    let mut header = *SSTABLE_HEADER;
+
+    header[5] = codec.id();
+
+    header
+}
+
+/// Reads the codec from the header of an SSTable
+pub fn parse_sstable_header(header: &[u8]) -> Result<CodecKind, FormatError> {
+    if header.len() < SSTABLE_HEADER.len() || header[..5] != SSTABLE_HEADER[..5] || header[6..SSTABLE_HEADER.len()] != SSTABLE_HEADER[6..] {
+        return Err(FormatError::BadHeader);
+    }
+
+    CodecKind::from_id(header[5]).ok_or(FormatError::UnknownCodec(header[5]))
+}
+
+/// Records in an SSTable are stored with their key prefix-compressed against the first key of their group:
+/// |-----------------------------------|
+/// | shared prefix length, 4-bytes     |
+/// |-----------------------------------|
+/// | record with key suffix ...        |
+/// |-----------------------------------|
+/// | CRC32 of the above, 4-bytes       |
+/// |-----------------------------------|
+/// The first record of a group always has a shared prefix length of 0, so it can be decoded on its own.
+/// Compressing against the first key, instead of the previous one, keeps binary searches within a group possible.
+pub fn encode_record(rec: &Record, shared: usize) -> Vec<u8> {
+    let mut suffix_rec = rec.to_owned();
+    suffix_rec.set_key(rec.key()[shared..].to_vec());
+
+    let mut buff = Vec::with_capacity(U32_SIZE + suffix_rec.size() as usize);
+
+    buff.write_u32::<LE>(shared as u32).expect("Error writing to a Vec");
+    suffix_rec.serialize_body(&mut buff).expect("Error writing to a Vec");
+
+    let crc = crc32(&buff);
+    buff.write_u32::<LE>(crc).expect("Error writing to a Vec");
+
+    buff
+}
+
+/// Decodes a record written by `encode_record`, given the first key of its group
+/// If verify_checksum is set, an error is returned when the record doesn't match its CRC
+pub fn decode_record(buff: &[u8], group_key: &[u8], verify_checksum: bool) -> Result<Record, FormatError> {
+    decode_record_with(buff, group_key, verify_checksum, false)
+}
+
+/// Decodes a record written by `encode_record` without copying its value, which is left empty; for scans of keys
+pub fn decode_record_key(buff: &[u8], group_key: &[u8], verify_checksum: bool) -> Result<Record, FormatError> {
+    decode_record_with(buff, group_key, verify_checksum, true)
+}
+
+fn decode_record_with(buff: &[u8], group_key: &[u8], verify_checksum: bool, keys_only: bool) -> Result<Record, FormatError> {
+    let (shared, suffix_rec) = decode_record_ref(buff, group_key, verify_checksum)?;
+    let mut rec = if keys_only { suffix_rec.to_key_record() } else { suffix_rec.to_record() };
+
+    if shared != 0 {
+        let mut key = group_key[..shared].to_vec();
+        key.extend_from_slice(suffix_rec.key());
+        rec.set_key(key);
+    }
+
+    Ok(rec)
+}
+
+/// Checks a record written by `encode_record` against its CRC, returning the length of the prefix it shares
+/// with its group key; for scanning damaged files, where the group key may be lost
+pub fn verify_record(buff: &[u8]) -> Result<usize, FormatError> {
+    let (shared, _) = parse_record(buff, true)?;
+
+    Ok(shared)
+}
+
+/// Compares the key of a record written by `encode_record` to a key, without copying the record
+pub fn compare_record_key(buff: &[u8], group_key: &[u8], key: &[u8], verify_checksum: bool) -> Result<Ordering, FormatError> {
+    let (shared, suffix_rec) = decode_record_ref(buff, group_key, verify_checksum)?;
+
+    Ok(compare_split_key(&group_key[..shared], suffix_rec.key(), key))
+}
+
+/// Compares the key made of a prefix and a suffix to a key, without joining them; each part is compared with memcmp
+pub fn compare_split_key(prefix: &[u8], suffix: &[u8], key: &[u8]) -> Ordering {
+    let split = prefix.len().min(key.len());
+
+    match prefix[..split].cmp(&key[..split]) {
+        Ordering::Equal if split < prefix.len() => Ordering::Greater, // the key is a prefix of the prefix
+        Ordering::Equal => suffix.cmp(&key[split..]),
+        ord => ord
+    }
+}
+
+/// Borrows the record, with its key suffix, returning the length of the prefix it shares with the group key
+pub fn decode_record_ref<'a>(buff: &'a [u8], group_key: &[u8], verify_checksum: bool) -> Result<(usize, RecordRef<'a>), FormatError> {
+    let (shared, rec) = parse_record(buff, verify_checksum)?;
+
+    if shared > group_key.len() {
+        return Err(FormatError::BadRecord(format!("Record shares more of the key than the group has: {} > {}", shared, group_key.len())));
+    }
+
+    Ok( (shared, rec) )
+}
+
+/// Borrows the record, with its key suffix, and the length of the prefix it shares, without the group key
+fn parse_record<'a>(buff: &'a [u8], verify_checksum: bool) -> Result<(usize, RecordRef<'a>), FormatError> {
+    if buff.len() < U32_SIZE * 2 {
+        return Err(FormatError::BadRecord(format!("Record is too short: {}", buff.len())));
+    }
+
+    let crc_offset = buff.len() - U32_SIZE;
+
+    if verify_checksum && crc32(&buff[..crc_offset]) != LE::read_u32(&buff[crc_offset..]) {
+        return Err(FormatError::BadChecksum);
+    }
+
+    let shared = LE::read_u32(&buff[..U32_SIZE]) as usize;
+    let rec = RecordRef::parse(&buff[U32_SIZE..crc_offset]).map_err(|e| FormatError::BadRecord(e.to_string()))?;
+
+    Ok( (shared, rec) )
+}
+
+/// Returns the length of the prefix shared by both keys
+///
+/// Compares 8 bytes at a time, or 16 with the simd feature on x86_64, as it's run on every record an SSTable is written with.
+pub fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let len = a.len().min(b.len());
+    let mut i = shared_prefix_len_simd(a, b, len);
+
+    while i + U64_SIZE <= len {
+        let diff = LE::read_u64(&a[i..]) ^ LE::read_u64(&b[i..]);
+
+        // read little endian, the first byte that differs is the lowest one set
+        if diff != 0 {
+            return i + diff.trailing_zeros() as usize / 8;
+        }
+
+        i += U64_SIZE;
+    }
+
+    while i < len && a[i] == b[i] {
+        i += 1;
+    }
+
+    i
+}
+
+/// The length of the prefix shared by the 16 byte chunks of both keys, up to len, compared with SSE2
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+fn shared_prefix_len_simd(a: &[u8], b: &[u8], len: usize) -> usize {
+    use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_cmpeq_epi8, _mm_movemask_epi8};
+
+    let mut i = 0;
+
+    while i + 16 <= len {
+        // SSE2 is on every x86_64 CPU, and the loads are unaligned and within both slices
+        let equal = unsafe {
+            let x = _mm_loadu_si128(a[i..].as_ptr() as *const __m128i);
+            let y = _mm_loadu_si128(b[i..].as_ptr() as *const __m128i);
+
+            _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32
+        };
+
+        if equal != 0xFFFF {
+            return i + (!equal).trailing_zeros() as usize;
+        }
+
+        i += 16;
+    }
+
+    i
+}
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+fn shared_prefix_len_simd(_a: &[u8], _b: &[u8], _len: usize) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use format::{file_prefix_len, encode_file_prefix, decode_file_prefix, aligned_offset, skip_padding, record_at, records, decode_record_key};
+    use format::{sstable_header, parse_sstable_header, encode_record, decode_record, compare_record_key, compare_split_key, shared_prefix_len, FormatError, BAD_COUNT};
+    use record::Record;
+    use codec::CodecKind;
+    use byteorder::{WriteBytesExt, LE};
+    use std::cmp::Ordering;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    const HEADER: &[u8; 4] = b"ABCD";
+
+    /// Lays out a file of the records, the way RecordFile appends them
+    fn file_of(recs: &[Vec<u8>], alignment: u64) -> Vec<u8> {
+        let mut file = encode_file_prefix(HEADER, 0, 0);
+        let mut last_record = 0;
+
+        for rec in recs {
+            let loc = aligned_offset(file.len() as u64, alignment, rec.len());
+
+            file.resize(loc as usize, 0);
+            file.write_u32::<LE>(rec.len() as u32).unwrap();
+            file.extend_from_slice(rec);
+            last_record = loc;
+        }
+
+        let prefix = encode_file_prefix(HEADER, recs.len() as u32, last_record);
+
+        file[..prefix.len()].copy_from_slice(&prefix);
+        file
+    }
+
+    #[test]
+    fn file_prefix() {
+        let prefix = encode_file_prefix(HEADER, 7, 1234);
+
+        assert_eq!(file_prefix_len(HEADER), prefix.len());
+        assert_eq!(Ok( (7, 1234) ), decode_file_prefix(&prefix, HEADER));
+        assert_eq!(Err(FormatError::BadHeader), decode_file_prefix(&prefix, b"DCBA"));
+        assert_eq!(Err(FormatError::BadCount), decode_file_prefix(&encode_file_prefix(HEADER, BAD_COUNT, 0), HEADER));
+        assert_eq!(Err(FormatError::Truncated { offset: 0, len: 16, end: 15 }), decode_file_prefix(&prefix[..15], HEADER));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut end = file_prefix_len(HEADER) as u64;
+        let mut locs = vec![];
+
+        for &size in [40, 10, 44, 10, 100, 3].iter() {
+            let loc = aligned_offset(end, 64, size);
+
+            locs.push(loc);
+            end = loc + 4 + size as u64;
+        }
+
+        // the same as RecordFile's aligned test
+        assert_eq!(vec![16, 64, 78, 128, 192, 296], locs);
+        assert_eq!(100, aligned_offset(100, 0, 1000));
+
+        // too short for a size, a size of 0, a record, then the end of the file
+        assert_eq!(Ok::<_, ()>(128), skip_padding(126, 64, |_| panic!("Read the size")));
+        assert_eq!(Ok::<_, ()>(128), skip_padding(100, 64, |_| Ok(Some(0))));
+        assert_eq!(Ok::<_, ()>(100), skip_padding(100, 64, |_| Ok(Some(10))));
+        assert_eq!(Ok::<_, ()>(100), skip_padding(100, 64, |_| Ok(None)));
+    }
+
+    #[test]
+    fn records_in_memory() {
+        for &alignment in [0, 64].iter() {
+            let recs = (0..20).map(|i| vec![i as u8 + 1; (i * 7) % 50 + 1]).collect::<Vec<_>>();
+            let file = file_of(&recs, alignment);
+
+            let read = records(&file, HEADER, alignment).unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+
+            assert_eq!(recs, read.iter().map(|&(_, rec)| rec.to_vec()).collect::<Vec<_>>());
+
+            for &(offset, rec) in read.iter() {
+                assert_eq!(rec, record_at(&file, offset).unwrap());
+            }
+
+            // a file cut in the middle of its last record can't be iterated
+            assert!(records(&file[..file.len() - 1], HEADER, alignment).is_err());
+        }
+
+        assert_eq!(0, records(&file_of(&[], 0), HEADER, 0).unwrap().count());
+
+        // a damaged last record offset, close to the largest there is
+        assert!(record_at(&file_of(&[], 0), u64::max_value() - 1).is_err());
+        assert!(records(&encode_file_prefix(HEADER, 1, u64::max_value() - 1), HEADER, 0).is_err());
+    }
+
+    #[test]
+    fn sstable_records() {
+        assert_eq!(Ok(CodecKind::Cbor), parse_sstable_header(&sstable_header(CodecKind::Cbor)));
+        assert_eq!(Err(FormatError::UnknownCodec(9)), parse_sstable_header(b"DATA\x08\x09\x00\x00"));
+        assert_eq!(Err(FormatError::BadHeader), parse_sstable_header(b"DATA"));
+
+        let group_key = b"KEY_0001".to_vec();
+        let rec = Record::new(b"KEY_0042".to_vec(), Some(b"VALUE".to_vec()));
+        let shared = shared_prefix_len(&group_key, rec.key());
+        let mut buff = encode_record(&rec, shared);
+
+        assert_eq!(6, shared);
+        assert_eq!(rec, decode_record(&buff, &group_key, true).unwrap());
+        assert_eq!(b"VALUE", decode_record(&buff, &group_key, true).unwrap().value());
+        assert_eq!(b"", decode_record_key(&buff, &group_key, true).unwrap().value());
+        assert_eq!(rec.key(), decode_record_key(&buff, &group_key, true).unwrap().key());
+
+        // compared in place, with the prefix from the group's key
+        assert_eq!(Ok(Ordering::Equal), compare_record_key(&buff, &group_key, b"KEY_0042", true));
+        assert_eq!(Ok(Ordering::Greater), compare_record_key(&buff, &group_key, b"KEY_0041", true));
+        assert_eq!(Ok(Ordering::Less), compare_record_key(&buff, &group_key, b"KEY_00420", true));
+
+        // the prefix can't be longer than the group's key
+        assert!(decode_record(&buff, b"KEY", false).is_err());
+
+        buff[6] ^= 0xFF;
+
+        assert_eq!(Err(FormatError::BadChecksum), decode_record(&buff, &group_key, true));
+        assert_eq!(Err(FormatError::BadChecksum), compare_record_key(&buff, &group_key, b"KEY_0042", true));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn records_garbage(bytes in vec(any::<u8>(), 0..256), alignment in 0..3u64) {
+            let mut file = encode_file_prefix(HEADER, 3, 20);
+
+            file.extend(bytes);
+
+            if let Ok(recs) = records(&file, HEADER, alignment * 32) {
+                let _ = recs.count();
+            }
+        }
+
+        #[test]
+        fn sstable_record_garbage(bytes in vec(any::<u8>(), 0..128), group_key in vec(any::<u8>(), 0..16)) {
+            let _ = decode_record(&bytes, &group_key, false);
+        }
+
+        // b shares a prefix of any length with a, to cross the chunks compared at once
+        #[test]
+        fn key_comparisons(a in vec(0..3u8, 0..48), shared in 0..48usize, tail in vec(0..3u8, 0..8), split in 0..48usize) {
+            let mut b = a[..shared.min(a.len())].to_vec();
+            b.extend(tail);
+            let split = split.min(a.len());
+
+            prop_assert_eq!(a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count(), shared_prefix_len(&a, &b));
+            prop_assert_eq!(a.cmp(&b), compare_split_key(&a[..split], &a[split..], &b));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//
+// The most read keys of a store, to find skewed workloads; see `KVSOptions::hot_keys`
+// Each read is counted in a count-min sketch, which never counts a key low, and only counts it high
+// when the keys sharing its counters are read too. The keys with the highest counts are kept, up to
+// the capacity. Every DECAY_READS reads the counts are halved, so keys that cool off drop out.
+//
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bloom::hash_key;
+
+const SKETCH_ROWS: usize = 4;
+const SKETCH_WIDTH: usize = 4096;   // counters per row
+const DECAY_READS: u64 = 1_000_000;
+
+/// The counts of the reads, and the hottest keys
+pub struct HotKeys {
+    capacity: usize,
+    sketch: Mutex<Sketch>
+}
+
+struct Sketch {
+    counters: Vec<u32>,       // SKETCH_ROWS rows of SKETCH_WIDTH
+    reads: u64,
+    top: HashMap<Vec<u8>, u64>,
+    floor: u64                // at most the lowest count in top, once it's full
+}
+
+impl HotKeys {
+    /// Keeps the capacity hottest keys; 0 doesn't count reads at all
+    pub fn new(capacity: usize) -> HotKeys {
+        let counters = if capacity == 0 { vec![] } else { vec![0; SKETCH_ROWS * SKETCH_WIDTH] };
+
+        HotKeys { capacity: capacity, sketch: Mutex::new(Sketch { counters: counters, reads: 0, top: HashMap::new(), floor: 0 }) }
+    }
+
+    /// Counts a read of the key
+    pub fn record(&self, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        // the rows are indexed by two halves of one hash, like the bloom filters
+        let hash = hash_key(key);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as u32 as usize);
+
+        let mut sketch = self.sketch.lock().unwrap();
+        let mut count = u32::max_value();
+
+        for row in 0..SKETCH_ROWS {
+            let i = row * SKETCH_WIDTH + h1.wrapping_add(row.wrapping_mul(h2)) % SKETCH_WIDTH;
+
+            sketch.counters[i] = sketch.counters[i].saturating_add(1);
+            count = count.min(sketch.counters[i]);
+        }
+
+        let count = count as u64;
+
+        if let Some(c) = sketch.top.get_mut(key) {
+            *c = count;
+        } else if sketch.top.len() < self.capacity {
+            sketch.top.insert(key.to_vec(), count);
+        } else if count > sketch.floor {
+            let (coldest, lowest) = sketch.top.iter().map(|(k, &c)| (k.clone(), c)).min_by_key(|&(_, c)| c).expect("The top keys are full");
+
+            if count > lowest {
+                sketch.top.remove(&coldest);
+                sketch.top.insert(key.to_vec(), count);
+            }
+
+            sketch.floor = sketch.top.values().cloned().min().unwrap_or(0);
+        }
+
+        sketch.reads += 1;
+
+        if sketch.reads % DECAY_READS == 0 {
+            sketch.decay();
+        }
+    }
+
+    /// The hottest keys, and their approximate reads, most read first
+    pub fn top(&self, n: usize) -> Vec<(Vec<u8>, u64)> {
+        let sketch = self.sketch.lock().unwrap();
+        let mut top = sketch.top.iter().map(|(k, &c)| (k.clone(), c)).collect::<Vec<_>>();
+
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+
+        top
+    }
+}
+
+impl Sketch {
+    fn decay(&mut self) {
+        for c in self.counters.iter_mut() {
+            *c /= 2;
+        }
+
+        for c in self.top.values_mut() {
+            *c /= 2;
+        }
+
+        self.top.retain(|_, c| *c > 0);
+        self.floor /= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hot_keys::HotKeys;
+
+    #[test]
+    fn top() {
+        let hot_keys = HotKeys::new(3);
+
+        // key i is read 100 / i times, with many keys read once
+        for i in 1..20u32 {
+            for _ in 0..(100 / i) {
+                hot_keys.record(format!("KEY_{}", i).as_bytes());
+            }
+        }
+
+        for i in 0..1000u32 {
+            hot_keys.record(format!("COLD_{}", i).as_bytes());
+        }
+
+        let top = hot_keys.top(10);
+
+        assert_eq!(vec![b"KEY_1".to_vec(), b"KEY_2".to_vec(), b"KEY_3".to_vec()], top.iter().map(|t| t.0.clone()).collect::<Vec<_>>());
+
+        // counted high, never low
+        assert!(top[0].1 >= 100 && top[1].1 >= 50 && top[2].1 >= 33);
+        assert_eq!(1, hot_keys.top(1).len());
+
+        let off = HotKeys::new(0);
+
+        off.record(b"KEY");
+        assert!(off.top(10).is_empty());
+    }
+}
@@ -0,0 +1,810 @@
+//
+// An HTTP server with a JSON API, for quick integrations, and debugging with curl
+// Built with the `http` feature. It's a plain HTTP/1.1 server on std::net, with a thread per connection,
+// which is plenty for tools, and a few clients, but not meant for thousands of connections.
+//
+// GET    /keys/{key}                        {"key": ..., "value": ...}, or 404
+// PUT    /keys/{key}                        the body is the value; 507 over a quota, or low on disk space, 503 when read-only, see `KVS::try_put`
+// DELETE /keys/{key}                        503 when read-only
+// POST   /counters/{key}?delta=..       {"key": ..., "count": ...}, the count after adding delta, 1 by default; 503 when read-only, see `KVS::increment`
+// GET    /scan?prefix=..&limit=..&cursor=.. {"entries": [{"key": ..., "value": ...}], "cursor": ...}
+// GET    /stats                             see `StoreStats::to_json`
+// GET    /healthz                           see `Health::to_json`; 503 when unhealthy, and no token is needed
+//
+// Connections are kept open for more requests, unless the client sends `Connection: close`,
+// or is idle for a minute; see the client module for a client that pools them.
+// Bodies are sent with a single Content-Length; Transfer-Encoding gets a 501.
+//
+// With an ACL, see the acl module, each request needs a token, as `Authorization: Bearer <token>`,
+// and is only allowed the keys and prefixes the token's ACL allows.
+//
+// With the `tls` feature, and a `TlsConfig`, the server only speaks HTTPS.
+//
+// Keys, prefixes, and cursors in the URL, and the keys and values in the responses, use the encoding
+// given with `?encoding=`: `utf8`, the default, with keys percent-encoded in the URL; `hex`; or `base64`,
+// the URL-safe alphabet without padding.
+//
+
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use acl::Acl;
+use kvs::{KVS, IncrementError, ReadOptions, WriteBatch, WriteError, WriteOptions};
+use stats::json_string;
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 10_000;
+const IDLE_TIMEOUT_SECS: u64 = 60;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// How keys and values are written in URLs and responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Utf8,
+    Hex,
+    Base64
+}
+
+impl Encoding {
+    fn parse(name: &str) -> Option<Encoding> {
+        match name {
+            "utf8" => Some(Encoding::Utf8),
+            "hex" => Some(Encoding::Hex),
+            "base64" => Some(Encoding::Base64),
+            _ => None
+        }
+    }
+
+    /// Decodes a key from the URL, after its percent-encoding is removed
+    pub(crate) fn decode(&self, s: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Encoding::Utf8 => Some(s.to_vec()),
+            Encoding::Hex => {
+                if s.len() % 2 != 0 {
+                    return None;
+                }
+
+                s.chunks(2).map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?)).collect()
+            },
+            Encoding::Base64 => {
+                let digits = s.iter().take_while(|&&b| b != b'=').map(|&b| BASE64.iter().position(|&c| c == b).map(|d| d as u32)).collect::<Option<Vec<_>>>()?;
+
+                if digits.len() % 4 == 1 {
+                    return None;
+                }
+
+                let mut bytes = Vec::with_capacity(digits.len() * 3 / 4);
+
+                for chunk in digits.chunks(4) {
+                    let n = chunk.iter().enumerate().fold(0, |n, (i, d)| n | d << (18 - 6 * i));
+
+                    bytes.extend(n.to_be_bytes()[1..chunk.len()].iter());
+                }
+
+                Some(bytes)
+            }
+        }
+    }
+
+    /// Encodes a key or value as a JSON string, or null when it isn't UTF-8 and that's the encoding
+    fn encode_json(&self, bytes: &[u8]) -> String {
+        match *self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok().map_or("null".to_string(), |s| json_string(&s)),
+            Encoding::Hex => format!("\"{}\"", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            Encoding::Base64 => {
+                let mut s = String::with_capacity((bytes.len() + 2) / 3 * 4 + 2);
+
+                s.push('"');
+
+                for chunk in bytes.chunks(3) {
+                    let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+
+                    for i in 0..chunk.len() + 1 {
+                        s.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+                    }
+                }
+
+                s.push('"');
+                s
+            }
+        }
+    }
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                if i + 2 >= bytes.len() {
+                    return None;
+                }
+
+                ret.push(hex_digit(bytes[i + 1])? << 4 | hex_digit(bytes[i + 2])?);
+                i += 3;
+            },
+            b'+' => { ret.push(b' '); i += 1; },
+            b => { ret.push(b); i += 1; }
+        }
+    }
+
+    Some(ret)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    token: Option<String>,  // from an `Authorization: Bearer` header
+    close: bool,            // the client doesn't want the connection kept open
+    body: Vec<u8>
+}
+
+impl Request {
+    /// Reads the next request, or None when the client closed the connection
+    fn read<R: Read>(stream: &mut BufReader<R>) -> Result<Option<Request>, IOError> {
+        let invalid = |msg: &str| IOError::new(ErrorKind::InvalidData, msg.to_string());
+        let mut line = String::new();
+        let mut header_bytes = 0;
+
+        if stream.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let (method, target, mut close) = {
+            let mut parts = line.split_whitespace();
+
+            match (parts.next(), parts.next(), parts.next()) {
+                // only HTTP/1.1 keeps connections open without being asked
+                (Some(method), Some(target), version) => (method.to_string(), target.to_string(), version != Some("HTTP/1.1")),
+                _ => return Err(invalid("Bad request line"))
+            }
+        };
+
+        let mut content_length = None;
+        let mut token = None;
+
+        loop {
+            line.clear();
+            header_bytes += stream.read_line(&mut line)?;
+
+            if header_bytes > MAX_HEADER_BYTES {
+                return Err(invalid("The headers are too large"));
+            }
+
+            let header = line.trim_end();
+
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(i) = header.find(':') {
+                if header[..i].eq_ignore_ascii_case("content-length") {
+                    let value = header[i + 1..].trim();
+
+                    // a body whose length two servers could read differently isn't read at all
+                    if content_length.is_some() {
+                        return Err(invalid("More than one Content-Length"));
+                    }
+
+                    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                        return Err(invalid("Bad Content-Length"));
+                    }
+
+                    content_length = Some(value.parse::<usize>().map_err(|_| invalid("Bad Content-Length"))?);
+                } else if header[..i].eq_ignore_ascii_case("transfer-encoding") {
+                    return Err(IOError::new(ErrorKind::Unsupported, "Transfer-Encoding isn't supported, send a Content-Length"));
+                } else if header[..i].eq_ignore_ascii_case("connection") {
+                    let value = header[i + 1..].trim();
+
+                    close = if value.eq_ignore_ascii_case("close") { true } else if value.eq_ignore_ascii_case("keep-alive") { false } else { close };
+                } else if header[..i].eq_ignore_ascii_case("authorization") {
+                    let value = header[i + 1..].trim();
+
+                    if value.len() > 7 && value.get(..7).map_or(false, |scheme| scheme.eq_ignore_ascii_case("bearer ")) {
+                        token = Some(value[7..].trim().to_string());
+                    }
+                }
+            }
+        }
+
+        let content_length = content_length.unwrap_or(0);
+
+        if content_length > MAX_BODY_BYTES {
+            return Err(invalid("The body is too large"));
+        }
+
+        let mut body = vec![0; content_length];
+
+        stream.read_exact(&mut body)?;
+
+        let (path, query) = match target.find('?') {
+            Some(i) => (target[..i].to_string(), &target[i + 1..]),
+            None => (target.clone(), "")
+        };
+
+        let query = query.split('&').filter(|p| !p.is_empty()).map(|pair| {
+            let (name, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, "")
+            };
+
+            (name.to_string(), value.to_string())
+        }).collect();
+
+        Ok(Some(Request { method: method, path: path, query: query, token: token, close: close, body: body }))
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    body: String
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        Response { status: status, body: body }
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response::json(status, format!("{{\"error\":{}}}", json_string(msg)))
+    }
+
+    fn write<W: Write>(&self, stream: &mut W, close: bool) -> Result<(), IOError> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            507 => "Insufficient Storage",
+            _ => "Internal Server Error"
+        };
+
+        let challenge = if self.status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+
+        write!(stream, "HTTP/1.1 {} {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+               self.status, reason, challenge, self.body.len(), if close { "close" } else { "keep-alive" }, self.body)?;
+        stream.flush()
+    }
+}
+
+/// Serves a store over HTTP, see the top of this module for the API
+pub struct HttpServer {
+    kvs: Arc<KVS>,
+    listener: TcpListener,
+    acl: Option<Arc<Acl>>,  // None lets anyone do anything
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>
+}
+
+impl HttpServer {
+    /// Listens on the address, such as "127.0.0.1:8080"; port 0 picks a free port
+    pub fn bind(kvs: Arc<KVS>, addr: &str) -> Result<HttpServer, IOError> {
+        Ok(HttpServer {
+            kvs: kvs,
+            listener: TcpListener::bind(addr)?,
+            acl: None,
+            #[cfg(feature = "tls")]
+            tls: None
+        })
+    }
+
+    /// Requires a token from the ACL with each request, and only allows what the token's ACL allows.
+    ///
+    /// Default: none, anyone can read and write any key
+    pub fn acl(&mut self, acl: Acl) -> &mut HttpServer {
+        self.acl = Some(Arc::new(acl)); self
+    }
+
+    /// Serves HTTPS, with the certificate, and checking client certificates, of the config.
+    ///
+    /// Default: none, plain HTTP
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, tls: TlsConfig) -> &mut HttpServer {
+        self.tls = Some(tls); self
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr, IOError> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until accepting a connection fails, each connection on a thread of its own
+    pub fn serve(&self) -> Result<(), IOError> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+
+            stream.set_read_timeout(Some(Duration::from_secs(IDLE_TIMEOUT_SECS)))?;
+
+            let kvs = self.kvs.clone();
+            let acl = self.acl.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+
+            thread::Builder::new().name("kvs-http".to_string()).spawn(move || {
+                let acl = acl.as_ref().map(|acl| &**acl);
+
+                #[cfg(feature = "tls")]
+                let result = match tls {
+                    Some(tls) => tls.accept(stream).and_then(|stream| handle_connection(&kvs, acl, stream)),
+                    None => handle_connection(&kvs, acl, stream)
+                };
+
+                #[cfg(not(feature = "tls"))]
+                let result = handle_connection(&kvs, acl, stream);
+
+                if let Err(e) = result {
+                    debug!("Error serving an HTTP request: {}", e);
+                }
+            })?;
+        }
+
+        Ok( () )
+    }
+}
+
+fn handle_connection<S: Read + Write>(kvs: &KVS, acl: Option<&Acl>, stream: S) -> Result<(), IOError> {
+    // responses are written under the reader, which only buffers what the client has sent
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let (response, close) = match Request::read(&mut reader) {
+            Ok(Some(request)) => (handle(kvs, acl, &request), request.close),
+            Ok(None) => return Ok( () ),
+            // the rest of a bad request can't be found, so the connection is closed
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (Response::error(400, &e.to_string()), true),
+            Err(ref e) if e.kind() == ErrorKind::Unsupported => (Response::error(501, &e.to_string()), true),
+            Err(e) => return Err(e)
+        };
+
+        response.write(reader.get_mut(), close)?;
+
+        if close {
+            return Ok( () );
+        }
+    }
+}
+
+fn handle(kvs: &KVS, acl: Option<&Acl>, request: &Request) -> Response {
+    // for load balancers, which don't have tokens
+    if request.path == "/healthz" && request.method == "GET" {
+        let health = kvs.health();
+
+        return Response::json(if health.is_healthy() { 200 } else { 503 }, health.to_json());
+    }
+
+    let principal = match acl {
+        None => None,
+        Some(acl) => match request.token.as_ref().and_then(|token| acl.authenticate(token)) {
+            Some(principal) => Some(principal),
+            None => return Response::error(401, "A known token is required, as Authorization: Bearer <token>")
+        }
+    };
+
+    // without an ACL, everything is allowed
+    let can_read = |key: &[u8]| principal.map_or(true, |p| p.can_read(key));
+    let can_write = |key: &[u8]| principal.map_or(true, |p| p.can_write(key));
+    let forbidden = || Response::error(403, &format!("{} isn't allowed to do that", principal.map_or("", |p| p.name.as_str())));
+
+    let encoding = match Encoding::parse(request.param("encoding").unwrap_or("utf8")) {
+        Some(encoding) => encoding,
+        None => return Response::error(400, "The encoding must be utf8, hex, or base64")
+    };
+
+    // keys, prefixes, and cursors are in the encoding, after the URL's percent-encoding
+    let decode = |s: &str| percent_decode(s).and_then(|s| encoding.decode(&s));
+
+    if request.path.starts_with("/keys/") {
+        let key = match decode(&request.path["/keys/".len()..]) {
+            Some(key) => key,
+            None => return Response::error(400, "The key isn't in the encoding")
+        };
+
+        return match request.method.as_str() {
+            "GET" if !can_read(&key) => forbidden(),
+            "PUT" | "DELETE" if !can_write(&key) => forbidden(),
+            "GET" => match kvs.try_get(&key, &ReadOptions::new()) {
+                Ok(Some(value)) => Response::json(200, format!("{{\"key\":{},\"value\":{}}}", encoding.encode_json(&key), encoding.encode_json(&value))),
+                Ok(None) => Response::error(404, "Not found"),
+                Err(e) => Response::error(500, &e.to_string())
+            },
+            "PUT" => match kvs.try_put(key, request.body.clone()) {
+                Ok( () ) => Response::json(204, String::new()),
+                Err(e @ WriteError::ReadOnly(_)) => Response::error(503, &e.to_string()),
+                Err(e) => Response::error(507, &e.to_string())
+            },
+            "DELETE" => {
+                let mut batch = WriteBatch::new();
+
+                batch.delete(&key);
+
+                match kvs.try_write(batch, &WriteOptions::new()) {
+                    Ok( () ) => Response::json(204, String::new()),
+                    Err(e @ WriteError::ReadOnly(_)) => Response::error(503, &e.to_string()),
+                    Err(e) => Response::error(507, &e.to_string())
+                }
+            },
+            _ => Response::error(405, "Keys can be read with GET, written with PUT, and removed with DELETE")
+        };
+    }
+
+    if request.path.starts_with("/counters/") {
+        let key = match decode(&request.path["/counters/".len()..]) {
+            Some(key) => key,
+            None => return Response::error(400, "The key isn't in the encoding")
+        };
+
+        let delta = match request.param("delta").map(|d| d.parse::<i64>()) {
+            None => 1,
+            Some(Ok(delta)) => delta,
+            Some(Err(_)) => return Response::error(400, "The delta must be a 64-bit integer")
+        };
+
+        return match request.method.as_str() {
+            "POST" if !can_read(&key) || !can_write(&key) => forbidden(),
+            "POST" => match kvs.increment(&key, delta) {
+                Ok(count) => Response::json(200, format!("{{\"key\":{},\"count\":{}}}", encoding.encode_json(&key), count)),
+                Err(e @ IncrementError::ReadOnly(_)) => Response::error(503, &e.to_string()),
+                Err(e) => Response::error(409, &e.to_string())
+            },
+            _ => Response::error(405, "Counters are incremented with POST")
+        };
+    }
+
+    if request.method != "GET" {
+        return Response::error(405, "Only GET is allowed");
+    }
+
+    match request.path.as_str() {
+        "/scan" => {
+            let prefix = match decode(request.param("prefix").unwrap_or("")) {
+                Some(prefix) => prefix,
+                None => return Response::error(400, "The prefix isn't in the encoding")
+            };
+
+            if !can_read(&prefix) {
+                return forbidden();
+            }
+
+            let limit = match request.param("limit").map(|l| l.parse::<usize>()) {
+                None => DEFAULT_SCAN_LIMIT,
+                Some(Ok(limit)) if limit > 0 && limit <= MAX_SCAN_LIMIT => limit,
+                Some(_) => return Response::error(400, &format!("The limit must be from 1 to {}", MAX_SCAN_LIMIT))
+            };
+
+            // cursors are always hex, they aren't meant to be read
+            let cursor = match request.param("cursor").map(|c| percent_decode(c).and_then(|c| Encoding::Hex.decode(&c))) {
+                None => None,
+                Some(Some(cursor)) => Some(cursor),
+                Some(None) => return Response::error(400, "The cursor isn't hex")
+            };
+
+            match kvs.prefix_page(&prefix, limit, cursor.as_ref().map(|c| c.as_slice())) {
+                Ok(page) => {
+                    let entries = page.entries.iter().map(|&(ref k, ref v)| format!("{{\"key\":{},\"value\":{}}}", encoding.encode_json(k), encoding.encode_json(v))).collect::<Vec<_>>();
+                    let cursor = page.cursor.map_or("null".to_string(), |c| Encoding::Hex.encode_json(&c));
+
+                    Response::json(200, format!("{{\"entries\":[{}],\"cursor\":{}}}", entries.join(","), cursor))
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound => Response::error(404, &e.to_string()),
+                Err(e) => Response::error(400, &e.to_string())
+            }
+        },
+        "/stats" if !principal.map_or(true, |p| p.stats) => forbidden(),
+        "/stats" => Response::json(200, kvs.stats().to_json()),
+        _ => Response::error(404, "Not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use acl::Acl;
+    use http::{HttpServer, Encoding, percent_decode};
+    use kvs::KVSOptions;
+    use std::fs::{self, File};
+    use std::io::{Error as IOError, ErrorKind, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use test_path::gen_dir;
+
+    /// Sends the request, returning the status and body of the response
+    fn send(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, String) {
+        send_as(addr, None, method, target, body)
+    }
+
+    fn send_as(addr: SocketAddr, token: Option<&str>, method: &str, target: &str, body: &[u8]) -> (u16, String) {
+        exchange(TcpStream::connect(addr).unwrap(), token, method, target, body).unwrap()
+    }
+
+    /// Sends the bytes as a request, returning the status of the response
+    fn send_raw(addr: SocketAddr, request: &[u8]) -> u16 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+
+        stream.write_all(request).unwrap();
+        stream.read_to_string(&mut response).unwrap();
+
+        response[9..12].parse().unwrap()
+    }
+
+    fn exchange<S: Read + Write>(mut stream: S, token: Option<&str>, method: &str, target: &str, body: &[u8]) -> Result<(u16, String), IOError> {
+        let auth = token.map_or(String::new(), |t| format!("Authorization: Bearer {}\r\n", t));
+
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", method, target, auth, body.len())?;
+        stream.write_all(body)?;
+
+        let mut response = String::new();
+
+        stream.read_to_string(&mut response)?;
+
+        if !response.starts_with("HTTP/1.1 ") {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Not an HTTP response: {:?}", response)));
+        }
+
+        let status = response[9..12].parse().unwrap();
+        let body = response[response.find("\r\n\r\n").unwrap() + 4..].to_string();
+
+        Ok( (status, body) )
+    }
+
+    #[test]
+    fn encodings() {
+        for &encoding in [Encoding::Utf8, Encoding::Hex, Encoding::Base64].iter() {
+            for len in 0..8 {
+                let bytes = (0..len).map(|i| b'a' + i as u8).collect::<Vec<_>>();
+                let json = encoding.encode_json(&bytes);
+
+                assert_eq!(Some(bytes), encoding.decode(json[1..json.len() - 1].as_bytes()), "{:?}", encoding);
+            }
+        }
+
+        assert_eq!("\"AP8\"", Encoding::Base64.encode_json(&[0x00, 0xFF]));
+        assert_eq!("null", Encoding::Utf8.encode_json(&[0xFF]));
+        assert_eq!(None, Encoding::Hex.decode(b"ABC"));
+        assert_eq!(Some(b"a b/c".to_vec()), percent_decode("a+b%2Fc"));
+        assert_eq!(None, percent_decode("a%2"));
+    }
+
+    #[test]
+    fn serve() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.quota(&b"q/".to_vec(), None, Some(1));
+
+        let kvs = Arc::new(options.create().unwrap());
+        let server = HttpServer::bind(kvs, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || server.serve());
+
+        assert_eq!(204, send(addr, "PUT", "/keys/some%20key", b"a \"value\"").0);
+        assert_eq!((200, "{\"key\":\"some key\",\"value\":\"a \\\"value\\\"\"}".to_string()), send(addr, "GET", "/keys/some%20key", b""));
+
+        for i in 0..5 {
+            assert_eq!(204, send(addr, "PUT", &format!("/keys/ff{:02x}?encoding=hex", i), &[i]).0);
+        }
+
+        assert_eq!((200, "{\"key\":\"_wM\",\"value\":\"Aw\"}".to_string()), send(addr, "GET", "/keys/_wM?encoding=base64", b""));
+        assert_eq!(204, send(addr, "DELETE", "/keys/ff03?encoding=hex", b"").0);
+        assert_eq!(404, send(addr, "GET", "/keys/ff03?encoding=hex", b"").0);
+
+        let (status, body) = send(addr, "GET", "/scan?prefix=ff&limit=2&encoding=hex", b"");
+
+        assert_eq!(200, status);
+        assert!(body.starts_with("{\"entries\":[{\"key\":\"ff00\",\"value\":\"00\"},{\"key\":\"ff01\",\"value\":\"01\"}],\"cursor\":\""), "{}", body);
+
+        let cursor = body.rsplit('"').nth(1).unwrap().to_string();
+
+        assert_eq!((200, "{\"entries\":[{\"key\":\"ff02\",\"value\":\"02\"},{\"key\":\"ff04\",\"value\":\"04\"}],\"cursor\":null}".to_string()),
+                   send(addr, "GET", &format!("/scan?prefix=ff&limit=2&encoding=hex&cursor={}", cursor), b""));
+
+        assert_eq!(400, send(addr, "GET", "/scan?limit=0", b"").0);
+        assert_eq!(400, send(addr, "GET", "/keys/0?encoding=hex", b"").0);
+        assert_eq!(400, send(addr, "GET", "/stats?encoding=rot13", b"").0);
+        assert_eq!(405, send(addr, "POST", "/keys/a", b"").0);
+
+        assert_eq!((200, "{\"key\":\"hits\",\"count\":1}".to_string()), send(addr, "POST", "/counters/hits", b""));
+        assert_eq!((200, "{\"key\":\"hits\",\"count\":-4}".to_string()), send(addr, "POST", "/counters/hits?delta=-5", b""));
+        assert_eq!(400, send(addr, "POST", "/counters/hits?delta=x", b"").0);
+        assert_eq!(409, send(addr, "POST", "/counters/some%20key", b"").0);
+        assert_eq!(405, send(addr, "GET", "/counters/hits", b"").0);
+
+        assert_eq!(204, send(addr, "PUT", "/keys/q%2F1", b"1").0);
+        assert_eq!(507, send(addr, "PUT", "/keys/q%2F2", b"1").0);
+        assert_eq!(404, send(addr, "GET", "/other", b"").0);
+
+        let (status, body) = send(addr, "GET", "/stats", b"");
+
+        assert_eq!(200, status);
+        assert!(body.starts_with("{\"mem_records\":"));
+        let (status, body) = send(addr, "GET", "/healthz", b"");
+
+        assert_eq!(200, status);
+        assert!(body.starts_with("{\"healthy\":true,"), "{}", body);
+    }
+
+    #[test]
+    fn serve_read_only() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(10);
+
+        let kvs = Arc::new(options.create().unwrap());
+
+        // a directory where the next WAL would go, so the full mem_table can't be swapped, and the store is read-only
+        let next = fs::read_dir(&*db_dir).unwrap()
+            .filter_map(|entry| entry.unwrap().path().file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()))
+            .max().unwrap() + 1;
+
+        fs::create_dir(db_dir.join(format!("{:06}.wal", next))).unwrap();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        assert!(kvs.background_error().is_some());
+
+        let server = HttpServer::bind(kvs, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || server.serve());
+
+        assert_eq!(503, send(addr, "PUT", "/keys/KEY_0", b"1").0);
+        assert_eq!(503, send(addr, "DELETE", "/keys/KEY_0", b"").0);
+        assert_eq!(503, send(addr, "POST", "/counters/hits", b"").0);
+        assert_eq!((200, "{\"key\":\"KEY_0\",\"value\":\"VALUE\"}".to_string()), send(addr, "GET", "/keys/KEY_0", b""));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let db_dir = gen_dir();
+        let kvs = Arc::new(KVSOptions::new(&db_dir).create().unwrap());
+        let server = HttpServer::bind(kvs, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || server.serve());
+
+        assert_eq!(204, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\n1"));
+
+        // bodies whose length could be read differently
+        assert_eq!(501, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n1\r\n1\r\n0\r\n\r\n"));
+        assert_eq!(501, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: identity\r\n\r\n1"));
+        assert_eq!(400, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n12"));
+        assert_eq!(400, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 1\r\n\r\n1"));
+        assert_eq!(400, send_raw(addr, b"PUT /keys/a HTTP/1.1\r\nContent-Length: +1\r\n\r\n1"));
+
+        // a scheme that isn't ASCII isn't a bearer token
+        assert_eq!(204, send_raw(addr, "PUT /keys/a HTTP/1.1\r\nAuthorization: beareré token\r\nContent-Length: 1\r\nConnection: close\r\n\r\n2".as_bytes()));
+        assert_eq!((200, "{\"key\":\"a\",\"value\":\"2\"}".to_string()), send(addr, "GET", "/keys/a", b""));
+    }
+
+    #[test]
+    fn serve_acl() {
+        let db_dir = gen_dir();
+        let acl_path = db_dir.join("acl.toml");
+
+        File::create(&acl_path).unwrap().write_all(b"[[tokens]]\nname = \"a\"\ntoken = \"secret-a\"\nread = [\"a/\"]\nwrite = [\"a/\"]\n\n\
+                                                      [[tokens]]\nname = \"b\"\ntoken = \"secret-b\"\nread = [\"b/\", \"a/\"]\nwrite = [\"b/\"]\nstats = true\n").unwrap();
+
+        let kvs = Arc::new(KVSOptions::new(&db_dir).create().unwrap());
+        let mut server = HttpServer::bind(kvs, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        server.acl(Acl::from_toml(&acl_path).unwrap());
+
+        thread::spawn(move || server.serve());
+
+        // a token is required, and must be known
+        assert_eq!(401, send(addr, "GET", "/keys/a%2F1", b"").0);
+        assert_eq!(401, send_as(addr, Some("secret"), "GET", "/keys/a%2F1", b"").0);
+
+        assert_eq!(204, send_as(addr, Some("secret-a"), "PUT", "/keys/a%2F1", b"1").0);
+        assert_eq!(403, send_as(addr, Some("secret-a"), "PUT", "/keys/b%2F1", b"1").0);
+        assert_eq!(204, send_as(addr, Some("secret-b"), "PUT", "/keys/b%2F1", b"1").0);
+
+        assert_eq!(200, send_as(addr, Some("secret-b"), "GET", "/keys/a%2F1", b"").0);
+        assert_eq!(403, send_as(addr, Some("secret-b"), "DELETE", "/keys/a%2F1", b"").0);
+        assert_eq!(403, send_as(addr, Some("secret-a"), "GET", "/keys/b%2F1", b"").0);
+
+        // a scan must be within a readable prefix
+        assert_eq!((200, "{\"entries\":[{\"key\":\"a/1\",\"value\":\"1\"}],\"cursor\":null}".to_string()),
+                   send_as(addr, Some("secret-a"), "GET", "/scan?prefix=a%2F", b""));
+        assert_eq!(403, send_as(addr, Some("secret-a"), "GET", "/scan?prefix=a", b"").0);
+        assert_eq!(403, send_as(addr, Some("secret-a"), "GET", "/scan", b"").0);
+
+        // a cursor can't be changed to resume the scan outside the prefix
+        assert_eq!(204, send_as(addr, Some("secret-a"), "PUT", "/keys/a%2F2", b"2").0);
+
+        let (status, body) = send_as(addr, Some("secret-a"), "GET", "/scan?prefix=a%2F&limit=1", b"");
+
+        assert_eq!(200, status, "{}", body);
+
+        let cursor = Encoding::Hex.decode(body.rsplit('"').nth(1).unwrap().as_bytes()).unwrap();
+        let mut tampered = cursor[..1 + 8].to_vec();
+
+        tampered.extend_from_slice(b"b/");
+
+        let tampered = Encoding::Hex.encode_json(&tampered);
+
+        assert_eq!(400, send_as(addr, Some("secret-a"), "GET", &format!("/scan?prefix=a%2F&cursor={}", &tampered[1..tampered.len() - 1]), b"").0);
+
+        assert_eq!(403, send_as(addr, Some("secret-a"), "GET", "/stats", b"").0);
+        assert_eq!(200, send(addr, "GET", "/healthz", b"").0); // for load balancers
+        assert_eq!(200, send_as(addr, Some("secret-b"), "GET", "/stats", b"").0);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn serve_tls() {
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use rustls::crypto::ring;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+        use rustls::pki_types::pem::PemObject;
+        use std::convert::TryFrom;
+        use tls::TlsConfig;
+
+        let fixture = |name: &str| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("tls").join(name);
+
+        let db_dir = gen_dir();
+        let kvs = Arc::new(KVSOptions::new(&db_dir).create().unwrap());
+        let mut server = HttpServer::bind(kvs, "127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        server.tls(TlsConfig::from_pem(&fixture("server.pem"), &fixture("server.key"), Some(&fixture("ca.pem"))).unwrap());
+
+        thread::spawn(move || server.serve());
+
+        let mut roots = RootCertStore::empty();
+
+        roots.add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap()).unwrap();
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap().with_root_certificates(roots);
+
+        let with_cert = Arc::new(builder.clone().with_client_auth_cert(
+            vec![CertificateDer::from_pem_file(fixture("client.pem")).unwrap()],
+            PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap()).unwrap());
+        let without_cert = Arc::new(builder.with_no_client_auth());
+
+        let send_tls = |config: &Arc<ClientConfig>, method: &str, target: &str, body: &[u8]| {
+            let conn = ClientConnection::new(config.clone(), ServerName::try_from("localhost").unwrap()).unwrap();
+
+            exchange(StreamOwned::new(conn, TcpStream::connect(addr).unwrap()), None, method, target, body)
+        };
+
+        assert_eq!(204, send_tls(&with_cert, "PUT", "/keys/a", b"1").unwrap().0);
+        assert_eq!((200, "{\"key\":\"a\",\"value\":\"1\"}".to_string()), send_tls(&with_cert, "GET", "/keys/a", b"").unwrap());
+
+        // clients without a certificate, and without TLS, get no response
+        assert!(send_tls(&without_cert, "GET", "/keys/a", b"").is_err());
+        assert!(exchange(TcpStream::connect(addr).unwrap(), None, "GET", "/keys/a", b"").is_err());
+    }
+}
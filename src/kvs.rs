@@ -1,35 +1,136 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::fs;
-use std::io::{Error as IOError};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error as IOError, ErrorKind, Read, Write};
 use std::iter;
+use std::mem;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
-
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, BE, LE};
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
 use itertools::kmerge;
 use itertools::Itertools;
 
-use regex::Regex;
-
-use record_file::RecordFile;
-use sstable::SSTable;
-use record::Record;
+use record_file::{RecordFile, drop_page_cache, free_space};
+use sstable::{SSTable, SSTableOptions, NewSSTable, DEFAULT_READAHEAD};
+use table_cache::{TableCache, TableMeta};
+use manifest::Manifest;
+use mem_table::{WalMemTable, MemTableView, MemTableKind};
+use codec::CodecKind;
+use lock_manager::LockManager;
+use version::{Version, VersionSet};
+use record::{Record, BATCH_TAG};
+use events::{EventListener, EventListeners, FlushInfo, CompactionStats, WriteStall, DiskSpace};
+use stats::{StoreStats, LevelStats, CacheStats, Health};
+use check::{CheckReport, Inconsistency, check_overlaps, check_seqs};
+use format::{file_prefix_len, decode_file_prefix};
+use quota::{Quota, Quotas, QuotaExceeded};
+use ttl::{ttl_for, PrefixTtl};
+use hot_keys::HotKeys;
+use cache::{CacheOptions, CachePolicyKind};
+use meta_cache::MetaCache;
+use row_cache::RowCache;
+use compaction_hook::{CompactionHook, CompactionHookSlot, Rewrite};
+use executor::{Executor, ExecutorSlot};
+use clock::{Clock, ClockSlot};
+use conflict_resolver::{ConflictResolver, ConflictResolverSlot, ReplicaConflict, Resolution};
+use sim::{self, CrashPoint};
+use wal_archive;
+use value_log::{ValueLog, ValuePointer};
+
+use {U32_SIZE, U64_SIZE};
 
 const WAL_HEADER: &[u8; 8] = b"WAL!\x01\x00\x00\x00";
+const OPTIONS_FILE: &str = "OPTIONS";
+const CLEAN_SHUTDOWN_FILE: &str = "CLEAN_SHUTDOWN";
 
 // constants for now
 const DEFAULT_MEM_COUNT: usize = 100_000;
-const DEFAULT_GROUP_COUNT: u32 = 10_000;
+const DEFAULT_TARGET_BLOCK_BYTES: usize = 512 * 1024;
 const DEFAULT_FILE_COUNT: usize = 6;
 const DEFAULT_BUFFER_SIZE: usize = 4096;
 const DEFAULT_CACHE_SIZE: usize = 100_000;
+const DEFAULT_DICT_SIZE: usize = 0;
+const DEFAULT_RECORD_ALIGNMENT: usize = 0;
+const DEFAULT_MAX_OPEN_TABLES: usize = 1_000;
+const DEFAULT_MAX_IMMUTABLES: usize = 2;
+const DEFAULT_WAL_RETENTION: usize = 0;
+const DEFAULT_WAL_SEGMENT_SIZE: u64 = 0;
+const DEFAULT_MAX_WAL_BYTES: u64 = 0;
+const DEFAULT_TTL_COMPACTION_PERCENT: usize = 50;
+const DEFAULT_VALUE_LOG_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const EXPIRED_CHECK_INTERVAL_MS: u64 = 60_000; // how often an idle store looks for expired SSTables
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 1_000;
+const LOCK_STRIPES: usize = 64;
+const CHANGE_BATCH: usize = 1_000; // the most changes read from a WAL with it locked
+const DEFAULT_CURSOR_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_MAX_CURSORS: usize = 1_000;
+const CURSOR_VERSION: u8 = 1;
+const MAX_HOT_KEYS: usize = 10_000;
+const STATS_HOT_KEYS: usize = 10;   // the hot keys shown in the stats
+const DEFAULT_IO_RETRIES: usize = 3;
+const DEFAULT_IO_RETRY_DELAY_MS: u64 = 100;
+const MAX_IO_RETRIES: usize = 10;   // the last waits 2^9 times the delay
+const SPACE_CHECK_INTERVAL_MS: u64 = 1_000; // how often the free space is checked, see `KVSOptions::min_free_space`
+const INGEST_CACHE_SIZE: usize = 100; // the records cached while checking a table to ingest
 
 #[derive(Debug, Clone)]
 pub struct KVSOptions {
     max_mem_count: usize,
-    group_count: u32,
+    group_count: Option<u32>,
+    target_block_bytes: usize,
+    group_by_size: bool,
     file_count: usize,
     rec_file_buffer_size: usize,
     rec_file_cache_size: usize,
+    cache_policy: CachePolicyKind,
+    meta_cache_size: usize,
+    pin_metadata: bool,
+    row_cache_size: usize,
+    dict_size: usize,
+    record_alignment: usize,
+    max_open_tables: usize,
+    max_immutables: usize,
+    mem_table: MemTableKind,
+    codec: CodecKind,
+    wal_retention: usize,
+    wal_segment_size: u64,
+    max_wal_bytes: u64,
+    wal_archive_dir: Option<PathBuf>,
+    ttl_compaction_percent: usize,
+    periodic_compaction_ms: u64,
+    sync_writes: bool,
+    preallocate: bool,
+    compaction_drop_inputs: bool,
+    compaction_prefetch_indexes: bool,
+    compaction_trivial_move: bool,
+    value_log_threshold: usize,
+    value_log_file_size: u64,
+    io_retries: usize,
+    io_retry_delay_ms: u64,
+    min_free_space: u64,
+    check_on_open: bool,
+    cursor_timeout_ms: u64,
+    max_cursors: usize,
+    hot_keys: usize,
+    quotas: Vec<Quota>,
+    default_ttl_ms: u64,
+    prefix_ttls: Vec<PrefixTtl>,
+    listeners: EventListeners,
+    compaction_hook: CompactionHookSlot,
+    executor: ExecutorSlot,
+    clock: ClockSlot,
+    conflict_resolver: ConflictResolverSlot,
     db_dir: PathBuf
 }
 
@@ -43,10 +144,50 @@ impl KVSOptions {
     /// ```
     pub fn new(db_dir: &PathBuf) -> KVSOptions {
         KVSOptions { max_mem_count: DEFAULT_MEM_COUNT,
-            group_count: DEFAULT_GROUP_COUNT,
+            group_count: None,
+            target_block_bytes: DEFAULT_TARGET_BLOCK_BYTES,
+            group_by_size: false,
             file_count: DEFAULT_FILE_COUNT,
             rec_file_buffer_size: DEFAULT_BUFFER_SIZE,
             rec_file_cache_size: DEFAULT_CACHE_SIZE,
+            cache_policy: CachePolicyKind::TinyLfu,
+            meta_cache_size: 0,
+            pin_metadata: false,
+            row_cache_size: 0,
+            dict_size: DEFAULT_DICT_SIZE,
+            record_alignment: DEFAULT_RECORD_ALIGNMENT,
+            max_open_tables: DEFAULT_MAX_OPEN_TABLES,
+            max_immutables: DEFAULT_MAX_IMMUTABLES,
+            mem_table: MemTableKind::SkipList,
+            codec: CodecKind::MsgPack,
+            wal_retention: DEFAULT_WAL_RETENTION,
+            wal_segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            max_wal_bytes: DEFAULT_MAX_WAL_BYTES,
+            wal_archive_dir: None,
+            ttl_compaction_percent: DEFAULT_TTL_COMPACTION_PERCENT,
+            periodic_compaction_ms: 0,
+            sync_writes: false,
+            preallocate: false,
+            compaction_drop_inputs: false,
+            compaction_prefetch_indexes: false,
+            compaction_trivial_move: false,
+            value_log_threshold: 0,
+            value_log_file_size: DEFAULT_VALUE_LOG_FILE_SIZE,
+            io_retries: DEFAULT_IO_RETRIES,
+            io_retry_delay_ms: DEFAULT_IO_RETRY_DELAY_MS,
+            min_free_space: 0,
+            check_on_open: false,
+            cursor_timeout_ms: DEFAULT_CURSOR_TIMEOUT_MS,
+            max_cursors: DEFAULT_MAX_CURSORS,
+            hot_keys: 0,
+            quotas: vec![],
+            default_ttl_ms: 0,
+            prefix_ttls: vec![],
+            listeners: EventListeners::new(),
+            compaction_hook: CompactionHookSlot(None),
+            executor: ExecutorSlot(None),
+            clock: ClockSlot(None),
+            conflict_resolver: ConflictResolverSlot(None),
             db_dir: db_dir.to_path_buf()
         }
     }
@@ -66,9 +207,29 @@ impl KVSOptions {
     ///
     /// The number of `u64` records kept in memory per data file equals: `num_records / group_count`
     ///
-    /// Default: 10,000
+    /// Default: selected for each data file using `target_block_bytes`
     pub fn group_count(&mut self, count: u32) -> &mut KVSOptions {
-        self.group_count = count; self
+        self.group_count = Some(count); self
+    }
+
+    /// The approximate size of a group of records in the data files, when `group_count` isn't set.
+    ///
+    /// The number of records in a group is picked from the average size of the records going
+    /// into each data file. Larger groups mean less memory for indices, but more reads per lookup.
+    ///
+    /// Default: 512KB
+    pub fn target_block_bytes(&mut self, size: usize) -> &mut KVSOptions {
+        self.target_block_bytes = size; self
+    }
+
+    /// Groups the records in the data files by size instead of count, each closed once it reaches `target_block_bytes`.
+    ///
+    /// Every lookup reads about the same number of bytes, even when the sizes of the values vary widely,
+    /// but the groups of small records are larger, and so are the indices of the groups of large ones.
+    ///
+    /// Default: false
+    pub fn group_by_size(&mut self, group_by_size: bool) -> &mut KVSOptions {
+        self.group_by_size = group_by_size; self
     }
 
     /// The number of files to keep in the database directory.
@@ -101,707 +262,8250 @@ impl KVSOptions {
         self.rec_file_cache_size = count; self
     }
 
-    /// Creates a `KVS` instance using the configured options.
+    /// Which records the record caches of the SSTables keep, see `CachePolicyKind`
     ///
-    /// **This should only be called when creating a new `KVS` instance, not opening an existing one.**
-    /// To open an existing KVS directory/database, use the `KVS::open` function.
+    /// LRU keeps the most recently read, so scans push out records that are read over and over.
+    /// TinyLFU keeps the most often read, only caching a record read once if it's read more than the
+    /// record it replaces, at the cost of a few bytes of counters per record of the cache.
     ///
-    /// # Examples
-    /// ```
-    /// let kvs = KVSOptions::new("/tmp/kvs").create().unwrap();
-    /// ```
-    /// # Panics
-    /// If any of the options are nonsensical.
-    pub fn create(self) -> Result<KVS, IOError> {
-        if self.max_mem_count < 2 { panic!("mem_count must be greater than 1: {}", self.max_mem_count); }
-        if self.group_count < 100 { panic!("group_count is too small, make > 100: {}", self.group_count); }
-        if self.file_count < 2 { panic!("file_count is too small, try > 2: {}", self.file_count); }
-        if self.rec_file_buffer_size < 4096 { panic!("file_buffer is too small, try > 4096: {}", self.rec_file_buffer_size); }
-        if self.rec_file_cache_size < 1 { panic!("cache_size must be greater than 1: {}", self.rec_file_cache_size); }
+    /// Default: `CachePolicyKind::TinyLfu`
+    pub fn cache_policy(&mut self, kind: CachePolicyKind) -> &mut KVSOptions {
+        self.cache_policy = kind; self
+    }
 
-        KVS::new(self)
+    /// The bytes of the cache of bloom filters and index blocks, which is kept apart from the record caches
+    ///
+    /// Lookups read a filter, and usually index blocks, before any record, so with their own cache, a
+    /// churn of records doesn't push them out. It's shared by all the SSTables. With 0, unless they're pinned,
+    /// each SSTable keeps its filters once read, and the index blocks are read through the record caches.
+    ///
+    /// Default: 0
+    pub fn meta_cache_size(&mut self, bytes: usize) -> &mut KVSOptions {
+        self.meta_cache_size = bytes; self
     }
-}
 
-pub struct KVS {
-    options: KVSOptions,
-    cur_sstable_num: u64,
-    wal_file: RecordFile,
-    mem_table: BTreeMap<Vec<u8>, Record>,
-    cur_sstable: SSTable,
-    sstables: BTreeSet<SSTable>,
-}
+    /// Keeps the filter and index block of every partition of every SSTable in memory, for as long as the table exists
+    ///
+    /// They're loaded when a table is created or opened, and aren't counted against `meta_cache_size`.
+    /// This store only has levels 0 and 1, so that's all the tables; the memory is about 1.25 bytes
+    /// per record, shown as `pinned_bytes` in `KVS::stats`.
+    ///
+    /// Default: false
+    pub fn pin_metadata(&mut self, pin: bool) -> &mut KVSOptions {
+        self.pin_metadata = pin; self
+    }
 
-/// Gets the timestamp/epoch in ms
-pub fn get_timestamp() -> u64 {
-    let ts = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    /// The number of keys whose newest record is cached, when it was read from the SSTables
+    ///
+    /// A hit skips searching the tables, so it's the fastest lookup of a hot key that isn't in a mem_table.
+    /// Each write removes its key, and a range delete removes the keys in its range. The memory used is
+    /// about the size of the keys and values, plus 64 bytes for each.
+    ///
+    /// Default: 0, off
+    pub fn row_cache_size(&mut self, count: usize) -> &mut KVSOptions {
+        self.row_cache_size = count; self
+    }
 
-    return ts.as_secs() * 1000 + ts.subsec_nanos() as u64 / 1_000_000;
-}
+    /// The max size of the zstd dictionary trained for the values of each SSTable.
+    ///
+    /// When set, a sample of the values going into each SSTable is used to train a dictionary
+    /// that is stored with the table, and all values are compressed with it. This works well
+    /// when there are many small values that look alike. A size of 0 disables compression.
+    ///
+    /// Default: 0
+    pub fn dict_size(&mut self, size: usize) -> &mut KVSOptions {
+        self.dict_size = size; self
+    }
 
+    /// The size of the blocks the records of SSTables are aligned to, so none crosses a block.
+    ///
+    /// For reading SSTables with direct I/O, or through mmap, a whole block at a time. A record that
+    /// would cross into the next block starts there instead, after padding; the padding written is in
+    /// `FlushInfo` and `CompactionStats`. Must be a power of 2 of at least 512, like 4096, or 0 to disable it.
+    ///
+    /// Default: 0
+    pub fn record_alignment(&mut self, size: usize) -> &mut KVSOptions {
+        self.record_alignment = size; self
+    }
 
-/// Used to coalesce records during iteration
-fn coalesce_records(prev: Record, curr: Record) -> Result<Record, (Record, Record)> {
-    // we always go with the newest timestamp
-    if prev.key() == curr.key() {
-        Ok(if prev.created() > curr.created() { prev } else { curr })
-    } else {
-        Err( (prev, curr) )
+    /// The max number of data files kept open at once.
+    ///
+    /// Data files are opened when they're needed, and the least recently used one is closed
+    /// when there are too many open. Each open data file uses 2 file handles.
+    ///
+    /// Default: 1,000
+    pub fn max_open_tables(&mut self, count: usize) -> &mut KVSOptions {
+        self.max_open_tables = count; self
     }
-}
 
-/*
- * Files have the following meanings:
- * data.wal       - Write Ahead Log; journal of all put & deletes that are in mem_table
- * table.current  - SSTable with the merges from mem_table
- * table-#.data   - SSTables without overlapping ranges
- * *-new          - A new version of the file with the same name
- */
-impl KVS {
-    /// Creates a new KVS given a directory to store the files
-    fn new(options: KVSOptions) -> Result<KVS, IOError> {
-        let db_dir = options.db_dir.to_path_buf();
-        let mut mem_table = BTreeMap::new();
+    /// The max number of full mem_tables waiting to be flushed.
+    ///
+    /// A full mem_table is flushed by a background thread while writes go to a new one.
+    /// When this many are waiting, writes stall until the oldest one is flushed.
+    ///
+    /// Default: 2
+    pub fn max_immutables(&mut self, count: usize) -> &mut KVSOptions {
+        self.max_immutables = count; self
+    }
 
-        let wal_file = RecordFile::new(&db_dir.join("data.wal"), WAL_HEADER, options.rec_file_buffer_size, options.rec_file_cache_size)?;
+    /// How the records of the mem_table are kept.
+    ///
+    /// `SkipList` suits most workloads, as reads never wait on writes. `BTree` takes less memory,
+    /// but reads and writes take turns. `Hash` has the fastest gets, but sorts all its records for
+    /// every flush, scan, and snapshot, so it's only for workloads of puts and gets.
+    ///
+    /// Default: `MemTableKind::SkipList`
+    pub fn mem_table(&mut self, kind: MemTableKind) -> &mut KVSOptions {
+        self.mem_table = kind; self
+    }
 
-        // read back in our WAL file if we have one
-        if wal_file.record_count() > 0 {
-            for bytes in wal_file.iter() {
-                let rec = Record::deserialize(bytes);
-                mem_table.insert(rec.key(), rec);
-            }
-        }
+    /// The format the metadata of the SSTables is written in.
+    ///
+    /// It's set when the store is created; opening a store with SSTables written in another format fails.
+    ///
+    /// Default: `CodecKind::MsgPack`
+    pub fn codec(&mut self, codec: CodecKind) -> &mut KVSOptions {
+        self.codec = codec; self
+    }
 
-        let sstable_current_path = db_dir.join("table.current");
+    /// The number of WALs kept after their mem_tables are flushed, for `KVS::subscribe`.
+    ///
+    /// Change streams read the changes from the WALs, so a stream can only start from, or fall
+    /// behind to, a change that's still in one. Each WAL holds about `mem_count` changes.
+    ///
+    /// Default: 0
+    pub fn wal_retention(&mut self, count: usize) -> &mut KVSOptions {
+        self.wal_retention = count; self
+    }
 
-        let sstable_current = if sstable_current_path.exists() {
-            SSTable::open(&sstable_current_path, options.rec_file_buffer_size, options.rec_file_cache_size)
-        } else {
-            SSTable::new(&sstable_current_path, &mut iter::empty::<Record>(), options.group_count, None, options.rec_file_buffer_size, options.rec_file_cache_size)
-        }.expect("Error opening current SSTable");
+    /// The size a WAL can grow to before its mem_table is flushed, even if it has fewer than `mem_count` records.
+    ///
+    /// Each mem_table has its own WAL segment, so this bounds the size of the segments, and how much
+    /// has to be replayed after a crash. 0 leaves it to `mem_count`.
+    ///
+    /// Default: 0
+    pub fn wal_segment_size(&mut self, bytes: u64) -> &mut KVSOptions {
+        self.wal_segment_size = bytes; self
+    }
 
-        let mut sstables = BTreeSet::<SSTable>::new();
+    /// The most bytes of WAL the mem_tables that aren't flushed can have, so recovery after a crash has a bound.
+    ///
+    /// Reaching it, the active mem_table is flushed even if it isn't full. A writer waits while the WALs
+    /// of the mem_tables already waiting to be flushed are over it, see `EventListener::on_write_stall`.
+    /// The backlog is in `StoreStats::wal_bytes`. 0 leaves it to `mem_count` and `max_immutables`.
+    ///
+    /// Default: 0
+    pub fn max_wal_bytes(&mut self, bytes: u64) -> &mut KVSOptions {
+        self.max_wal_bytes = bytes; self
+    }
 
-        let re = Regex::new(r"^table-(\d+).data$").unwrap();
-        let mut max_sstable_num : u64 = 0;
+    /// A directory to keep WAL segments in once they're flushed, for `KVS::restore_to`.
+    ///
+    /// Segments are moved to the archive instead of being removed, named by the sequence numbers of
+    /// their first record and the one after their last. Nothing removes them from the archive.
+    /// Writes with `WriteOptions::disable_wal` aren't in any segment.
+    ///
+    /// Default: none
+    pub fn wal_archive_dir(&mut self, dir: &PathBuf) -> &mut KVSOptions {
+        self.wal_archive_dir = Some(dir.to_path_buf()); self
+    }
 
-        // gather up all the SSTables in this directory
-        for entry in fs::read_dir(db_dir.to_path_buf())? {
-            let entry = entry.expect("Error reading directory entry");
-            let path = entry.path();
+    /// The percent of an SSTable's records that must have expired for it to be rewritten without them.
+    ///
+    /// Records written with `put_with_ttl` are dropped by compactions once they expire, but tables
+    /// holding keys that aren't written to again may not be compacted for a long time. The background
+    /// thread rewrites those tables on its own, after flushes and about once a minute. 0 disables it.
+    ///
+    /// Default: 50
+    pub fn ttl_compaction_percent(&mut self, percent: usize) -> &mut KVSOptions {
+        self.ttl_compaction_percent = percent; self
+    }
 
-            if path.is_dir() {
-                continue
-            }
+    /// Rewrites the SSTables whose newest record is older than this, when some of their records may have
+    /// expired, and compacts the current SSTable once it has had no writes for this long, dropping its deletes.
+    /// It bounds how long expired and deleted records stay on disk in key ranges that are no longer written.
+    ///
+    /// Default: none
+    pub fn periodic_compaction(&mut self, period: Duration) -> &mut KVSOptions {
+        self.periodic_compaction_ms = period.as_secs() * 1000 + period.subsec_nanos() as u64 / 1_000_000; self
+    }
 
-            let file_name = path.file_name().expect("Error getting file name");
-            let captures = re.captures(file_name.to_str().expect("Error getting string for file name"));
+    /// Wait for the WAL to reach the disk after every write, as if `WriteOptions::sync` were always set.
+    ///
+    /// Default: false
+    pub fn sync_writes(&mut self, sync: bool) -> &mut KVSOptions {
+        self.sync_writes = sync; self
+    }
 
-            if let Some(capture) = captures {
-                // add to our set of tables
-                sstables.insert(SSTable::open(&path, options.rec_file_buffer_size, options.rec_file_cache_size)?);
+    /// Reserve the disk space for new WALs and compacted SSTables before writing them.
+    ///
+    /// A WAL reserves the size of the one it replaces, and an SSTable its share of the tables being
+    /// compacted. The files are less fragmented, and a full disk fails the rotation or compaction up
+    /// front, rather than part way through. Only Linux reserves the space.
+    ///
+    /// Default: false
+    pub fn preallocate(&mut self, preallocate: bool) -> &mut KVSOptions {
+        self.preallocate = preallocate; self
+    }
 
-                // get the number of the table
-                let sstable_num = capture.get(1).expect("Error capturing SSTable number").as_str().parse::<u64>().expect("Error parsing number");
+    /// Advise the OS to drop the pages of the SSTables a compaction read, once it's done with them.
+    ///
+    /// A compaction reads every record of its tables, which pushes the pages of hot records out of
+    /// the OS's page cache. Tables still read by iterators are read from disk again. Only Linux
+    /// drops the pages.
+    ///
+    /// Default: false
+    pub fn compaction_drop_inputs(&mut self, drop: bool) -> &mut KVSOptions {
+        self.compaction_drop_inputs = drop; self
+    }
 
-                if sstable_num > max_sstable_num {
-                    max_sstable_num = sstable_num;
-                }
-            }
-        }
+    /// Advise the OS to read the index blocks and filters of the SSTables a compaction writes.
+    ///
+    /// The first lookups in a new table then don't wait on the disk for them. It's not needed when
+    /// they're pinned, see `pin_metadata`. Only Linux reads them ahead.
+    ///
+    /// Default: false
+    pub fn compaction_prefetch_indexes(&mut self, prefetch: bool) -> &mut KVSOptions {
+        self.compaction_prefetch_indexes = prefetch; self
+    }
 
-        return Ok(KVS {
-            options: options,
-            cur_sstable_num: max_sstable_num + 1,
-            wal_file: wal_file,
-            mem_table: mem_table,
-            cur_sstable: sstable_current,
-            sstables: sstables,
-        })
+    /// Move the current SSTable to the others as it is, by a manifest edit, when a merge would only copy it.
+    ///
+    /// That's when none of its keys are in the range of another SSTable, as with sequential inserts, and it has
+    /// no deletes, and there's no `compaction_hook`. The table isn't split into `file_count` tables, and keeps
+    /// its expired records until a TTL compaction drops them; in return, its records aren't written again.
+    ///
+    /// Default: false
+    pub fn compaction_trivial_move(&mut self, trivial_move: bool) -> &mut KVSOptions {
+        self.compaction_trivial_move = trivial_move; self
     }
 
-    /// Opens an existing KVS directory/database.
+    /// Keep values of at least this many bytes in a value log, with only pointers to them in the SSTables; 0 for none.
     ///
-    /// All of the original options used to create the KVS instance will be used when open.
+    /// The values are moved to the log when their mem_table is flushed, so compactions copy the pointers
+    /// instead of the values, at the cost of a read from the log for each lookup and scanned record.
+    /// The space of values that are overwritten or deleted is only freed by `KVS::collect_value_log`.
+    /// With a `compaction_hook`, each compaction reads the values back for the hook, and moves those
+    /// it keeps to the log again.
     ///
-    /// # Examples
-    /// ```
-    /// {   // scope so it is dropped after opening
-    ///     KVSOptions::new("/tmp/kvs").create().unwrap();
-    /// }
+    /// Default: 0
+    pub fn value_log_threshold(&mut self, bytes: usize) -> &mut KVSOptions {
+        self.value_log_threshold = bytes; self
+    }
+
+    /// Start a new value log file once the one being appended to reaches this size.
     ///
-    /// let kvs = KVS::open("/tmp/kvs").unwrap();
-    /// ```
-    pub fn open(db_dir: &PathBuf) -> Result<KVS, IOError> {
+    /// `KVS::collect_value_log` frees the space of one file at a time, so smaller files are collected
+    /// sooner, and with fewer live values to move.
+    ///
+    /// Default: 64 MB
+    pub fn value_log_file_size(&mut self, bytes: u64) -> &mut KVSOptions {
+        self.value_log_file_size = bytes; self
+    }
 
+    /// How many times a flush or compaction tries an IO operation again after a transient error.
+    ///
+    /// Errors from bad data, and missing or forbidden files, aren't tried again. When the retries run
+    /// out, the flushes and compactions stop, and the store is read-only, see `KVS::background_error`.
+    ///
+    /// Default: 3
+    pub fn io_retries(&mut self, count: usize) -> &mut KVSOptions {
+        self.io_retries = count; self
     }
 
-    /// Returns the path to the WAL file (or new one)
-    fn wal_file_path(&self, new: bool) -> PathBuf {
-        if new {
-            self.options.db_dir.join("data.wal-new")
-        } else {
-            self.options.db_dir.join("data.wal")
-        }
+    /// How long to wait before the first retry of an IO operation, doubled for each one after it, see `io_retries`.
+    ///
+    /// Default: 100ms
+    pub fn io_retry_delay(&mut self, delay: Duration) -> &mut KVSOptions {
+        self.io_retry_delay_ms = delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000; self
     }
 
-    /// Returns the path to the current SSTable (or new one)
-    fn cur_sstable_path(&self, new: bool) -> PathBuf {
-        if new {
-            self.options.db_dir.join("table.current-new")
-        } else {
-            self.options.db_dir.join("table.current")
-        }
+    /// The free disk space to keep, so the store stops growing before the disk is full.
+    ///
+    /// Under it, compactions are paused, as they write their new tables before removing the old ones,
+    /// and `KVS::try_put` and `KVS::try_write` fail with `WriteError::OutOfSpace` unless they only
+    /// delete; the other writes aren't limited. Listeners get `on_disk_space` when the store goes under
+    /// it, and back over it. The space is checked at most once a second. Only Linux checks it.
+    ///
+    /// Default: 0, off
+    pub fn min_free_space(&mut self, bytes: u64) -> &mut KVSOptions {
+        self.min_free_space = bytes; self
+    }
+
+    /// Check the store for inconsistencies when it's opened, failing to open it if there are any, see `KVS::check`
+    ///
+    /// Each SSTable in the manifest is opened, so it takes longer to open a store with many.
+    ///
+    /// Default: false
+    pub fn check_on_open(&mut self, check: bool) -> &mut KVSOptions {
+        self.check_on_open = check; self
     }
 
-    /// Generates the path to the current SSTable
-    fn sstable_path(&self) -> PathBuf {
-        self.options.db_dir.join(format!("table-{}.data", self.cur_sstable_num))
+    /// How long the view of the store a paginated scan reads is kept after its last page, see `KVS::range_page`
+    ///
+    /// A cursor passed back after that gets an error, and the listing has to start over.
+    ///
+    /// Default: 60 seconds
+    pub fn cursor_timeout(&mut self, timeout: Duration) -> &mut KVSOptions {
+        self.cursor_timeout_ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000; self
     }
 
-    /// Creates a new WAL file, deletes current WAL file, and renames the new to current
-    fn update_wal_file(&mut self) {
-        {
-            // create a new WAL file
-            RecordFile::new(&self.wal_file_path(true), WAL_HEADER, self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error creating WAL file: {:?}", self.wal_file_path(true)));
-        }
+    /// The most views of paginated scans kept at once; starting another drops the one read longest ago
+    ///
+    /// Default: 1000
+    pub fn max_cursors(&mut self, count: usize) -> &mut KVSOptions {
+        self.max_cursors = count; self
+    }
 
-        // remove the old one
-        fs::remove_file(&self.wal_file_path(false)).expect(&format!("Error removing: {:?}", self.wal_file_path(false)));
+    /// Counts the reads of each key, and keeps this many of the most read, see `KVS::hot_keys`
+    ///
+    /// The counts are approximate, as they share counters; a key can be counted high, never low. They're
+    /// halved every million reads, so keys that stop being read drop out. Counting takes a lock on each
+    /// read, so it's off by default.
+    ///
+    /// Default: 0, off
+    pub fn hot_keys(&mut self, count: usize) -> &mut KVSOptions {
+        self.hot_keys = count; self
+    }
 
-        // rename the new to old
-        fs::rename(self.wal_file_path(true), &self.wal_file_path(false)).expect(&format!("Error renaming WAL file: {:?} -> {:?}", self.wal_file_path(true), self.wal_file_path(false)));
+    /// Counts the keys starting with the prefix, and the bytes of their keys and values, and limits them
+    ///
+    /// Writes with `KVS::try_put` and `KVS::try_write` that would take the prefix over a limit fail with
+    /// `WriteError::QuotaExceeded`; the other writes are counted, but not limited. A limit of None only counts.
+    /// The usage is in `KVS::stats`. It's counted when the store is opened, then each write of a key with
+    /// the prefix looks up the old value. Records that expire are counted until the store is reopened.
+    ///
+    /// Default: none
+    pub fn quota(&mut self, prefix: &Vec<u8>, max_bytes: Option<u64>, max_keys: Option<u64>) -> &mut KVSOptions {
+        self.quotas.push(Quota { prefix: prefix.to_vec(), max_bytes: max_bytes, max_keys: max_keys }); self
+    }
 
-        self.wal_file = RecordFile::new(&self.wal_file_path(false), WAL_HEADER, self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error opening WAL file: {:?}", self.wal_file_path(false)));
+    /// Expires every put after the TTL, unless it sets its own, like `KVS::put_with_ttl`, or its key has a `prefix_ttl`.
+    ///
+    /// The TTL is from the record's timestamp, when it's written. Changing it doesn't change the records already written.
+    ///
+    /// Default: none
+    pub fn default_ttl(&mut self, ttl: Duration) -> &mut KVSOptions {
+        self.default_ttl_ms = ttl.as_secs() * 1000 + ttl.subsec_nanos() as u64 / 1_000_000; self
     }
 
-    /// flush the mem_table to disk
-    /// return: true if the flush occured
-    fn flush(&mut self, check_size: bool) -> bool {
-        debug!("Starting a flush");
+    /// Expires the puts of keys starting with the prefix after the TTL, unless they set their own.
+    ///
+    /// It's in place of the `default_ttl`, so a TTL of 0 keeps the keys under the prefix from expiring.
+    /// When a key has more than one prefix with a TTL, the longest one's is used.
+    ///
+    /// Default: none
+    pub fn prefix_ttl(&mut self, prefix: &Vec<u8>, ttl: Duration) -> &mut KVSOptions {
+        self.prefix_ttls.push(PrefixTtl { prefix: prefix.to_vec(), ttl_ms: ttl.as_secs() * 1000 + ttl.subsec_nanos() as u64 / 1_000_000 }); self
+    }
 
-        if check_size && self.mem_table.len() < self.options.max_mem_count {
-            debug!("Too few records in mem_table: {} < {}", self.mem_table.len(), self.options.max_mem_count);
-            return false; // don't need to do anything yet
-        }
+    /// Adds a listener that's called after flushes and compactions, and when writes stall.
+    ///
+    /// Listeners are called in the order they're added. They aren't saved with the other options,
+    /// so they need to be added again when opening the store.
+    ///
+    /// Default: none
+    pub fn event_listener(&mut self, listener: Arc<EventListener>) -> &mut KVSOptions {
+        self.listeners.add(listener); self
+    }
 
-        // update the reference to our current SSTable
-        self.cur_sstable = {
-            let mem_it: Box<Iterator<Item=Record>> = Box::new(self.mem_table.values().map(move |r| r.to_owned()));
-            let ss_it: Box<Iterator<Item=Record>> = Box::new(self.cur_sstable.iter());
+    /// Sets a hook that can rewrite runs of records as they're compacted, like `timeseries::Rollup`.
+    ///
+    /// Like listeners, the hook isn't saved with the other options, so it needs to be set again when opening the store.
+    ///
+    /// Default: none
+    pub fn compaction_hook(&mut self, hook: Arc<CompactionHook>) -> &mut KVSOptions {
+        self.compaction_hook = CompactionHookSlot(Some(hook)); self
+    }
 
-            // create an iterator that merge-sorts and also coalesces out similar records
-            let mut it = kmerge(vec![mem_it, ss_it]).coalesce(coalesce_records);
+    /// Runs the flushes and compactions on an executor, like a `ThreadPool` shared by several stores.
+    ///
+    /// Otherwise the store starts a background thread of its own. The executor is given a job when
+    /// a mem_table fills, which runs until there's nothing left to flush; without a thread waiting
+    /// around, SSTables are only checked for expired records after flushes. Like listeners, the
+    /// executor isn't saved with the other options.
+    ///
+    /// Default: none
+    pub fn executor(&mut self, executor: Arc<Executor>) -> &mut KVSOptions {
+        self.executor = ExecutorSlot(Some(executor)); self
+    }
 
-            {
-                // create and close the new SSTable
-                SSTable::new(&self.cur_sstable_path(true), &mut it, self.options.group_count as u32, None, self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error creating SSTable: {:?}", &self.cur_sstable_path(true)));
-            }
+    /// Takes the timestamps of records, and the time they expire by, from a clock, like a `HybridLogicalClock`.
+    ///
+    /// Each record is given a timestamp by the clock as it's written, instead of when it was made.
+    /// Like listeners, the clock isn't saved with the other options.
+    ///
+    /// Default: the system clock
+    pub fn clock(&mut self, clock: Arc<Clock>) -> &mut KVSOptions {
+        self.clock = ClockSlot(Some(clock)); self
+    }
 
-            // remove the old one if it exists
-            // in theory, this should *always* exist because we create blank ones
-            // however, flush() is called by Drop, so we could be in a funky state during this call
-            if self.cur_sstable_path(false).exists() {
-                fs::remove_file(&self.cur_sstable_path(false)).expect(&format!("Error removing: {:?}", self.cur_sstable_path(false)));
-            }
+    /// Settles the conflicts between the changes of other replicas given to `KVS::apply_changes`, and this store's writes.
+    ///
+    /// A change conflicts when the key was written here at the same time or later. Without a resolver,
+    /// the later write wins. Like listeners, the resolver isn't saved with the other options.
+    ///
+    /// Default: none
+    pub fn conflict_resolver(&mut self, resolver: Arc<ConflictResolver>) -> &mut KVSOptions {
+        self.conflict_resolver = ConflictResolverSlot(Some(resolver)); self
+    }
 
-            // rename the new to old
-            fs::rename(&self.cur_sstable_path(true), &self.cur_sstable_path(false)).expect(&format!("Error renaming current SSTable: {:?} -> {:?}", self.cur_sstable_path(true), self.cur_sstable_path(false)));
+    /// Reads the options from a TOML file.
+    ///
+    /// The file must set `db_dir`; any of the other options, named after their methods, can be set too:
+    /// ```toml
+    /// db_dir = "/var/lib/kvs"
+    /// mem_count = 500000
+    /// sync_writes = true
+    /// ```
+    pub fn from_toml(path: &PathBuf) -> Result<KVSOptions, IOError> {
+        let file = OptionsFile::read(path)?;
 
-            SSTable::open(&self.cur_sstable_path(false), self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error opening current SSTable: {:?}", self.cur_sstable_path(false)))
+        let db_dir = match file.db_dir {
+            Some(ref db_dir) => db_dir.to_path_buf(),
+            None => return Err(IOError::new(ErrorKind::InvalidInput, format!("db_dir is not set in: {}", path.display())))
         };
 
-        // remove everything in the mem_table
-        self.mem_table.clear();
-
-        // update the WAL file
-        self.update_wal_file();
+        let mut options = KVSOptions::new(&db_dir);
 
-        debug!("Leaving flush");
+        options.apply(file);
+        options.validate()?;
 
-        true
+        Ok(options)
     }
 
-    /// Compacts the mem_table, current_sstable, and sstables into new sstables
-    /// return: true if the compaction actually ran
-    fn compact(&mut self) -> bool {
-        debug!("Starting a compaction");
-
-        // we wait until we have enough records for every file to get self.options.max_mem_count
-        if self.mem_table.len() as u64 + self.cur_sstable.record_count() < (self.options.max_mem_count * self.options.file_count) as u64 {
-            debug!("Not enough records for compact: {} < {}", self.mem_table.len() as u64 + self.cur_sstable.record_count(), (self.options.max_mem_count * self.options.file_count) as u64);
-            return false;
-        }
-
-        // save off the file paths to the old SSTables as it's not nice to delete files that are still open
-        let sstable_paths = self.sstables.iter().map(|table| table.file_path()).collect::<Vec<_>>();
-
-        // create iterators for all the SSTables and the mem_table
-        self.sstables = {
-            let mem_it: Box<Iterator<Item=Record>> = Box::new(self.mem_table.values().map(move |r| r.to_owned()));
-            let ss_cur_it: Box<Iterator<Item=Record>> = Box::new(self.cur_sstable.iter());
-            let mut ss_its = Vec::with_capacity(self.options.file_count + 2);
-            let mut record_count = self.mem_table.len() as u64 + self.cur_sstable.record_count();
-
-            ss_its.push(mem_it);
-            ss_its.push(ss_cur_it);
+    /// Checks that none of the options are nonsensical.
+    pub fn validate(&self) -> Result<(), IOError> {
+        let invalid = |msg: String| Err(IOError::new(ErrorKind::InvalidInput, msg));
+
+        if self.max_mem_count < 2 { return invalid(format!("mem_count must be greater than 1: {}", self.max_mem_count)); }
+        if let Some(count) = self.group_count { if count < 100 { return invalid(format!("group_count is too small, make > 100: {}", count)); } }
+        if self.target_block_bytes < 4096 { return invalid(format!("target_block_bytes is too small, try > 4096: {}", self.target_block_bytes)); }
+        if self.group_by_size && self.group_count.is_some() { return invalid("group_by_size and group_count can't both be set".to_string()); }
+        if self.file_count < 2 { return invalid(format!("file_count is too small, try > 2: {}", self.file_count)); }
+        if self.rec_file_buffer_size < 4096 { return invalid(format!("file_buffer is too small, try > 4096: {}", self.rec_file_buffer_size)); }
+        if self.rec_file_cache_size < 1 { return invalid(format!("cache_size must be greater than 1: {}", self.rec_file_cache_size)); }
+        if self.dict_size != 0 && self.dict_size < 256 { return invalid(format!("dict_size is too small, try > 256: {}", self.dict_size)); }
+        if self.record_alignment != 0 && (self.record_alignment < 512 || !self.record_alignment.is_power_of_two()) { return invalid(format!("record_alignment must be 0, or a power of 2 of at least 512: {}", self.record_alignment)); }
+        if self.max_open_tables < 1 { return invalid(format!("max_open_tables must be at least 1: {}", self.max_open_tables)); }
+        if self.max_immutables < 1 { return invalid(format!("max_immutables must be at least 1: {}", self.max_immutables)); }
+        if self.ttl_compaction_percent > 100 { return invalid(format!("ttl_compaction_percent must be at most 100: {}", self.ttl_compaction_percent)); }
+        if self.value_log_file_size < 4096 { return invalid(format!("value_log_file_size is too small, try > 4096: {}", self.value_log_file_size)); }
+        if self.io_retries > MAX_IO_RETRIES { return invalid(format!("io_retries must be at most {}: {}", MAX_IO_RETRIES, self.io_retries)); }
+        if self.cursor_timeout_ms == 0 { return invalid(format!("cursor_timeout must be at least 1ms: {}", self.cursor_timeout_ms)); }
+        if self.max_cursors < 1 { return invalid(format!("max_cursors must be at least 1: {}", self.max_cursors)); }
+        if self.hot_keys > MAX_HOT_KEYS { return invalid(format!("hot_keys must be at most {}: {}", MAX_HOT_KEYS, self.hot_keys)); }
+        if let Some((i, q)) = self.quotas.iter().enumerate().find(|&(i, q)| self.quotas[..i].iter().any(|o| o.prefix == q.prefix)) { return invalid(format!("quota {} repeats the prefix {:?}", i, q.prefix)); }
+        if let Some((i, t)) = self.prefix_ttls.iter().enumerate().find(|&(i, t)| self.prefix_ttls[..i].iter().any(|o| o.prefix == t.prefix)) { return invalid(format!("prefix_ttl {} repeats the prefix {:?}", i, t.prefix)); }
+
+        Ok( () )
+    }
 
-            for sstable in self.sstables.iter() {
-                record_count += sstable.record_count();
-                ss_its.push(Box::new(sstable.iter()));
-            }
+    /// Creates a `KVS` instance using the configured options.
+    ///
+    /// **This should only be called when creating a new `KVS` instance, not opening an existing one.**
+    /// To open an existing KVS directory/database, use the `KVS::open` function.
+    ///
+    /// The options are saved in the directory, for `KVS::open` to use.
+    ///
+    /// # Examples
+    /// ```
+    /// let kvs = KVSOptions::new("/tmp/kvs").create().unwrap();
+    /// ```
+    ///
+    /// Options that are nonsensical get an error of kind `InvalidInput`, see `validate`.
+    pub fn create(self) -> Result<KVS, IOError> {
+        self.validate()?;
 
-            let cur_time = get_timestamp();
+        OptionsFile::from(&self).write(&self.db_dir.join(OPTIONS_FILE))?;
 
-            let mut it =
-                kmerge(ss_its).coalesce(coalesce_records).filter(|rec| {
-                    !rec.is_delete() && !rec.is_expired(cur_time) // remove all deleted and expired
-                });
+        KVS::new(self)
+    }
 
-            let records_per_file = record_count / self.options.file_count as u64;
+    /// Sets all the options that are in the file
+    fn apply(&mut self, file: OptionsFile) {
+        if let Some(count) = file.mem_count { self.mem_count(count); }
+        if let Some(count) = file.group_count { self.group_count(count); }
+        if let Some(size) = file.target_block_bytes { self.target_block_bytes(size); }
+        if let Some(group_by_size) = file.group_by_size { self.group_by_size(group_by_size); }
+        if let Some(count) = file.file_count { self.file_count(count); }
+        if let Some(size) = file.file_buffer { self.file_buffer(size); }
+        if let Some(count) = file.cache_size { self.cache_size(count); }
+        if let Some(kind) = file.cache_policy { self.cache_policy(kind); }
+        if let Some(bytes) = file.meta_cache_size { self.meta_cache_size(bytes); }
+        if let Some(pin) = file.pin_metadata { self.pin_metadata(pin); }
+        if let Some(count) = file.row_cache_size { self.row_cache_size(count); }
+        if let Some(size) = file.dict_size { self.dict_size(size); }
+        if let Some(size) = file.record_alignment { self.record_alignment(size); }
+        if let Some(count) = file.max_open_tables { self.max_open_tables(count); }
+        if let Some(count) = file.max_immutables { self.max_immutables(count); }
+        if let Some(kind) = file.mem_table { self.mem_table(kind); }
+        if let Some(codec) = file.codec { self.codec(codec); }
+        if let Some(count) = file.wal_retention { self.wal_retention(count); }
+        if let Some(size) = file.wal_segment_size { self.wal_segment_size(size); }
+        if let Some(bytes) = file.max_wal_bytes { self.max_wal_bytes(bytes); }
+        if let Some(dir) = file.wal_archive_dir { self.wal_archive_dir(&dir); }
+        if let Some(percent) = file.ttl_compaction_percent { self.ttl_compaction_percent(percent); }
+        if let Some(ms) = file.periodic_compaction_ms { self.periodic_compaction(Duration::from_millis(ms)); }
+        if let Some(sync) = file.sync_writes { self.sync_writes(sync); }
+        if let Some(preallocate) = file.preallocate { self.preallocate(preallocate); }
+        if let Some(drop) = file.compaction_drop_inputs { self.compaction_drop_inputs(drop); }
+        if let Some(prefetch) = file.compaction_prefetch_indexes { self.compaction_prefetch_indexes(prefetch); }
+        if let Some(trivial_move) = file.compaction_trivial_move { self.compaction_trivial_move(trivial_move); }
+        if let Some(bytes) = file.value_log_threshold { self.value_log_threshold(bytes); }
+        if let Some(bytes) = file.value_log_file_size { self.value_log_file_size(bytes); }
+        if let Some(count) = file.io_retries { self.io_retries(count); }
+        if let Some(ms) = file.io_retry_delay_ms { self.io_retry_delay(Duration::from_millis(ms)); }
+        if let Some(bytes) = file.min_free_space { self.min_free_space(bytes); }
+        if let Some(check) = file.check_on_open { self.check_on_open(check); }
+        if let Some(ms) = file.cursor_timeout_ms { self.cursor_timeout(Duration::from_millis(ms)); }
+        if let Some(count) = file.max_cursors { self.max_cursors(count); }
+        if let Some(count) = file.hot_keys { self.hot_keys(count); }
+        if let Some(ms) = file.default_ttl_ms { self.default_ttl(Duration::from_millis(ms)); }
+        if let Some(prefix_ttls) = file.prefix_ttls { self.prefix_ttls = prefix_ttls; }
+        if let Some(quotas) = file.quotas { self.quotas = quotas; }
+    }
 
-            debug!("RECORDS PER FILE: {} = {} / {}", records_per_file, record_count, self.options.file_count as u64);
+    /// The record caches of the SSTables, and a new metadata cache if there's one, for a store being opened
+    fn cache_options(&self) -> CacheOptions {
+        let meta = if self.meta_cache_size != 0 || self.pin_metadata { Some(Arc::new(MetaCache::new(self.meta_cache_size))) } else { None };
 
-            let mut new_sstables = BTreeSet::<SSTable>::new();
+        CacheOptions { size: self.rec_file_cache_size, policy: self.cache_policy, meta: meta, pin_meta: self.pin_metadata, value_log: None }
+    }
 
-            // create all the tables but the last one
-            for _i in 0..self.options.file_count-1 {
-                let sstable = SSTable::new(&self.sstable_path(), &mut it, self.options.group_count as u32, Some(records_per_file), self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error creating SSTable: {:?}", self.sstable_path()));
-                self.cur_sstable_num += 1;
-                new_sstables.insert(sstable);
-            }
+    /// The options used when creating SSTables
+    fn sstable_options(&self) -> SSTableOptions {
+        SSTableOptions {
+            group_count: self.group_count,
+            target_block_bytes: self.target_block_bytes,
+            group_by_size: self.group_by_size,
+            dict_size: self.dict_size,
+            codec: self.codec,
+            alignment: self.record_alignment,
+            preallocate: 0
+        }
+    }
 
-            // the last one gets all the rest of the records
-            let sstable = SSTable::new(&self.sstable_path(), &mut it, self.options.group_count as u32, None, self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error creating SSTable: {:?}", self.sstable_path()));
-            self.cur_sstable_num += 1;
-            new_sstables.insert(sstable);
+    /// The options used when creating SSTables of about the size, which is reserved if `preallocate` is set
+    fn sstable_options_sized(&self, bytes: u64) -> SSTableOptions {
+        SSTableOptions { preallocate: if self.preallocate { bytes } else { 0 }, ..self.sstable_options() }
+    }
+}
 
-            new_sstables
-        };
+/// The options as they're written in a TOML file, everything is optional
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct OptionsFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    db_dir: Option<PathBuf>,
+    mem_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_count: Option<u32>,
+    target_block_bytes: Option<usize>,
+    group_by_size: Option<bool>,
+    file_count: Option<usize>,
+    file_buffer: Option<usize>,
+    cache_size: Option<usize>,
+    cache_policy: Option<CachePolicyKind>,
+    meta_cache_size: Option<usize>,
+    pin_metadata: Option<bool>,
+    row_cache_size: Option<usize>,
+    dict_size: Option<usize>,
+    record_alignment: Option<usize>,
+    max_open_tables: Option<usize>,
+    max_immutables: Option<usize>,
+    mem_table: Option<MemTableKind>,
+    codec: Option<CodecKind>,
+    wal_retention: Option<usize>,
+    wal_segment_size: Option<u64>,
+    max_wal_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wal_archive_dir: Option<PathBuf>,
+    ttl_compaction_percent: Option<usize>,
+    periodic_compaction_ms: Option<u64>,
+    sync_writes: Option<bool>,
+    preallocate: Option<bool>,
+    compaction_drop_inputs: Option<bool>,
+    compaction_prefetch_indexes: Option<bool>,
+    compaction_trivial_move: Option<bool>,
+    value_log_threshold: Option<usize>,
+    value_log_file_size: Option<u64>,
+    io_retries: Option<usize>,
+    io_retry_delay_ms: Option<u64>,
+    min_free_space: Option<u64>,
+    check_on_open: Option<bool>,
+    cursor_timeout_ms: Option<u64>,
+    max_cursors: Option<usize>,
+    hot_keys: Option<usize>,
+    default_ttl_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix_ttls: Option<Vec<PrefixTtl>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quotas: Option<Vec<Quota>> // last, as TOML writes tables after values
+}
 
-        // remove all the old SSTables
-        for sstable_path in sstable_paths.iter() {
-            fs::remove_file(&sstable_path).expect(&format!("Error removing old SSTable: {:?}", sstable_path));
+impl OptionsFile {
+    /// All the options, except the directory, which is wherever the file is found
+    fn from(options: &KVSOptions) -> OptionsFile {
+        OptionsFile {
+            db_dir: None,
+            mem_count: Some(options.max_mem_count),
+            group_count: options.group_count,
+            target_block_bytes: Some(options.target_block_bytes),
+            group_by_size: Some(options.group_by_size),
+            file_count: Some(options.file_count),
+            file_buffer: Some(options.rec_file_buffer_size),
+            cache_size: Some(options.rec_file_cache_size),
+            cache_policy: Some(options.cache_policy),
+            meta_cache_size: Some(options.meta_cache_size),
+            pin_metadata: Some(options.pin_metadata),
+            row_cache_size: Some(options.row_cache_size),
+            dict_size: Some(options.dict_size),
+            record_alignment: Some(options.record_alignment),
+            max_open_tables: Some(options.max_open_tables),
+            max_immutables: Some(options.max_immutables),
+            mem_table: Some(options.mem_table),
+            codec: Some(options.codec),
+            wal_retention: Some(options.wal_retention),
+            wal_segment_size: Some(options.wal_segment_size),
+            max_wal_bytes: Some(options.max_wal_bytes),
+            wal_archive_dir: options.wal_archive_dir.clone(),
+            ttl_compaction_percent: Some(options.ttl_compaction_percent),
+            periodic_compaction_ms: Some(options.periodic_compaction_ms),
+            sync_writes: Some(options.sync_writes),
+            preallocate: Some(options.preallocate),
+            compaction_drop_inputs: Some(options.compaction_drop_inputs),
+            compaction_prefetch_indexes: Some(options.compaction_prefetch_indexes),
+            compaction_trivial_move: Some(options.compaction_trivial_move),
+            value_log_threshold: Some(options.value_log_threshold),
+            value_log_file_size: Some(options.value_log_file_size),
+            io_retries: Some(options.io_retries),
+            io_retry_delay_ms: Some(options.io_retry_delay_ms),
+            min_free_space: Some(options.min_free_space),
+            check_on_open: Some(options.check_on_open),
+            cursor_timeout_ms: Some(options.cursor_timeout_ms),
+            max_cursors: Some(options.max_cursors),
+            hot_keys: Some(options.hot_keys),
+            default_ttl_ms: Some(options.default_ttl_ms),
+            prefix_ttls: if options.prefix_ttls.is_empty() { None } else { Some(options.prefix_ttls.clone()) },
+            quotas: if options.quotas.is_empty() { None } else { Some(options.quotas.clone()) }
         }
+    }
 
-        // remove the current SSTable
-        fs::remove_file(self.cur_sstable_path(false)).expect(&format!("Error removing current SSTable: {:?}", self.cur_sstable_path(false)));
+    fn read(path: &PathBuf) -> Result<OptionsFile, IOError> {
+        let mut contents = String::new();
 
-        // create a new empty current SSTable
-        self.cur_sstable = SSTable::new(&self.cur_sstable_path(false), &mut iter::empty::<Record>(), self.options.group_count, None, self.options.rec_file_buffer_size, self.options.rec_file_cache_size).expect(&format!("Error creating blank current SSTable: {:?}", self.cur_sstable_path(false)));
+        File::open(path)?.read_to_string(&mut contents)?;
 
-        // remove everything from the mem_table
-        self.mem_table.clear();
+        toml::from_str(&contents).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("Error parsing options in {}: {}", path.display(), e)))
+    }
 
-        // update the WAL file
-        self.update_wal_file();
+    fn write(&self, path: &PathBuf) -> Result<(), IOError> {
+        let contents = toml::to_string(self).expect("Error serializing options");
+        let mut file = File::create(path)?;
 
-        debug!("Leaving compact");
+        file.write_all(contents.as_bytes())?;
+        file.sync_data()?;
 
-        true
+        sim::on_sync(path);
+
+        Ok( () )
     }
+}
 
-    pub fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>> {
-        debug!("Called get: {:?}", key);
+/// The WAL of the active mem_table, and how much of what's written to it is on disk
+struct Wal {
+    file: RecordFile,
+    first_seq: u64, // the sequence number of the first record in the file
+    size: u64,      // the length of the file, once it's flushed
+    last_ts: u64,   // the newest timestamp given to a record or range tombstone
+    written: u64,   // the number of records written to the WALs since the store was opened
+    synced: u64,    // the number of those records known to be on disk
+    watchers: Vec<(Vec<u8>, Sender<Change>)> // the prefixes being watched, see `KVS::watch`
+}
 
-        let cur_time = get_timestamp();
+impl Wal {
+    /// Appends a record, and sends it to the watchers of its prefix
+    fn append(&mut self, rec: &Record) -> Result<(), IOError> {
+        let seq = self.first_seq + self.file.record_count() as u64;
 
-        debug!("MEM TABLE: {}", self.mem_table.len());
+        let loc = self.file.append_record(rec)?;
 
-        // first check the mem_table
-        if self.mem_table.contains_key(key) {
-            let rec = self.mem_table.get(key).unwrap();
+        self.size = loc + (U32_SIZE as u32 + rec.size()) as u64;
+        self.written += 1;
 
-            // found an expired or deleted key
-            return if rec.is_expired(cur_time) || rec.is_delete() {
-                debug!("Found expired or deleted key");
-                None
-            } else {
-                Some(rec.value())
-            };
-        }
+        if !self.watchers.is_empty() {
+            let change = Change::new(seq, rec.clone());
 
-        // next check the current SSTable
-        if let Some(rec) = self.cur_sstable.get(key.to_vec()).expect("Error reading from SSTable") {
-            return if rec.is_expired(cur_time) || rec.is_delete() {
-                debug!("Found expired or deleted key");
-                None
-            } else {
-                Some(rec.value())
-            };
+            // watchers that dropped their receivers are removed
+            self.watchers.retain(|&(ref prefix, ref sender)| !change.has_prefix(prefix) || sender.send(change.clone()).is_ok());
         }
 
-        // finally, need to go to SSTables
-        for sstable in self.sstables.iter() {
-            debug!("SSTABLE: {:?}", sstable);
-
-            let ret_opt = sstable.get(key.to_vec()).expect("Error reading from SSTable");
-
-            // we didn't find the key
-            if ret_opt.is_none() {
-                continue;
-            }
+        Ok( () )
+    }
+}
 
-            let rec = ret_opt.unwrap();
+/// What readers see: the mem_tables, and the SSTables
+struct State {
+    mem_table: Arc<WalMemTable>,       // the active mem_table, all writes go here
+    immutables: Vec<Arc<WalMemTable>>, // full mem_tables waiting to be flushed, oldest first
+    cur_sstable: Arc<SSTable>,
+    sstables: BTreeSet<TableMeta>
+}
 
-            // sanity check
-            if rec.is_delete() {
-                panic!("Found deleted key in SSTable: {:?}", sstable);
-            }
+impl State {
+    /// Returns all the range tombstones that have not been applied by a compaction
+    fn range_tombstones(&self) -> Vec<Record> {
+        let mut tombstones = self.mem_table.range_tombstones();
 
-            return Some(rec.value());
+        for mem_table in self.immutables.iter() {
+            tombstones.extend(mem_table.range_tombstones());
         }
 
-        None // if we get to here, we don't have it
-    }
+        tombstones.extend(self.cur_sstable.range_tombstones().iter().cloned());
 
-    fn insert(&mut self, record: Record) {
-        self.wal_file.append_record(&record).expect("Error writing to WAL file");
+        tombstones
+    }
 
-        // insert into the mem_table
-        self.mem_table.insert(record.key(), record);
+    /// The bytes of the WALs of the mem_tables waiting to be flushed
+    fn immutable_wal_bytes(&self) -> u64 {
+        self.immutables.iter().map(|m| m.wal_bytes()).sum()
+    }
 
-        // check to see if we need to flush to disk
-        if self.mem_table.len() >= self.options.max_mem_count {
-            // compact won't do anything if it's not needed
-            if !self.compact() {
-                // see if we need to flush, if a compaction didn't occur
-                self.flush(true);
-            }
-        }
+    /// Returns true if the record was deleted by a range tombstone
+    fn is_range_deleted(&self, rec: &Record) -> bool {
+        self.mem_table.is_range_deleted(rec) ||
+        self.immutables.iter().any(|m| m.is_range_deleted(rec)) ||
+        self.cur_sstable.range_tombstones().iter().any(|t| t.covers(rec))
     }
+}
 
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
-//        debug!("Called put: {:?}", key);
+/// What the background thread is doing, shared with the threads waiting on it
+struct Background {
+    shutdown: bool,                     // set when the store is dropped or closed, or its background work is cancelled
+    busy: bool,                         // flushing or compacting
+    scheduled: bool,                    // a job was given to the executor, and hasn't finished
+    paused: bool,                       // no flush or compaction is started until the background work is resumed
+    failed: bool,                       // the thread panicked, and has stopped
+    panic: Option<Box<Any + Send>>,     // the panic, until a waiting thread raises it
+    error: Option<BackgroundError>      // the IO error that stopped the flushes and compactions
+}
 
-        // create a record, and call insert
-        let rec = Record::new(key.to_vec(), Some(value));
+/// Why a compaction is due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompactionReason {
+    Size,    // the current SSTable has the records for a merge, see `Core::compaction_threshold`
+    Expired, // an SSTable has `ttl_compaction_percent` of its records expired
+    Periodic // an SSTable hasn't been written for `periodic_compaction`
+}
 
-        self.insert(rec)
+/// A compaction that's due, scored by the records it's estimated to drop for each it reads
+#[derive(Debug)]
+struct CompactionJob {
+    table: Option<TableMeta>, // the SSTable to rewrite without its expired records, None to merge the current SSTable
+    reason: CompactionReason,
+    score: f64
+}
+
+/// A key/value store that can be shared between threads
+///
+/// Writers append to the WAL one at a time, but the syncs of concurrent writers are grouped
+/// together, and readers never wait on writers. When the mem_table is full it's swapped for an
+/// empty one, and a background thread writes it to disk; writers only wait for it when
+/// `max_immutables` full mem_tables are already waiting.
+pub struct KVS {
+    core: Arc<Core>,
+    flusher: Option<JoinHandle<()>> // the background thread, unless there's an executor
+}
+
+/// The parts of the store shared with the background thread
+struct Core {
+    this: Weak<Core>,           // for the jobs given to the executor
+    options: KVSOptions,
+    manifest: Mutex<Manifest>,  // held while file numbers are handed out, and the manifest is saved
+    wal: Mutex<Wal>,            // held while a write is added to the WAL and the mem_table
+    wal_sync: Mutex<()>,        // held while syncing the WAL, so the writers waiting on it share the next sync
+    state: RwLock<State>,
+    table_cache: TableCache,
+    versions: Mutex<VersionSet>, // versions handed out to iterators
+    table_lock: Mutex<()>,       // held for a whole flush or compaction, so only one runs at a time
+    background: Mutex<Background>,
+    work_ready: Condvar,         // a mem_table was made immutable, or the store is shutting down; see `signal_work`
+    work_done: Condvar,          // a mem_table was flushed, or the background thread stopped
+    locks: LockManager,          // the keys locked by pessimistic transactions
+    next_txn_id: AtomicU64,
+    cursors: Mutex<HashMap<(u64, Vec<u8>, Option<Vec<u8>>), (Snapshot, Instant)>>, // the views of paginated scans, by sequence number and range, and when they were last read
+    quotas: Quotas,              // changed with the WAL locked, see `insert_if`
+    hot_keys: HotKeys,           // counts the reads of KVS and Transaction gets
+    cache: CacheOptions,         // for the SSTables, with the metadata cache they share
+    row_cache: Option<RowCache>, // the newest records read from the SSTables, see `KVSOptions::row_cache_size`
+    read_only: AtomicBool,       // set with the background error, so writes check it without locking the background
+    free_space: AtomicU64,       // the bytes free as of the last check, see `low_on_space`
+    space_checked: AtomicU64     // when the free space was last checked, in ms
+}
+
+/// Gets the timestamp/epoch in ms
+pub fn get_timestamp() -> u64 {
+    if let Some(ts) = sim::virtual_time() {
+        return ts;
+    }
+
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+
+    return ts.as_secs() * 1000 + ts.subsec_nanos() as u64 / 1_000_000;
+}
+
+
+/// Used to coalesce records during iteration
+fn coalesce_records(prev: Record, curr: Record) -> Result<Record, (Record, Record)> {
+    // we always go with the newest timestamp; a tie in the same store goes to the newer table, which comes after
+    if prev.key() == curr.key() {
+        Ok(if prev.created() > curr.created() { prev } else { curr })
+    } else {
+        Err( (prev, curr) )
+    }
+}
+
+/// Takes the `BATCH_TAG` field off a record read from a WAL, returning the records of its batch written after it
+fn take_batch_left(rec: &mut Record) -> u64 {
+    rec.take_field(BATCH_TAG).filter(|left| left.len() == U64_SIZE).map_or(0, |left| LE::read_u64(&left))
+}
+
+/// Reads the records of a WAL into the mem_table, returning the newest timestamp
+/// Anything after the last record that can be read, left by a crash, is truncated, along with the
+/// records of a batch whose last record isn't there, so a batch is replayed whole or not at all.
+fn replay_wal(wal_file: &mut RecordFile, mem_table: &WalMemTable) -> Result<u64, IOError> {
+    let mut last_ts = 0;
+    let mut records_end;
+    let mut batch = vec![]; // the records of a batch read so far, added once its last one is
+
+    {
+        let mut records = wal_file.iter_with_offsets()?;
+
+        loop {
+            let offset = records.offset();
+
+            match records.next() {
+                None => {
+                    records_end = offset;
+                    break;
+                },
+                Some(Ok( (_, bytes) )) => {
+                    let mut rec = Record::deserialize(bytes);
+                    let left = take_batch_left(&mut rec);
+
+                    batch.push( (offset, rec) );
+
+                    if left == 0 {
+                        for (_, rec) in batch.drain(..) {
+                            last_ts = last_ts.max(rec.created());
+                            mem_table.insert(rec);
+                        }
+                    }
+                },
+                Some(Err(e)) => {
+                    warn!("Error reading WAL {:?} at {}, dropping the records from there: {}", wal_file.file_path(), offset, e);
+                    records_end = offset;
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(&(offset, _)) = batch.first() {
+        warn!("The batch at {} of WAL {:?} wasn't all written, dropping its {} records", offset, wal_file.file_path(), batch.len());
+        records_end = offset;
+    }
+
+    // drop a torn record, or what was written after the count last was, so appends follow the records
+    if fs::metadata(wal_file.file_path())?.len() > records_end {
+        wal_file.truncate_to(records_end)?;
+    }
+
+    Ok(last_ts)
+}
+
+/// The record of a result, or None after saving its error, to end an iterator at the first error
+fn until_error(res: Result<Record, IOError>, error: &RefCell<Option<IOError>>) -> Option<Record> {
+    match res {
+        Ok(rec) => Some(rec),
+        Err(e) => { *error.borrow_mut() = Some(e); None }
+    }
+}
+
+/// The length of a file, or 0 if it can't be found
+fn file_size(path: &PathBuf) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// The number of records in a WAL that isn't open, from the count at its start
+fn wal_record_count(path: &PathBuf) -> Result<u64, IOError> {
+    let mut prefix = vec![0; file_prefix_len(WAL_HEADER)];
+
+    File::open(path)?.read_exact(&mut prefix)?;
+
+    let (record_count, _) = decode_file_prefix(&prefix, WAL_HEADER)?;
+
+    Ok(record_count as u64)
+}
+
+/// Reads and removes the marker `KVS::close` leaves, returning the WAL number, WAL length, and newest timestamp in it
+///
+/// The marker is removed before anything is written, so it's only trusted while the WAL is the one it names, at the same length.
+fn take_clean_shutdown(db_dir: &PathBuf) -> Result<Option<(u64, u64, u64)>, IOError> {
+    let path = db_dir.join(CLEAN_SHUTDOWN_FILE);
+    let mut contents = String::new();
+
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+
+    fs::remove_file(&path)?;
+    sim::on_remove(&path);
+
+    let fields = contents.split_whitespace().map(|f| f.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+
+    match fields {
+        Ok(ref fields) if fields.len() == 3 => Ok(Some( (fields[0], fields[1], fields[2]) )),
+        _ => {
+            warn!("Ignoring the unreadable clean shutdown marker {:?}: {:?}", path, contents);
+            Ok(None)
+        }
+    }
+}
+
+/// Writes the marker for the next open, once the mem_tables are flushed and the WAL is synced
+fn write_clean_shutdown(db_dir: &PathBuf, wal_number: u64, wal_size: u64, last_ts: u64) -> Result<(), IOError> {
+    let path = db_dir.join(CLEAN_SHUTDOWN_FILE);
+    let mut file = File::create(&path)?;
+    let mut marker = vec![];
+
+    // written in one go, as a File isn't buffered
+    writeln!(marker, "{} {} {}", wal_number, wal_size, last_ts)?;
+    file.write_all(&marker)?;
+    file.sync_data()?;
+
+    sim::on_sync(&path);
+
+    Ok( () )
+}
+
+/// Returns an error if the SSTable was written with another codec than the store's
+fn check_codec(sstable: &SSTable, codec: CodecKind) -> Result<(), IOError> {
+    if sstable.codec() != codec {
+        return Err(IOError::new(ErrorKind::InvalidData, format!("The SSTable {:?} is written with the {:?} codec, but the store uses {:?}", sstable.file_path(), sstable.codec(), codec)));
+    }
+
+    Ok( () )
+}
+
+/// Returns the first key after all the keys starting with the prefix, if there is one
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last != 0xFF {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// Returns true if an IO operation that failed may work if tried again
+///
+/// Bad data or arguments, and missing or forbidden files, won't get better by waiting.
+fn is_transient(e: &IOError) -> bool {
+    match e.kind() {
+        ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof | ErrorKind::NotFound | ErrorKind::PermissionDenied => false,
+        _ => true
+    }
+}
+
+/// Returns an error once the deadline has passed
+fn check_deadline(deadline: Option<Instant>) -> Result<(), DeadlineExceeded> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(DeadlineExceeded { entries: vec![] }),
+        _ => Ok( () )
+    }
+}
+
+/// A cursor is a version byte, the sequence number of the view it reads, and the last key returned
+fn encode_cursor(seq: u64, last_key: &[u8]) -> Vec<u8> {
+    let mut cursor = vec![0; 9];
+
+    cursor[0] = CURSOR_VERSION;
+    BE::write_u64(&mut cursor[1..], seq);
+    cursor.extend_from_slice(last_key);
+
+    cursor
+}
+
+fn decode_cursor(cursor: &[u8]) -> Result<(u64, Vec<u8>), IOError> {
+    if cursor.len() < 9 || cursor[0] != CURSOR_VERSION {
+        return Err(IOError::new(ErrorKind::InvalidInput, "Not a cursor from KVS::range_page"));
+    }
+
+    Ok( (BE::read_u64(&cursor[1..]), cursor[9..].to_vec()) )
+}
+
+/// Reads up to `CHANGE_BATCH` changes from `seq` on, from a WAL starting at `first_seq`
+/// * offset - the offset of `seq`, or None to find it from the start of the WAL
+/// return: the changes, and the offset of the change after them, if `seq` is in the WAL
+fn read_wal_changes(wal_file: &RecordFile, first_seq: u64, seq: u64, offset: Option<u64>) -> Result<(Vec<Change>, Option<u64>), IOError> {
+    let end_seq = first_seq + wal_file.record_count() as u64;
+    let (mut cur_seq, offset) = match offset {
+        Some(offset) => (seq, offset),
+        None => (first_seq, wal_file.first_offset())
+    };
+    let mut records = wal_file.iter_with_offsets_from(offset)?;
+    let mut changes = Vec::new();
+
+    while cur_seq < end_seq && changes.len() < CHANGE_BATCH {
+        let (_, bytes) = match records.next() {
+            Some(rec) => rec?,
+            None => break
+        };
+
+        if cur_seq >= seq {
+            changes.push(Change::new(cur_seq, Record::deserialize(bytes)));
+        }
+
+        cur_seq += 1;
+    }
+
+    Ok( (changes, if cur_seq >= seq { Some(records.offset()) } else { None }) )
+}
+
+/*
+ * Files have the following meanings:
+ * MANIFEST       - The numbers of the files below that make up the store
+ * OPTIONS        - The options the store was created with, in TOML
+ * ######.wal     - Write Ahead Log; journal of all put & deletes that are in the active mem_table,
+ *                  or in one of the immutable mem_tables waiting to be flushed, or kept for change streams
+ * ######.sst     - The current SSTable with the merges from mem_table, and range deletes not yet compacted,
+ *                  or one of the SSTables without overlapping ranges
+ * MANIFEST-new   - A new version of the MANIFEST
+ */
+impl KVS {
+    /// Creates a new KVS given a directory to store the files
+    fn new(options: KVSOptions) -> Result<KVS, IOError> {
+        let db_dir = options.db_dir.to_path_buf();
+        let mut last_ts = 0;
+
+        let mut manifest = Manifest::open(&db_dir)?;
+
+        if let Some(ref archive_dir) = options.wal_archive_dir {
+            fs::create_dir_all(archive_dir)?;
+        }
+
+        // after a clean shutdown everything is flushed, and there's no WAL to replay
+        let clean_shutdown = take_clean_shutdown(&db_dir)?.and_then(|(wal_number, wal_size, ts)| {
+            let clean = wal_number == manifest.wal_number() && manifest.immutable_wal_numbers().is_empty() && file_size(&manifest.wal_path()) == wal_size;
+
+            if clean { Some(ts) } else { None }
+        });
+
+        // anything not in the manifest is left over from a flush or compaction that didn't finish
+        if clean_shutdown.is_none() {
+            manifest.remove_obsolete_files(&HashSet::new())?;
+        }
+
+        // the mem_tables that were being flushed when the store was closed
+        let mut immutables = Vec::new();
+        let mut next_seq = 0; // the sequence number after the last record of the WALs read so far
+
+        for number in manifest.immutable_wal_numbers().to_vec() {
+            let mut wal_file = RecordFile::new(&manifest.wal_file(number), WAL_HEADER, options.rec_file_buffer_size, options.rec_file_cache_size)?;
+            let mem_table = WalMemTable::new(options.mem_table, number);
+
+            // stores from before sequence numbers were kept number their WALs from here
+            let first_seq = manifest.wal_first_seq(number).unwrap_or(next_seq);
+
+            manifest.set_wal_first_seq(number, first_seq);
+            last_ts = last_ts.max(replay_wal(&mut wal_file, &mem_table)?);
+            next_seq = first_seq + wal_file.record_count() as u64;
+            mem_table.set_wal_bytes(wal_file.ends_at()?);
+            immutables.push(Arc::new(mem_table));
+        }
+
+        let mut wal_file = RecordFile::new(&manifest.wal_path(), WAL_HEADER, options.rec_file_buffer_size, options.rec_file_cache_size)?;
+
+        wal_file.sync()?; // so the header of a new WAL file is on disk
+
+        let wal_number = manifest.wal_number();
+        let first_seq = manifest.wal_first_seq(wal_number).unwrap_or(next_seq);
+
+        manifest.set_wal_first_seq(wal_number, first_seq);
+
+        // read back in our WAL file if we have one
+        let mem_table = WalMemTable::new(options.mem_table, manifest.wal_number());
+
+        last_ts = last_ts.max(match clean_shutdown {
+            Some(ts) => {
+                debug!("Skipping the WAL replay after a clean shutdown");
+                ts
+            },
+            None => replay_wal(&mut wal_file, &mem_table)?
+        });
+
+        let sstable_current_path = manifest.current_path();
+        let mut cache = options.cache_options();
+
+        // the log is opened to read the values the SSTables point to, even once no more are added to it
+        if options.value_log_threshold != 0 || !manifest.value_log_numbers().is_empty() {
+            let value_log = ValueLog::new(options.rec_file_buffer_size);
+
+            for &number in manifest.value_log_numbers() {
+                value_log.open_file(number, &manifest.value_log_path(number))?;
+            }
+
+            if options.value_log_threshold != 0 && manifest.value_log_numbers().is_empty() {
+                let number = manifest.new_file_number();
+
+                value_log.open_file(number, &manifest.value_log_path(number))?;
+                manifest.add_value_log(number);
+                manifest.save()?;
+            }
+
+            cache.value_log = Some(Arc::new(value_log));
+        }
+
+        let sstable_current = if sstable_current_path.exists() {
+            SSTable::open(&sstable_current_path, options.rec_file_buffer_size, cache.clone())
+        } else {
+            SSTable::new(NewSSTable::new(&sstable_current_path, manifest.current_number(), &options.sstable_options(), options.rec_file_buffer_size, cache.clone()), &mut iter::empty::<Record>())
+        }.expect("Error opening current SSTable");
+
+        check_codec(&sstable_current, options.codec)?;
+
+        for tombstone in sstable_current.range_tombstones() {
+            last_ts = last_ts.max(tombstone.created());
+        }
+
+        let mut sstables = BTreeSet::<TableMeta>::new();
+        let table_cache = TableCache::new(options.max_open_tables, options.rec_file_buffer_size, cache.clone());
+
+        // gather up all the SSTables in the manifest
+        for path in manifest.table_paths() {
+            let sstable = SSTable::open(&path, options.rec_file_buffer_size, cache.clone())?;
+
+            check_codec(&sstable, options.codec)?;
+            sstables.insert(table_cache.insert(sstable));
+        }
+
+        let wal_size = wal_file.ends_at()?;
+
+        let quotas = Quotas::new(&options.quotas);
+        let hot_keys = HotKeys::new(options.hot_keys);
+        let row_cache = if options.row_cache_size != 0 { Some(RowCache::new(options.row_cache_size)) } else { None };
+        let core = Arc::new_cyclic(|this| Core {
+            this: this.clone(),
+            options: options,
+            manifest: Mutex::new(manifest),
+            wal: Mutex::new(Wal { size: wal_size, file: wal_file, first_seq: first_seq, last_ts: last_ts, written: 0, synced: 0, watchers: vec![] }),
+            wal_sync: Mutex::new(()),
+            state: RwLock::new(State {
+                mem_table: Arc::new(mem_table),
+                immutables: immutables.clone(),
+                cur_sstable: Arc::new(sstable_current),
+                sstables: sstables
+            }),
+            table_cache: table_cache,
+            versions: Mutex::new(VersionSet::new()),
+            table_lock: Mutex::new(()),
+            background: Mutex::new(Background { shutdown: false, busy: false, scheduled: false, paused: false, failed: false, panic: None, error: None }),
+            work_ready: Condvar::new(),
+            work_done: Condvar::new(),
+            locks: LockManager::new(LOCK_STRIPES),
+            next_txn_id: AtomicU64::new(1),
+            cursors: Mutex::new(HashMap::new()),
+            quotas: quotas,
+            hot_keys: hot_keys,
+            cache: cache,
+            row_cache: row_cache,
+            read_only: AtomicBool::new(false),
+            free_space: AtomicU64::new(u64::max_value()),
+            space_checked: AtomicU64::new(0)
+        });
+
+        for quota in core.options.quotas.iter() {
+            core.count_quota(&quota.prefix);
+        }
+
+        // finish the flushes that were interrupted
+        for mem_table in immutables {
+            core.flush_mem_table(mem_table, Instant::now())?;
+        }
+
+        if core.options.check_on_open {
+            let report = core.check();
+
+            if !report.is_consistent() {
+                return Err(IOError::new(ErrorKind::InvalidData, report.to_string()));
+            }
+
+            debug!("{}", report);
+        }
+
+        let flusher = if core.options.executor.0.is_some() { None } else {
+            let core = core.clone();
+            let context = sim::context();
+
+            Some(thread::Builder::new().name("kvs-flush".to_string()).spawn(move || {
+                sim::enter(context);
+                core.run_background();
+            })?)
+        };
+
+        return Ok(KVS { core: core, flusher: flusher })
+    }
+
+    /// Opens an existing KVS directory/database.
+    ///
+    /// All of the original options used to create the KVS instance will be used when open.
+    ///
+    /// # Examples
+    /// ```
+    /// {   // scope so it is dropped after opening
+    ///     KVSOptions::new("/tmp/kvs").create().unwrap();
+    /// }
+    ///
+    /// let kvs = KVS::open("/tmp/kvs").unwrap();
+    /// ```
+    pub fn open(db_dir: &PathBuf) -> Result<KVS, IOError> {
+        let mut options = KVSOptions::new(db_dir);
+
+        options.apply(OptionsFile::read(&db_dir.join(OPTIONS_FILE))?);
+        options.validate()?;
+
+        KVS::new(options)
+    }
+
+    /// Recovers a store to a point in the past, from the WAL segments archived with `KVSOptions::wal_archive_dir`
+    ///
+    /// The store in the options' directory, such as a copy of a backup, or a new store if there isn't
+    /// one, gets the archived changes from its `next_seq` up to the point. The archive must hold every
+    /// change from there on, so restoring a new store needs an archive kept since the store was created.
+    /// The options are saved with the store, like `KVSOptions::create`, and can't archive to the same directory.
+    pub fn restore_to(options: KVSOptions, archive_dir: &PathBuf, point: RestorePoint) -> Result<KVS, IOError> {
+        if options.wal_archive_dir.as_ref() == Some(archive_dir) {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("Can't archive to {:?} while restoring from it", archive_dir)));
+        }
+
+        let buffer_size = options.rec_file_buffer_size;
+        let kvs = options.create()?;
+        let mut seq = kvs.next_seq();
+        let mut batch = vec![]; // the records of a batch read so far, a batch the archive ends in the middle of is left out
+
+        for (first_seq, end_seq, path) in wal_archive::segments(archive_dir)? {
+            if end_seq <= seq {
+                continue;
+            }
+
+            if first_seq > seq {
+                return Err(IOError::new(ErrorKind::NotFound, format!("The changes from {} to {} aren't in the archive {:?}", seq, first_seq, archive_dir)));
+            }
+
+            let wal_file = RecordFile::new(&path, WAL_HEADER, buffer_size, 1)?;
+
+            for rec in wal_file.iter_with_offsets()?.skip((seq - first_seq) as usize) {
+                let mut rec = Record::deserialize(rec?.1);
+                let left = take_batch_left(&mut rec);
+
+                let past = match point {
+                    RestorePoint::Seq(end) => seq >= end,
+                    RestorePoint::Timestamp(ts) => rec.created() > ts
+                };
+
+                // a batch the point falls in is left out whole
+                if past {
+                    return Ok(kvs);
+                }
+
+                batch.push(rec);
+                seq += 1;
+
+                if left == 0 {
+                    kvs.core.insert(mem::replace(&mut batch, vec![]), &WriteOptions { keep_timestamps: true, ..WriteOptions::new() });
+                }
+            }
+        }
+
+        debug!("Restored {:?} to the end of the archive: {}", kvs.core.options.db_dir, seq);
+
+        Ok(kvs)
+    }
+
+    pub fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>> {
+        self.get_with_options(key, &ReadOptions::new())
+    }
+
+    /// Gets the value of a key, using the `ReadOptions`
+    pub fn get_with_options(&self, key: &Vec<u8>, options: &ReadOptions) -> Option<Vec<u8>> {
+        self.core.hot_keys.record(key);
+        self.core.get_with_options(key, options)
+    }
+
+    /// Gets the value of a key as `Bytes`, for the APIs that take them
+    ///
+    /// It isn't zero-copy: the value is copied out of the mem_table, or decoded from the record cache or file
+    /// of an SSTable, like `get`, and the `Bytes` takes over that buffer. Clones and slices of it share the buffer.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&self, key: &Vec<u8>) -> Option<Bytes> {
+        self.get(key).map(Bytes::from)
+    }
+
+    /// Gets the value of a key, unless the `ReadOptions::deadline` passes first, or reading an SSTable fails
+    pub fn try_get(&self, key: &Vec<u8>, options: &ReadOptions) -> Result<Option<Vec<u8>>, ReadError> {
+        let deadline = options.deadline.map(|deadline| Instant::now() + deadline);
+
+        self.core.hot_keys.record(key);
+        self.core.get_until(key, options, deadline)
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.put_with_options(key, value, &WriteOptions::new())
+    }
+
+    /// Puts a key/value pair that expires after the TTL
+    ///
+    /// Once expired, the key reads as deleted, and the record is dropped by the next compaction of its SSTable.
+    pub fn put_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        let ttl_ms = ttl.as_secs() * 1000 + ttl.subsec_nanos() as u64 / 1_000_000;
+        let rec = Record::new_with_ttl(key, Some(value), self.core.now() + ttl_ms);
+
+        self.core.insert(vec![rec], &WriteOptions::new())
+    }
+
+    /// Puts a key/value pair, using the `WriteOptions`
+    pub fn put_with_options(&self, key: Vec<u8>, value: Vec<u8>, options: &WriteOptions) {
+//        debug!("Called put: {:?}", key);
+
+        // create a record, and call insert
+        let rec = Record::new(key.to_vec(), Some(value));
+
+        self.core.insert(vec![rec], options)
+    }
+
+    pub fn delete(&self, key: &Vec<u8>) {
+        debug!("Called delete: {:?}", key);
+
+        // create a record, and call insert
+        let rec = Record::new(key.to_vec(), None);
+
+        self.core.insert(vec![rec], &WriteOptions::new())
+    }
+
+    /// Applies all the puts and deletes in the batch, using the `WriteOptions`
+    ///
+    /// The batch is added to the mem_table as a whole, so a flush never splits it.
+    pub fn write(&self, batch: WriteBatch, options: &WriteOptions) {
+        debug!("Called write: {} records", batch.len());
+
+        self.core.insert(batch.records, options)
+    }
+
+    /// Puts a key/value pair, unless it would take a prefix over its quota, see `KVSOptions::quota`,
+    /// or the disk is low on space, see `KVSOptions::min_free_space`
+    pub fn try_put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), WriteError> {
+        let mut batch = WriteBatch::new();
+
+        batch.put(key, value);
+
+        self.try_write(batch, &WriteOptions::new())
+    }
+
+    /// Applies the batch, like `write`, unless it would take a prefix over its quota, or the disk is low on space
+    ///
+    /// A batch that only shrinks a prefix over its quota, such as one of deletes, is always applied,
+    /// and a batch of only deletes is applied when the disk is low on space. After a background error,
+    /// see `background_error`, it fails with `WriteError::ReadOnly` instead of panicking like `write`.
+    pub fn try_write(&self, batch: WriteBatch, options: &WriteOptions) -> Result<(), WriteError> {
+        self.core.writable().map_err(WriteError::ReadOnly)?;
+
+        if batch.records.iter().any(|rec| !rec.is_delete()) {
+            self.core.check_space().map_err(WriteError::OutOfSpace)?;
+        }
+
+        let written = self.core.insert_if(batch.records, options, |exceeded| match exceeded {
+            Some(e) => Err(WriteError::QuotaExceeded(e.clone())),
+            None => Ok( () )
+        });
+
+        written.map_err(|e| match e {
+            InsertError::Check(e) => e,
+            InsertError::ReadOnly(e) => WriteError::ReadOnly(e)
+        })
+    }
+
+    /// Puts the new value, or deletes the key when it's None, if the key's value is the expected one
+    ///
+    /// None expects the key not to be there. The value is compared and written with the WAL locked,
+    /// so no other write can come in between, for counters and claiming keys without a transaction.
+    pub fn compare_and_swap(&self, key: &Vec<u8>, expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<(), CompareFailed> {
+        let rec = Record::new(key.to_vec(), new.map(|value| value.to_vec()));
+
+        self.core.insert_if(vec![rec], &WriteOptions::new(), |_| {
+            let current = self.core.get_with_options(key, &ReadOptions::new());
+
+            if current.as_ref().map(|value| value.as_slice()) == expected {
+                Ok( () )
+            } else {
+                Err(CompareFailed { current: current })
+            }
+        }).map_err(InsertError::or_panic)
+    }
+
+    /// Adds the delta to the counter at the key, returning its new value; a key that isn't there counts as 0
+    ///
+    /// Counters are 8 byte little-endian i64s, see `encode_counter`. The counter is read and written with
+    /// the WAL locked, like `compare_and_swap`, so concurrent increments are never lost. After a background
+    /// error it fails with `IncrementError::ReadOnly`, like `try_write`.
+    pub fn increment(&self, key: &Vec<u8>, delta: i64) -> Result<i64, IncrementError> {
+        let mut count = 0;
+
+        let written = self.core.insert_with(&WriteOptions::new(), || {
+            let current = match self.core.get_with_options(key, &ReadOptions::new()) {
+                None => 0,
+                Some(value) => decode_counter(&value).ok_or(IncrementError::NotACounter(value))?
+            };
+
+            count = current.checked_add(delta).ok_or(IncrementError::Overflow(current))?;
+
+            Ok(vec![Record::new(key.to_vec(), Some(encode_counter(count)))])
+        }, |_| Ok( () ));
+
+        match written {
+            Ok( () ) => Ok(count),
+            Err(InsertError::Check(e)) => Err(e),
+            Err(InsertError::ReadOnly(e)) => Err(IncrementError::ReadOnly(e))
+        }
+    }
+
+    /// Deletes all the keys in the range [start, end)
+    ///
+    /// The keys are hidden right away, and removed from disk by flushes and the next compaction.
+    /// The range delete is kept with the current SSTable until a compaction applies it to all the tables.
+    /// SSTables entirely inside the range are dropped by the compaction without being rewritten.
+    pub fn delete_range(&self, start: &Vec<u8>, end: &Vec<u8>) {
+        if let Err(e) = self.core.delete_range(start, end, None) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Deletes all the keys starting with the prefix, with a range delete
+    ///
+    /// With `compact`, the SSTables holding keys with the prefix are rewritten without them before
+    /// this returns, rather than waiting for the next compaction; those with only such keys are dropped.
+    /// An IO error rewriting them is returned, and makes the store read-only, like one in the background work.
+    /// A prefix that's empty, or all 0xFF bytes, has no key to end the range at, and gets an error of kind
+    /// `InvalidInput`; see `delete_range`.
+    pub fn delete_prefix(&self, prefix: &Vec<u8>, compact: bool) -> Result<(), IOError> {
+        let end = prefix_end(prefix).ok_or_else(|| IOError::new(ErrorKind::InvalidInput, "delete_prefix needs a prefix with a byte other than 0xFF"))?;
+        let tombstone = self.core.delete_range(prefix, &end, None).map_err(|e| IOError::new(e.kind, e.to_string()))?;
+
+        if compact {
+            if let Err(e) = self.core.compact_range_delete(&tombstone) {
+                self.core.set_background_error(&e);
+
+                return Err(e);
+            }
+        }
+
+        Ok( () )
+    }
+
+    /// Compacts the keys in the range [start, end) down to the level: 0 is the current SSTable, 1 the others
+    ///
+    /// The mem_tables are flushed to the current SSTable first. For level 1, the current SSTable is then merged
+    /// with the others if it has keys in the range, whether or not it has the records for a merge, which applies
+    /// its deletes and range deletes; otherwise the SSTables with keys in the range are rewritten without their
+    /// expired records. The merge takes in all of the current SSTable, not just the range.
+    /// Returns an error for a level over 1, the background error of a read-only store, or an IO error of the compaction.
+    pub fn compact_range(&self, start: &Vec<u8>, end: &Vec<u8>, target_level: usize) -> Result<(), IOError> {
+        self.core.compact_range(start, end, target_level)
+    }
+
+    /// Returns a read-only view of the store as it is now, to use with `ReadOptions::snapshot`
+    ///
+    /// The files the snapshot reads aren't removed until it is dropped, nor are the mem_tables, which it reads
+    /// as they were, so the records the writes after it replace in them are kept until then too.
+    pub fn snapshot(&self) -> Snapshot {
+        self.core.snapshot().expect("Error opening SSTable")
+    }
+
+    /// Starts a transaction, which reads the store as it is now, and writes when it's committed
+    ///
+    /// See `Transaction` for when a commit fails.
+    pub fn begin(&self) -> Transaction {
+        self.begin_with_options(&TransactionOptions::new())
+    }
+
+    /// Starts a transaction, using the `TransactionOptions`
+    pub fn begin_with_options(&self, options: &TransactionOptions) -> Transaction {
+        let mut read_options = ReadOptions::new();
+
+        // pessimistic transactions read the latest values, as they lock the keys before reading them
+        if !options.pessimistic {
+            read_options.snapshot(&self.core.snapshot().expect("Error opening SSTable"));
+        }
+
+        Transaction {
+            kvs: self,
+            id: self.core.next_txn_id.fetch_add(1, Ordering::Relaxed),
+            options: options.clone(),
+            read_options: read_options,
+            keys: BTreeSet::new(),
+            writes: BTreeMap::new(),
+            lock_failed: None
+        }
+    }
+
+    /// Returns a stream of the changes committed from the sequence number `from_seq` on
+    ///
+    /// Every write to the WAL gets the next sequence number, starting from 0; writes with
+    /// `WriteOptions::disable_wal` aren't in the WAL, so they have none and aren't streamed.
+    /// The stream returns `None` once it has caught up, and the changes committed since when it's
+    /// polled again. Changes are read from the WALs, so only those still in the active WAL,
+    /// or in one of the `KVSOptions::wal_retention` flushed WALs, can be streamed; the stream
+    /// returns an error of kind `NotFound` when it starts from, or falls behind to, one that's gone.
+    pub fn subscribe(&self, from_seq: u64) -> ChangeStream {
+        ChangeStream {
+            kvs: self,
+            seq: from_seq,
+            position: None,
+            changes: VecDeque::new()
+        }
+    }
+
+    /// The sequence number the next write will get; subscribing from it streams only newer changes
+    pub fn next_seq(&self) -> u64 {
+        let wal = self.core.wal.lock().unwrap();
+
+        wal.first_seq + wal.file.record_count() as u64
+    }
+
+    /// Returns a channel that gets the changes to keys starting with the prefix, from the next write on
+    ///
+    /// The changes are sent as they're written to the WAL, so writes with `WriteOptions::disable_wal`
+    /// aren't sent. Range deletes are sent when they cover any key with the prefix. The channel isn't
+    /// bounded, so a receiver that's slow to read keeps the changes in memory; dropping it ends the watch.
+    pub fn watch(&self, prefix: &Vec<u8>) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+
+        self.core.wal.lock().unwrap().watchers.push( (prefix.to_vec(), sender) );
+
+        receiver
+    }
+
+    /// Applies the changes of another replica, from its `subscribe` or `watch`, by last-write-wins
+    ///
+    /// A put or delete is written, with its timestamp, if it's newer than the key's latest record: the later
+    /// timestamp wins, and on a tie the larger value, with any value larger than a delete, so replicas
+    /// applying each other's changes agree. A change that isn't newer goes to the `KVSOptions::conflict_resolver`,
+    /// if there is one. Range deletes are always written, as they only delete what's older. The timestamps
+    /// are given to the clock's `update`, see `KVSOptions::clock`, so the writes of this store come after them.
+    /// Each change should be applied once, going on from the last `seq` applied, as one that conflicts is
+    /// settled again if it's applied again. Returns the number of changes that wrote anything.
+    ///
+    /// A range delete without an end gets an error of kind `InvalidInput`, before any change is written.
+    /// An IO error reading a key's record, or the background error of a read-only store, is returned
+    /// as it's hit, with the changes before it written.
+    pub fn apply_changes(&self, changes: &[Change]) -> Result<usize, IOError> {
+        if changes.iter().any(|change| change.op == ChangeOp::DeleteRange && change.value.is_none()) {
+            return Err(IOError::new(ErrorKind::InvalidInput, "A range delete needs the end of its range"));
+        }
+
+        let options = WriteOptions { keep_timestamps: true, ..WriteOptions::new() };
+        let mut applied = 0;
+
+        for change in changes.iter() {
+            if let Some(ref clock) = self.core.options.clock.0 {
+                clock.update(change.ts);
+            }
+
+            // the value of a range delete is its end
+            match change.value {
+                Some(ref end) if change.op == ChangeOp::DeleteRange => {
+                    self.core.delete_range(&change.key, end, Some(change.ts)).map_err(|e| IOError::new(e.kind, e.to_string()))?;
+                    applied += 1;
+                    continue;
+                },
+                _ => ()
+            }
+
+            let mut rec = Record::new(change.key.to_vec(), change.value.clone());
+            rec.set_created(change.ts);
+
+            // compared with the WAL locked, so a write in between can't be overwritten by an older change;
+            // the error is None for a change that isn't written, and the IO error of a read that failed
+            let written = self.core.insert_with(&options, || {
+                let state = self.core.state.read().unwrap();
+
+                let found = match self.core.find(&state, &change.key, &ReadOptions::new(), None) {
+                    Ok(found) => found,
+                    Err(ReadError::IO(e)) => return Err(Some(e)),
+                    Err(e) => return Err(Some(IOError::new(ErrorKind::Other, e.to_string()))) // there's no deadline
+                };
+
+                match found {
+                    Some(current) if !rec.is_newer_than(&current) => {
+                        let range_deleted = state.is_range_deleted(&current);
+
+                        self.core.resolve(rec, current, range_deleted).map_err(|_| None)
+                    },
+                    _ => Ok(vec![rec])
+                }
+            }, |_| Ok( () ));
+
+            match written {
+                Ok( () ) => applied += 1,
+                Err(InsertError::Check(None)) => (),
+                Err(InsertError::Check(Some(e))) => return Err(e),
+                Err(InsertError::ReadOnly(e)) => return Err(IOError::new(e.kind, e.to_string()))
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Returns an iterator over all the key/value pairs, in key order
+    ///
+    /// The iterator reads the store as it was when created; writes, flushes, and compactions
+    /// after that aren't seen, and the files it reads aren't removed until it is dropped.
+    pub fn iter(&self) -> Iter {
+        self.core.new_iter(None, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key/value pairs with keys in the range [start, end), in key order
+    ///
+    /// See `iter` for how the iterator relates to later writes.
+    pub fn range(&self, start: &Vec<u8>, end: &Vec<u8>) -> Iter {
+        self.range_with_options(start, end, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key/value pairs with keys in the range [start, end), using the `ReadOptions`
+    pub fn range_with_options(&self, start: &Vec<u8>, end: &Vec<u8>, options: &ReadOptions) -> Iter {
+        self.core.new_iter(Some( (start.to_vec(), end.to_vec()) ), options)
+    }
+
+    /// Returns an iterator over the newest record of every key, in key order, with its timestamp and expiry
+    ///
+    /// Unlike `iter`, deletes not yet dropped by a merge are returned, as tombstones, and so are records that
+    /// have expired. Keys deleted by a range delete aren't, nor are the range deletes. The records carry no
+    /// sequence number, only the WAL does, see `subscribe`. See `iter` for how the iterator relates to later writes.
+    pub fn raw_iter(&self) -> RawIter {
+        RawIter(self.core.merged_iter(None, None, &ReadOptions::new(), true).expect("Error reading SSTable"))
+    }
+
+    /// Like `raw_iter`, over the keys in the range [start, end), using the `ReadOptions`
+    pub fn raw_range_with_options(&self, start: &Vec<u8>, end: &Vec<u8>, options: &ReadOptions) -> RawIter {
+        RawIter(self.core.merged_iter(Some(start.to_vec()), Some(end.to_vec()), options, true).expect("Error reading SSTable"))
+    }
+
+    /// Returns the key/value pairs with keys in the range [start, end), unless the `ReadOptions::deadline` passes first
+    ///
+    /// The error holds the pairs read until then, so the caller can return them, or read on from the last key.
+    pub fn try_range(&self, start: &Vec<u8>, end: &Vec<u8>, options: &ReadOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DeadlineExceeded> {
+        let mut it = self.range_with_options(start, end, options);
+        let entries = it.by_ref().collect::<Vec<_>>();
+
+        if it.deadline_exceeded() {
+            return Err(DeadlineExceeded { entries: entries });
+        }
+
+        Ok(entries)
+    }
+
+    /// Counts the keys in the range [start, end), with a keys only scan, see `ReadOptions::keys_only`
+    pub fn count_range(&self, start: &Vec<u8>, end: &Vec<u8>) -> u64 {
+        let mut options = ReadOptions::new();
+
+        options.keys_only(true).fill_cache(false);
+
+        self.range_with_options(start, end, &options).count() as u64
+    }
+
+    /// Sums, and finds the smallest and largest, of the values in the range [start, end) that are numbers
+    ///
+    /// Numbers are 8 byte little-endian u64s, like `encode_counter` without the sign; other values are
+    /// counted as skipped. The values are read one at a time, without collecting the range.
+    pub fn aggregate_range(&self, start: &Vec<u8>, end: &Vec<u8>) -> Aggregate {
+        let mut options = ReadOptions::new();
+
+        options.fill_cache(false);
+
+        self.range_with_options(start, end, &options).fold(Aggregate::default(), |mut agg, (_, value)| {
+            if value.len() == 8 {
+                let n = LE::read_u64(&value);
+
+                agg.count += 1;
+                agg.sum = agg.sum.saturating_add(n);
+                agg.min = Some(agg.min.map_or(n, |min| min.min(n)));
+                agg.max = Some(agg.max.map_or(n, |max| max.max(n)));
+            } else {
+                agg.skipped += 1;
+            }
+
+            agg
+        })
+    }
+
+    /// Samples about n keys from the indexes of the SSTables, in key order, to pick split points without a scan
+    ///
+    /// Each SSTable gives samples in proportion to its records, evenly spaced through it, so the samples
+    /// follow the distribution of the keys; the keys between two samples are about the same in number.
+    /// The mem_tables aren't sampled, and the keys of deleted or overwritten records may be.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<Vec<u8>>, IOError> {
+        let version = {
+            let state = self.core.state.read().unwrap();
+
+            self.core.pin_tables(&state)?
+        };
+
+        let total = version.tables().iter().map(|t| t.record_count()).sum::<u64>();
+        let mut keys = vec![];
+
+        if total == 0 {
+            return Ok(keys);
+        }
+
+        let mut before = 0;
+
+        // the shares of the tables add up to n
+        for table in version.tables().iter() {
+            let share = n as u64 * (before + table.record_count()) / total - n as u64 * before / total;
+
+            before += table.record_count();
+            keys.extend(table.sample_keys(share as usize)?);
+        }
+
+        keys.sort();
+        keys.dedup();
+
+        Ok(keys)
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in the range [start, end), and a cursor to the rest
+    ///
+    /// The first page is read without a cursor. The cursor of each page is passed back, with the same range,
+    /// for the next one, until a page comes without a cursor. A cursor is opaque bytes holding the last key
+    /// returned, and the sequence number of the view of the store the pages read, so a listing doesn't see
+    /// the writes made after its first page. The view is kept for `KVSOptions::cursor_timeout` after each page,
+    /// and only while it's one of the `KVSOptions::max_cursors` read most recently; a listing that fits in one
+    /// page keeps none. A cursor passed back after its view is dropped, or with a different range, gets an error
+    /// of kind `NotFound`, and bytes that aren't a cursor of the range get `InvalidInput`.
+    pub fn range_page(&self, start: &Vec<u8>, end: &Vec<u8>, limit: usize, cursor: Option<&[u8]>) -> Result<Page, IOError> {
+        self.core.page(start, Some(end), limit, cursor)
+    }
+
+    /// Returns up to `limit` key/value pairs with keys starting with the prefix, like `range_page`
+    pub fn prefix_page(&self, prefix: &Vec<u8>, limit: usize, cursor: Option<&[u8]>) -> Result<Page, IOError> {
+        self.core.page(prefix, prefix_end(prefix).as_ref(), limit, cursor)
+    }
+
+    /// Returns an upper bound on the number of records
+    /// To get an exact count, we'd need to read all the records in searching for deletes
+    pub fn count_estimate(&self) -> u64 {
+        self.core.count_estimate()
+    }
+
+    /// Returns the sizes and key ranges of the levels, the hit rates of the caches, and the compaction work pending
+    ///
+    /// The cache hits and misses are counted since the store was opened; the record cache's are
+    /// of the SSTables that are open now.
+    pub fn stats(&self) -> StoreStats {
+        self.core.stats()
+    }
+
+    /// Returns a property of the store as text, or None if there's no property with the name
+    ///
+    /// For tools that query stores generically, the properties are:
+    /// - `kvs.num-files-at-level<N>`: the SSTables of level 0, the current one, or 1
+    /// - `kvs.estimate-num-keys`: like `count_estimate`
+    /// - `kvs.estimate-live-data-size`: the bytes of all the SSTables
+    /// - `kvs.mem-table-size`: the approximate bytes of the records in the active mem_table
+    /// - `kvs.cur-size-all-mem-tables`: those of the active and the immutable mem_tables
+    /// - `kvs.num-entries-active-mem-table`, `kvs.num-immutable-mem-table`
+    /// - `kvs.compaction-pending`: 1 if the current SSTable has the records for a merge, else 0
+    /// - `kvs.estimate-pending-compaction-bytes`: the bytes the merge would read
+    /// - `kvs.background-errors`: 1 if the store is read-only after a background error, else 0
+    /// - `kvs.stats`: the stats, as the `kvs stats` command prints them
+    pub fn property(&self, name: &str) -> Option<String> {
+        self.core.property(name)
+    }
+
+    /// Returns the flush backlog, the size of the WALs, the free disk space, and any background error
+    ///
+    /// The store is unhealthy when writers are waiting for flushes, or it's read-only after a background
+    /// error. Unlike `stats`, it doesn't panic when the store has failed.
+    pub fn health(&self) -> Health {
+        self.core.health()
+    }
+
+    /// Looks through the whole store for inconsistencies a crash or bug could leave behind
+    ///
+    /// The files of the manifest must be there, the SSTables must open, with their file numbers as their ids,
+    /// and the SSTables after the current one mustn't overlap. The WALs must number their records one after
+    /// another, and be those of the mem_tables. Flushes and compactions wait while it runs.
+    pub fn check(&self) -> CheckReport {
+        self.core.check()
+    }
+
+    /// The SSTables of the store as it is now, for reading them directly, like to load them into another store
+    ///
+    /// The files aren't removed until the `LiveFiles` is dropped, even if a compaction replaces them. The current
+    /// SSTable comes first, if it has any records, and may overlap the others and hold deletes; the others don't,
+    /// and can be given to `ingest_external`. The records still in the mem_tables aren't in any of them.
+    pub fn export_live_files(&self) -> LiveFiles {
+        let version = {
+            let state = self.core.state.read().unwrap();
+
+            self.core.pin_tables(&state).expect("Error opening SSTable")
+        };
+
+        let files = version.tables().iter().enumerate().filter_map(|(i, sstable)| {
+            sstable.key_range().map(|(smallest, largest)| LiveFile {
+                path: sstable.file_path(),
+                level: if i == 0 { 0 } else { 1 },
+                smallest_key: smallest,
+                largest_key: largest,
+                record_count: sstable.record_count(),
+                oldest_ts: sstable.oldest_ts(),
+                newest_ts: sstable.newest_ts(),
+                file_bytes: file_size(&sstable.file_path())
+            })
+        }).collect();
+
+        LiveFiles { files: files, version: version }
+    }
+
+    /// Adds SSTables written elsewhere, like those of another store after its current one, without rewriting them
+    ///
+    /// The tables are copied, or moved, into the store as they are, with only their info rewritten to give each
+    /// its file number. They can't hold deletes, or overlap each other, the store's tables, or any key written
+    /// since its last compaction, which would be newer. Nothing is added unless they all can be, and moved tables
+    /// are moved back. Returns the number of records added.
+    pub fn ingest_external(&self, paths: &[PathBuf], options: &IngestOptions) -> Result<u64, IOError> {
+        self.core.ingest_external(paths, options)
+    }
+
+    /// Frees the space of the oldest value log file, by moving the values still pointed to into the newest one
+    ///
+    /// The SSTables pointing into the file are rewritten with the new pointers; the values they don't point
+    /// to, which were overwritten or deleted, are dropped with the file. The file being appended to isn't
+    /// collected, see `KVSOptions::value_log_file_size`; once the value log is off, every file is, with the
+    /// values moved back into the SSTables. Returns false if there was no file to collect.
+    /// Flushes and compactions wait while it runs.
+    pub fn collect_value_log(&self) -> Result<bool, IOError> {
+        self.core.collect_value_log()
+    }
+
+    /// The n most read keys, and about how many times each was read, most read first
+    ///
+    /// Empty unless `KVSOptions::hot_keys` is set, and at most that many.
+    pub fn hot_keys(&self, n: usize) -> Vec<(Vec<u8>, u64)> {
+        self.core.hot_keys.top(n)
+    }
+
+    /// Flushes the mem_table to the current SSTable, even if it isn't full, so what was written is in an SSTable
+    ///
+    /// With `wait`, returns once it's flushed, along with the mem_tables waiting before it; otherwise the
+    /// background thread flushes it. An empty mem_table isn't flushed, but is still waited for.
+    ///
+    /// # Panics
+    /// Like `wait_for_flushes`.
+    pub fn flush(&self, wait: bool) {
+        self.core.check_writable();
+
+        let is_empty = {
+            let state = self.core.state.read().unwrap();
+
+            state.mem_table.len() == 0 && state.mem_table.range_tombstones().is_empty()
+        };
+
+        if !is_empty {
+            self.core.flush_with(false, wait);
+        } else if wait {
+            self.core.wait_for_flushes();
+        }
+    }
+
+    /// Waits until the background thread has flushed all the full mem_tables, and finished any compaction
+    ///
+    /// # Panics
+    /// If the background thread panicked; its panic is raised in the first thread to wait on it.
+    /// Or if the store is read-only after a background error.
+    pub fn wait_for_flushes(&self) {
+        self.core.wait_for_flushes()
+    }
+
+    /// The IO error that made the store read-only, if there's been one
+    ///
+    /// Flushes and compactions try IO operations again after transient errors, see `KVSOptions::io_retries`.
+    /// An error they can't get past stops them, rather than panicking the background thread, as does an
+    /// error writing the WAL, or starting a new one. The store can still be read, and what was written is still in the WAL, but writes, and waiting for a flush,
+    /// panic. Closing the store returns the error; the next open replays the WAL, and flushes it again.
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        self.core.background.lock().unwrap().error.clone()
+    }
+
+    /// Stops the flushes and compactions, for a fast shutdown
+    ///
+    /// A running flush is finished, but a running compaction is abandoned. The full mem_tables are
+    /// left in their WALs for the next open to replay, so this is meant to be followed by closing or
+    /// dropping the store; a writer that has to wait for a flush after this panics. With `wait`,
+    /// returns once the background work has stopped.
+    pub fn cancel_background_work(&self, wait: bool) {
+        self.core.shutdown();
+
+        if wait {
+            self.core.wait_for_background();
+        }
+    }
+
+    /// Stops the flushes and compactions until `resume_background_work`, for a quiet spell or a file system snapshot
+    ///
+    /// Returns once the running flush or compaction, if any, is done; a compaction due is left for the resume.
+    /// Writes go on into the mem_tables, but a writer, or `wait_for_flushes`, that has to wait for a flush waits
+    /// until the resume. Closing or dropping the store resumes it, so the mem_table can be flushed.
+    pub fn pause_background_work(&self) {
+        self.core.pause_background()
+    }
+
+    /// Lets the flushes and compactions paused by `pause_background_work` go on
+    pub fn resume_background_work(&self) {
+        self.core.resume_background()
+    }
+
+    /// Closes the store, returning the errors a drop can only panic with
+    ///
+    /// With `flush` the mem_table is flushed first, unless the background work was cancelled. Then
+    /// the background work is stopped and the WAL synced. When nothing is left in the WAL, a clean
+    /// shutdown marker is written, and the next open skips the WAL replay and the search for files
+    /// left by a crash.
+    ///
+    /// A store that's read-only after a background error isn't flushed; its WAL is synced, then the
+    /// error is returned.
+    ///
+    /// # Panics
+    /// If the background thread panicked, like `wait_for_flushes`.
+    pub fn close(mut self, flush: bool) -> Result<(), IOError> {
+        self.core.resume_background();
+
+        if flush && !self.core.is_shut_down() && !self.core.is_read_only() {
+            self.core.flush(false);
+        }
+
+        self.stop_background();
+
+        let manifest = self.core.lock_manifest();
+        let mut wal = self.core.wal.lock().unwrap();
+
+        wal.file.sync()?;
+
+        if let Some(error) = self.background_error() {
+            return Err(IOError::new(error.kind, error));
+        }
+
+        let flushed = wal.file.record_count() == 0 && self.core.state.read().unwrap().immutables.is_empty();
+
+        if flushed {
+            write_clean_shutdown(&self.core.options.db_dir, manifest.wal_number(), file_size(&manifest.wal_path()), wal.last_ts)?;
+        }
+
+        debug!("Closed {:?}, flushed: {}", self.core.options.db_dir, flushed);
+
+        Ok( () )
+    }
+
+    /// Stops the background work once it's done with the flush or compaction it's running
+    fn stop_background(&mut self) {
+        self.core.shutdown();
+
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join(); // the thread keeps its panic for the waiting threads, so it never fails to join
+        }
+
+        self.core.wait_for_background();
+    }
+}
+
+impl Core {
+    /// Creates a new, empty, WAL file; it's used once the manifest is saved with its number
+    fn new_wal_file(&self, manifest: &mut Manifest) -> Result<(u64, RecordFile), IOError> {
+        let number = manifest.new_file_number();
+        let path = manifest.wal_file(number);
+
+        let mut wal_file = RecordFile::new(&path, WAL_HEADER, self.options.rec_file_buffer_size, self.options.rec_file_cache_size)?;
+
+        // the WAL being replaced is about the size this one will grow to
+        if self.options.preallocate {
+            wal_file.preallocate(file_size(&manifest.wal_path()))?;
+        }
+
+        wal_file.sync()?; // so the header is on disk before the manifest references it
+
+        Ok( (number, wal_file) )
+    }
+
+    /// Locks the manifest, raising the background thread's panic if it panicked while holding it
+    fn lock_manifest(&self) -> MutexGuard<Manifest> {
+        match self.manifest.lock() {
+            Ok(manifest) => manifest,
+            Err(_) => {
+                self.wait_until(|_, _| false);
+                unreachable!()
+            }
+        }
+    }
+
+    /// Hands out the number and path of a new SSTable
+    fn new_table_path(&self) -> (u64, PathBuf) {
+        let mut manifest = self.lock_manifest();
+        let number = manifest.new_file_number();
+
+        (number, manifest.table_path(number))
+    }
+
+    /// Saves the manifest, then removes the files it no longer references that aren't being iterated over
+    fn save_manifest(&self, manifest: &Manifest) -> Result<(), IOError> {
+        let pinned = self.versions.lock().unwrap().pinned_files();
+
+        self.retry("saving the manifest", || manifest.save())?;
+
+        sim::crash_point(CrashPoint::ManifestSaved);
+
+        // the next save removes what this one couldn't
+        if let Err(e) = manifest.remove_obsolete_files(&pinned) {
+            warn!("Error removing obsolete files: {}", e);
+        }
+
+        if let Some(ref value_log) = self.cache.value_log {
+            value_log.retain(manifest.value_log_numbers(), &pinned);
+        }
+
+        // the tables the manifest dropped are only still read by older versions, which can load their metadata again
+        if let Some(ref meta) = self.cache.meta {
+            meta.retain_tables(&manifest.table_numbers().iter().cloned().chain(iter::once(manifest.current_number())).collect());
+        }
+
+        Ok( () )
+    }
+
+    /// Runs an IO operation of a flush or compaction, trying it again after transient errors
+    ///
+    /// Waits `io_retry_delay` before the first retry, doubling the wait each time. The operation must
+    /// be safe to run again after failing part way through.
+    fn retry<T, F>(&self, what: &str, mut op: F) -> Result<T, IOError> where F: FnMut() -> Result<T, IOError> {
+        let mut delay = Duration::from_millis(self.options.io_retry_delay_ms);
+
+        for _ in 0..self.options.io_retries {
+            match op() {
+                Err(ref e) if is_transient(e) => {
+                    warn!("Error {}, trying again in {:?}: {}", what, delay, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                },
+                result => return result
+            }
+        }
+
+        op()
+    }
+
+    /// Makes the active mem_table immutable, and replaces it with an empty one with a new WAL
+    ///
+    /// Writers only wait while the mem_tables are swapped, not while the immutable one is written out.
+    fn rotate(&self, manifest: &mut Manifest) -> Result<(), IOError> {
+        let (wal_number, wal_file) = self.new_wal_file(manifest)?;
+
+        // the WAL is locked before the save, so the new WAL's sequence numbers start after the old one's last record
+        let mut wal = self.wal.lock().unwrap();
+        let old_number = manifest.wal_number();
+        let old_size = wal.size;
+        let first_seq = wal.first_seq + wal.file.record_count() as u64;
+
+        // the old WAL is kept until its mem_table is flushed, and writes to the new one must survive a crash
+        manifest.add_immutable_wal(old_number);
+        manifest.set_wal_first_seq(wal_number, first_seq);
+        manifest.set_wal(wal_number);
+        manifest.save()?;
+
+        // sync the old WAL, as writers waiting for a sync will sync the new one
+        wal.file.sync()?;
+        wal.synced = wal.written;
+        wal.size = wal_file.first_offset();
+        wal.file = wal_file;
+        wal.first_seq = first_seq;
+
+        let mut state = self.state.write().unwrap();
+        let mem_table = mem::replace(&mut state.mem_table, Arc::new(WalMemTable::new(self.options.mem_table, wal_number)));
+
+        mem_table.set_wal_bytes(old_size);
+        state.immutables.push(mem_table);
+
+        Ok( () )
+    }
+
+    /// Hands the mem_table to the background thread to flush
+    /// return: true if the mem_table was swapped
+    ///
+    /// Without `check_size` the mem_table is swapped even if it's not full, and this waits for
+    /// the background thread to flush it, and everything before it.
+    fn flush(&self, check_size: bool) -> bool {
+        self.flush_with(check_size, !check_size)
+    }
+
+    /// Like `flush`, waiting for the mem_table to be flushed only with `wait`
+    fn flush_with(&self, check_size: bool, wait: bool) -> bool {
+        debug!("Starting a flush");
+
+        let mut manifest = loop {
+            // too many mem_tables are waiting to be flushed, so wait for the oldest one
+            if self.state.read().unwrap().immutables.len() >= self.options.max_immutables {
+                let stall = if self.needs_compaction() { WriteStall::Compaction } else { WriteStall::Flush };
+
+                self.options.listeners.notify(|l| l.on_write_stall(stall));
+
+                let max_immutables = self.options.max_immutables;
+
+                self.wait_until(|state, _| state.immutables.len() < max_immutables);
+            }
+
+            // or their WALs are over max_wal_bytes, and there'd be too much to replay after a crash
+            let max_wal_bytes = self.options.max_wal_bytes;
+
+            if max_wal_bytes != 0 && self.state.read().unwrap().immutable_wal_bytes() >= max_wal_bytes {
+                self.options.listeners.notify(|l| l.on_write_stall(WriteStall::Flush));
+                self.wait_until(|state, _| state.immutable_wal_bytes() < max_wal_bytes);
+            }
+
+            let manifest = self.lock_manifest();
+
+            // another writer may have swapped the mem_table while we waited for the manifest
+            if check_size && !self.mem_table_full() {
+                debug!("The mem_table isn't full");
+                return false; // don't need to do anything yet
+            }
+
+            let (immutable_count, immutable_bytes) = {
+                let state = self.state.read().unwrap();
+
+                (state.immutables.len(), state.immutable_wal_bytes())
+            };
+
+            // or it took the room we waited for
+            if immutable_count < self.options.max_immutables && (max_wal_bytes == 0 || immutable_bytes < max_wal_bytes) {
+                break manifest;
+            }
+        };
+
+        let rotated = self.rotate(&mut manifest);
+
+        drop(manifest);
+
+        // the write that filled the mem_table is in the WAL, so only the writes after it fail
+        if let Err(e) = rotated {
+            self.set_background_error(&e);
+
+            if wait {
+                self.wait_for_flushes(); // panics with the error
+            }
+
+            return false;
+        }
+
+        self.signal_work();
+
+        if wait {
+            self.wait_for_flushes();
+        }
+
+        debug!("Leaving flush");
+
+        true
+    }
+
+    /// Flushes the full mem_tables, oldest first, and compacts when needed, until the store is dropped
+    fn run_background(&self) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            loop {
+                // None when it's time to look for expired SSTables
+                let mem_table = {
+                    let mut background = self.background.lock().unwrap();
+
+                    loop {
+                        if background.shutdown || background.error.is_some() {
+                            return;
+                        }
+
+                        if !background.paused {
+                            if let Some(mem_table) = self.state.read().unwrap().immutables.first().cloned() {
+                                background.busy = true;
+                                break Some(mem_table);
+                            }
+                        }
+
+                        let (guard, timeout) = self.work_ready.wait_timeout(background, Duration::from_millis(EXPIRED_CHECK_INTERVAL_MS)).unwrap();
+
+                        background = guard;
+
+                        if timeout.timed_out() && !background.paused {
+                            background.busy = true;
+                            break None;
+                        }
+                    }
+                };
+
+                if let Err(e) = self.background_work(mem_table) {
+                    self.set_background_error(&e);
+                }
+
+                let mut background = self.background.lock().unwrap();
+
+                background.busy = false;
+                self.work_done.notify_all();
+            }
+        }));
+
+        if let Err(payload) = result {
+            self.background_failed(payload);
+        }
+    }
+
+    /// Flushes the mem_tables on the executor, oldest first, and compacts when needed, until there are none left
+    fn run_scheduled(&self) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            loop {
+                let mem_table = {
+                    let mut background = self.background.lock().unwrap();
+
+                    // checked with the background locked, so a mem_table made immutable after this gets a new job
+                    match self.state.read().unwrap().immutables.first().cloned() {
+                        Some(ref mem_table) if !background.shutdown && !background.paused && background.error.is_none() => {
+                            background.busy = true;
+                            mem_table.clone()
+                        },
+                        _ => {
+                            background.busy = false;
+                            background.scheduled = false;
+                            self.work_done.notify_all();
+                            return;
+                        }
+                    }
+                };
+
+                if let Err(e) = self.background_work(Some(mem_table)) {
+                    self.set_background_error(&e);
+                }
+            }
+        }));
+
+        if let Err(payload) = result {
+            self.background_failed(payload);
+        }
+    }
+
+    /// Flushes the mem_table and compacts if it's needed, then rewrites SSTables with enough expired records, or too old
+    fn background_work(&self, mem_table: Option<Arc<WalMemTable>>) -> Result<(), IOError> {
+        if let Some(mem_table) = mem_table {
+            {
+                let _tables = self.table_lock.lock().unwrap();
+
+                self.flush_mem_table(mem_table, Instant::now())?;
+            }
+
+            // writers waiting for room can go on during the compaction
+            {
+                let _background = self.background.lock().unwrap();
+
+                self.work_done.notify_all();
+            }
+
+        }
+
+        // only what's due is done, the most worth doing first
+        self.run_compactions(&[CompactionReason::Size, CompactionReason::Expired, CompactionReason::Periodic])?;
+
+        Ok( () )
+    }
+
+    /// Keeps the panic of the background work for a waiting thread to raise, as nothing waits on it until the drop
+    fn background_failed(&self, payload: Box<Any + Send>) {
+        let mut background = self.background.lock().unwrap();
+
+        background.busy = false;
+        background.scheduled = false;
+        background.failed = true;
+        background.panic = Some(payload);
+        self.work_done.notify_all();
+    }
+
+    /// Stops the flushes and compactions after an IO error the retries didn't get past, leaving the store read-only
+    ///
+    /// The tables written for the job that failed aren't in the manifest, so the next open removes them.
+    fn set_background_error(&self, e: &IOError) {
+        error!("Background error in {:?}, the store is now read-only: {}", self.options.db_dir, e);
+
+        let mut background = self.background.lock().unwrap();
+
+        // the first error is the one to report, the others may just follow from it
+        if background.error.is_none() {
+            background.error = Some(BackgroundError { kind: e.kind(), message: e.to_string() });
+        }
+
+        self.read_only.store(true, Ordering::SeqCst);
+        self.work_done.notify_all();
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// The background error, if the store is read-only
+    fn writable(&self) -> Result<(), BackgroundError> {
+        if self.is_read_only() {
+            return Err(self.background.lock().unwrap().error.clone().expect("A read-only store has a background error"));
+        }
+
+        Ok( () )
+    }
+
+    /// Makes the store read-only after an error writing the WAL, as the writes after it couldn't be replayed
+    fn wal_error(&self, e: &IOError) -> BackgroundError {
+        self.set_background_error(e);
+
+        self.writable().expect_err("A store is read-only after a background error")
+    }
+
+    /// Panics with the background error, if the store is read-only, for the writes that can't return it
+    fn check_writable(&self) {
+        // the error is cloned out of the mutex, so it isn't poisoned by the panic
+        if let Err(e) = self.writable() {
+            panic!("{}", e);
+        }
+    }
+
+    /// The records to write for a change from another replica that isn't newer than the key's record, if any
+    fn resolve(&self, rec: Record, current: Record, range_deleted: bool) -> Result<Vec<Record>, ()> {
+        let resolver = match self.options.conflict_resolver.0 {
+            Some(ref resolver) => resolver,
+            None => return Err( () )
+        };
+
+        // the same version, applied before
+        if !current.is_newer_than(&rec) {
+            return Err( () );
+        }
+
+        let conflict = ReplicaConflict {
+            key: rec.key().to_vec(),
+            local: if current.is_delete() || range_deleted { None } else { Some(current.value().to_vec()) },
+            local_ts: current.created(),
+            remote: if rec.is_delete() { None } else { Some(rec.value().to_vec()) },
+            remote_ts: rec.created()
+        };
+
+        let writes = match resolver.resolve(&conflict) {
+            Resolution::KeepLocal => return Err( () ),
+            Resolution::TakeRemote => vec![rec.into_parts()],
+            Resolution::Write(writes) => writes
+        };
+
+        if writes.is_empty() {
+            return Err( () );
+        }
+
+        // newer than both versions, so the other replicas take it too
+        let created = self.timestamp().max(current.created() + 1);
+
+        Ok(writes.into_iter().map(|(key, value)| { let mut rec = Record::new(key, value); rec.set_created(created); rec }).collect())
+    }
+
+    /// The current time, from the clock of the options if there is one
+    fn now(&self) -> u64 {
+        match self.options.clock.0 {
+            Some(ref clock) => clock.now(),
+            None => get_timestamp()
+        }
+    }
+
+    /// A timestamp for a record being written, from the clock of the options if there is one
+    fn timestamp(&self) -> u64 {
+        match self.options.clock.0 {
+            Some(ref clock) => clock.timestamp(),
+            None => get_timestamp()
+        }
+    }
+
+    /// Returns true if the free disk space is under `min_free_space`, checking it at most once a second
+    fn low_on_space(&self) -> bool {
+        if self.options.min_free_space == 0 {
+            return false;
+        }
+
+        let now = get_timestamp();
+        let checked = self.space_checked.load(Ordering::SeqCst);
+
+        // one thread checks, the others go by the last check
+        if (checked == 0 || now >= checked + SPACE_CHECK_INTERVAL_MS) &&
+            self.space_checked.compare_exchange(checked, now.max(1), Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.update_free_space();
+        }
+
+        self.free_space.load(Ordering::SeqCst) < self.options.min_free_space
+    }
+
+    /// Reads the free disk space, telling the listeners when it goes under, or back over, `min_free_space`
+    fn update_free_space(&self) {
+        let free = match free_space(&self.options.db_dir) {
+            Ok(Some(free)) => free,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Error getting the free space of {:?}: {}", self.options.db_dir, e);
+                return;
+            }
+        };
+
+        let min = self.options.min_free_space;
+        let was_low = self.free_space.swap(free, Ordering::SeqCst) < min;
+
+        if was_low != (free < min) {
+            let space = DiskSpace { free_bytes: free, min_free_bytes: min, low: free < min };
+
+            if space.low {
+                warn!("{:?} is low on space, {} bytes free; pausing compactions, and rejecting puts", self.options.db_dir, free);
+            } else {
+                info!("{:?} has {} bytes free again", self.options.db_dir, free);
+            }
+
+            self.options.listeners.notify(|l| l.on_disk_space(&space));
+        }
+    }
+
+    /// Returns an error if the disk is low on space
+    fn check_space(&self) -> Result<(), OutOfSpace> {
+        if self.low_on_space() {
+            return Err(OutOfSpace { free_bytes: self.free_space.load(Ordering::SeqCst), min_free_bytes: self.options.min_free_space });
+        }
+
+        Ok( () )
+    }
+
+    /// Wakes the background thread, or gives the executor a job if it doesn't have one already
+    fn signal_work(&self) {
+        let executor = {
+            let mut background = self.background.lock().unwrap();
+
+            match self.options.executor.0 {
+                None => {
+                    self.work_ready.notify_one();
+                    return;
+                },
+                Some(ref executor) => {
+                    if background.scheduled || background.shutdown || background.paused || background.failed || background.error.is_some() {
+                        return;
+                    }
+
+                    background.scheduled = true;
+                    executor.clone()
+                }
+            }
+        };
+
+        // the job is given without the background locked, in case the executor runs it right away
+        let core = self.this.upgrade().expect("The store was dropped");
+
+        executor.execute(Box::new(move || core.run_scheduled()));
+    }
+
+    /// Waits until the condition holds for the state and the background thread
+    ///
+    /// If the background thread panicked, its panic is raised instead, and a background error panics.
+    fn wait_until<F>(&self, done: F) where F: Fn(&State, &Background) -> bool {
+        let mut background = self.background.lock().unwrap();
+
+        loop {
+            if background.failed {
+                let payload = background.panic.take();
+
+                drop(background); // so the mutex isn't poisoned by the panic
+
+                match payload {
+                    Some(payload) => panic::resume_unwind(payload),
+                    None => panic!("The background thread of the store panicked")
+                }
+            }
+
+            // nothing is going to flush
+            if let Some(error) = background.error.clone() {
+                drop(background);
+
+                panic!("{}", error);
+            }
+
+            if done(&self.state.read().unwrap(), &*background) {
+                return;
+            }
+
+            // nothing is going to flush
+            if background.shutdown && !background.busy {
+                drop(background);
+
+                panic!("The background work of the store was cancelled");
+            }
+
+            background = self.work_done.wait(background).unwrap();
+        }
+    }
+
+    fn wait_for_flushes(&self) {
+        self.wait_until(|state, background| state.immutables.is_empty() && !background.busy);
+    }
+
+    /// Stops the background thread once it's done with the flush or compaction it's running
+    ///
+    /// Called when the store is dropped, so it doesn't panic on a poisoned lock.
+    fn shutdown(&self) {
+        let mut background = self.background.lock().unwrap_or_else(PoisonError::into_inner);
+
+        background.shutdown = true;
+        self.work_ready.notify_one();
+    }
+
+    /// Keeps flushes and compactions from starting, and waits for the running one to finish
+    fn pause_background(&self) {
+        let mut background = self.background.lock().unwrap();
+
+        background.paused = true;
+
+        while background.busy {
+            background = self.work_done.wait(background).unwrap();
+        }
+    }
+
+    /// Lets flushes and compactions start again, flushing the mem_tables that filled while paused
+    ///
+    /// Called when the store is dropped, so it doesn't panic on a poisoned lock.
+    fn resume_background(&self) {
+        let paused = mem::replace(&mut self.background.lock().unwrap_or_else(PoisonError::into_inner).paused, false);
+
+        if paused {
+            self.signal_work();
+        }
+    }
+
+    /// Returns true once the store is shutting down, or its background work was cancelled
+    fn is_shut_down(&self) -> bool {
+        self.background.lock().unwrap_or_else(PoisonError::into_inner).shutdown
+    }
+
+    /// Waits until the background work stops, after a shutdown; it may have failed
+    fn wait_for_background(&self) {
+        let mut background = self.background.lock().unwrap_or_else(PoisonError::into_inner);
+
+        while background.busy || background.scheduled {
+            background = self.work_done.wait(background).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Merges the oldest immutable mem_table into a new current SSTable
+    ///
+    /// Called with the table lock held, or before the background thread is started.
+    fn flush_mem_table(&self, mem_table: Arc<WalMemTable>, start: Instant) -> Result<(), IOError> {
+        debug!("Flushing {} records, about {} bytes", mem_table.len(), mem_table.approx_size());
+
+        let cur_sstable = self.state.read().unwrap().cur_sstable.clone();
+
+        // the range deletes are carried forward, as they still apply to the older SSTables
+        let mut range_tombstones = mem_table.range_tombstones();
+        range_tombstones.extend(cur_sstable.range_tombstones().iter().cloned());
+
+        // the large values go to the value log once, ahead of the tries at writing the table
+        let separated = if self.options.value_log_threshold != 0 {
+            Some(self.retry("writing to the value log", || mem_table.iter().map(|rec| self.separate_value(rec)).collect::<Result<Vec<_>, _>>())?)
+        } else {
+            None
+        };
+
+        // each try writes a table with a new number, so it can't find one left by the last
+        let (current_number, current_path, new_sstable) = self.retry("creating SSTable", || {
+            let (number, path) = self.new_table_path();
+            let mem_it: Box<Iterator<Item=Record>> = match separated {
+                Some(ref records) => Box::new(records.iter().cloned()),
+                None => mem_table.iter()
+            };
+            let ss_it: Box<Iterator<Item=Record>> = Box::new(cur_sstable.iter_raw());
+
+            // create an iterator that merge-sorts, coalesces out similar records, and removes range deleted ones
+            let mut it = kmerge(vec![mem_it, ss_it]).coalesce(coalesce_records).filter(|rec| {
+                !range_tombstones.iter().any(|t| t.covers(rec))
+            });
+
+            let sstable = SSTable::new(NewSSTable { range_tombstones: range_tombstones.clone(), ..NewSSTable::new(&path, number, &self.options.sstable_options(), self.options.rec_file_buffer_size, self.cache.clone()) }, &mut it)?;
+
+            Ok( (number, path, Arc::new(sstable)) )
+        })?;
+
+        sim::crash_point(CrashPoint::FlushTableWritten);
+
+        if separated.is_some() {
+            self.sync_value_log()?;
+        }
+
+        let record_count = new_sstable.record_count();
+        let padding_bytes = new_sstable.padding_bytes();
+
+        // update the reference to our current SSTable, and drop the flushed mem_table
+        {
+            let mut state = self.state.write().unwrap();
+
+            state.cur_sstable = new_sstable;
+            state.immutables.retain(|m| !Arc::ptr_eq(m, &mem_table));
+        }
+
+        // switch to the new file, which removes the old current SSTable and the mem_table's WAL file
+        {
+            let mut manifest = self.lock_manifest();
+
+            manifest.set_current(current_number);
+            manifest.remove_immutable_wal(mem_table.wal_number());
+
+            for (number, first_seq) in manifest.retain_wal(mem_table.wal_number(), self.options.wal_retention) {
+                self.archive_wal(&manifest, number, first_seq)?;
+            }
+
+            self.save_manifest(&manifest)?;
+        }
+
+        let info = FlushInfo { file_path: current_path, record_count: record_count, padding_bytes: padding_bytes, duration: start.elapsed() };
+
+        self.options.listeners.notify(|l| l.on_flush_completed(&info));
+
+        Ok( () )
+    }
+
+    /// Archives a WAL that's no longer needed, before the manifest save removes it, if there's an archive
+    fn archive_wal(&self, manifest: &Manifest, number: u64, first_seq: Option<u64>) -> Result<(), IOError> {
+        let archive_dir = match self.options.wal_archive_dir {
+            Some(ref dir) => dir,
+            None => return Ok( () )
+        };
+
+        let path = manifest.wal_file(number);
+
+        // only stores from before sequence numbers were kept have WALs without them
+        let first_seq = match first_seq {
+            Some(seq) => seq,
+            None => {
+                warn!("Not archiving {:?}, as its first sequence number isn't known", path);
+                return Ok( () );
+            }
+        };
+
+        // an archived segment left by a failed try is replaced
+        self.retry("archiving WAL", || {
+            let record_count = RecordFile::new(&path, WAL_HEADER, self.options.rec_file_buffer_size, 1)?.record_count();
+
+            wal_archive::archive(&path, archive_dir, first_seq, first_seq + record_count as u64).map(|_| ())
+        })
+    }
+
+    /// Runs the compactions due for the reasons, highest score first, scoring what's left again after each
+    /// return: true if any ran
+    fn run_compactions(&self, reasons: &[CompactionReason]) -> Result<bool, IOError> {
+        let _tables = self.table_lock.lock().unwrap();
+        let mut ran = false;
+
+        // a job leaves its tables no longer due, so there are never more to run than were due at first
+        for _ in 0..self.due_compactions(reasons).len() {
+            if self.is_shut_down() {
+                debug!("Not compacting, the store is shutting down");
+                break;
+            }
+
+            if self.background.lock().unwrap().paused {
+                debug!("Not compacting, the background work is paused");
+                break;
+            }
+
+            if self.low_on_space() {
+                debug!("Not compacting, the disk is low on space");
+                break;
+            }
+
+            let job = match self.due_compactions(reasons).into_iter().next() {
+                Some(job) => job,
+                None => break
+            };
+
+            debug!("Compacting for {:?} with a score of {:.3}: {:?}", job.reason, job.score, job.table);
+
+            match job.table {
+                Some(table) => {
+                    let cur_time = self.now();
+
+                    self.rewrite_tables(vec![table], vec![], |rec| !rec.is_expired(cur_time))?;
+                },
+                None => if !self.merge_tables()? {
+                    break; // abandoned for a shutdown
+                }
+            }
+
+            ran = true;
+        }
+
+        Ok(ran)
+    }
+
+    /// The compactions due for the reasons, highest score first
+    ///
+    /// A merge of the current SSTable is scored by its deletes and the expired records of the tables it merges,
+    /// over the records of the tables whose keys it overlaps; a rewrite by the expired records of its table over
+    /// all of them. Called with the table lock held.
+    fn due_compactions(&self, reasons: &[CompactionReason]) -> Vec<CompactionJob> {
+        let cur_time = self.now();
+        let period = self.options.periodic_compaction_ms;
+        let is_stale = |newest_ts: u64| period != 0 && newest_ts.saturating_add(period) <= cur_time;
+        let state = self.state.read().unwrap();
+        let cur = TableMeta::new(&state.cur_sstable);
+        let mut jobs = Vec::new();
+
+        let merge = if reasons.contains(&CompactionReason::Size) && cur.record_count() >= self.compaction_threshold() {
+            Some(CompactionReason::Size)
+        } else if reasons.contains(&CompactionReason::Periodic) && cur.record_count() != 0 && is_stale(cur.newest_ts()) {
+            Some(CompactionReason::Periodic)
+        } else {
+            None
+        };
+
+        if let Some(reason) = merge {
+            let overlapping = state.sstables.iter().filter(|table| {
+                table.record_count() != 0 && table.smallest_key() <= cur.largest_key() && cur.smallest_key() <= table.largest_key()
+            }).collect::<Vec<_>>();
+            let dropped = cur.tombstone_count() + cur.expired_estimate(cur_time) + state.sstables.iter().map(|table| table.expired_estimate(cur_time)).sum::<u64>();
+            let read = cur.record_count() + overlapping.iter().map(|table| table.record_count()).sum::<u64>();
+
+            jobs.push(CompactionJob { table: None, reason: reason, score: dropped as f64 / read.max(1) as f64 });
+        }
+
+        for table in state.sstables.iter().filter(|table| table.record_count() != 0) {
+            let reason = if reasons.contains(&CompactionReason::Expired) && self.options.ttl_compaction_percent != 0
+                && table.expired_count(cur_time) * 100 >= table.record_count() * self.options.ttl_compaction_percent as u64 {
+                Some(CompactionReason::Expired)
+            } else if reasons.contains(&CompactionReason::Periodic) && is_stale(table.newest_ts()) && table.has_expired(cur_time) {
+                Some(CompactionReason::Periodic)
+            } else {
+                None
+            };
+
+            if let Some(reason) = reason {
+                jobs.push(CompactionJob { table: Some(table.clone()), reason: reason, score: table.expired_estimate(cur_time) as f64 / table.record_count() as f64 });
+            }
+        }
+
+        jobs.sort_by(|a, b| b.score.partial_cmp(&a.score).expect("A score is never NaN"));
+
+        jobs
+    }
+
+    /// Merges the current SSTable and the others into `file_count` new SSTables, and a blank current one
+    ///
+    /// Called with the table lock held.
+    fn merge_tables(&self) -> Result<bool, IOError> {
+        let start = Instant::now();
+
+        let (cur_sstable, sstables) = {
+            let state = self.state.read().unwrap();
+
+            (state.cur_sstable.clone(), state.sstables.clone())
+        };
+
+        if self.can_move_current(&cur_sstable, &sstables) {
+            return self.move_current(cur_sstable, start);
+        }
+
+        // save off the file paths to the old SSTables as it's not nice to delete files that are still open
+        let sstable_paths = sstables.iter().map(|table| table.file_path()).collect::<Vec<_>>();
+        let range_tombstones = cur_sstable.range_tombstones().to_vec();
+
+        // SSTables completely covered by a newer range tombstone are dropped without reading them
+        let (dropped, kept) :(Vec<_>, Vec<_>) = sstables.iter().partition(|table| {
+            range_tombstones.iter().any(|t| t.contains_range(table.smallest_key(), table.largest_key()) && table.newest_ts() < t.created())
+        });
+
+        debug!("Dropping {} SSTables covered by range tombstones: {:?}", dropped.len(), dropped);
+
+        // open all the tables being merged, they stay open until the merge is done
+        let kept = kept.iter().map(|table| self.retry("opening SSTable", || self.table_cache.get(&table.file_path()))).collect::<Result<Vec<_>, _>>()?;
+
+        let mut stats = CompactionStats {
+            input_tables: kept.len() + 1,
+            dropped_tables: dropped.len(),
+            moved_tables: 0,
+            output_tables: self.options.file_count,
+            input_records: 0,
+            output_records: 0,
+            padding_bytes: 0,
+            duration: Default::default()
+        };
+
+        // nothing has changed until the tables are all written, so a failed try starts the merge over
+        let written = self.retry("writing compacted SSTables", || {
+            // create iterators for all the SSTables
+            let ss_cur_it: Box<Iterator<Item=Record>> = Box::new(cur_sstable.iter_raw());
+            let mut ss_its = Vec::with_capacity(self.options.file_count + 1);
+            let mut record_count = cur_sstable.record_count();
+            let mut total_bytes = file_size(&cur_sstable.file_path());
+
+            ss_its.push(ss_cur_it);
+
+            for sstable in kept.iter() {
+                record_count += sstable.record_count();
+                total_bytes += file_size(&sstable.file_path());
+                ss_its.push(Box::new(sstable.iter_raw()));
+            }
+
+            let cur_time = self.now();
+
+            let live =
+                kmerge(ss_its).coalesce(coalesce_records).filter(|rec| {
+                    // remove all deleted, expired, and range deleted
+                    !rec.is_delete() && !rec.is_expired(cur_time) && !range_tombstones.iter().any(|t| t.covers(rec))
+                });
+
+            // the hook is given the values, and what it keeps goes back to the value log
+            // the records end at the first value that can't be read or written, which is saved and checked after each table
+            let hook_values = self.options.compaction_hook.0.is_some() && self.cache.value_log.is_some();
+            let value_error = RefCell::new(None);
+            let live: Box<Iterator<Item=Record>> = if hook_values {
+                Box::new(live.map(|rec| self.load_value(rec)).scan((), |_, res| until_error(res, &value_error)).fuse())
+            } else {
+                Box::new(live)
+            };
+            let rewrite = Rewrite::new(self.options.compaction_hook.0.clone(), live, cur_time);
+            let mut it: Box<Iterator<Item=Record>> = if hook_values {
+                Box::new(rewrite.map(|rec| self.separate_value(rec)).scan((), |_, res| until_error(res, &value_error)).fuse())
+            } else {
+                Box::new(rewrite)
+            };
+
+            stats.input_records = record_count;
+            stats.output_records = 0;
+            stats.padding_bytes = 0;
+
+            let records_per_file = record_count / self.options.file_count as u64;
+
+            debug!("RECORDS PER FILE: {} = {} / {}", records_per_file, record_count, self.options.file_count as u64);
+
+            let mut new_sstables = BTreeSet::<TableMeta>::new();
+            let mut table_numbers = Vec::with_capacity(self.options.file_count);
+            let sstable_options = self.options.sstable_options_sized(total_bytes / self.options.file_count as u64);
+
+            // create all the tables, the last one gets all the rest of the records
+            for i in 0..self.options.file_count {
+                // the tables written so far aren't in the manifest, so they're just removed
+                if self.is_shut_down() {
+                    debug!("Abandoning the compaction after {} SSTables", i);
+
+                    self.remove_new_tables(&new_sstables);
+
+                    return Ok(None);
+                }
+
+                let count = if i == self.options.file_count-1 { None } else { Some(records_per_file) };
+                let (number, path) = self.new_table_path();
+
+                let sstable = match SSTable::new(NewSSTable { count: count, ..NewSSTable::new(&path, number, &sstable_options, self.options.rec_file_buffer_size, self.cache.clone()) }, &mut it) {
+                    Ok(sstable) => sstable,
+                    Err(e) => {
+                        self.remove_new_tables(&new_sstables);
+
+                        return Err(e);
+                    }
+                };
+
+                self.prefetch_indexes(&sstable);
+
+                stats.output_records += sstable.record_count();
+                stats.padding_bytes += sstable.padding_bytes();
+                table_numbers.push(number);
+                new_sstables.insert(self.table_cache.insert(sstable));
+
+                if let Some(e) = value_error.borrow_mut().take() {
+                    self.remove_new_tables(&new_sstables);
+
+                    return Err(e);
+                }
+            }
+
+            Ok(Some( (new_sstables, table_numbers) ))
+        })?;
+
+        let (new_sstables, table_numbers) = match written {
+            Some(written) => written,
+            None => return Ok(false)
+        };
+
+        if self.options.compaction_hook.0.is_some() {
+            self.sync_value_log()?;
+        }
+
+        sim::crash_point(CrashPoint::CompactionTablesWritten);
+
+        // create a new empty current SSTable
+        let (current_number, new_cur_sstable) = match self.new_blank_current() {
+            Ok(blank) => blank,
+            Err(e) => {
+                self.remove_new_tables(&new_sstables);
+
+                return Err(e);
+            }
+        };
+
+        // switch readers to the new tables; every table has been rewritten without the range deleted records
+        {
+            let mut state = self.state.write().unwrap();
+
+            state.sstables = new_sstables;
+            state.cur_sstable = new_cur_sstable;
+
+            // the hook may have rewritten any of the records cached
+            if let Some(ref rows) = self.row_cache {
+                if self.options.compaction_hook.0.is_some() {
+                    rows.clear();
+                }
+            }
+        }
+
+        // close all the old SSTables
+        for sstable_path in sstable_paths.iter() {
+            self.table_cache.evict(sstable_path);
+        }
+
+        self.drop_pages(iter::once(cur_sstable.file_path()).chain(kept.iter().map(|sstable| sstable.file_path())));
+
+        // switch to the new files, which removes the old SSTables and current SSTable
+        {
+            let mut manifest = self.lock_manifest();
+
+            manifest.set_tables(table_numbers);
+            manifest.set_current(current_number);
+            self.save_manifest(&manifest)?;
+        }
+
+        stats.duration = start.elapsed();
+
+        self.options.listeners.notify(|l| l.on_compaction_completed(&stats));
+
+        debug!("Leaving compact");
+
+        Ok(true)
+    }
+
+    /// Returns true if a merge would only copy the current SSTable, so it's moved instead, see `KVSOptions::compaction_trivial_move`
+    fn can_move_current(&self, cur_sstable: &SSTable, sstables: &BTreeSet<TableMeta>) -> bool {
+        self.options.compaction_trivial_move && cur_sstable.record_count() != 0 && cur_sstable.tombstone_count() == 0 && cur_sstable.range_tombstones().is_empty() &&
+            self.options.compaction_hook.0.is_none() &&
+            !sstables.iter().any(|table| {
+                table.record_count() != 0 && table.smallest_key() <= cur_sstable.largest_key() && cur_sstable.smallest_key() <= table.largest_key()
+            })
+    }
+
+    /// Moves the current SSTable to the others as it is, by a manifest edit, leaving a blank current SSTable
+    ///
+    /// Called with the table lock held.
+    fn move_current(&self, cur_sstable: Arc<SSTable>, start: Instant) -> Result<bool, IOError> {
+        let (current_number, new_cur_sstable) = self.new_blank_current()?;
+        let moved = TableMeta::new(&cur_sstable);
+
+        debug!("Moving the current SSTable to the others: {:?}", moved);
+
+        {
+            let mut state = self.state.write().unwrap();
+
+            state.sstables.insert(moved.clone());
+            state.cur_sstable = new_cur_sstable;
+        }
+
+        {
+            let mut manifest = self.lock_manifest();
+            let mut table_numbers = manifest.table_numbers().to_vec();
+
+            table_numbers.push(manifest.current_number());
+            manifest.set_tables(table_numbers);
+            manifest.set_current(current_number);
+            self.save_manifest(&manifest)?;
+        }
+
+        let stats = CompactionStats {
+            input_tables: 1,
+            dropped_tables: 0,
+            moved_tables: 1,
+            output_tables: 0,
+            input_records: moved.record_count(),
+            output_records: moved.record_count(),
+            padding_bytes: 0,
+            duration: start.elapsed()
+        };
+
+        self.options.listeners.notify(|l| l.on_compaction_completed(&stats));
+
+        Ok(true)
+    }
+
+    /// Creates an empty SSTable to be the current one, after a merge
+    fn new_blank_current(&self) -> Result<(u64, Arc<SSTable>), IOError> {
+        self.retry("creating blank current SSTable", || {
+            let (number, path) = self.new_table_path();
+
+            SSTable::new(NewSSTable::new(&path, number, &self.options.sstable_options(), self.options.rec_file_buffer_size, self.cache.clone()), &mut iter::empty::<Record>()).map(|sstable| (number, Arc::new(sstable)))
+        })
+    }
+
+    /// Closes and removes the tables written by a compaction that didn't finish
+    fn remove_new_tables(&self, tables: &BTreeSet<TableMeta>) {
+        for table in tables.iter() {
+            self.table_cache.evict(&table.file_path());
+
+            if let Err(e) = fs::remove_file(table.file_path()) {
+                warn!("Error removing SSTable {:?} of an unfinished compaction: {}", table.file_path(), e);
+            }
+        }
+    }
+
+    /// Moves a value of at least `value_log_threshold` bytes to the value log, leaving a pointer to it in the record
+    fn separate_value(&self, mut rec: Record) -> Result<Record, IOError> {
+        if let Some(ref value_log) = self.cache.value_log {
+            if self.options.value_log_threshold != 0 && !rec.is_delete() && !rec.is_value_pointer() && rec.value().len() >= self.options.value_log_threshold {
+                let pointer = value_log.append(&rec)?;
+
+                rec.set_value_pointer(pointer.encode());
+            }
+        }
+
+        Ok(rec)
+    }
+
+    /// Reads the value a record points to out of the value log
+    fn load_value(&self, rec: Record) -> Result<Record, IOError> {
+        match self.cache.value_log {
+            Some(ref value_log) => value_log.resolve(rec),
+            None => Ok(rec)
+        }
+    }
+
+    /// Syncs the values appended to the value log, then starts a new file if it's reached `value_log_file_size`
+    ///
+    /// Called with the table lock held, before the SSTables pointing to the values are in the manifest.
+    fn sync_value_log(&self) -> Result<(), IOError> {
+        let value_log = match self.cache.value_log {
+            Some(ref value_log) => value_log,
+            None => return Ok( () )
+        };
+
+        self.retry("syncing the value log", || value_log.sync())?;
+
+        if self.options.value_log_threshold != 0 && value_log.head_len()? >= self.options.value_log_file_size {
+            self.rotate_value_log(value_log)?;
+        }
+
+        Ok( () )
+    }
+
+    /// Starts a new value log file to append to; the others are only read from then on
+    fn rotate_value_log(&self, value_log: &ValueLog) -> Result<(), IOError> {
+        let mut manifest = self.lock_manifest();
+        let number = manifest.new_file_number();
+
+        value_log.open_file(number, &manifest.value_log_path(number))?;
+        manifest.add_value_log(number);
+        self.save_manifest(&manifest)
+    }
+
+    /// Moves the values still pointed to out of the oldest value log file, then removes it, see `KVS::collect_value_log`
+    /// return: true if a file was removed
+    fn collect_value_log(&self) -> Result<bool, IOError> {
+        let value_log = match self.cache.value_log {
+            Some(ref value_log) => value_log.clone(),
+            None => return Ok(false)
+        };
+
+        let _tables = self.table_lock.lock().unwrap();
+
+        // the file being appended to is only collected once it's reached `value_log_file_size`, and there's a new one
+        let numbers = value_log.numbers();
+        let appended = if self.options.value_log_threshold != 0 { 1 } else { 0 };
+
+        if numbers.len() <= appended {
+            return Ok(false);
+        }
+
+        let log_number = numbers[0];
+
+        let (cur_sstable, sstables) = {
+            let state = self.state.read().unwrap();
+
+            (state.cur_sstable.clone(), state.sstables.clone())
+        };
+
+        let rewritten = sstables.iter().filter(|table| table.value_logs().contains(&log_number)).cloned().collect::<Vec<_>>();
+        let opened = rewritten.iter().map(|table| self.retry("opening SSTable", || self.table_cache.get(&table.file_path()))).collect::<Result<Vec<_>, _>>()?;
+        let rewrite_current = cur_sstable.value_logs().contains(&log_number);
+
+        debug!("Collecting value log {}, pointed into by {} SSTables", log_number, rewritten.len() + rewrite_current as usize);
+
+        // the values are moved first, by their old pointers, so writing the tables can't fail part way through a record
+        let mut moved = HashMap::new();
+
+        for sstable in opened.iter().chain(iter::once(&cur_sstable).filter(|_| rewrite_current)) {
+            for rec in sstable.iter_raw() {
+                if rec.is_value_pointer() && ValuePointer::decode(rec.value())?.file_number == log_number && !moved.contains_key(rec.value()) {
+                    let pointer = rec.value().to_vec();
+
+                    moved.insert(pointer, self.separate_value(value_log.resolve(rec)?)?);
+                }
+            }
+        }
+
+        self.sync_value_log()?;
+
+        let relocate = |rec: Record| {
+            if !rec.is_value_pointer() {
+                return rec;
+            }
+
+            match moved.get(rec.value()) {
+                Some(moved_rec) => moved_rec.clone(),
+                None => rec
+            }
+        };
+
+        let mut new_numbers = Vec::new();
+        let mut new_sstables = Vec::new();
+
+        // the tables written before a failure aren't in the manifest, so the next open removes them
+        for (meta, sstable) in rewritten.iter().zip(opened.iter()) {
+            let (number, new_sstable) = self.retry("creating SSTable", || {
+                let (number, path) = self.new_table_path();
+                let mut it = sstable.iter_raw().map(&relocate);
+
+                SSTable::new(NewSSTable::new(&path, number, &self.options.sstable_options_sized(file_size(&meta.file_path())), self.options.rec_file_buffer_size, self.cache.clone()), &mut it).map(|new_sstable| (number, new_sstable))
+            })?;
+
+            new_numbers.push(number);
+            new_sstables.push(self.table_cache.insert(new_sstable));
+        }
+
+        let new_current = if rewrite_current {
+            Some(self.retry("creating SSTable", || {
+                let (number, path) = self.new_table_path();
+                let mut it = cur_sstable.iter_raw().map(&relocate);
+
+                SSTable::new(NewSSTable { range_tombstones: cur_sstable.range_tombstones().to_vec(), ..NewSSTable::new(&path, number, &self.options.sstable_options(), self.options.rec_file_buffer_size, self.cache.clone()) }, &mut it).map(|new_sstable| (number, Arc::new(new_sstable)))
+            })?)
+        } else {
+            None
+        };
+
+        {
+            let mut state = self.state.write().unwrap();
+
+            for meta in rewritten.iter() {
+                state.sstables.remove(meta);
+            }
+
+            state.sstables.extend(new_sstables);
+
+            if let Some((_, ref new_cur_sstable)) = new_current {
+                state.cur_sstable = new_cur_sstable.clone();
+            }
+        }
+
+        let old_paths = rewritten.iter().map(|meta| meta.file_path()).collect::<HashSet<_>>();
+
+        for path in old_paths.iter() {
+            self.table_cache.evict(path);
+        }
+
+        // switch to the new files, which removes the old ones and the value log file
+        {
+            let mut manifest = self.lock_manifest();
+            let mut table_numbers = manifest.table_numbers().iter().cloned().filter(|&n| !old_paths.contains(&manifest.table_path(n))).collect::<Vec<_>>();
+
+            table_numbers.extend(new_numbers);
+            manifest.set_tables(table_numbers);
+
+            if let Some((current_number, _)) = new_current {
+                manifest.set_current(current_number);
+            }
+
+            manifest.remove_value_log(log_number);
+            self.save_manifest(&manifest)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Rewrites the SSTables with keys in the range of the tombstone without the keys it covers
+    ///
+    /// The tombstone stays with the mem_table, or current SSTable, as it still hides the keys in them.
+    fn compact_range_delete(&self, tombstone: &Record) -> Result<(), IOError> {
+        let _tables = self.table_lock.lock().unwrap();
+
+        let end = tombstone.range_end().expect("Not a range tombstone");
+        let (covered, overlapping) :(Vec<_>, Vec<_>) = self.state.read().unwrap().sstables.iter().filter(|table| {
+            table.record_count() != 0 && table.smallest_key() < end && table.largest_key() >= tombstone.key()
+        }).cloned().partition(|table| tombstone.contains_range(table.smallest_key(), table.largest_key()) && table.newest_ts() < tombstone.created());
+
+        // dropping tables frees space, but rewriting them takes more first
+        let overlapping = if self.low_on_space() { vec![] } else { overlapping };
+
+        debug!("Dropping {} and rewriting {} SSTables for a range delete", covered.len(), overlapping.len());
+
+        if !covered.is_empty() || !overlapping.is_empty() {
+            self.rewrite_tables(overlapping, covered, |rec| !tombstone.covers(rec))?;
+        }
+
+        Ok( () )
+    }
+
+    /// Flushes the mem_tables, then with level 1, merges the current SSTable if it has keys in [start, end),
+    /// and rewrites the others with keys in the range without their expired records
+    fn compact_range(&self, start: &Vec<u8>, end: &Vec<u8>, target_level: usize) -> Result<(), IOError> {
+        if target_level > 1 {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("No level {} to compact to, the last is 1", target_level)));
+        }
+
+        self.writable().map_err(|e| IOError::new(e.kind, e.to_string()))?;
+        self.flush(false);
+
+        if target_level == 0 || start >= end {
+            return Ok( () );
+        }
+
+        let _tables = self.table_lock.lock().unwrap();
+        let in_range = |smallest: &[u8], largest: &[u8]| smallest < end.as_slice() && largest >= start.as_slice();
+
+        let merge = {
+            let state = self.state.read().unwrap();
+            let cur_sstable = &state.cur_sstable;
+
+            (cur_sstable.record_count() != 0 && in_range(cur_sstable.smallest_key(), cur_sstable.largest_key())) ||
+                cur_sstable.range_tombstones().iter().any(|t| in_range(t.key(), t.range_end().expect("Not a range tombstone")))
+        };
+
+        // a merge rewrites all the tables, so there's nothing left expired
+        if merge {
+            debug!("Merging the current SSTable for a compaction of the range");
+
+            self.merge_tables()?;
+
+            return Ok( () );
+        }
+
+        let cur_time = self.now();
+        let expired = self.state.read().unwrap().sstables.iter().filter(|table| {
+            table.record_count() != 0 && in_range(table.smallest_key(), table.largest_key()) && table.has_expired(cur_time)
+        }).cloned().collect::<Vec<_>>();
+
+        debug!("Rewriting {} SSTables with expired records for a compaction of the range", expired.len());
+
+        if !expired.is_empty() {
+            self.rewrite_tables(expired, vec![], |rec| !rec.is_expired(cur_time))?;
+        }
+
+        Ok( () )
+    }
+
+    /// Rewrites SSTables with only the records `keep` accepts, and drops others without reading them
+    ///
+    /// Called with the table lock held. The SSTables hold the oldest version of every key, so dropping
+    /// a record can't bring back an older one; the current SSTable can't be rewritten this way,
+    /// as its records hide those in the others.
+    fn rewrite_tables<F>(&self, rewritten: Vec<TableMeta>, dropped: Vec<TableMeta>, keep: F) -> Result<(), IOError> where F: Fn(&Record) -> bool {
+        let start = Instant::now();
+        let mut sstables = self.state.read().unwrap().sstables.clone();
+        let mut stats = CompactionStats {
+            input_tables: rewritten.len(),
+            dropped_tables: dropped.len(),
+            moved_tables: 0,
+            output_tables: 0,
+            input_records: 0,
+            output_records: 0,
+            padding_bytes: 0,
+            duration: Default::default()
+        };
+        let mut new_numbers = Vec::new();
+
+        for meta in dropped.iter() {
+            sstables.remove(meta);
+        }
+
+        for meta in rewritten.iter() {
+            let sstable = self.retry("opening SSTable", || self.table_cache.get(&meta.file_path()))?;
+
+            sstables.remove(meta);
+            stats.input_records += sstable.record_count();
+
+            // a table with nothing left is just dropped
+            if !sstable.iter_raw().any(|rec| keep(&rec)) {
+                stats.dropped_tables += 1;
+                continue;
+            }
+
+            // the tables written before a failure aren't in the manifest, so the next open removes them
+            let (number, new_sstable) = self.retry("creating SSTable", || {
+                let (number, path) = self.new_table_path();
+                let mut it = sstable.iter_raw().filter(|rec| keep(rec));
+
+                SSTable::new(NewSSTable::new(&path, number, &self.options.sstable_options_sized(file_size(&meta.file_path())), self.options.rec_file_buffer_size, self.cache.clone()), &mut it).map(|new_sstable| (number, new_sstable))
+            })?;
+
+            self.prefetch_indexes(&new_sstable);
+
+            stats.output_tables += 1;
+            stats.output_records += new_sstable.record_count();
+            stats.padding_bytes += new_sstable.padding_bytes();
+            new_numbers.push(number);
+            sstables.insert(self.table_cache.insert(new_sstable));
+        }
+
+        self.state.write().unwrap().sstables = sstables;
+
+        let old_paths = rewritten.iter().chain(dropped.iter()).map(|meta| meta.file_path()).collect::<HashSet<_>>();
+
+        for path in old_paths.iter() {
+            self.table_cache.evict(path);
+        }
+
+        self.drop_pages(rewritten.iter().map(|meta| meta.file_path()));
+
+        // switch to the new files, which removes the old ones
+        {
+            let mut manifest = self.lock_manifest();
+            let mut table_numbers = manifest.table_numbers().iter().cloned().filter(|&n| !old_paths.contains(&manifest.table_path(n))).collect::<Vec<_>>();
+
+            table_numbers.extend(new_numbers);
+            manifest.set_tables(table_numbers);
+            self.save_manifest(&manifest)?;
+        }
+
+        stats.duration = start.elapsed();
+
+        self.options.listeners.notify(|l| l.on_compaction_completed(&stats));
+
+        Ok( () )
+    }
+
+    /// Has the OS read the index blocks and filters of a table a compaction wrote, if `compaction_prefetch_indexes` is set
+    fn prefetch_indexes(&self, sstable: &SSTable) {
+        if !self.options.compaction_prefetch_indexes {
+            return;
+        }
+
+        // only a hint, so the compaction goes on without it
+        if let Err(e) = sstable.prefetch_metadata() {
+            debug!("Error prefetching the indexes of {:?}: {}", sstable.file_path(), e);
+        }
+    }
+
+    /// Has the OS drop the pages of the tables a compaction read, if `compaction_drop_inputs` is set
+    fn drop_pages<I>(&self, paths: I) where I: Iterator<Item=PathBuf> {
+        if !self.options.compaction_drop_inputs {
+            return;
+        }
+
+        for path in paths {
+            if let Err(e) = drop_page_cache(&path) {
+                debug!("Error dropping the pages of {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Returns true if the current SSTable has enough records for every file to get `max_mem_count`
+    fn needs_compaction(&self) -> bool {
+        let state = self.state.read().unwrap();
+
+        state.cur_sstable.record_count() >= self.compaction_threshold()
+    }
+
+    /// The records the current SSTable needs for a compaction
+    fn compaction_threshold(&self) -> u64 {
+        (self.options.max_mem_count * self.options.file_count) as u64
+    }
+
+    fn get_with_options(&self, key: &Vec<u8>, options: &ReadOptions) -> Option<Vec<u8>> {
+        self.get_until(key, options, None).expect("Error reading from SSTable")
+    }
+
+    /// Gets the value of a key, unless the deadline passes before the SSTables holding it are read
+    fn get_until(&self, key: &Vec<u8>, options: &ReadOptions, deadline: Option<Instant>) -> Result<Option<Vec<u8>>, ReadError> {
+        debug!("Called get: {:?}", key);
+
+        let cur_time = self.now();
+
+        let (rec, range_deleted) = match options.snapshot {
+            Some(ref snapshot) => match snapshot.find(key, options, deadline)? {
+                Some(rec) => { let d = snapshot.range_tombstones.iter().any(|t| t.covers(&rec)); (rec, d) },
+                None => return Ok(None)
+            },
+            None => {
+                let state = self.state.read().unwrap();
+
+                match self.find(&state, key, options, deadline)? {
+                    Some(rec) => { let d = state.is_range_deleted(&rec); (rec, d) },
+                    None => return Ok(None) // we don't have it
+                }
+            }
+        };
+
+        // found an expired or deleted key
+        if rec.is_expired(cur_time) || rec.is_delete() || range_deleted {
+            debug!("Found expired or deleted key");
+            Ok(None)
+        } else {
+            Ok(rec.into_parts().1)
+        }
+    }
+
+    /// Finds the newest record for a key
+    fn find(&self, state: &State, key: &Vec<u8>, options: &ReadOptions, deadline: Option<Instant>) -> Result<Option<Record>, ReadError> {
+        debug!("MEM TABLE: {}", state.mem_table.len());
+
+        // taken before the mem_tables are read, so a write after that keeps the record found out of the row cache
+        let row_seq = self.row_cache.as_ref().map(|rows| rows.seq(key));
+
+        // first check the mem_tables, newest first
+        if let Some(rec) = state.mem_table.get(key) {
+            return Ok(Some(rec));
+        }
+
+        if let Some(rec) = state.immutables.iter().rev().filter_map(|m| m.get(key)).next() {
+            return Ok(Some(rec));
+        }
+
+        // then the row cache, and the SSTables on a miss
+        if let Some(ref rows) = self.row_cache {
+            if let Some(rec) = rows.get(key) {
+                return Ok(Some(rec));
+            }
+        }
+
+        let found = self.find_in_tables(state, key, options, deadline)?;
+
+        // not when range deleted, as the record would show again once a compaction drops the tombstone
+        if let (Some(rows), Some(seq), Some(rec)) = (self.row_cache.as_ref(), row_seq, found.as_ref()) {
+            if options.fill_cache && !state.is_range_deleted(rec) {
+                rows.insert(rec, seq);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Finds the newest record for a key in the SSTables, checking the deadline before each one
+    fn find_in_tables(&self, state: &State, key: &Vec<u8>, options: &ReadOptions, deadline: Option<Instant>) -> Result<Option<Record>, ReadError> {
+        check_deadline(deadline)?;
+
+        // first check the current SSTable
+        if let Some(rec) = state.cur_sstable.get_with(key.to_vec(), options.fill_cache, options.verify_checksums)? {
+            return Ok(Some(rec));
+        }
+
+        // finally, need to go to SSTables, only opening the ones that could have the key
+        for table in state.sstables.iter().filter(|table| table.contains_key(key)) {
+            debug!("SSTABLE: {:?}", table);
+
+            check_deadline(deadline)?;
+
+            let sstable = self.table_cache.get(&table.file_path())?;
+
+            if let Some(rec) = sstable.get_with(key.to_vec(), options.fill_cache, options.verify_checksums)? {
+                // sanity check
+                if rec.is_delete() {
+                    panic!("Found deleted key in SSTable: {:?}", sstable);
+                }
+
+                return Ok(Some(rec));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn insert(&self, records: Vec<Record>, options: &WriteOptions) {
+        // nothing to check, so it only fails on a read-only store
+        let _ = self.insert_if(records, options, |_| Ok::<(), ()>( () )).map_err(InsertError::or_panic);
+    }
+
+    /// Inserts the records if the check passes; it's run with the WAL locked, so no other writes come in between
+    ///
+    /// The check is given the quota the records would take over its limit, if any.
+    fn insert_if<F, E>(&self, records: Vec<Record>, options: &WriteOptions, check: F) -> Result<(), InsertError<E>>
+        where F: FnOnce(Option<&QuotaExceeded>) -> Result<(), E>
+    {
+        self.insert_with(options, || Ok(records), check)
+    }
+
+    /// Inserts the records made with the WAL locked, if the check passes, for writes that read what they replace
+    ///
+    /// An error writing or syncing the WAL makes the store read-only, like a background error, and is returned
+    /// as `InsertError::ReadOnly`; the records of a batch are only inserted into the mem_table once all are in the WAL.
+    fn insert_with<M, F, E>(&self, options: &WriteOptions, make: M, check: F) -> Result<(), InsertError<E>>
+        where M: FnOnce() -> Result<Vec<Record>, E>, F: FnOnce(Option<&QuotaExceeded>) -> Result<(), E>
+    {
+        self.writable().map_err(InsertError::ReadOnly)?;
+
+        let written = {
+            let mut wal = self.wal.lock().unwrap();
+            let records = make().map_err(InsertError::Check)?;
+            let charge = self.quotas.charge(&records, |key| self.get_with_options(&key.to_vec(), &ReadOptions::new()));
+
+            check(charge.exceeded.as_ref()).map_err(InsertError::Check)?;
+
+            self.quotas.apply(charge);
+
+            // the mem_table is only swapped while the WAL is locked, so it matches the WAL written to
+            let mem_table = self.state.read().unwrap().mem_table.clone();
+            let keys = if self.row_cache.is_some() { records.iter().map(|rec| rec.key().to_vec()).collect() } else { vec![] };
+
+            let batch_len = records.len() as u64;
+            let mut appended = Vec::with_capacity(records.len());
+
+            for (i, mut record) in records.into_iter().enumerate() {
+                // stamped as it's written, so the clock's timestamps are in the order of the WAL
+                if let Some(ref clock) = self.options.clock.0 {
+                    if !options.keep_timestamps {
+                        record.set_created(clock.timestamp());
+                    }
+                }
+
+                // never let a record look older than a range tombstone written before it
+                if record.created() < wal.last_ts && !options.keep_timestamps {
+                    record.set_created(wal.last_ts);
+                }
+
+                wal.last_ts = wal.last_ts.max(record.created());
+
+                // the TTLs of the options are only for the puts that don't set their own
+                if !options.keep_timestamps && !record.is_delete() && record.ttl() == u64::max_value() {
+                    let ttl_ms = ttl_for(record.key(), self.options.default_ttl_ms, &self.options.prefix_ttls);
+
+                    if ttl_ms != 0 {
+                        record.set_ttl(record.created().saturating_add(ttl_ms));
+                    }
+                }
+
+                // the records of a batch say how many follow, so a replay leaves out a batch that wasn't all written
+                let res = if !options.disable_wal && batch_len > 1 {
+                    let mut left = [0; U64_SIZE];
+
+                    LE::write_u64(&mut left, batch_len - i as u64 - 1);
+                    record.set_field(BATCH_TAG, &left);
+
+                    let res = wal.append(&record);
+
+                    record.take_field(BATCH_TAG);
+                    res
+                } else if !options.disable_wal {
+                    wal.append(&record)
+                } else {
+                    Ok( () )
+                };
+
+                if let Err(e) = res {
+                    drop(wal);
+                    return Err(InsertError::ReadOnly(self.wal_error(&e)));
+                }
+
+                appended.push(record);
+            }
+
+            // insert into the mem_table
+            for record in appended {
+                mem_table.insert(record);
+            }
+
+            // once the records can be read from the mem_table, so the old ones aren't cached again
+            if let Some(ref rows) = self.row_cache {
+                for key in keys {
+                    rows.invalidate(&key);
+                }
+            }
+
+            wal.written
+        };
+
+        if (options.sync || self.options.sync_writes) && !options.disable_wal {
+            if let Err(e) = self.sync_wal(written) {
+                return Err(InsertError::ReadOnly(self.wal_error(&e)));
+            }
+        }
+
+        // check to see if the mem_table needs to be flushed
+        if self.mem_table_full() {
+            self.flush(true);
+        }
+
+        Ok( () )
+    }
+
+    /// Returns true if the active mem_table has `mem_count` records, its WAL has reached `wal_segment_size`,
+    /// or the WALs of all the mem_tables have reached `max_wal_bytes`
+    fn mem_table_full(&self) -> bool {
+        let wal = self.wal.lock().unwrap();
+        let state = self.state.read().unwrap();
+
+        state.mem_table.len() >= self.options.max_mem_count ||
+            (self.options.wal_segment_size != 0 && wal.size >= self.options.wal_segment_size) ||
+            (self.options.max_wal_bytes != 0 && wal.size + state.immutable_wal_bytes() >= self.options.max_wal_bytes)
+    }
+
+    /// Waits for the first `written` records of the WAL to reach the disk
+    ///
+    /// Writers that come in during a sync wait for it to finish, then the first of them syncs for all of them.
+    fn sync_wal(&self, written: u64) -> Result<(), IOError> {
+        let _syncing = self.wal_sync.lock().unwrap();
+
+        let (fd, path, target) = {
+            let mut wal = self.wal.lock().unwrap();
+
+            // a sync that finished while we waited covered our records
+            if wal.synced >= written {
+                return Ok( () );
+            }
+
+            (wal.file.sync_handle()?, wal.file.file_path(), wal.written)
+        };
+
+        // sync without the WAL locked, so other writers can append to it
+        fd.sync_data()?;
+        sim::on_sync(&path);
+
+        let mut wal = self.wal.lock().unwrap();
+
+        wal.synced = wal.synced.max(target);
+
+        Ok( () )
+    }
+
+    /// Reads up to `CHANGE_BATCH` changes from `seq` on, from the WAL holding it
+    /// * position - the WAL and offset of `seq`, when known from the last read
+    /// return: the changes, and the WAL and offset of the change after them, when known
+    fn read_changes(&self, seq: u64, position: Option<(u64, u64)>) -> Result<(Vec<Change>, Option<(u64, u64)>), IOError> {
+        // the manifest is held so the WALs aren't swapped or removed while they're read
+        let manifest = self.lock_manifest();
+        let mut numbers = manifest.retained_wal_numbers().to_vec();
+
+        numbers.extend_from_slice(manifest.immutable_wal_numbers());
+        numbers.push(manifest.wal_number());
+
+        // the newest WAL starting at, or before, the change
+        let found = numbers.iter().rev().filter_map(|&n| manifest.wal_first_seq(n).map(|first_seq| (n, first_seq))).find(|&(_, first_seq)| first_seq <= seq);
+
+        let (number, first_seq) = match found {
+            Some(found) => found,
+            None => return Err(IOError::new(ErrorKind::NotFound, format!("Change {} is no longer in a WAL, see KVSOptions::wal_retention", seq)))
+        };
+
+        let offset = match position {
+            Some((n, offset)) if n == number => Some(offset),
+            _ => None
+        };
+
+        let (changes, offset) = if number == manifest.wal_number() {
+            let wal = self.wal.lock().unwrap();
+
+            read_wal_changes(&wal.file, first_seq, seq, offset)?
+        } else {
+            let wal_file = RecordFile::new(&manifest.wal_file(number), WAL_HEADER, self.options.rec_file_buffer_size, 1)?;
+
+            read_wal_changes(&wal_file, first_seq, seq, offset)?
+        };
+
+        Ok( (changes, offset.map(|offset| (number, offset))) )
+    }
+
+    /// * created - the timestamp of a range delete from another replica, None for a new one
+    /// return: the range tombstone written, or the background error of a read-only store
+    fn delete_range(&self, start: &Vec<u8>, end: &Vec<u8>, created: Option<u64>) -> Result<Record, BackgroundError> {
+        debug!("Called delete_range: {:?} - {:?}", start, end);
+
+        self.writable()?;
+
+        let mut wal = self.wal.lock().unwrap();
+
+        // the tombstone must be newer than everything already written, unless it's an older one being applied
+        let created = created.unwrap_or_else(|| self.timestamp().max(wal.last_ts + 1));
+        let tombstone = Record::new_range_delete(start.to_vec(), end.to_vec(), created);
+
+        if let Err(e) = wal.append(&tombstone) {
+            drop(wal);
+
+            return Err(self.wal_error(&e));
+        }
+        wal.last_ts = wal.last_ts.max(created);
+
+        self.state.read().unwrap().mem_table.insert(tombstone.clone());
+
+        if let Some(ref rows) = self.row_cache {
+            rows.invalidate_range(start, end);
+        }
+
+        // recounted while the WAL is locked, so no write to the prefixes is missed, or counted twice
+        for prefix in self.quotas.overlapping(start, end) {
+            self.count_quota(&prefix);
+        }
+
+        Ok(tombstone)
+    }
+
+    /// Counts the keys with the prefix of a quota, and their bytes, setting its usage
+    fn count_quota(&self, prefix: &[u8]) {
+        let range = prefix_end(prefix).map(|end| (prefix.to_vec(), end));
+
+        let (keys, bytes) = self.new_iter(range, &ReadOptions::new())
+            .filter(|&(ref key, _)| key.starts_with(prefix))
+            .fold((0, 0), |(keys, bytes), (key, value)| (keys + 1, bytes + (key.len() + value.len()) as u64));
+
+        self.quotas.set(prefix, keys, bytes);
+    }
+
+    /// The current SSTable, and all the others, pinned for as long as the version lives
+    fn pin_tables(&self, state: &State) -> Result<Arc<Version>, IOError> {
+        let mut tables = vec![state.cur_sstable.clone()];
+
+        for table in state.sstables.iter() {
+            tables.push(self.table_cache.get(&table.file_path())?);
+        }
+
+        Ok(self.versions.lock().unwrap().add(tables))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot, IOError> {
+        let state = self.state.read().unwrap();
+        let version = self.pin_tables(&state)?;
+
+        // viewed as they are now, as the active mem_table changes with every write
+        let mem_tables = iter::once(&state.mem_table).chain(state.immutables.iter().rev()).map(MemTableView::new).collect();
+
+        Ok(Snapshot {
+            version: version,
+            mem_tables: Arc::new(mem_tables),
+            range_tombstones: Arc::new(state.range_tombstones())
+        })
+    }
+
+    fn new_iter(&self, range: Option<(Vec<u8>, Vec<u8>)>, options: &ReadOptions) -> Iter {
+        let (start, end) = match range {
+            Some((start, end)) => (Some(start), Some(end)),
+            None => (None, None)
+        };
+
+        self.merged_iter(start, end, options, false).expect("Error reading SSTable")
+    }
+
+    /// The newest record of every key in [start, end), without those range deleted; and unless `raw`, without deletes
+    /// and expired records
+    ///
+    /// The SSTables are read from the group holding the start, and those outside the range aren't read at all.
+    fn merged_iter(&self, start: Option<Vec<u8>>, end: Option<Vec<u8>>, options: &ReadOptions, raw: bool) -> Result<Iter, IOError> {
+        let deadline = options.deadline.map(|deadline| Instant::now() + deadline);
+        let snapshot = match options.snapshot {
+            Some(ref snapshot) => snapshot.clone(),
+            None => self.snapshot()?
+        };
+
+        let before_end = { let end = end.clone(); move |key: &[u8]| end.as_ref().map_or(true, |end| key < end.as_slice()) };
+        let from = start.clone().unwrap_or_default();
+
+        // the records of newer mem_tables replace those of older ones
+        let keys_only = options.keys_only;
+        let mut mem_records = BTreeMap::new();
+
+        for mem_table in snapshot.mem_tables.iter().rev() {
+            for rec in mem_table.iter_from(&from).take_while(|rec| before_end(rec.key())) {
+                mem_records.insert(rec.key().to_vec(), if keys_only { rec.to_key_record() } else { rec });
+            }
+        }
+
+        let mut its: Vec<Box<Iterator<Item=Record>>> = vec![Box::new(mem_records.into_iter().map(|(_, rec)| rec))];
+
+        for sstable in snapshot.version.tables().iter() {
+            if sstable.record_count() == 0 || sstable.largest_key() < from.as_slice() || !before_end(sstable.smallest_key()) {
+                continue;
+            }
+
+            let mut it = SSTable::iter_shared(sstable.clone(), options.fill_cache, options.verify_checksums, options.readahead, keys_only);
+
+            if start.is_some() {
+                it.seek(&from)?;
+            }
+
+            its.push(Box::new(it));
+        }
+
+        let range_tombstones = snapshot.range_tombstones.clone();
+        let cur_time = self.now();
+
+        let records = kmerge(its).coalesce(coalesce_records)
+            .skip_while(move |rec| rec.key() < from.as_slice())
+            .take_while(move |rec| before_end(rec.key()))
+            .filter(move |rec| {
+                // remove all deleted, expired, and range deleted
+                (raw || (!rec.is_delete() && !rec.is_expired(cur_time))) && !range_tombstones.iter().any(|t| t.covers(rec))
+            });
+
+        Ok(Iter { _version: snapshot.version, records: Box::new(records), deadline: deadline, deadline_exceeded: false })
+    }
+
+    /// Reads a page of the keys from start, up to end if there is one
+    fn page(&self, start: &[u8], end: Option<&Vec<u8>>, limit: usize, cursor: Option<&[u8]>) -> Result<Page, IOError> {
+        if limit == 0 {
+            return Err(IOError::new(ErrorKind::InvalidInput, "A page must have a limit of at least 1"));
+        }
+
+        let now = Instant::now();
+        let timeout = Duration::from_millis(self.options.cursor_timeout_ms);
+
+        let (seq, snapshot, from, kept) = {
+            let mut cursors = self.cursors.lock().unwrap();
+
+            cursors.retain(|_, &mut (_, last_read)| now.duration_since(last_read) < timeout);
+
+            match cursor {
+                Some(cursor) => {
+                    let (seq, mut last_key) = decode_cursor(cursor)?;
+
+                    // a cursor only resumes a listing of the range it came from
+                    if last_key.as_slice() < start || end.map_or(false, |end| last_key >= *end) {
+                        return Err(IOError::new(ErrorKind::InvalidInput, "The cursor is outside the range of the listing"));
+                    }
+
+                    let view = cursors.get_mut(&(seq, start.to_vec(), end.cloned()))
+                        .ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("The view of cursor {} was dropped, see KVSOptions::cursor_timeout and max_cursors", seq)))?;
+
+                    view.1 = now;
+                    last_key.push(0); // the smallest key after it
+
+                    (seq, view.0.clone(), last_key.max(start.to_vec()), true)
+                },
+                None => {
+                    // the WAL is locked so no write is in the snapshot without being before the sequence number
+                    let wal = self.wal.lock().unwrap();
+                    let seq = wal.first_seq + wal.file.record_count() as u64;
+
+                    // listings of the same range started at the same sequence number share a view
+                    match cursors.get_mut(&(seq, start.to_vec(), end.cloned())) {
+                        Some(view) => { view.1 = now; (seq, view.0.clone(), start.to_vec(), true) },
+                        None => (seq, self.snapshot()?, start.to_vec(), false)
+                    }
+                }
+            }
+        };
+
+        let mut options = ReadOptions::new();
+
+        options.snapshot(&snapshot);
+
+        let mut it = self.merged_iter(Some(from), end.cloned(), &options, false)?;
+
+        let entries = it.by_ref().take(limit).collect::<Vec<_>>();
+
+        let cursor = match it.next() {
+            Some(_) => Some(encode_cursor(seq, &entries.last().expect("A page has at least 1 entry").0)),
+            None => None
+        };
+
+        // a new listing's view is only kept for its next page, dropping the one read longest ago to make room
+        if cursor.is_some() && !kept {
+            let mut cursors = self.cursors.lock().unwrap();
+
+            if cursors.len() >= self.options.max_cursors {
+                let oldest = cursors.iter().min_by_key(|&(_, &(_, last_read))| last_read).map(|(key, _)| key.clone());
+
+                if let Some(key) = oldest {
+                    cursors.remove(&key);
+                }
+            }
+
+            cursors.entry( (seq, start.to_vec(), end.cloned()) ).or_insert( (snapshot, now) );
+        }
+
+        Ok(Page { entries: entries, cursor: cursor })
+    }
+
+    fn count_estimate(&self) -> u64 {
+        let state = self.state.read().unwrap();
+        let mut sum = state.mem_table.len() as u64 + state.immutables.iter().map(|m| m.len() as u64).sum::<u64>();
+
+        debug!("mem_table count: {}", sum);
+
+        sum += state.cur_sstable.record_count();
+
+        debug!("cur_sstable count: {}", state.cur_sstable.record_count());
+
+        for sstable in state.sstables.iter() {
+            debug!("{:?} count: {}", sstable, sstable.record_count());
+            sum += sstable.record_count();
+        }
+
+        debug!("SUM: {}", sum);
+
+        return sum;
+    }
+
+    fn stats(&self) -> StoreStats {
+        let wal_size = self.wal.lock().unwrap().size;
+        let state = self.state.read().unwrap();
+        let cur_time = self.now();
+
+        let non_empty = |count: u64, key: &[u8]| if count == 0 { None } else { Some(key.to_vec()) };
+
+        let level0 = LevelStats {
+            level: 0,
+            table_count: 1,
+            file_bytes: file_size(&state.cur_sstable.file_path()),
+            record_count: state.cur_sstable.record_count(),
+            smallest_key: non_empty(state.cur_sstable.record_count(), state.cur_sstable.smallest_key()),
+            largest_key: non_empty(state.cur_sstable.record_count(), state.cur_sstable.largest_key()),
+            bloom_bytes: state.cur_sstable.bloom_bytes()
+        };
+
+        // the tables don't overlap, and are in key order
+        let live = state.sstables.iter().filter(|t| t.record_count() != 0).collect::<Vec<_>>();
+
+        let level1 = LevelStats {
+            level: 1,
+            table_count: state.sstables.len(),
+            file_bytes: state.sstables.iter().map(|t| file_size(&t.file_path())).sum(),
+            record_count: state.sstables.iter().map(|t| t.record_count()).sum(),
+            smallest_key: live.first().map(|t| t.smallest_key().to_vec()),
+            largest_key: live.last().map(|t| t.largest_key().to_vec()),
+            bloom_bytes: state.sstables.iter().map(|t| t.bloom_bytes()).sum()
+        };
+
+        let compaction_pending = state.cur_sstable.record_count() >= self.compaction_threshold();
+
+        // the current SSTable may also be in the table cache
+        let mut record_cache = CacheStats::default();
+        let mut readahead_bytes = 0;
+        let mut seen = HashSet::new();
+
+        for sstable in iter::once(state.cur_sstable.clone()).chain(self.table_cache.open_tables()) {
+            if seen.insert(sstable.file_path()) {
+                let (hits, misses) = sstable.cache_stats();
+
+                record_cache.hits += hits;
+                record_cache.misses += misses;
+                readahead_bytes += sstable.readahead_bytes();
+            }
+        }
+
+        let (hits, misses) = self.table_cache.cache_stats();
+        let (meta_hits, meta_misses) = self.cache.meta.as_ref().map_or((0, 0), |meta| meta.cache_stats());
+        let (meta_bytes, pinned_bytes) = self.cache.meta.as_ref().map_or((0, 0), |meta| meta.bytes());
+        let (row_hits, row_misses) = self.row_cache.as_ref().map_or((0, 0), |rows| rows.cache_stats());
+
+        StoreStats {
+            mem_records: state.mem_table.len() as u64,
+            immutables: state.immutables.len(),
+            immutable_records: state.immutables.iter().map(|m| m.len() as u64).sum(),
+            wal_bytes: wal_size + state.immutable_wal_bytes(),
+            open_tables: self.table_cache.len(),
+            table_cache: CacheStats { hits: hits, misses: misses },
+            record_cache: record_cache,
+            readahead_bytes: readahead_bytes,
+            meta_cache: CacheStats { hits: meta_hits, misses: meta_misses },
+            meta_bytes: meta_bytes,
+            pinned_bytes: pinned_bytes,
+            row_cache: CacheStats { hits: row_hits, misses: row_misses },
+            cached_rows: self.row_cache.as_ref().map_or(0, |rows| rows.len() as u64),
+            compaction_pending: compaction_pending,
+            pending_compaction_bytes: if compaction_pending { level0.file_bytes + level1.file_bytes } else { 0 },
+            expired_records: state.sstables.iter().map(|t| t.expired_count(cur_time)).sum(),
+            levels: vec![level0, level1],
+            quotas: self.quotas.usage(),
+            hot_keys: self.hot_keys.top(STATS_HOT_KEYS)
+        }
+    }
+
+    fn property(&self, name: &str) -> Option<String> {
+        const LEVEL_FILES: &str = "kvs.num-files-at-level";
+
+        let stats = self.stats();
+
+        if name.starts_with(LEVEL_FILES) {
+            let level = name[LEVEL_FILES.len()..].parse::<usize>().ok()?;
+
+            return stats.levels.get(level).map(|l| l.table_count.to_string());
+        }
+
+        let (active_bytes, immutable_bytes) = {
+            let state = self.state.read().unwrap();
+
+            (state.mem_table.approx_size(), state.immutables.iter().map(|m| m.approx_size()).sum::<usize>())
+        };
+
+        let value = match name {
+            "kvs.estimate-num-keys" => self.count_estimate().to_string(),
+            "kvs.estimate-live-data-size" => stats.levels.iter().map(|l| l.file_bytes).sum::<u64>().to_string(),
+            "kvs.mem-table-size" => active_bytes.to_string(),
+            "kvs.cur-size-all-mem-tables" => (active_bytes + immutable_bytes).to_string(),
+            "kvs.num-entries-active-mem-table" => stats.mem_records.to_string(),
+            "kvs.num-immutable-mem-table" => stats.immutables.to_string(),
+            "kvs.compaction-pending" => (stats.compaction_pending as u8).to_string(),
+            "kvs.estimate-pending-compaction-bytes" => stats.pending_compaction_bytes.to_string(),
+            "kvs.background-errors" => (self.is_read_only() as u8).to_string(),
+            "kvs.stats" => stats.to_string(),
+            _ => return None
+        };
+
+        Some(value)
+    }
+
+    fn check(&self) -> CheckReport {
+        // no flush or compaction changes the files while they're looked at
+        let _tables = self.table_lock.lock().unwrap();
+        let manifest = self.lock_manifest();
+        let next_file_number = manifest.next_file_number();
+        let mut report = CheckReport::default();
+        let mut ranges = vec![];
+
+        // the current SSTable first, it may overlap the others
+        for (i, &number) in iter::once(&manifest.current_number()).chain(manifest.table_numbers()).enumerate() {
+            let path = manifest.table_path(number);
+
+            report.tables += 1;
+
+            if number >= next_file_number {
+                report.problems.push(Inconsistency::FileNumber { path: path.clone(), next_file_number: next_file_number });
+            }
+
+            if !path.exists() {
+                report.problems.push(Inconsistency::MissingFile(path));
+                continue;
+            }
+
+            let sstable = match self.table_cache.get(&path) {
+                Ok(sstable) => sstable,
+                Err(e) => {
+                    report.problems.push(Inconsistency::BadFile { path: path, error: e.to_string() });
+                    continue;
+                }
+            };
+
+            if sstable.id() != number {
+                report.problems.push(Inconsistency::TableId { path: path.clone(), id: sstable.id() });
+            }
+
+            if i != 0 {
+                ranges.push((path, sstable.key_range()));
+            }
+        }
+
+        ranges.sort_by(|a, b| a.1.cmp(&b.1));
+        report.problems.extend(check_overlaps(&ranges));
+
+        // the WALs, oldest first, the active one last
+        let wal = self.wal.lock().unwrap();
+        let state = self.state.read().unwrap();
+        let mut wals = vec![];
+
+        for &number in manifest.retained_wal_numbers().iter().chain(manifest.immutable_wal_numbers()).chain(iter::once(&manifest.wal_number())) {
+            let path = manifest.wal_file(number);
+
+            report.wals += 1;
+
+            if number >= next_file_number {
+                report.problems.push(Inconsistency::FileNumber { path: path.clone(), next_file_number: next_file_number });
+            }
+
+            // the count at the start of the active WAL is only written out now and then
+            let record_count = if number == manifest.wal_number() && path.exists() { Ok(wal.file.record_count() as u64) } else { wal_record_count(&path) };
+
+            match record_count {
+                Ok(record_count) => wals.push((path, manifest.wal_first_seq(number), record_count)),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => report.problems.push(Inconsistency::MissingFile(path)),
+                Err(e) => report.problems.push(Inconsistency::BadFile { path: path, error: e.to_string() })
+            }
+        }
+
+        report.problems.extend(check_seqs(&wals));
+
+        let manifest_wals = manifest.immutable_wal_numbers().iter().cloned().chain(iter::once(manifest.wal_number())).collect::<Vec<_>>();
+        let mem_table_wals = state.immutables.iter().chain(iter::once(&state.mem_table)).map(|m| m.wal_number()).collect::<Vec<_>>();
+
+        if manifest_wals != mem_table_wals {
+            report.problems.push(Inconsistency::WalMismatch { manifest: manifest_wals, mem_tables: mem_table_wals });
+        }
+
+        // left by a crash, or still being read
+        match manifest.obsolete_files() {
+            Ok(files) => report.unreferenced = files,
+            Err(e) => warn!("Error listing the files of {:?}: {}", self.options.db_dir, e)
+        }
+
+        if !report.is_consistent() {
+            warn!("{}", report);
+        }
+
+        report
+    }
+
+    fn ingest_external(&self, paths: &[PathBuf], options: &IngestOptions) -> Result<u64, IOError> {
+        let invalid = |msg: String| Err(IOError::new(ErrorKind::InvalidInput, msg));
+
+        if self.is_read_only() {
+            return Err(IOError::new(ErrorKind::Other, "The store is read-only after a background error"));
+        }
+
+        // no flush or compaction changes the tables while they're added
+        let _tables = self.table_lock.lock().unwrap();
+
+        let buffer_size = self.options.rec_file_buffer_size;
+        let cache = CacheOptions { size: INGEST_CACHE_SIZE, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
+        let mut ranges = vec![];
+        let mut ids = vec![]; // the ids the tables had, given back to moved ones that are taken back
+        let mut record_count = 0;
+
+        // every table is checked before any is moved
+        for path in paths.iter() {
+            let sstable = SSTable::open(path, buffer_size, cache.clone())?;
+
+            if sstable.tombstone_count() != 0 || !sstable.range_tombstones().is_empty() {
+                return invalid(format!("{:?} has deletes, only the SSTables after a current one can be ingested", path));
+            }
+
+            if !sstable.value_logs().is_empty() {
+                return invalid(format!("{:?} points into the value log of another store", path));
+            }
+
+            if options.verify_checksums {
+                sstable.verify()?;
+            }
+
+            // an empty table adds nothing
+            if let Some(range) = sstable.key_range() {
+                record_count += sstable.record_count();
+                ranges.push((path.clone(), Some(range)));
+                ids.push(sstable.id());
+            }
+        }
+
+        let mut all_ranges = ranges.clone();
+
+        all_ranges.extend(self.state.read().unwrap().sstables.iter().map(|table| (table.file_path(), Some((table.smallest_key().to_vec(), table.largest_key().to_vec())))));
+        all_ranges.sort_by(|a, b| a.1.cmp(&b.1));
+
+        if let Some(overlap) = check_overlaps(&all_ranges).first() {
+            return invalid(format!("Can't ingest overlapping SSTables: {}", overlap));
+        }
+
+        // the tables that have been copied or moved, with their numbers, taken back if any can't be added
+        let mut placed = vec![];
+        let undo = |placed: &[(PathBuf, u64, PathBuf)]| {
+            for (&(ref src, _, ref dst), &id) in placed.iter().zip(ids.iter()) {
+                self.table_cache.evict(dst);
+
+                let result = if options.move_files {
+                    SSTable::open(dst, buffer_size, cache.clone()).and_then(|mut sstable| sstable.set_id(id)).and_then(|_| fs::rename(dst, src))
+                } else {
+                    fs::remove_file(dst)
+                };
+
+                if let Err(e) = result {
+                    warn!("Error taking back {:?}, ingested as {:?}: {}", src, dst, e);
+                }
+            }
+        };
+
+        for &(ref src, _) in ranges.iter() {
+            let (number, dst) = self.new_table_path();
+            let result = if options.move_files { fs::rename(src, &dst) } else { fs::copy(src, &dst).map(|_| ()) };
+
+            if let Err(e) = result {
+                undo(&placed);
+                return Err(e);
+            }
+
+            placed.push((src.clone(), number, dst.clone()));
+
+            if let Err(e) = SSTable::open(&dst, buffer_size, cache.clone()).and_then(|mut sstable| sstable.set_id(number)) {
+                undo(&placed);
+                return Err(e);
+            }
+        }
+
+        let mut manifest = self.lock_manifest();
+        let _wal = self.wal.lock().unwrap();
+
+        // with the WAL locked, no write can come between the check and the tables being added
+        {
+            let state = self.state.read().unwrap();
+
+            for &(ref src, ref range) in ranges.iter() {
+                let &(ref smallest, ref largest) = range.as_ref().expect("Empty tables aren't ingested");
+                let in_range = |key: &[u8]| smallest.as_slice() <= key && key <= largest.as_slice();
+                let in_mem_tables = iter::once(&state.mem_table).chain(state.immutables.iter()).any(|m| m.get_ge(smallest).map_or(false, |rec| in_range(rec.key())));
+                let in_current = state.cur_sstable.get_ge(smallest.clone()).map(|rec| rec.map_or(false, |rec| in_range(rec.key())));
+
+                if in_mem_tables || in_current.unwrap_or(true) {
+                    undo(&placed);
+                    return invalid(format!("{:?} has keys written since the last compaction, which would hide them", src));
+                }
+            }
+        }
+
+        let mut metas = vec![];
+
+        for &(_, _, ref dst) in placed.iter() {
+            match SSTable::open(dst, buffer_size, self.cache.clone()) {
+                Ok(sstable) => metas.push(self.table_cache.insert(sstable)),
+                Err(e) => {
+                    undo(&placed);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.state.write().unwrap().sstables.extend(metas);
+
+        let mut table_numbers = manifest.table_numbers().to_vec();
+
+        table_numbers.extend(placed.iter().map(|&(_, number, _)| number));
+        manifest.set_tables(table_numbers);
+        self.save_manifest(&manifest)?;
+
+        // recounted while the WAL is locked, like after a range delete
+        for &(_, ref range) in ranges.iter() {
+            let &(ref smallest, ref largest) = range.as_ref().expect("Empty tables aren't ingested");
+            let mut end = largest.clone();
+
+            end.push(0);
+
+            for prefix in self.quotas.overlapping(smallest, &end) {
+                self.count_quota(&prefix);
+            }
+        }
+
+        info!("Ingested {} SSTables into {:?}, with {} records", placed.len(), self.options.db_dir, record_count);
+
+        Ok(record_count)
+    }
+
+    fn health(&self) -> Health {
+        // the WALs of the full mem_tables are only replaced once they're flushed
+        let immutable_wals = self.state.read().unwrap().immutables.iter().map(|m| m.wal_number()).collect::<Vec<_>>();
+        let immutable_bytes = {
+            let manifest = self.manifest.lock().unwrap_or_else(PoisonError::into_inner);
+
+            immutable_wals.iter().map(|&number| file_size(&manifest.wal_file(number))).sum::<u64>()
+        };
+
+        let disk_free_bytes = free_space(&self.options.db_dir).unwrap_or_else(|e| {
+            debug!("Error getting the free space of {:?}: {}", self.options.db_dir, e);
+            None
+        });
+
+        Health {
+            immutables: immutable_wals.len(),
+            max_immutables: self.options.max_immutables,
+            wal_bytes: self.wal.lock().unwrap_or_else(PoisonError::into_inner).size + immutable_bytes,
+            disk_free_bytes: disk_free_bytes,
+            background_error: self.background.lock().unwrap_or_else(PoisonError::into_inner).error.as_ref().map(|e| e.message.clone())
+        }
+    }
+
+}
+
+/// Options for writes, see `KVS::put_with_options` and `KVS::write`
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    disable_wal: bool,
+    sync: bool,
+    keep_timestamps: bool // for the changes replayed by `KVS::restore_to`, or applied by `KVS::apply_changes`
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions::new()
+    }
+}
+
+impl WriteOptions {
+    pub fn new() -> WriteOptions {
+        WriteOptions { disable_wal: false, sync: false, keep_timestamps: false }
+    }
+
+    /// Skip writing to the WAL.
+    ///
+    /// Faster for bulk loads, but the writes are lost if the process crashes before the next flush.
+    ///
+    /// Default: false
+    pub fn disable_wal(&mut self, disable: bool) -> &mut WriteOptions {
+        self.disable_wal = disable; self
+    }
+
+    /// Wait for the WAL to reach the disk before returning.
+    ///
+    /// Default: false
+    pub fn sync(&mut self, sync: bool) -> &mut WriteOptions {
+        self.sync = sync; self
+    }
+}
+
+/// A group of puts and deletes, applied together by `KVS::write`
+#[derive(Debug)]
+pub struct WriteBatch {
+    records: Vec<Record>
+}
+
+impl Default for WriteBatch {
+    fn default() -> WriteBatch {
+        WriteBatch::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { records: vec![] }
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut WriteBatch {
+        self.records.push(Record::new(key, Some(value))); self
+    }
+
+    pub fn delete(&mut self, key: &Vec<u8>) -> &mut WriteBatch {
+        self.records.push(Record::new(key.to_vec(), None)); self
+    }
+
+    /// The number of puts and deletes in the batch
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+/// Options for adding SSTables written elsewhere, see `KVS::ingest_external`
+#[derive(Clone, Debug)]
+pub struct IngestOptions {
+    move_files: bool,
+    verify_checksums: bool
+}
+
+impl Default for IngestOptions {
+    fn default() -> IngestOptions {
+        IngestOptions::new()
+    }
+}
+
+impl IngestOptions {
+    pub fn new() -> IngestOptions {
+        IngestOptions { move_files: false, verify_checksums: true }
+    }
+
+    /// Whether the tables are moved into the store, instead of copied; they must be on the same file system.
+    ///
+    /// Default: false
+    pub fn move_files(&mut self, move_files: bool) -> &mut IngestOptions {
+        self.move_files = move_files; self
+    }
+
+    /// Whether every record is checked against its CRC before any table is added.
+    ///
+    /// Default: true
+    pub fn verify_checksums(&mut self, verify: bool) -> &mut IngestOptions {
+        self.verify_checksums = verify; self
+    }
+}
+
+/// Options for reads, see `KVS::get_with_options` and `KVS::range_with_options`
+#[derive(Clone)]
+pub struct ReadOptions {
+    fill_cache: bool,
+    verify_checksums: bool,
+    readahead: u64,
+    deadline: Option<Duration>,
+    snapshot: Option<Snapshot>,
+    keys_only: bool
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions::new()
+    }
+}
+
+impl ReadOptions {
+    pub fn new() -> ReadOptions {
+        ReadOptions { fill_cache: true, verify_checksums: true, readahead: DEFAULT_READAHEAD, deadline: None, snapshot: None, keys_only: false }
+    }
+
+    /// Whether the records read are added to the record cache.
+    ///
+    /// Turn this off for large scans, so they don't push the hot records out of the cache.
+    ///
+    /// Default: true
+    pub fn fill_cache(&mut self, fill: bool) -> &mut ReadOptions {
+        self.fill_cache = fill; self
+    }
+
+    /// Whether the records read from disk are checked against their CRCs.
+    ///
+    /// `KVS::try_get` returns a record that doesn't match its CRC as a `ReadError::IO` of kind `InvalidData`;
+    /// the reads that don't return errors panic.
+    ///
+    /// Default: true
+    pub fn verify_checksums(&mut self, verify: bool) -> &mut ReadOptions {
+        self.verify_checksums = verify; self
+    }
+
+    /// The most bytes a scan reads ahead of its records in each SSTable, 0 for none.
+    ///
+    /// Scans start reading ahead once they've read a few records from a table, so gets and short scans
+    /// don't read more than they need. Only Linux reads ahead.
+    ///
+    /// Default: 256 KiB
+    pub fn readahead(&mut self, bytes: u64) -> &mut ReadOptions {
+        self.readahead = bytes; self
+    }
+
+    /// How long a read can take, from when it starts, before it gives up with `DeadlineExceeded`.
+    ///
+    /// `KVS::try_get` checks it before reading each SSTable that could hold the key; the mem_tables are
+    /// always read. Scans stop between records, and `KVS::try_range` returns the entries read until then;
+    /// see `Iter::deadline_exceeded` for the other scans. Gets that can't fail don't check it.
+    ///
+    /// Default: None
+    pub fn deadline(&mut self, deadline: Duration) -> &mut ReadOptions {
+        self.deadline = Some(deadline); self
+    }
+
+    /// Read the store as it was when the snapshot was taken, instead of its latest state.
+    ///
+    /// Default: None
+    pub fn snapshot(&mut self, snapshot: &Snapshot) -> &mut ReadOptions {
+        self.snapshot = Some(snapshot.clone()); self
+    }
+
+    /// Scan only the keys; the values of the pairs are left empty.
+    ///
+    /// The values read from the SSTables aren't copied or decompressed, which makes listing or counting
+    /// keys much cheaper. Gets ignore it.
+    ///
+    /// Default: false
+    pub fn keys_only(&mut self, keys_only: bool) -> &mut ReadOptions {
+        self.keys_only = keys_only; self
+    }
+}
+
+/// A read-only view of a `KVS` at a point in time, see `KVS::snapshot`
+#[derive(Clone)]
+pub struct Snapshot {
+    version: Arc<Version>,
+    mem_tables: Arc<Vec<MemTableView>>, // newest first
+    range_tombstones: Arc<Vec<Record>>
+}
+
+impl Snapshot {
+    /// Finds the newest record for a key, checking the deadline before each SSTable
+    fn find(&self, key: &Vec<u8>, options: &ReadOptions, deadline: Option<Instant>) -> Result<Option<Record>, ReadError> {
+        for mem_table in self.mem_tables.iter() {
+            if let Some(rec) = mem_table.get(key) {
+                return Ok(Some(rec));
+            }
+        }
+
+        // the current SSTable is first, the rest don't overlap
+        for sstable in self.version.tables().iter() {
+            check_deadline(deadline)?;
+
+            if let Some(rec) = sstable.get_with(key.to_vec(), options.fill_cache, options.verify_checksums)? {
+                return Ok(Some(rec));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// An SSTable of `KVS::export_live_files`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveFile {
+    pub path: PathBuf,
+    pub level: usize,          // 0 for the current SSTable, 1 for the others, like `LevelStats`
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    pub record_count: u64,
+    pub oldest_ts: u64,        // the oldest created timestamp of a record
+    pub newest_ts: u64,        // the newest
+    pub file_bytes: u64
+}
+
+/// The SSTables of a store at a point in time, whose files are kept until it's dropped; see `KVS::export_live_files`
+pub struct LiveFiles {
+    pub files: Vec<LiveFile>,
+    version: Arc<Version> // keeps the files from being removed
+}
+
+impl LiveFiles {
+    /// The paths of the SSTables after the current one, which `KVS::ingest_external` can take
+    pub fn ingestable_paths(&self) -> Vec<PathBuf> {
+        self.files.iter().filter(|file| file.level != 0).map(|file| file.path.clone()).collect()
+    }
+}
+
+impl fmt::Debug for LiveFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LiveFiles").field("files", &self.files).field("tables", &self.version.tables().len()).finish()
+    }
+}
+
+/// Options for transactions, see `KVS::begin_with_options`
+#[derive(Clone, Debug)]
+pub struct TransactionOptions {
+    pessimistic: bool,
+    lock_timeout: Duration
+}
+
+impl Default for TransactionOptions {
+    fn default() -> TransactionOptions {
+        TransactionOptions::new()
+    }
+}
+
+impl TransactionOptions {
+    pub fn new() -> TransactionOptions {
+        TransactionOptions { pessimistic: false, lock_timeout: Duration::from_millis(DEFAULT_LOCK_TIMEOUT_MS) }
+    }
+
+    /// Lock each key when it's first read or written, instead of checking for changes at the commit.
+    ///
+    /// Other pessimistic transactions wait for the locks, so commits don't fail when many
+    /// transactions change the same keys. Writes outside of transactions don't take the locks.
+    ///
+    /// Default: false
+    pub fn pessimistic(&mut self, pessimistic: bool) -> &mut TransactionOptions {
+        self.pessimistic = pessimistic; self
+    }
+
+    /// How long a pessimistic transaction waits for a lock before giving up, and failing its commit.
+    ///
+    /// Transactions that lock the same keys in different orders can deadlock; this is what breaks it.
+    ///
+    /// Default: 1 second
+    pub fn lock_timeout(&mut self, timeout: Duration) -> &mut TransactionOptions {
+        self.lock_timeout = timeout; self
+    }
+}
+
+/// A group of reads and writes that are committed together, or not at all, see `KVS::begin`
+///
+/// Reads see the store as it was when the transaction began, along with the transaction's own
+/// writes, which are kept until the commit. The commit fails if any key that was read or written
+/// has changed since the transaction began, so committed transactions act as if they ran one at a time.
+///
+/// A pessimistic transaction instead locks each key it reads or writes until it's committed or
+/// dropped, and reads the latest values. Its commit only fails if it timed out waiting for a lock.
+pub struct Transaction<'a> {
+    kvs: &'a KVS,
+    id: u64,
+    options: TransactionOptions,
+    read_options: ReadOptions,                  // reads the snapshot taken when the transaction began
+    keys: BTreeSet<Vec<u8>>,                    // the keys read or written; checked for changes by the commit, or locked
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>, // None for deletes
+    lock_failed: Option<Vec<u8>>                // the first key that couldn't be locked
+}
+
+impl<'a> Transaction<'a> {
+    pub fn get(&mut self, key: &Vec<u8>) -> Option<Vec<u8>> {
+        self.add_key(key);
+        self.kvs.core.hot_keys.record(key);
+
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => self.kvs.core.get_with_options(key, &self.read_options)
+        }
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.add_key(&key);
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: &Vec<u8>) {
+        self.add_key(key);
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Adds to the keys the transaction uses, locking it if the transaction is pessimistic
+    fn add_key(&mut self, key: &Vec<u8>) {
+        // the transaction can't commit after a lock failed, so there's no use waiting on any more locks
+        if self.keys.contains(key) || self.lock_failed.is_some() {
+            return;
+        }
+
+        if self.options.pessimistic && !self.kvs.core.locks.lock(self.id, key, self.options.lock_timeout) {
+            self.lock_failed = Some(key.to_vec());
+
+            return;
+        }
+
+        self.keys.insert(key.to_vec());
+    }
+
+    /// Writes all the puts and deletes as one batch, unless a key read or written has changed
+    pub fn commit(self) -> Result<(), Conflict> {
+        self.commit_with_options(&WriteOptions::new())
+    }
+
+    /// Commits the transaction, using the `WriteOptions`
+    pub fn commit_with_options(self, options: &WriteOptions) -> Result<(), Conflict> {
+        if let Some(ref key) = self.lock_failed {
+            return Err(Conflict { key: key.to_vec() });
+        }
+
+        let core = &self.kvs.core;
+        let read_options = &self.read_options;
+        let keys = &self.keys;
+        let pessimistic = self.options.pessimistic;
+        let records = self.writes.iter().map(|(key, value)| Record::new(key.to_vec(), value.clone())).collect::<Vec<_>>();
+
+        debug!("Committing a transaction: {} keys, {} records", keys.len(), records.len());
+
+        core.insert_if(records, options, |_| {
+            // the keys of a pessimistic transaction are locked, so other transactions couldn't change them
+            if pessimistic {
+                return Ok( () );
+            }
+
+            // the latest values must still be the ones the transaction began with
+            match keys.iter().find(|key| core.get_with_options(key, read_options) != core.get_with_options(key, &ReadOptions::new())) {
+                Some(key) => Err(Conflict { key: key.to_vec() }),
+                None => Ok( () )
+            }
+        }).map_err(InsertError::or_panic)
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if self.options.pessimistic {
+            for key in self.keys.iter() {
+                self.kvs.core.locks.unlock(self.id, key);
+            }
+        }
+    }
+}
+
+/// The error of a `Transaction` commit, when a key has changed since the transaction began,
+/// or a pessimistic transaction timed out waiting for its lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub key: Vec<u8> // the first key found to have changed, or that couldn't be locked
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transaction conflict on key: {:?}", self.key)
+    }
+}
+
+impl Error for Conflict { }
+
+/// The error of `KVS::compare_and_swap`, when the key doesn't have the expected value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareFailed {
+    pub current: Option<Vec<u8>> // the value found instead, None if the key isn't there
+}
+
+impl fmt::Display for CompareFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.current {
+            Some(ref value) => write!(f, "The key has a different value: {:?}", value),
+            None => write!(f, "The key isn't there")
+        }
+    }
+}
+
+impl Error for CompareFailed { }
+
+/// The value of a counter, see `KVS::increment`
+pub fn encode_counter(count: i64) -> Vec<u8> {
+    let mut value = vec![0; 8];
+
+    LE::write_i64(&mut value, count);
+    value
+}
+
+/// The count of a counter's value, None if it isn't 8 bytes
+pub fn decode_counter(value: &[u8]) -> Option<i64> {
+    if value.len() == 8 { Some(LE::read_i64(value)) } else { None }
+}
+
+/// The error of `KVS::increment`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncrementError {
+    NotACounter(Vec<u8>),     // the key's value isn't 8 bytes
+    Overflow(i64),            // adding the delta to the counter, at this count, would overflow
+    ReadOnly(BackgroundError) // the store is read-only, see `KVS::background_error`
+}
+
+impl fmt::Display for IncrementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IncrementError::NotACounter(ref value) => write!(f, "The key's value isn't a counter, it's {} bytes", value.len()),
+            IncrementError::Overflow(count) => write!(f, "The counter would overflow from {}", count),
+            IncrementError::ReadOnly(ref e) => e.fmt(f)
+        }
+    }
+}
+
+impl Error for IncrementError { }
+
+/// The error of a read that ran out of time, see `ReadOptions::deadline`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)> // those a scan read in time, in order; none for a get
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Deadline exceeded, after reading {} entries", self.entries.len())
+    }
+}
+
+impl Error for DeadlineExceeded { }
+
+/// Why `KVS::try_get` didn't read the value of a key
+#[derive(Debug)]
+pub enum ReadError {
+    DeadlineExceeded(DeadlineExceeded),
+    IO(IOError) // reading an SSTable failed, or a record didn't match its checksum
+}
+
+impl From<DeadlineExceeded> for ReadError {
+    fn from(e: DeadlineExceeded) -> ReadError {
+        ReadError::DeadlineExceeded(e)
+    }
+}
+
+impl From<IOError> for ReadError {
+    fn from(e: IOError) -> ReadError {
+        ReadError::IO(e)
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::DeadlineExceeded(ref e) => e.fmt(f),
+            ReadError::IO(ref e) => e.fmt(f)
+        }
+    }
+}
+
+impl Error for ReadError { }
+
+/// The IO error that stopped the flushes and compactions, leaving the store read-only, see `KVS::background_error`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackgroundError {
+    pub kind: ErrorKind,
+    pub message: String
+}
+
+impl fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The store is read-only after a background error: {}", self.message)
+    }
+}
+
+impl Error for BackgroundError { }
+
+/// Why `Core::insert_with` didn't write the records
+enum InsertError<E> {
+    Check(E),                  // the records couldn't be made, or the check failed
+    ReadOnly(BackgroundError)  // the store is read-only, after a background error or one writing the WAL
+}
+
+impl<E> InsertError<E> {
+    /// The error of the check, panicking with the background error for the writes that can't return it
+    fn or_panic(self) -> E {
+        match self {
+            InsertError::Check(e) => e,
+            InsertError::ReadOnly(e) => panic!("{}", e)
+        }
+    }
+}
+
+/// The error of `KVS::try_write` when the disk is low on space, see `KVSOptions::min_free_space`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfSpace {
+    pub free_bytes: u64,
+    pub min_free_bytes: u64
+}
+
+impl fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Out of space: {} bytes free, the store keeps {} free", self.free_bytes, self.min_free_bytes)
+    }
+}
+
+impl Error for OutOfSpace { }
+
+/// Why `KVS::try_put` or `KVS::try_write` didn't write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    QuotaExceeded(QuotaExceeded),
+    OutOfSpace(OutOfSpace),
+    ReadOnly(BackgroundError)
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::QuotaExceeded(ref e) => e.fmt(f),
+            WriteError::OutOfSpace(ref e) => e.fmt(f),
+            WriteError::ReadOnly(ref e) => e.fmt(f)
+        }
+    }
+}
+
+impl Error for WriteError { }
+
+/// Where `KVS::restore_to` stops replaying changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    Seq(u64),      // just before the change with the sequence number
+    Timestamp(u64) // after the last change written at, or before, the time, in ms since the epoch
+}
+
+/// The kinds of changes in a `ChangeStream`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Put,
+    Delete,
+    DeleteRange
+}
+
+/// A change committed to the store, see `KVS::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub seq: u64,
+    pub op: ChangeOp,
+    pub key: Vec<u8>,           // the start of the range, for a DeleteRange
+    pub value: Option<Vec<u8>>, // the value of a Put, or the end of the range for a DeleteRange
+    pub ts: u64                 // the timestamp of the record written, see `KVS::apply_changes`
+}
+
+impl Change {
+    fn new(seq: u64, rec: Record) -> Change {
+        let range_end = rec.range_end().map(|end| end.to_vec());
+        let op = if range_end.is_some() {
+            ChangeOp::DeleteRange
+        } else if rec.is_delete() {
+            ChangeOp::Delete
+        } else {
+            ChangeOp::Put
+        };
+
+        let ts = rec.created();
+        let (key, value) = rec.into_parts();
+
+        Change { seq: seq, op: op, key: key, value: range_end.or(value), ts: ts }
+    }
+
+    /// Returns true if the change is to a key starting with the prefix, or is a range delete covering one
+    fn has_prefix(&self, prefix: &[u8]) -> bool {
+        match self.op {
+            ChangeOp::DeleteRange => {
+                let end = self.value.as_ref().expect("DeleteRange without an end");
+
+                // a start past the prefix, that doesn't have it, is past every key with it
+                end.as_slice() > prefix && (self.key.as_slice() <= prefix || self.key.starts_with(prefix))
+            },
+            _ => self.key.starts_with(prefix)
+        }
+    }
+}
+
+/// The changes committed to a `KVS`, in the order they were written
+///
+/// Unlike most iterators, `next` returns `None` once caught up, then more changes as they're written.
+pub struct ChangeStream<'a> {
+    kvs: &'a KVS,
+    seq: u64,                     // the sequence number of the next change
+    position: Option<(u64, u64)>, // the WAL, and offset in it, of the next change, when known
+    changes: VecDeque<Change>     // read, but not yet returned
+}
+
+impl<'a> Iterator for ChangeStream<'a> {
+    type Item = Result<Change, IOError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.changes.is_empty() {
+            match self.kvs.core.read_changes(self.seq, self.position) {
+                Ok( (changes, position) ) => {
+                    self.changes.extend(changes);
+                    self.position = position;
+                },
+                Err(e) => return Some(Err(e))
+            }
+        }
+
+        self.changes.pop_front().map(|change| {
+            self.seq = change.seq + 1;
+            Ok(change)
+        })
+    }
+}
+
+/// A page of a paginated scan, see `KVS::range_page`
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub cursor: Option<Vec<u8>> // for the next page, None after the last page
+}
+
+/// The numbers in a range, see `KVS::aggregate_range`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Aggregate {
+    pub count: u64,       // the values that are numbers
+    pub sum: u64,         // their sum, stopping at u64::MAX
+    pub min: Option<u64>, // None without any numbers
+    pub max: Option<u64>,
+    pub skipped: u64      // the values that aren't numbers
+}
+
+/// An iterator over the key/value pairs of a `KVS`
+pub struct Iter {
+    _version: Arc<Version>, // keeps the files being read from being removed
+    records: Box<Iterator<Item=Record>>,
+    deadline: Option<Instant>,
+    deadline_exceeded: bool
+}
+
+impl Iter {
+    /// Whether the scan stopped because its `ReadOptions::deadline` passed, rather than at the end
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline_exceeded
+    }
+
+    fn next_record(&mut self) -> Option<Record> {
+        if check_deadline(self.deadline).is_err() {
+            self.deadline_exceeded = true;
+            return None;
+        }
+
+        self.records.next()
+    }
+}
+
+impl Iterator for Iter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().map(|rec| { let (key, value) = rec.into_parts(); (key, value.expect("Deleted records are filtered out")) })
+    }
+}
+
+/// The newest record of a key, with what's known about it; see `KVS::raw_iter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>, // None for a delete
+    pub created: u64,           // the timestamp of the write, see `KVSOptions::clock`
+    pub expiry: Option<u64>,    // when the record expires, if it was written with a TTL; it may have already
+    pub tombstone: bool         // the record is a delete, which hides the key's older records until a merge drops it
+}
+
+/// An iterator over the `RawEntry` of every key, see `KVS::raw_iter`
+pub struct RawIter(Iter);
+
+impl RawIter {
+    /// Whether the scan stopped because its `ReadOptions::deadline` passed, rather than at the end
+    pub fn deadline_exceeded(&self) -> bool {
+        self.0.deadline_exceeded()
+    }
+}
+
+impl Iterator for RawIter {
+    type Item = RawEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_record().map(|rec| {
+            let (created, ttl, tombstone) = (rec.created(), rec.ttl(), rec.is_delete());
+            let (key, value) = rec.into_parts();
+
+            RawEntry { key: key, value: value, created: created, expiry: if ttl == u64::max_value() { None } else { Some(ttl) }, tombstone: tombstone }
+        })
+    }
+}
+
+impl Drop for KVS {
+    fn drop(&mut self) {
+        debug!("KVS Drop");
+
+        self.core.resume_background();
+
+        // don't write anything while unwinding, the state of the store can't be trusted
+        // nor after a close, with the background work cancelled, or after a background error, as nothing would flush
+        let result = if thread::panicking() || self.core.is_shut_down() || self.core.is_read_only() {
+            Ok( () )
+        } else {
+            // call flush without checking the size, which waits for the background thread
+            panic::catch_unwind(AssertUnwindSafe(|| { self.core.flush(false); }))
+        };
+
+        self.stop_background();
+
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use kvs::{KVSOptions, KVS, ReadOptions, WriteOptions, WriteBatch, RawEntry, Conflict, CompareFailed, IncrementError, Aggregate, encode_counter, decode_counter, ReadError, WriteError, TransactionOptions, Change, ChangeOp, RestorePoint, IngestOptions};
+    use std::time::Duration;
+    use mem_table::MemTableKind;
+    use cache::{CacheOptions, CachePolicyKind};
+    use codec::CodecKind;
+    use kvs::{OptionsFile, OPTIONS_FILE, CLEAN_SHUTDOWN_FILE, WAL_HEADER, CompactionReason, CompactionJob, file_size, replay_wal};
+    use compaction_hook::CompactionHook;
+    use mem_table::WalMemTable;
+    use quota::QuotaExceeded;
+    use check::Inconsistency;
+    use record::{Record, BATCH_TAG};
+    use record_file::RecordFile;
+    use sstable::{SSTable, NewSSTable};
+    use wal_archive;
+    use events::{EventListener, FlushInfo, CompactionStats, WriteStall, DiskSpace};
+    use executor::{Executor, ThreadPool};
+    use clock::{ManualClock, HybridLogicalClock};
+    use conflict_resolver::{ConflictResolver, ReplicaConflict, Resolution};
+    use testkit::{SimulatedStorage, CrashPoint, set_clock, advance_clock, clear_clock};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::fs::{self, File};
+    use std::io::{Error as IOError, ErrorKind, Write};
+    use std::path::PathBuf;
+    use rand::{thread_rng, Rng};
+    use test_path::gen_dir;
+
+    const MAX_MEM_COUNT: usize = 100;
+    const MAX_FILE_COUNT: usize = 6;
+
+    #[test]
+    fn new() {
+        let db_dir = gen_dir();
+        let _kvs = KVSOptions::new(&db_dir).create().unwrap();
+    }
+
+    #[test]
+    fn put_flush_get() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        let key = "KEY".as_bytes();
+        let value = "VALUE".as_bytes();
+
+        kvs.put(key.to_vec(), value.to_vec());
+
+        assert_eq!(kvs.count_estimate(), 1);
+
+        kvs.core.flush(false);
+
+        assert_eq!(kvs.count_estimate(), 1);
+
+        let ret = kvs.get(&key.to_vec());
+
+        assert_eq!(value, ret.unwrap().as_slice());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn get_bytes() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        kvs.put("MEM".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+        kvs.put("FLUSHED".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec());
+        kvs.core.flush(false);
+        kvs.put("MEM".as_bytes().to_vec(), "VALUE_3".as_bytes().to_vec());
+
+        let value = kvs.get_bytes(&"FLUSHED".as_bytes().to_vec()).unwrap();
+
+        assert_eq!("VALUE_2".as_bytes(), &value[..]);
+        assert_eq!("2".as_bytes(), &value.slice(6..)[..]);
+        assert_eq!("VALUE_3".as_bytes(), &kvs.get_bytes(&"MEM".as_bytes().to_vec()).unwrap()[..]);
+        assert_eq!(None, kvs.get_bytes(&"NONE".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn auto_flush() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for _i in 0..MAX_MEM_COUNT+1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT+1) as u64);
+    }
+
+    #[test]
+    fn compact() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for _i in 0..MAX_MEM_COUNT*MAX_FILE_COUNT + 1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+    }
+
+    #[test]
+    fn compact2() {
+        let db_dir = gen_dir();
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+            for _i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+                let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+                let key = format!("KEY_{}", rnd).as_bytes().to_vec();
+                let value = rnd.as_bytes().to_vec();
+
+                kvs.put(key, value);
+            }
+
+            assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+
+            kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+            assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+        }
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for _i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        assert_eq!(kvs.count_estimate(), ((MAX_MEM_COUNT*MAX_FILE_COUNT+1)*2) as u64);
+
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        assert_eq!(kvs.count_estimate(), ((MAX_MEM_COUNT*MAX_FILE_COUNT+1)*2) as u64);
+    }
+
+    #[test]
+    fn delete() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        let key = "KEY".as_bytes();
+        let value = "VALUE".as_bytes();
+
+        kvs.put(key.to_vec(), value.to_vec());
+
+        assert_eq!(kvs.count_estimate(), 1);
+
+        kvs.delete(&key.to_vec());
+
+        // should still be only 1 record
+        assert_eq!(kvs.count_estimate(), 1);
+
+        assert!(kvs.get(&key.to_vec()).is_none(), "Found key after deleting it!");
+
+        kvs.core.flush(false);
+
+        // should still be only 1 record
+        assert_eq!(kvs.count_estimate(), 1);
+
+        assert!(kvs.get(&key.to_vec()).is_none(), "Found key after deleting it!");
+    }
+
+    #[test]
+    fn put_close_open_get() {
+        let db_dir = gen_dir();
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+            // fill half the mem_table, so we're sure we don't flush
+            for i in 0..MAX_MEM_COUNT / 2 {
+                let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+                let key = format!("KEY_{}", i).as_bytes().to_vec();
+                let value = rnd.as_bytes().to_vec();
+
+                kvs.put(key, value);
+            }
+        }
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT / 2 {
+                let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+                assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
+            }
+        }
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT / 2 {
+                let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+                assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn put_compact_get() {
+        let db_dir = gen_dir();
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+            assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
+        }
+    }
+
+    #[test]
+    fn put_compact_update_get() {
+        let db_dir = gen_dir();
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        // compact would have happened here
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+            let value = format!("VALUE_{}", i).as_bytes().to_vec();
+
+            kvs.put(key, value); // update our keys
+        }
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+            let ret = kvs.get(&key);
+            assert!(ret.is_some(), "Couldn't find key: {}", i);
+            assert_eq!(ret.unwrap(), format!("VALUE_{}", i).as_bytes().to_vec(), "Didn't get update for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn put_compact_delete_get() {
+        let db_dir = gen_dir();
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+            let value = rnd.as_bytes().to_vec();
+
+            kvs.put(key, value);
+        }
+
+        // compact would have happened here
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+            kvs.delete(&key); // delete our key
+        }
+
+        // compact would happen here
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
+            let key = format!("KEY_{}", i).as_bytes().to_vec();
+
+            let ret = kvs.get(&key);
+            assert!(ret.is_none(), "Found deleted key: {}", i);
+        }
+    }
+
+    #[test]
+    fn delete_range() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.delete_range(&"KEY_3".as_bytes().to_vec(), &"KEY_7".as_bytes().to_vec());
+
+        for i in 0..10 {
+            let ret = kvs.get(&format!("KEY_{}", i).as_bytes().to_vec());
+
+            assert_eq!(i < 3 || i >= 7, ret.is_some(), "Wrong result for key: {}", i);
+        }
+
+        // puts after the range delete are visible
+        kvs.put("KEY_5".as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+
+        assert_eq!(kvs.get(&"KEY_5".as_bytes().to_vec()).unwrap(), "NEW_VALUE".as_bytes().to_vec());
+
+        kvs.core.flush(false);
+
+        assert!(kvs.get(&"KEY_4".as_bytes().to_vec()).is_none());
+        assert!(kvs.get(&"KEY_5".as_bytes().to_vec()).is_some());
+    }
+
+    #[test]
+    fn delete_range_close_open() {
+        let db_dir = gen_dir();
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+            for i in 0..10 {
+                kvs.put(format!("KEY_{}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            kvs.delete_range(&"KEY_3".as_bytes().to_vec(), &"KEY_7".as_bytes().to_vec());
+        }
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..10 {
+            let ret = kvs.get(&format!("KEY_{}", i).as_bytes().to_vec());
+
+            assert_eq!(i < 3 || i >= 7, ret.is_some(), "Wrong result for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn delete_range_flush_open() {
+        let db_dir = gen_dir();
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+            let kvs = options.create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            // compact would have happened here, so the range delete must cover the older SSTables
+            kvs.delete_range(&format!("KEY_{:05}", 10).as_bytes().to_vec(), &format!("KEY_{:05}", 20).as_bytes().to_vec());
+            kvs.core.flush(false);
+
+            assert_eq!(1, kvs.core.state.read().unwrap().cur_sstable.range_tombstones().len());
+            assert!(kvs.get(&format!("KEY_{:05}", 15).as_bytes().to_vec()).is_none());
+        }
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..30 {
+            let ret = kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec());
+
+            assert_eq!(i < 10 || i >= 20, ret.is_some(), "Wrong result for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn delete_range_compact() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        // compact would have happened here
+        kvs.wait_for_flushes();
+
+        assert_eq!(kvs.core.state.read().unwrap().sstables.len(), MAX_FILE_COUNT);
+
+        // delete everything but the first and last tables
+        let start = format!("KEY_{:05}", MAX_MEM_COUNT).as_bytes().to_vec();
+        let end = format!("KEY_{:05}", MAX_MEM_COUNT * (MAX_FILE_COUNT - 1)).as_bytes().to_vec();
+
+        kvs.delete_range(&start, &end);
+
+        // write enough to cause another compaction
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("OTHER_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.wait_for_flushes();
+
+        assert!(kvs.core.state.read().unwrap().range_tombstones().is_empty());
+        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT * 2 + MAX_MEM_COUNT * MAX_FILE_COUNT) as u64);
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let ret = kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec());
+
+            assert_eq!(i < MAX_MEM_COUNT || i >= MAX_MEM_COUNT * (MAX_FILE_COUNT - 1), ret.is_some(), "Wrong result for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn iter_range() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        // spread the keys over the SSTables, the current SSTable, and the mem_table
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + MAX_MEM_COUNT + MAX_MEM_COUNT / 2 {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.put(format!("KEY_{:05}", 10).as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+        kvs.delete(&format!("KEY_{:05}", 20).as_bytes().to_vec());
+
+        let records = kvs.iter().collect::<Vec<_>>();
+
+        assert_eq!(MAX_MEM_COUNT * MAX_FILE_COUNT + MAX_MEM_COUNT + MAX_MEM_COUNT / 2 - 1, records.len());
+        assert!(records.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(records.contains(&(format!("KEY_{:05}", 10).as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec())));
+
+        let keys = kvs.range(&format!("KEY_{:05}", 15).as_bytes().to_vec(), &format!("KEY_{:05}", 25).as_bytes().to_vec()).map(|(k, _)| k).collect::<Vec<_>>();
+        let expected = (15..25).filter(|i| *i != 20).map(|i| format!("KEY_{:05}", i).as_bytes().to_vec()).collect::<Vec<_>>();
+
+        assert_eq!(expected, keys);
+    }
+
+    #[test]
+    fn raw_iter() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+        let key = |i: usize| format!("KEY_{:05}", i).into_bytes();
+
+        for i in 0..30 {
+            if i < 10 {
+                kvs.put_with_ttl(key(i), b"VALUE".to_vec(), Duration::from_secs(1));
+            } else {
+                kvs.put(key(i), b"VALUE".to_vec());
+            }
+        }
+
+        // the deletes are in the current SSTable, and the mem_table
+        advance_clock(1);
+        kvs.delete(&key(10));
+        kvs.delete_range(&key(20), &key(25));
+        kvs.flush(true);
+        kvs.delete(&key(11));
+        advance_clock(1_000);
+
+        let entries = kvs.raw_iter().collect::<Vec<_>>();
+
+        assert_eq!((0..30).filter(|i| *i < 20 || *i >= 25).map(key).collect::<Vec<_>>(), entries.iter().map(|e| e.key.clone()).collect::<Vec<_>>());
+
+        // the expired records are there, with their expiry
+        assert!(entries[..10].iter().all(|e| e.expiry == Some(1_001_000) && e.created == 1_000_000 && !e.tombstone));
+        assert_eq!(RawEntry { key: key(10), value: None, created: 1_000_001, expiry: None, tombstone: true }, entries[10]);
+        assert!(entries[11].tombstone);
+        assert_eq!(RawEntry { key: key(12), value: Some(b"VALUE".to_vec()), created: 1_000_000, expiry: None, tombstone: false }, entries[12]);
+        assert_eq!(13, kvs.iter().count());
+
+        let keys = kvs.raw_range_with_options(&key(9), &key(12), &ReadOptions::new()).map(|e| (e.key, e.tombstone)).collect::<Vec<_>>();
+
+        assert_eq!(vec![(key(9), false), (key(10), true), (key(11), true)], keys);
+
+        clear_clock();
+    }
+
+    #[test]
+    fn iter_pins_files() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.wait_for_flushes();
+
+        let old_paths = kvs.core.state.read().unwrap().sstables.iter().map(|t| t.file_path()).collect::<Vec<_>>();
+        let mut it = kvs.iter();
+
+        assert!(it.next().is_some());
+
+        // write enough to cause another compaction
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("OTHER_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        assert!(old_paths.iter().all(|p| p.exists()));
+        assert_eq!(MAX_MEM_COUNT * MAX_FILE_COUNT - 1, it.count()); // count consumes, and drops, the iterator
+
+        kvs.core.flush(false);
+
+        assert!(old_paths.iter().all(|p| !p.exists()));
+    }
+
+    #[test]
+    fn snapshot_get_range() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        let snapshot = kvs.snapshot();
+        let mut read_options = ReadOptions::new();
+        read_options.snapshot(&snapshot).fill_cache(false);
+
+        // overwrite and delete keys in the SSTables and the mem_table, then flush
+        kvs.put(format!("KEY_{:05}", 10).as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+        kvs.delete(&format!("KEY_{:05}", 220).as_bytes().to_vec());
+        kvs.put(format!("KEY_{:05}", 1000).as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+        kvs.delete_range(&format!("KEY_{:05}", 100).as_bytes().to_vec(), &format!("KEY_{:05}", 110).as_bytes().to_vec());
+        kvs.core.flush(false);
+
+        let key = format!("KEY_{:05}", 10).as_bytes().to_vec();
+
+        assert_eq!("NEW_VALUE".as_bytes().to_vec(), kvs.get(&key).unwrap());
+        assert_eq!("VALUE_10".as_bytes().to_vec(), kvs.get_with_options(&key, &read_options).unwrap());
+        assert!(kvs.get(&format!("KEY_{:05}", 220).as_bytes().to_vec()).is_none());
+        assert!(kvs.get_with_options(&format!("KEY_{:05}", 220).as_bytes().to_vec(), &read_options).is_some());
+        assert!(kvs.get_with_options(&format!("KEY_{:05}", 105).as_bytes().to_vec(), &read_options).is_some());
+        assert!(kvs.get_with_options(&format!("KEY_{:05}", 1000).as_bytes().to_vec(), &read_options).is_none());
+        assert_eq!(MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2, kvs.range_with_options(&b"KEY".to_vec(), &b"KEZ".to_vec(), &read_options).count());
+
+        let start = format!("KEY_{:05}", 100).as_bytes().to_vec();
+        let end = format!("KEY_{:05}", 120).as_bytes().to_vec();
+
+        assert_eq!(10, kvs.range(&start, &end).count());
+        assert_eq!(20, kvs.range_with_options(&start, &end, &read_options).count());
+    }
+
+    #[test]
+    fn write_batch_options() {
+        let db_dir = gen_dir();
+
+        {
+            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+            let mut batch = WriteBatch::new();
+
+            batch.put("KEY_1".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec())
+                 .put("KEY_2".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec())
+                 .delete(&"KEY_1".as_bytes().to_vec());
+
+            assert_eq!(3, batch.len());
+
+            let mut write_options = WriteOptions::new();
+            write_options.sync(true);
+
+            kvs.write(batch, &write_options);
+
+            write_options.disable_wal(true);
+
+            kvs.put_with_options("KEY_3".as_bytes().to_vec(), "VALUE_3".as_bytes().to_vec(), &write_options);
+
+            assert!(kvs.get(&"KEY_1".as_bytes().to_vec()).is_none());
+            assert!(kvs.get(&"KEY_2".as_bytes().to_vec()).is_some());
+            assert!(kvs.get(&"KEY_3".as_bytes().to_vec()).is_some());
+            assert_eq!(3, kvs.core.wal.lock().unwrap().file.record_count()); // KEY_3 was never written to the WAL
+        }
+
+        // the drop flushed everything, WAL or not
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        assert!(kvs.get(&"KEY_1".as_bytes().to_vec()).is_none());
+        assert!(kvs.get(&"KEY_2".as_bytes().to_vec()).is_some());
+        assert!(kvs.get(&"KEY_3".as_bytes().to_vec()).is_some());
+    }
+
+    #[test]
+    fn write_batch_torn() {
+        let db_dir = gen_dir();
+        let wal_path = db_dir.join("torn.wal");
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT);
+            let kvs = options.create().unwrap();
+            let mut batch = WriteBatch::new();
+
+            kvs.put("KEY_0".as_bytes().to_vec(), "VALUE_0".as_bytes().to_vec());
+
+            for i in 1..4 {
+                batch.put(format!("KEY_{}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            kvs.write(batch, WriteOptions::new().sync(true));
+
+            // a copy of the WAL, before the drop flushes it
+            fs::copy(kvs.core.lock_manifest().wal_path(), &wal_path).unwrap();
+        }
+
+        let mut wal_file = RecordFile::new(&wal_path, WAL_HEADER, 4096, 1).unwrap();
+        let offsets = wal_file.iter_with_offsets().unwrap().map(|rec| rec.unwrap().0).collect::<Vec<_>>();
+
+        assert_eq!(4, offsets.len());
+
+        // the whole batch is replayed, without the fields that tie it together
+        let mem_table = WalMemTable::new(MemTableKind::SkipList, 1);
+
+        replay_wal(&mut wal_file, &mem_table).unwrap();
+
+        assert_eq!(4, mem_table.len());
+        assert!(mem_table.iter().all(|rec| rec.field(BATCH_TAG).is_none()));
+
+        // a crash part way through the batch leaves it out, and the records before it in
+        wal_file.truncate_to(offsets[3]).unwrap();
+
+        let mem_table = WalMemTable::new(MemTableKind::SkipList, 1);
+
+        replay_wal(&mut wal_file, &mem_table).unwrap();
+
+        assert_eq!(vec![b"KEY_0".to_vec()], mem_table.iter().map(|rec| rec.key().to_vec()).collect::<Vec<_>>());
+        assert_eq!(1, wal_file.record_count()); // the rest of the batch is truncated, so appends follow KEY_0
+    }
+
+    #[derive(Default)]
+    struct CountingListener {
+        flushes: Mutex<usize>,
+        compactions: Mutex<Vec<(u64, u64)>>,
+        stalls: Mutex<Vec<WriteStall>>,
+        stalled: (Mutex<bool>, Condvar) // holds up the first flush until a write stalls
+    }
+
+    impl EventListener for CountingListener {
+        fn on_flush_completed(&self, info: &FlushInfo) {
+            assert!(info.file_path.exists());
+            *self.flushes.lock().unwrap() += 1;
+
+            let mut stalled = self.stalled.0.lock().unwrap();
+
+            while !*stalled {
+                stalled = self.stalled.1.wait(stalled).unwrap();
+            }
+        }
+
+        fn on_compaction_completed(&self, stats: &CompactionStats) {
+            self.compactions.lock().unwrap().push( (stats.input_records, stats.output_records) );
+        }
+
+        fn on_write_stall(&self, stall: WriteStall) {
+            self.stalls.lock().unwrap().push(stall);
+
+            *self.stalled.0.lock().unwrap() = true;
+            self.stalled.1.notify_all();
+        }
+    }
+
+    #[test]
+    fn event_listener() {
+        let db_dir = gen_dir();
+        let listener = Arc::new(CountingListener::default());
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).max_immutables(1).event_listener(listener.clone());
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        // the first flush is held up, so the second mem_table waits for it, and the third stalls
+        assert_eq!(Some(&WriteStall::Flush), listener.stalls.lock().unwrap().first());
+
+        kvs.wait_for_flushes();
+
+        // every full mem_table is flushed, then the current SSTable has enough records to compact
+        assert_eq!(MAX_FILE_COUNT, *listener.flushes.lock().unwrap());
+        assert_eq!(vec![( (MAX_MEM_COUNT * MAX_FILE_COUNT) as u64, (MAX_MEM_COUNT * MAX_FILE_COUNT) as u64 )], *listener.compactions.lock().unwrap());
+    }
+
+    #[test]
+    fn read_during_flush() {
+        let db_dir = gen_dir();
+        let listener = Arc::new(CountingListener::default());
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).event_listener(listener.clone());
+        let kvs = options.create().unwrap();
+
+        // the first flush is held up, so the second mem_table stays immutable
+        for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.put(format!("KEY_{:05}", MAX_MEM_COUNT + 1).as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+
+        // the first flush may still be running; it's held up once it's done
+        while *listener.flushes.lock().unwrap() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(1, kvs.core.state.read().unwrap().immutables.len());
+        assert!(listener.stalls.lock().unwrap().is_empty());
+
+        for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+            assert!(kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec()).is_some(), "Couldn't find key: {}", i);
+        }
+
+        // the active mem_table is read before the immutable one
+        assert_eq!("NEW_VALUE".as_bytes().to_vec(), kvs.get(&format!("KEY_{:05}", MAX_MEM_COUNT + 1).as_bytes().to_vec()).unwrap());
+
+        *listener.stalled.0.lock().unwrap() = true;
+        listener.stalled.1.notify_all();
+
+        kvs.wait_for_flushes();
+
+        assert!(kvs.core.state.read().unwrap().immutables.is_empty());
+        assert_eq!(2, *listener.flushes.lock().unwrap());
+        assert_eq!("NEW_VALUE".as_bytes().to_vec(), kvs.get(&format!("KEY_{:05}", MAX_MEM_COUNT + 1).as_bytes().to_vec()).unwrap());
+    }
+
+    #[test]
+    fn open_saved_options() {
+        let db_dir = gen_dir();
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+            let kvs = options.create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT / 2 {
+                kvs.put(format!("KEY_{}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+        }
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert_eq!(MAX_MEM_COUNT, kvs.core.options.max_mem_count);
+        assert_eq!(MAX_FILE_COUNT, kvs.core.options.file_count);
+
+        for i in 0..MAX_MEM_COUNT / 2 {
+            assert!(kvs.get(&format!("KEY_{}", i).as_bytes().to_vec()).is_some(), "Couldn't find key: {}", i);
+        }
+    }
+
+    #[test]
+    fn mem_table_kinds() {
+        for &kind in [MemTableKind::SkipList, MemTableKind::BTree, MemTableKind::Hash].iter() {
+            let db_dir = gen_dir();
+
+            {
+                let mut options = KVSOptions::new(&db_dir);
+                options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).mem_table(kind);
+                let kvs = options.create().unwrap();
+
+                // written in reverse, so the flushes must sort the records
+                for i in (0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2).rev() {
+                    kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+                }
+
+                kvs.delete(&format!("KEY_{:05}", 10).as_bytes().to_vec());
+
+                let keys = kvs.range(&format!("KEY_{:05}", 5).as_bytes().to_vec(), &format!("KEY_{:05}", 15).as_bytes().to_vec()).map(|(k, _)| k).collect::<Vec<_>>();
+                let expected = (5..15).filter(|i| *i != 10).map(|i| format!("KEY_{:05}", i).as_bytes().to_vec()).collect::<Vec<_>>();
+
+                assert_eq!(expected, keys, "{:?}", kind);
+            }
+
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            assert_eq!(kind, kvs.core.options.mem_table);
+            assert!(kvs.get(&format!("KEY_{:05}", 10).as_bytes().to_vec()).is_none());
+
+            for i in (0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2).filter(|i| *i != 10) {
+                assert!(kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec()).is_some(), "Couldn't find key {} with: {:?}", i, kind);
+            }
+        }
+    }
+
+    #[test]
+    fn from_toml() {
+        let db_dir = gen_dir();
+        let path = db_dir.join("kvs.toml");
+
+        File::create(&path).unwrap().write_all(format!("db_dir = {:?}\nmem_count = 500\nsync_writes = true\nmem_table = \"hash\"\ncache_policy = \"lru\"\n", db_dir).as_bytes()).unwrap();
+
+        let options = KVSOptions::from_toml(&path).unwrap();
+
+        assert_eq!(*db_dir, options.db_dir);
+        assert_eq!(500, options.max_mem_count);
+        assert!(options.sync_writes);
+        assert_eq!(MemTableKind::Hash, options.mem_table);
+        assert_eq!(CachePolicyKind::Lru, options.cache_policy);
+
+        // nonsensical and unknown options are errors
+        File::create(&path).unwrap().write_all(format!("db_dir = {:?}\nmem_count = 1\n", db_dir).as_bytes()).unwrap();
+
+        assert!(KVSOptions::from_toml(&path).is_err());
+
+        File::create(&path).unwrap().write_all(format!("db_dir = {:?}\nmem_size = 500\n", db_dir).as_bytes()).unwrap();
+
+        assert!(KVSOptions::from_toml(&path).is_err());
+    }
+
+    #[test]
+    fn crash_during_flush_and_compaction() {
+        let points = [
+            (CrashPoint::FlushTableWritten, MAX_MEM_COUNT * 3),
+            (CrashPoint::ManifestWritten, MAX_MEM_COUNT * 3),
+            (CrashPoint::ManifestSaved, MAX_MEM_COUNT * 3),
+            (CrashPoint::CompactionTablesWritten, MAX_MEM_COUNT * MAX_FILE_COUNT)
+        ];
+
+        for &(point, crash_count) in points.iter() {
+            let db_dir = gen_dir();
+            let storage = SimulatedStorage::new(&db_dir).unwrap();
+
+            storage.partial_writes(crash_count as u64);
+
+            let crashed = storage.run(|| {
+                let mut options = KVSOptions::new(&db_dir);
+                options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+                let kvs = options.create().unwrap();
+
+                for i in 0..crash_count {
+                    // arm just before the last write, which fills the mem_table, once the earlier ones are flushed
+                    if i == crash_count - 1 {
+                        kvs.wait_for_flushes();
+                        storage.crash_at(point);
+                    }
+
+                    kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+                }
+            });
+
+            assert!(crashed, "Didn't crash at: {:?}", point);
+
+            storage.crash().unwrap();
+
+            // everything flushed before the crash is still there
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            for i in 0..crash_count - MAX_MEM_COUNT {
+                assert!(kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec()).is_some(), "Lost key {} crashing at: {:?}", i, point);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        let db_dir = gen_dir();
+        let thread_count = 4;
+        let per_thread = MAX_MEM_COUNT * MAX_FILE_COUNT / 2;
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+            let kvs = Arc::new(options.create().unwrap());
+
+            // enough writes for flushes and a compaction while the threads are writing
+            let threads = (0..thread_count).map(|t| {
+                let kvs = kvs.clone();
+
+                thread::spawn(move || {
+                    let mut write_options = WriteOptions::new();
+                    write_options.sync(t % 2 == 0);
+
+                    for i in 0..per_thread {
+                        let key = format!("KEY_{}_{:05}", t, i).as_bytes().to_vec();
+
+                        kvs.put_with_options(key.clone(), format!("VALUE_{}", i).as_bytes().to_vec(), &write_options);
+
+                        assert!(kvs.get(&key).is_some(), "Couldn't read back key: {}", i);
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            assert_eq!((thread_count * per_thread) as u64, kvs.count_estimate());
+            assert_eq!(thread_count * per_thread, kvs.iter().count());
+        }
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        for t in 0..thread_count {
+            for i in 0..per_thread {
+                assert!(kvs.get(&format!("KEY_{}_{:05}", t, i).as_bytes().to_vec()).is_some(), "Lost key {} of thread {}", i, t);
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_commit_conflict() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        kvs.put("KEY_1".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+        kvs.put("KEY_2".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec());
+
+        let mut txn = kvs.begin();
+
+        assert_eq!(Some("VALUE_1".as_bytes().to_vec()), txn.get(&"KEY_1".as_bytes().to_vec()));
+
+        txn.put("KEY_1".as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+        txn.delete(&"KEY_2".as_bytes().to_vec());
+
+        // the transaction sees its own writes, nobody else does until the commit
+        assert_eq!(Some("NEW_VALUE".as_bytes().to_vec()), txn.get(&"KEY_1".as_bytes().to_vec()));
+        assert!(txn.get(&"KEY_2".as_bytes().to_vec()).is_none());
+        assert_eq!(Some("VALUE_1".as_bytes().to_vec()), kvs.get(&"KEY_1".as_bytes().to_vec()));
+
+        assert!(txn.commit().is_ok());
+        assert_eq!(Some("NEW_VALUE".as_bytes().to_vec()), kvs.get(&"KEY_1".as_bytes().to_vec()));
+        assert!(kvs.get(&"KEY_2".as_bytes().to_vec()).is_none());
+
+        // a key read by the transaction is changed before it commits
+        let mut txn = kvs.begin();
+
+        txn.get(&"KEY_1".as_bytes().to_vec());
+        txn.put("KEY_3".as_bytes().to_vec(), "VALUE_3".as_bytes().to_vec());
+
+        kvs.put("KEY_1".as_bytes().to_vec(), "OTHER_VALUE".as_bytes().to_vec());
+
+        assert_eq!(Err(Conflict { key: "KEY_1".as_bytes().to_vec() }), txn.commit());
+        assert!(kvs.get(&"KEY_3".as_bytes().to_vec()).is_none());
+
+        // a key written by the transaction is deleted before it commits
+        let mut txn = kvs.begin();
+
+        txn.put("KEY_1".as_bytes().to_vec(), "TXN_VALUE".as_bytes().to_vec());
+
+        kvs.delete(&"KEY_1".as_bytes().to_vec());
+
+        assert!(txn.commit().is_err());
+        assert!(kvs.get(&"KEY_1".as_bytes().to_vec()).is_none());
+    }
+
+    #[test]
+    fn transaction_concurrent_increments() {
+        let db_dir = gen_dir();
+        let thread_count = 4;
+        let per_thread = 50;
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = Arc::new(options.create().unwrap());
+        let key = "COUNTER".as_bytes().to_vec();
+
+        kvs.put(key.clone(), 0u64.to_string().into_bytes());
+
+        // every thread adds to the counter, retrying when another thread got there first
+        let threads = (0..thread_count).map(|_| {
+            let (kvs, key) = (kvs.clone(), key.clone());
+
+            thread::spawn(move || {
+                for _ in 0..per_thread {
+                    loop {
+                        let mut txn = kvs.begin();
+                        let count = String::from_utf8(txn.get(&key).unwrap()).unwrap().parse::<u64>().unwrap();
+
+                        txn.put(key.clone(), (count + 1).to_string().into_bytes());
+
+                        if txn.commit().is_ok() {
+                            break;
+                        }
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!((thread_count * per_thread).to_string().into_bytes(), kvs.get(&key).unwrap());
+    }
+
+    #[test]
+    fn pessimistic_transactions() {
+        let db_dir = gen_dir();
+        let thread_count = 4;
+        let per_thread = 50;
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = Arc::new(options.create().unwrap());
+        let key = "COUNTER".as_bytes().to_vec();
+
+        kvs.put(key.clone(), 0u64.to_string().into_bytes());
+
+        // the threads take turns on the counter's lock, so no commit fails
+        let threads = (0..thread_count).map(|_| {
+            let (kvs, key) = (kvs.clone(), key.clone());
+
+            thread::spawn(move || {
+                let mut txn_options = TransactionOptions::new();
+                txn_options.pessimistic(true).lock_timeout(Duration::from_secs(60));
+
+                for _ in 0..per_thread {
+                    let mut txn = kvs.begin_with_options(&txn_options);
+                    let count = String::from_utf8(txn.get(&key).unwrap()).unwrap().parse::<u64>().unwrap();
+
+                    txn.put(key.clone(), (count + 1).to_string().into_bytes());
+                    txn.commit().unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!((thread_count * per_thread).to_string().into_bytes(), kvs.get(&key).unwrap());
+
+        // a transaction waiting on a lock held by another gives up after the timeout
+        let mut txn_options = TransactionOptions::new();
+        txn_options.pessimistic(true).lock_timeout(Duration::from_millis(10));
+
+        let mut first = kvs.begin_with_options(&txn_options);
+        let mut second = kvs.begin_with_options(&txn_options);
+
+        first.put(key.clone(), "FIRST".as_bytes().to_vec());
+        second.put(key.clone(), "SECOND".as_bytes().to_vec());
+
+        // and takes no more locks once one failed
+        let other = "OTHER".as_bytes().to_vec();
+        let mut fourth = kvs.begin_with_options(&txn_options);
+
+        second.put(other.clone(), "SECOND".as_bytes().to_vec());
+        fourth.put(other.clone(), "FOURTH".as_bytes().to_vec());
+
+        assert!(fourth.commit().is_ok());
+        assert_eq!(Err(Conflict { key: key.clone() }), second.commit());
+        assert!(first.commit().is_ok());
+
+        // the locks are released by the commit
+        let mut third = kvs.begin_with_options(&txn_options);
+
+        assert_eq!(Some("FIRST".as_bytes().to_vec()), third.get(&key));
+        assert!(third.commit().is_ok());
+    }
+
+    #[test]
+    fn change_stream() {
+        let db_dir = gen_dir();
+
+        set_clock(1_000);
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).wal_retention(2);
+            let kvs = options.create().unwrap();
+
+            kvs.put("KEY_1".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+            kvs.delete(&"KEY_1".as_bytes().to_vec());
+            kvs.delete_range(&"KEY_2".as_bytes().to_vec(), &"KEY_5".as_bytes().to_vec());
+
+            let mut stream = kvs.subscribe(0);
+
+            assert_eq!(Change { seq: 0, op: ChangeOp::Put, key: "KEY_1".as_bytes().to_vec(), value: Some("VALUE_1".as_bytes().to_vec()), ts: 1_000 }, stream.next().unwrap().unwrap());
+            assert_eq!(Change { seq: 1, op: ChangeOp::Delete, key: "KEY_1".as_bytes().to_vec(), value: None, ts: 1_000 }, stream.next().unwrap().unwrap());
+            assert_eq!(Change { seq: 2, op: ChangeOp::DeleteRange, key: "KEY_2".as_bytes().to_vec(), value: Some("KEY_5".as_bytes().to_vec()), ts: 1_001 }, stream.next().unwrap().unwrap());
+            assert!(stream.next().is_none());
+
+            // writes without the WAL have no sequence number
+            let mut write_options = WriteOptions::new();
+            write_options.disable_wal(true);
+
+            kvs.put_with_options("KEY_2".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec(), &write_options);
+
+            assert_eq!(3, kvs.next_seq());
+
+            // the stream picks up the changes written since it caught up, across flushes
+            for i in 0..MAX_MEM_COUNT * 2 {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            kvs.wait_for_flushes();
+
+            let changes = stream.by_ref().map(|change| change.unwrap()).collect::<Vec<_>>();
+
+            assert_eq!(MAX_MEM_COUNT * 2, changes.len());
+            assert_eq!((3..3 + MAX_MEM_COUNT as u64 * 2).collect::<Vec<_>>(), changes.iter().map(|c| c.seq).collect::<Vec<_>>());
+            assert_eq!(format!("KEY_{:05}", MAX_MEM_COUNT * 2 - 1).as_bytes().to_vec(), changes.last().unwrap().key);
+
+            // starting from the middle of a flushed WAL
+            let change = kvs.subscribe(50).next().unwrap().unwrap();
+
+            assert_eq!(50, change.seq);
+            assert_eq!(format!("KEY_{:05}", 47).as_bytes().to_vec(), change.key);
+        }
+
+        // the sequence numbers carry on after the store is opened again
+        let kvs = KVS::open(&db_dir).unwrap();
+        let next_seq = kvs.next_seq();
+
+        assert_eq!(3 + MAX_MEM_COUNT as u64 * 2, next_seq);
+
+        kvs.put("KEY_1".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+
+        assert_eq!(next_seq, kvs.subscribe(next_seq).next().unwrap().unwrap().seq);
+
+        // only the last 2 flushed WALs are kept
+        for i in 0..MAX_MEM_COUNT * 2 {
+            kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.wait_for_flushes();
+
+        assert_eq!(ErrorKind::NotFound, kvs.subscribe(0).next().unwrap().unwrap_err().kind());
+
+        clear_clock();
+    }
+
+    #[test]
+    fn watch_prefix() {
+        let db_dir = gen_dir();
+
+        set_clock(1_000);
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        kvs.put("config/old".as_bytes().to_vec(), "VALUE_0".as_bytes().to_vec());
+
+        let config = kvs.watch(&"config/".as_bytes().to_vec());
+        let other = kvs.watch(&"other/".as_bytes().to_vec());
+
+        kvs.put("config/a".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+        kvs.put("users/a".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec());
+        kvs.delete(&"config/a".as_bytes().to_vec());
+        kvs.delete_range(&"a".as_bytes().to_vec(), &"config/b".as_bytes().to_vec()); // covers config/a
+        kvs.delete_range(&"config0".as_bytes().to_vec(), &"d".as_bytes().to_vec());  // starts past config/
+
+        let changes = config.try_iter().collect::<Vec<_>>();
+
+        assert_eq!(vec![1, 3, 4], changes.iter().map(|c| c.seq).collect::<Vec<_>>());
+        assert_eq!(Change { seq: 1, op: ChangeOp::Put, key: "config/a".as_bytes().to_vec(), value: Some("VALUE_1".as_bytes().to_vec()), ts: 1_000 }, changes[0]);
+        assert_eq!(ChangeOp::Delete, changes[1].op);
+        assert_eq!(ChangeOp::DeleteRange, changes[2].op);
+        assert!(other.try_recv().is_err());
+
+        // a dropped receiver ends the watch
+        drop(other);
+        kvs.put("other/a".as_bytes().to_vec(), "VALUE_3".as_bytes().to_vec());
+
+        assert_eq!(1, kvs.core.wal.lock().unwrap().watchers.len());
+
+        clear_clock();
+    }
+
+    #[test]
+    fn apply_changes() {
+        let db_dirs = vec![gen_dir(), gen_dir()];
+        let stores = db_dirs.iter().map(|db_dir| {
+            let mut options = KVSOptions::new(db_dir);
+            options.clock(Arc::new(HybridLogicalClock::with_physical(Arc::new(ManualClock::new(1_000)))));
+
+            options.create().unwrap()
+        }).collect::<Vec<_>>();
+        let key = |k: &str| k.as_bytes().to_vec();
+        let change = |seq: u64, op: ChangeOp, k: &str, value: Option<&str>, ts: u64| Change { seq: seq, op: op, key: key(k), value: value.map(key), ts: ts };
+
+        // both write KEY_1 at the same time, and the other store's KEY_2 is older
+        stores[0].put(key("KEY_1"), key("VALUE_A"));
+        stores[0].put(key("KEY_2"), key("VALUE_A"));
+        stores[1].put(key("KEY_1"), key("VALUE_B"));
+
+        let changes = stores.iter().map(|kvs| kvs.subscribe(0).map(|c| c.unwrap()).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        assert_eq!(vec![1_000, 1_001], changes[0].iter().map(|c| c.ts).collect::<Vec<_>>());
+        assert_eq!(1, stores[0].apply_changes(&changes[1]).unwrap());
+        assert_eq!(0, stores[1].apply_changes(&changes[0][..1]).unwrap());
+        assert_eq!(1, stores[1].apply_changes(&changes[0][1..]).unwrap());
+
+        // the larger value wins the tie, in both stores; applying changes again writes nothing
+        for kvs in stores.iter() {
+            assert_eq!(Some(key("VALUE_B")), kvs.get(&key("KEY_1")));
+            assert_eq!(Some(key("VALUE_A")), kvs.get(&key("KEY_2")));
+            assert_eq!(0, kvs.apply_changes(&changes[1]).unwrap());
+        }
+
+        // an older change loses, a newer one wins, and the clock moves past it
+        assert_eq!(0, stores[0].apply_changes(&[change(0, ChangeOp::Delete, "KEY_2", None, 999)]).unwrap());
+        assert_eq!(1, stores[0].apply_changes(&[change(0, ChangeOp::Delete, "KEY_2", None, 5_000)]).unwrap());
+        assert_eq!(None, stores[0].get(&key("KEY_2")));
+
+        stores[0].put(key("KEY_3"), key("VALUE_A"));
+
+        assert_eq!(5_001, stores[0].subscribe(stores[0].next_seq() - 1).next().unwrap().unwrap().ts);
+
+        // a range delete only deletes what's older
+        stores[0].apply_changes(&[change(0, ChangeOp::Put, "KEY_4", Some("VALUE_B"), 7_000), change(0, ChangeOp::DeleteRange, "KEY_0", Some("KEY_9"), 6_000)]).unwrap();
+
+        assert_eq!(vec![key("KEY_4")], stores[0].iter().map(|(k, _)| k).collect::<Vec<_>>());
+
+        // a range delete without an end is rejected, along with the changes with it
+        let invalid = stores[0].apply_changes(&[change(0, ChangeOp::Put, "KEY_5", Some("VALUE_B"), 8_000), change(0, ChangeOp::DeleteRange, "KEY_0", None, 8_000)]);
+
+        assert_eq!(ErrorKind::InvalidInput, invalid.unwrap_err().kind());
+        assert_eq!(None, stores[0].get(&key("KEY_5")));
+    }
+
+    #[test]
+    fn conflict_resolver() {
+        /// Keeps both values of a conflict, under the key with the replica's name added
+        struct Siblings;
+
+        impl ConflictResolver for Siblings {
+            fn resolve(&self, conflict: &ReplicaConflict) -> Resolution {
+                let sibling = |name: &str| [&conflict.key[..], name.as_bytes()].concat();
+
+                Resolution::Write(vec![(conflict.key.clone(), None), (sibling("/local"), conflict.local.clone()), (sibling("/remote"), conflict.remote.clone())])
+            }
+        }
+
+        let db_dirs = vec![gen_dir(), gen_dir()];
+        let stores = db_dirs.iter().map(|db_dir| {
+            let mut options = KVSOptions::new(db_dir);
+            options.clock(Arc::new(HybridLogicalClock::with_physical(Arc::new(ManualClock::new(1_000))))).conflict_resolver(Arc::new(Siblings));
+
+            options.create().unwrap()
+        }).collect::<Vec<_>>();
+        let key = |k: &str| k.as_bytes().to_vec();
+
+        stores[0].put(key("KEY"), key("VALUE_A"));
+        stores[1].put(key("KEY"), key("VALUE_B"));
+
+        let changes = stores.iter().map(|kvs| kvs.subscribe(0).map(|c| c.unwrap()).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+        // the first store hadn't seen a version newer than the change; the second had
+        assert_eq!(1, stores[0].apply_changes(&changes[1]).unwrap());
+        assert_eq!(1, stores[1].apply_changes(&changes[0]).unwrap());
+
+        let siblings = vec![(key("KEY/local"), key("VALUE_B")), (key("KEY/remote"), key("VALUE_A"))];
+
+        assert_eq!(siblings, stores[1].iter().collect::<Vec<_>>());
+
+        // the resolution is newer than both, so it's taken as it is
+        let resolution = stores[1].subscribe(1).map(|c| c.unwrap()).collect::<Vec<_>>();
+
+        assert!(resolution.iter().all(|c| c.ts > 1_000));
+        assert_eq!(3, stores[0].apply_changes(&resolution).unwrap());
+        assert_eq!(siblings, stores[0].iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ttl_compaction() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+
+        // the first 4 tables of the compaction get only records with a TTL
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let key = format!("KEY_{:05}", i).as_bytes().to_vec();
+            let value = format!("VALUE_{}", i).as_bytes().to_vec();
+
+            if i < MAX_MEM_COUNT * 4 {
+                kvs.put_with_ttl(key, value, Duration::from_secs(1));
+            } else {
+                kvs.put(key, value);
+            }
+        }
+
+        kvs.core.flush(false);
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        assert_eq!(MAX_FILE_COUNT, kvs.core.state.read().unwrap().sstables.len());
+        assert!(!kvs.core.run_compactions(&[CompactionReason::Expired]).unwrap()); // nothing has expired yet
+
+        advance_clock(1_000);
+
+        assert!(kvs.core.run_compactions(&[CompactionReason::Expired]).unwrap());
+        assert_eq!(2, kvs.core.state.read().unwrap().sstables.len());
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let ret = kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec());
+
+            assert_eq!(i >= MAX_MEM_COUNT * 4, ret.is_some(), "Wrong result for key: {}", i);
+        }
+
+        clear_clock();
+    }
+
+    #[test]
+    fn periodic_compaction() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).ttl_compaction_percent(0).periodic_compaction(Duration::from_secs(60));
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+
+        // too few expire for the TTL compaction, and only some of each table
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let key = format!("KEY_{:05}", i).as_bytes().to_vec();
+            let value = format!("VALUE_{}", i).as_bytes().to_vec();
+
+            if i % 10 == 0 {
+                kvs.put_with_ttl(key, value, Duration::from_secs(1));
+            } else {
+                kvs.put(key, value);
+            }
+        }
+
+        kvs.core.flush(false);
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        advance_clock(1);
+
+        // deletes that stay in the current SSTable, as there aren't enough for a compaction
+        for i in 0..10 {
+            kvs.delete(&format!("KEY_{:05}", i * 10 + 1).as_bytes().to_vec());
+        }
+
+        kvs.core.flush(false);
+
+        advance_clock(1_000);
+
+        assert!(!kvs.core.run_compactions(&[CompactionReason::Periodic]).unwrap()); // written too recently
+
+        advance_clock(60_000);
+
+        assert!(kvs.core.run_compactions(&[CompactionReason::Periodic]).unwrap());
+
+        {
+            let state = kvs.core.state.read().unwrap();
+
+            assert_eq!(0, state.cur_sstable.record_count());
+            assert_eq!((MAX_MEM_COUNT * MAX_FILE_COUNT * 9 / 10 - 10) as u64, state.sstables.iter().map(|t| t.record_count()).sum::<u64>());
+        }
+
+        // nothing is left to drop, so the tables aren't rewritten again
+        assert!(!kvs.core.run_compactions(&[CompactionReason::Periodic]).unwrap());
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let ret = kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec());
+
+            assert_eq!(i % 10 != 0 && !(i < 100 && i % 10 == 1), ret.is_some(), "Wrong result for key: {}", i);
+        }
+
+        clear_clock();
+    }
+
+    #[test]
+    fn compaction_priority() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).ttl_compaction_percent(50).periodic_compaction(Duration::from_secs(60));
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+
+        // all the records of the first table expire, and half of the second's
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let key = format!("KEY_{:05}", i).as_bytes().to_vec();
+            let value = format!("VALUE_{}", i).as_bytes().to_vec();
+
+            if i < MAX_MEM_COUNT || (i < MAX_MEM_COUNT * 2 && i % 2 == 0) {
+                kvs.put_with_ttl(key, value, Duration::from_secs(1));
+            } else {
+                kvs.put(key, value);
+            }
+        }
+
+        kvs.core.flush(false);
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        advance_clock(1);
+
+        // deletes of the keys of the last table, too few for a merge by size
+        for i in 0..30 {
+            kvs.delete(&format!("KEY_{:05}", MAX_MEM_COUNT * (MAX_FILE_COUNT - 1) + i).as_bytes().to_vec());
+        }
+
+        kvs.core.flush(false);
+
+        let all = [CompactionReason::Size, CompactionReason::Expired, CompactionReason::Periodic];
+        let scores = |jobs: Vec<CompactionJob>| jobs.into_iter().map(|job| (job.table.map(|t| t.smallest_key().to_vec()), job.reason, job.score)).collect::<Vec<_>>();
+
+        assert!(kvs.core.due_compactions(&all).is_empty());
+
+        advance_clock(1_000);
+
+        // the tables with the most expired first
+        assert_eq!(vec![(Some(b"KEY_00000".to_vec()), CompactionReason::Expired, 1.0), (Some(b"KEY_00100".to_vec()), CompactionReason::Expired, 0.5)], scores(kvs.core.due_compactions(&all)));
+
+        advance_clock(60_000);
+
+        // the merge drops the deletes, and all the expired records, reading only the last table with the current one
+        assert_eq!(vec![(None, CompactionReason::Periodic, 180.0 / 130.0), (Some(b"KEY_00000".to_vec()), CompactionReason::Expired, 1.0), (Some(b"KEY_00100".to_vec()), CompactionReason::Expired, 0.5)], scores(kvs.core.due_compactions(&all)));
+
+        assert!(kvs.core.run_compactions(&all).unwrap());
+        assert!(kvs.core.due_compactions(&all).is_empty());
+
+        {
+            let state = kvs.core.state.read().unwrap();
+
+            assert_eq!(0, state.cur_sstable.record_count());
+            assert_eq!((MAX_MEM_COUNT * MAX_FILE_COUNT - 150 - 30) as u64, state.sstables.iter().map(|t| t.record_count()).sum::<u64>());
+        }
+
+        clear_clock();
+    }
+
+    struct MoveListener(Mutex<Vec<(usize, usize)>>);
+
+    impl EventListener for MoveListener {
+        fn on_compaction_completed(&self, stats: &CompactionStats) {
+            self.0.lock().unwrap().push( (stats.moved_tables, stats.output_tables) );
+        }
+    }
+
+    #[test]
+    fn compaction_trivial_move() {
+        let db_dir = gen_dir();
+        let listener = Arc::new(MoveListener(Mutex::new(vec![])));
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).compaction_trivial_move(true).event_listener(listener.clone());
+
+        let kvs = options.create().unwrap();
+        let count = MAX_MEM_COUNT * MAX_FILE_COUNT;
+        let write = |start: usize, value: &str, delete: bool| {
+            for i in start..start + count {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), value.as_bytes().to_vec());
+
+                // in the same mem_table, so the delete is flushed with the others
+                if delete && i == start + MAX_MEM_COUNT / 2 {
+                    kvs.delete(&format!("KEY_{:05}", start).as_bytes().to_vec());
+                }
+            }
+
+            kvs.core.flush(false);
+            kvs.core.wait_for_background();
+            kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+            listener.0.lock().unwrap().drain(..).collect::<Vec<_>>()
+        };
+
+        // sequential inserts are moved, the current SSTable becoming one of the others
+        assert_eq!(vec![(1, 0)], write(0, "FIRST", false));
+        assert_eq!(vec![(1, 0)], write(count, "FIRST", false));
+        assert_eq!(2, kvs.core.state.read().unwrap().sstables.len());
+
+        // keys in the range of the others are merged
+        assert_eq!(vec![(0, MAX_FILE_COUNT)], write(count / 2, "SECOND", false));
+
+        // as is a table with a delete to apply
+        assert_eq!(vec![(0, MAX_FILE_COUNT)], write(count * 2, "THIRD", true));
+
+        drop(kvs);
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert!(kvs.check().is_consistent());
+
+        for i in 0..count * 3 {
+            let expected = if i == count * 2 { None } else if i >= count * 2 { Some("THIRD") } else if i >= count / 2 && i < count * 3 / 2 { Some("SECOND") } else { Some("FIRST") };
+
+            assert_eq!(expected.map(|v| v.as_bytes().to_vec()), kvs.get(&format!("KEY_{:05}", i).as_bytes().to_vec()), "Wrong value for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn value_log() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).value_log_threshold(100);
+
+        let kvs = options.create().unwrap();
+        let count = MAX_MEM_COUNT * MAX_FILE_COUNT;
+        // every other value is large enough for the value log
+        let value = |i: usize| if i % 2 == 0 { vec![i as u8; 1000] } else { format!("VALUE_{}", i).into_bytes() };
+
+        for i in 0..count {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), value(i));
+        }
+
+        kvs.core.flush(false);
+        kvs.core.wait_for_background();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        // the SSTables only hold pointers to the large values
+        {
+            let state = kvs.core.state.read().unwrap();
+            let table_bytes = state.sstables.iter().map(|table| file_size(&table.file_path())).sum::<u64>();
+
+            assert_eq!(MAX_FILE_COUNT, state.sstables.len());
+            assert!(state.sstables.iter().all(|table| table.value_logs().len() == 1));
+            assert!(table_bytes < (count / 2 * 1000) as u64, "The SSTables hold the values: {}", table_bytes);
+        }
+
+        assert_eq!(Some(value(10)), kvs.get(&b"KEY_00010".to_vec()));
+        assert_eq!(Some(value(11)), kvs.get(&b"KEY_00011".to_vec()));
+        assert_eq!((0..count).map(value).collect::<Vec<_>>(), kvs.iter().map(|(_, v)| v).collect::<Vec<_>>());
+
+        drop(kvs);
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert!(kvs.check().is_consistent());
+
+        for i in 0..count {
+            assert_eq!(Some(value(i)), kvs.get(&format!("KEY_{:05}", i).into_bytes()), "Wrong value for key: {}", i);
+        }
+    }
+
+    #[test]
+    fn collect_value_log() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).value_log_threshold(100).value_log_file_size(64 * 1024);
+
+        let kvs = options.create().unwrap();
+        let count = MAX_MEM_COUNT * MAX_FILE_COUNT;
+        let write = |keys: Vec<usize>, value: u8| {
+            for i in keys {
+                kvs.put(format!("KEY_{:05}", i).into_bytes(), vec![value; 1000]);
+            }
+
+            kvs.core.flush(false);
+            kvs.core.wait_for_background();
+            kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+        };
+        let log_paths = || {
+            let manifest = kvs.core.lock_manifest();
+
+            manifest.value_log_numbers().iter().map(|&n| manifest.value_log_path(n)).collect::<Vec<_>>()
+        };
+        let log_bytes = |paths: &[PathBuf]| paths.iter().map(|path| file_size(path)).sum::<u64>();
+
+        // the second write overwrites nine in ten of the first's values, and the third adds the keys for them to be merged
+        write((0..count).collect(), 1);
+        write((0..count).filter(|i| i % 10 != 0).collect(), 2);
+        write((count..count + count / 10).collect(), 2);
+
+        let old_paths = log_paths();
+        let old_bytes = log_bytes(&old_paths);
+
+        // every flush fills a file past the size, so each file has a new one after it
+        assert!(old_paths.len() > MAX_FILE_COUNT);
+
+        for _ in 0..old_paths.len() - 1 {
+            assert!(kvs.collect_value_log().unwrap());
+        }
+
+        // the old files are gone, and only the values still pointed to were moved to the new ones
+        assert!(old_paths[..old_paths.len() - 1].iter().all(|path| !path.exists()));
+        assert!(log_bytes(&log_paths()) < old_bytes * 2 / 3, "Too little was collected: {} of {}", log_bytes(&log_paths()), old_bytes);
+
+        let expected = |i: usize| Some(vec![if i < count && i % 10 == 0 { 1 } else { 2 }; 1000]);
+
+        for i in 0..count + count / 10 {
+            assert_eq!(expected(i), kvs.get(&format!("KEY_{:05}", i).into_bytes()), "Wrong value for key: {}", i);
+        }
+
+        drop(kvs);
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert!(kvs.check().is_consistent());
+        assert_eq!((0..count + count / 10).map(expected).map(Option::unwrap).collect::<Vec<_>>(), kvs.iter().map(|(_, v)| v).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn value_log_error() {
+        // keeps every record, but has the merge read the values out of the value log
+        struct Keep;
+
+        impl CompactionHook for Keep {
+            fn run_of(&self, _key: &[u8], _now: u64) -> Option<Vec<u8>> { None }
+            fn rewrite(&self, _run: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> { None }
+        }
+
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).value_log_threshold(100).compaction_hook(Arc::new(Keep));
+
+        let kvs = options.create().unwrap();
+        let count = MAX_MEM_COUNT * MAX_FILE_COUNT;
+
+        for i in 0..count {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), vec![1; 1000]);
+        }
+
+        kvs.core.flush(false);
+        kvs.core.wait_for_background();
+
+        // the values the next merge reads are gone
+        {
+            let manifest = kvs.core.lock_manifest();
+
+            for &number in manifest.value_log_numbers().iter() {
+                fs::OpenOptions::new().write(true).open(manifest.value_log_path(number)).unwrap().set_len(0).unwrap();
+            }
+        }
+
+        let tables = kvs.core.state.read().unwrap().sstables.iter().map(|table| table.file_path()).collect::<Vec<_>>();
+
+        // small values, between the keys of the first write, so the merge reads the tables with the values
+        for i in 0..MAX_MEM_COUNT {
+            kvs.put(format!("KEY_{:05}_2", i * MAX_FILE_COUNT).into_bytes(), b"VALUE".to_vec());
+        }
+
+        assert_eq!(ErrorKind::UnexpectedEof, kvs.compact_range(&b"KEY".to_vec(), &b"KEZ".to_vec(), 1).unwrap_err().kind());
+        assert_eq!(tables, kvs.core.state.read().unwrap().sstables.iter().map(|table| table.file_path()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn flush() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        let kvs = options.create().unwrap();
+        let cur_count = || kvs.core.state.read().unwrap().cur_sstable.record_count();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.flush(true);
+
+        assert_eq!(0, kvs.core.state.read().unwrap().mem_table.len());
+        assert_eq!(10, cur_count());
+
+        // an empty mem_table leaves the current SSTable as it is
+        let current = kvs.core.lock_manifest().current_number();
+
+        kvs.flush(true);
+
+        assert_eq!(current, kvs.core.lock_manifest().current_number());
+
+        kvs.put(b"KEY_10".to_vec(), b"VALUE".to_vec());
+        kvs.flush(false);
+        kvs.wait_for_flushes();
+
+        assert_eq!(11, cur_count());
+        assert_eq!(Some(b"VALUE".to_vec()), kvs.get(&b"KEY_10".to_vec()));
+    }
+
+    #[test]
+    fn compact_range() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).ttl_compaction_percent(0);
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+        let key = |i: usize| format!("KEY_{:05}", i).into_bytes();
+        let counts = || {
+            let state = kvs.core.state.read().unwrap();
+
+            (state.cur_sstable.record_count(), state.sstables.iter().map(|table| table.record_count()).sum::<u64>())
+        };
+
+        // half a merge's records, every tenth with a TTL
+        for i in 0..MAX_MEM_COUNT * 3 {
+            if i % 10 == 0 {
+                kvs.put_with_ttl(key(i), b"VALUE".to_vec(), Duration::from_secs(1));
+            } else {
+                kvs.put(key(i), b"VALUE".to_vec());
+            }
+        }
+
+        assert!(kvs.compact_range(&key(0), &key(1), 2).is_err());
+
+        kvs.compact_range(&key(0), &key(1), 0).unwrap();
+
+        assert_eq!((300, 0), counts());
+
+        // a range the current SSTable has no keys in leaves it
+        kvs.compact_range(&key(1_000), &key(2_000), 1).unwrap();
+
+        assert_eq!((300, 0), counts());
+
+        kvs.compact_range(&key(100), &key(101), 1).unwrap();
+
+        assert_eq!((0, 300), counts());
+
+        // the deletes, newer than the puts, are applied by the merge
+        advance_clock(1);
+
+        for i in 0..100 {
+            kvs.delete(&key(i));
+        }
+
+        kvs.compact_range(&key(0), &key(100), 1).unwrap();
+
+        assert_eq!((0, 200), counts());
+
+        // and the tables with expired records are rewritten without them
+        advance_clock(1_000);
+
+        kvs.compact_range(&key(0), &key(1_000), 1).unwrap();
+
+        assert_eq!((0, 180), counts());
+
+        for i in 0..MAX_MEM_COUNT * 3 {
+            assert_eq!(i >= 100 && i % 10 != 0, kvs.get(&key(i)).is_some(), "Wrong result for key: {}", i);
+        }
+
+        clear_clock();
+    }
+
+    #[test]
+    fn delete_prefix() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        // 6 tables of 100 keys: a/ in the first 2, b/ in the next 3, and c/ in the last one
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let prefix = if i < 200 { "a" } else if i < 500 { "b" } else { "c" };
+
+            kvs.put(format!("{}/{:05}", prefix, i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+        }
+
+        kvs.core.flush(false);
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        kvs.delete_prefix(&"a".as_bytes().to_vec(), false).unwrap();
+
+        assert!(kvs.get(&"a/00010".as_bytes().to_vec()).is_none());
+        assert_eq!(MAX_FILE_COUNT, kvs.core.state.read().unwrap().sstables.len());
+
+        // only the table with the b/0025 keys is rewritten, and the newer write in the mem_table is hidden too
+        kvs.put("b/00250".as_bytes().to_vec(), "NEW_VALUE".as_bytes().to_vec());
+        kvs.delete_prefix(&"b/0025".as_bytes().to_vec(), true).unwrap();
+
+        assert_eq!(MAX_FILE_COUNT, kvs.core.state.read().unwrap().sstables.len());
+        assert!(kvs.get(&"b/00250".as_bytes().to_vec()).is_none());
+        assert_eq!(Some("VALUE_260".as_bytes().to_vec()), kvs.get(&"b/00260".as_bytes().to_vec()));
+
+        // the tables with only b/ keys are dropped
+        kvs.delete_prefix(&"b".as_bytes().to_vec(), true).unwrap();
+
+        assert_eq!(3, kvs.core.state.read().unwrap().sstables.len());
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            let prefix = if i < 200 { "a" } else if i < 500 { "b" } else { "c" };
+            let ret = kvs.get(&format!("{}/{:05}", prefix, i).as_bytes().to_vec());
+
+            assert_eq!(prefix == "c", ret.is_some(), "Wrong result for key: {}/{:05}", prefix, i);
+        }
+
+        assert_eq!(Some(vec![0x01, 0x03]), super::prefix_end(&[0x01, 0x02, 0xFF]));
+        assert_eq!(None, super::prefix_end(&[0xFF, 0xFF]));
+
+        assert_eq!(ErrorKind::InvalidInput, kvs.delete_prefix(&vec![], false).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, kvs.delete_prefix(&vec![0xFF, 0xFF], true).unwrap_err().kind());
+        assert!(kvs.get(&"c/00550".as_bytes().to_vec()).is_some());
+    }
+
+    #[test]
+    fn codecs() {
+        for &codec in [CodecKind::MsgPack, CodecKind::Bincode, CodecKind::Cbor].iter() {
+            let db_dir = gen_dir();
+
+            {
+                let mut options = KVSOptions::new(&db_dir);
+                options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).codec(codec);
+                let kvs = options.create().unwrap();
+
+                for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+                    kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+                }
+
+                kvs.delete_range(&format!("KEY_{:05}", 10).as_bytes().to_vec(), &format!("KEY_{:05}", 20).as_bytes().to_vec());
+                kvs.wait_for_flushes();
+            }
+
+            {
+                let kvs = KVS::open(&db_dir).unwrap();
+
+                assert_eq!(codec, kvs.core.options.codec);
+                assert!(kvs.get(&format!("KEY_{:05}", 15).as_bytes().to_vec()).is_none(), "{:?}", codec);
+                assert!(kvs.get(&format!("KEY_{:05}", 25).as_bytes().to_vec()).is_some(), "{:?}", codec);
+            }
+
+            // opening the tables with another codec fails, instead of misreading them
+            let path = db_dir.join(OPTIONS_FILE);
+            let mut file = OptionsFile::read(&path).unwrap();
+
+            file.codec = Some(if codec == CodecKind::Cbor { CodecKind::MsgPack } else { CodecKind::Cbor });
+            file.write(&path).unwrap();
+
+            assert_eq!(ErrorKind::InvalidData, KVS::open(&db_dir).err().unwrap().kind(), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn preallocate() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).preallocate(true);
+
+        {
+            let kvs = options.create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT * (MAX_FILE_COUNT + 2) {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            kvs.wait_for_flushes();
+        }
+
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        assert_eq!(MAX_MEM_COUNT * (MAX_FILE_COUNT + 2), kvs.iter().count());
+        assert_eq!(Some(b"VALUE_7".to_vec()), kvs.get(&b"KEY_00007".to_vec()));
+    }
+
+    #[test]
+    fn wal_archive() {
+        let db_dir = gen_dir();
+        let archive_dir = db_dir.join("archive");
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(1_000_000).wal_segment_size(4096).wal_archive_dir(&archive_dir);
+            let kvs = options.create().unwrap();
+
+            for i in 0..1000 {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            kvs.wait_for_flushes();
+        }
+
+        let segments = wal_archive::segments(&archive_dir).unwrap();
+
+        // the segments are about the size, and hold every change, in order
+        assert!(segments.len() > 10, "{:?}", segments);
+        assert_eq!(0, segments[0].0);
+        assert_eq!(1000, segments.last().unwrap().1);
+
+        for (i, &(first_seq, end_seq, ref path)) in segments.iter().enumerate() {
+            let wal_file = RecordFile::new(path, WAL_HEADER, 4096, 1).unwrap();
+
+            assert!(i == 0 || segments[i - 1].1 == first_seq);
+            assert_eq!(end_seq - first_seq, wal_file.record_count() as u64);
+            assert!(wal_file.ends_at().unwrap() < 4096 + 100);
+            assert_eq!(format!("KEY_{:05}", first_seq).as_bytes(), Record::deserialize(wal_file.iter().next().unwrap().1).key());
+        }
+
+        // the WALs themselves are gone
+        assert!(fs::read_dir(&db_dir).unwrap().filter(|e| e.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "wal")).count() <= 1);
+    }
+
+    #[test]
+    fn restore_to() {
+        let db_dir = gen_dir();
+        let archive_root = gen_dir();
+        let archive_dir = archive_root.join("archive");
+        let backup_dir = gen_dir();
+        let key = |i: usize| format!("KEY_{:05}", i).as_bytes().to_vec();
+
+        set_clock(1_000);
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).wal_archive_dir(&archive_dir);
+            let kvs = options.create().unwrap();
+
+            for i in 0..250 {
+                kvs.put(key(i), b"V1".to_vec());
+            }
+        }
+
+        // a backup of the store as it was closed
+        for entry in fs::read_dir(&db_dir).unwrap() {
+            let path = entry.unwrap().path();
+
+            fs::copy(&path, backup_dir.join(path.file_name().unwrap())).unwrap();
+        }
+
+        set_clock(2_000);
+
+        {
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            kvs.delete(&key(10)); // seq 250
+
+            for i in 250..500 {
+                kvs.put(key(i), b"V2".to_vec());
+            }
+        }
+
+        // from nothing, to just before a change
+        let restore_dir = gen_dir();
+        let kvs = KVS::restore_to(KVSOptions::new(&restore_dir), &archive_dir, RestorePoint::Seq(300)).unwrap();
+
+        assert_eq!(300, kvs.next_seq());
+        assert_eq!(None, kvs.get(&key(10)));
+        assert_eq!(Some(b"V2".to_vec()), kvs.get(&key(298)));
+        assert_eq!(None, kvs.get(&key(299)));
+
+        // to a time
+        let restore_dir = gen_dir();
+        let kvs = KVS::restore_to(KVSOptions::new(&restore_dir), &archive_dir, RestorePoint::Timestamp(1_500)).unwrap();
+
+        assert_eq!(250, kvs.next_seq());
+        assert_eq!(Some(b"V1".to_vec()), kvs.get(&key(10)));
+
+        // from the backup, to the end of the archive
+        let kvs = KVS::restore_to(KVSOptions::new(&backup_dir), &archive_dir, RestorePoint::Seq(u64::max_value())).unwrap();
+
+        assert_eq!(501, kvs.next_seq());
+        assert_eq!(499, kvs.iter().count());
+
+        // the archive doesn't have the changes before it
+        fs::remove_file(archive_dir.join(wal_archive::segment_name(0, 100))).unwrap();
+
+        let restore_dir = gen_dir();
+
+        assert_eq!(ErrorKind::NotFound, KVS::restore_to(KVSOptions::new(&restore_dir), &archive_dir, RestorePoint::Seq(300)).err().unwrap().kind());
+
+        clear_clock();
+    }
+
+    #[test]
+    fn executor() {
+        /// Runs the jobs right away, on the writer's thread
+        struct Inline;
+
+        impl Executor for Inline {
+            fn execute(&self, job: Box<FnOnce() + Send>) {
+                job();
+            }
+        }
+
+        let pool = Arc::new(ThreadPool::new(1).unwrap());
+        let executors :Vec<Arc<Executor>> = vec![pool.clone(), pool, Arc::new(Inline)];
+        let db_dirs = executors.iter().map(|_| gen_dir()).collect::<Vec<_>>();
+
+        {
+            let stores = executors.into_iter().zip(db_dirs.iter()).map(|(executor, db_dir)| {
+                let mut options = KVSOptions::new(db_dir);
+                options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).executor(executor);
+
+                options.create().unwrap()
+            }).collect::<Vec<_>>();
+
+            // enough for flushes and a compaction in every store
+            for i in 0..MAX_MEM_COUNT * (MAX_FILE_COUNT + 2) {
+                for kvs in stores.iter() {
+                    kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+                }
+            }
+
+            for kvs in stores.iter() {
+                kvs.wait_for_flushes();
+
+                assert_eq!(0, kvs.core.state.read().unwrap().immutables.len());
+                assert!(kvs.core.state.read().unwrap().sstables.len() > 0);
+            }
+        }
+
+        for db_dir in db_dirs.iter() {
+            let kvs = KVSOptions::new(db_dir).create().unwrap();
+
+            assert_eq!(MAX_MEM_COUNT * (MAX_FILE_COUNT + 2), kvs.iter().count());
+        }
+    }
+
+    #[test]
+    fn clock() {
+        let db_dir = gen_dir();
+        let physical = Arc::new(ManualClock::new(1_000));
+        let mut options = KVSOptions::new(&db_dir);
+        options.clock(Arc::new(HybridLogicalClock::with_physical(physical.clone())));
+
+        let kvs = options.create().unwrap();
+        let created = |key: &[u8]| kvs.core.state.read().unwrap().mem_table.get(key).unwrap().created();
+
+        kvs.put_with_ttl(b"A".to_vec(), b"VALUE".to_vec(), Duration::from_secs(1));
+        kvs.put(b"B".to_vec(), b"VALUE".to_vec());
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"C".to_vec(), b"VALUE".to_vec());
+        batch.delete(&b"D".to_vec());
+        kvs.write(batch, &WriteOptions::new());
+
+        // every record gets a timestamp of its own, whenever it was made
+        assert_eq!(vec![1_000, 1_001, 1_002, 1_003], [&b"A"[..], b"B", b"C", b"D"].iter().map(|key| created(key)).collect::<Vec<_>>());
+        assert_eq!(1_004, kvs.core.delete_range(&b"X".to_vec(), &b"Y".to_vec(), None).unwrap().created());
+
+        assert!(kvs.get(&b"A".to_vec()).is_some());
+
+        // expired by the clock, not the system's
+        physical.advance(1_000);
+
+        assert!(kvs.get(&b"A".to_vec()).is_none());
+        assert!(kvs.get(&b"B".to_vec()).is_some());
+    }
+
+    #[test]
+    fn close() {
+        let db_dir = gen_dir();
+        let marker = db_dir.join(CLEAN_SHUTDOWN_FILE);
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+            let kvs = options.create().unwrap();
+
+            kvs.put("KEY_1".as_bytes().to_vec(), "VALUE_1".as_bytes().to_vec());
+            kvs.close(true).unwrap();
+        }
+
+        assert!(marker.exists());
+
+        // the marker is taken by the open, and the WAL isn't empty after this close
+        {
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            assert!(!marker.exists());
+            assert_eq!("VALUE_1".as_bytes().to_vec(), kvs.get(&"KEY_1".as_bytes().to_vec()).unwrap());
+
+            kvs.put("KEY_2".as_bytes().to_vec(), "VALUE_2".as_bytes().to_vec());
+            kvs.close(false).unwrap();
+        }
+
+        assert!(!marker.exists());
+
+        // a marker for another WAL is ignored
+        {
+            File::create(&marker).unwrap().write_all(b"12345 0 0\n").unwrap();
+
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            assert_eq!(2, kvs.iter().count());
+        }
+    }
+
+    #[test]
+    fn cancel_background_work() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).max_immutables(100);
+
+        {
+            let kvs = options.create().unwrap();
+
+            kvs.cancel_background_work(true);
+
+            // the full mem_tables wait in their WALs
+            for i in 0..MAX_MEM_COUNT * 3 {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
+            }
+
+            assert!(kvs.core.state.read().unwrap().immutables.len() >= 2);
+            assert!(!kvs.core.run_compactions(&[CompactionReason::Size]).unwrap());
+
+            kvs.close(true).unwrap();
+        }
+
+        assert!(!db_dir.join(CLEAN_SHUTDOWN_FILE).exists());
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert_eq!(MAX_MEM_COUNT * 3, kvs.iter().count());
+    }
+
+    #[test]
+    fn pause_background_work() {
+        let pool :Arc<Executor> = Arc::new(ThreadPool::new(1).unwrap());
+
+        // on the background thread, and on an executor
+        for executor in vec![None, Some(pool)] {
+            let db_dir = gen_dir();
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).max_immutables(100);
+
+            if let Some(executor) = executor {
+                options.executor(executor);
+            }
+
+            let kvs = options.create().unwrap();
+
+            kvs.pause_background_work();
+
+            // the full mem_tables wait, and so do the records for a merge
+            for i in 0..MAX_MEM_COUNT * (MAX_FILE_COUNT + 1) {
+                kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
+            }
+
+            thread::sleep(Duration::from_millis(100));
+
+            assert!(kvs.core.state.read().unwrap().immutables.len() > MAX_FILE_COUNT);
+            assert!(!kvs.core.run_compactions(&[CompactionReason::Size]).unwrap());
+
+            kvs.resume_background_work();
+            kvs.wait_for_flushes();
+
+            assert!(kvs.core.state.read().unwrap().immutables.is_empty());
+            assert!(kvs.core.state.read().unwrap().sstables.len() > 0);
+
+            // dropping a paused store flushes it
+            kvs.pause_background_work();
+            kvs.put(b"KEY_LAST".to_vec(), b"VALUE".to_vec());
+            drop(kvs);
+
+            let kvs = KVS::open(&db_dir).unwrap();
+
+            assert_eq!(MAX_MEM_COUNT * (MAX_FILE_COUNT + 1) + 1, kvs.iter().count());
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        let kvs = options.create().unwrap();
+        let count = MAX_MEM_COUNT * MAX_FILE_COUNT + 50;
+
+        for i in 0..count {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        let stats = kvs.stats();
+        let in_levels = stats.levels.iter().map(|l| l.record_count).sum::<u64>();
+
+        assert_eq!(kvs.count_estimate(), stats.mem_records + stats.immutable_records + in_levels);
+        assert_eq!(vec![0, 1], stats.levels.iter().map(|l| l.level).collect::<Vec<_>>());
+        assert_eq!(MAX_FILE_COUNT, stats.levels[1].table_count);
+        assert_eq!(Some(b"KEY_00000".to_vec()), stats.levels[1].smallest_key);
+        assert!(stats.levels[1].bloom_bytes > 0 && stats.levels[1].file_bytes > stats.levels[1].bloom_bytes);
+        assert!(!stats.compaction_pending);
+        assert_eq!(0, stats.pending_compaction_bytes);
+
+        // the second read of each key comes from the record cache
+        for _ in 0..2 {
+            for i in 0..10 {
+                assert!(kvs.get(&format!("KEY_{:05}", i).into_bytes()).is_some());
+            }
+        }
+
+        let after = kvs.stats();
+
+        assert!(after.record_cache.hits > stats.record_cache.hits);
+        assert!(after.table_cache.hits + after.table_cache.misses > stats.table_cache.hits + stats.table_cache.misses);
+        assert!(after.to_json().starts_with("{\"mem_records\":"));
+    }
+
+    #[test]
+    fn property() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 50 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        let property = |name: &str| kvs.property(name).map(|value| value.parse::<u64>().unwrap());
+        let stats = kvs.stats();
+
+        assert_eq!(Some(1), property("kvs.num-files-at-level0"));
+        assert_eq!(Some(MAX_FILE_COUNT as u64), property("kvs.num-files-at-level1"));
+        assert_eq!(None, property("kvs.num-files-at-level2"));
+        assert_eq!(Some(kvs.count_estimate()), property("kvs.estimate-num-keys"));
+        assert_eq!(Some(stats.levels[0].file_bytes + stats.levels[1].file_bytes), property("kvs.estimate-live-data-size"));
+        assert_eq!(Some(stats.mem_records), property("kvs.num-entries-active-mem-table"));
+        assert!(property("kvs.mem-table-size").unwrap() > 0);
+        assert!(property("kvs.cur-size-all-mem-tables").unwrap() >= property("kvs.mem-table-size").unwrap());
+        assert_eq!(Some(0), property("kvs.compaction-pending"));
+        assert_eq!(Some(0), property("kvs.background-errors"));
+        assert!(kvs.property("kvs.stats").unwrap().starts_with("mem_table:"));
+        assert_eq!(None, kvs.property("kvs.no-such-property"));
+        assert_eq!(None, kvs.property("kvs.num-files-at-levelX"));
+    }
+
+    #[test]
+    fn quota() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).quota(&b"a/".to_vec(), None, Some(3)).quota(&b"b/".to_vec(), Some(20), None);
+
+        let kvs = options.create().unwrap();
+        let usage = |kvs: &KVS| kvs.stats().quotas.iter().map(|u| (u.keys, u.bytes)).collect::<Vec<_>>();
+
+        // spread over the mem_table and SSTables
+        for i in 0..MAX_MEM_COUNT {
+            kvs.put(format!("c/{:05}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.try_put(b"a/1".to_vec(), b"1".to_vec()).unwrap();
+        kvs.try_put(b"a/2".to_vec(), b"1".to_vec()).unwrap();
+        kvs.try_put(b"b/1".to_vec(), b"1234567".to_vec()).unwrap();
+
+        assert_eq!(vec![(2, 8), (1, 10)], usage(&kvs));
+        assert_eq!(Err(WriteError::QuotaExceeded(QuotaExceeded { prefix: b"b/".to_vec(), keys: 2, bytes: 21 })), kvs.try_put(b"b/2".to_vec(), b"12345678".to_vec()));
+        assert_eq!(None, kvs.get(&b"b/2".to_vec()));
+
+        // other writes are counted, but not limited
+        kvs.put(b"a/3".to_vec(), b"1".to_vec());
+        kvs.put(b"a/4".to_vec(), b"1".to_vec());
+
+        assert_eq!(vec![(4, 16), (1, 10)], usage(&kvs));
+        assert!(kvs.try_put(b"a/5".to_vec(), b"1".to_vec()).is_err());
+
+        // shrinking a prefix over its quota is allowed
+        let mut batch = WriteBatch::new();
+
+        batch.delete(&b"a/3".to_vec()).delete(&b"a/4".to_vec());
+        kvs.try_write(batch, &WriteOptions::new()).unwrap();
+
+        assert_eq!(vec![(2, 8), (1, 10)], usage(&kvs));
+
+        kvs.delete_prefix(&b"b/".to_vec(), false).unwrap();
+
+        assert_eq!(vec![(2, 8), (0, 0)], usage(&kvs));
+
+        // the quotas are saved with the options, and counted again when opened
+        kvs.close(true).unwrap();
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        assert_eq!(vec![(2, 8), (0, 0)], usage(&kvs));
+        assert_eq!(Some(3), kvs.stats().quotas[0].quota.max_keys);
+
+        assert!(KVSOptions::new(&db_dir).quota(&b"a/".to_vec(), None, None).quota(&b"a/".to_vec(), None, None).validate().is_err());
+    }
+
+    #[test]
+    fn default_ttl() {
+        let db_dir = gen_dir();
+        let key = |k: &str| k.as_bytes().to_vec();
+
+        set_clock(1_000);
+
+        {
+            let mut options = KVSOptions::new(&db_dir);
+            options.default_ttl(Duration::from_secs(10)).prefix_ttl(&key("session/"), Duration::from_secs(1)).prefix_ttl(&key("session/keep/"), Duration::from_secs(0));
+
+            let kvs = options.create().unwrap();
+
+            for k in ["users/a", "session/a", "session/keep/a"].iter() {
+                kvs.put(key(k), key("VALUE"));
+            }
+
+            kvs.put_with_ttl(key("session/b"), key("VALUE"), Duration::from_secs(5));
+        }
+
+        // the TTLs are saved with the options
+        let kvs = KVS::open(&db_dir).unwrap();
+        let keys = |kvs: &KVS| kvs.iter().map(|(k, _)| String::from_utf8(k).unwrap()).collect::<Vec<_>>();
+
+        advance_clock(1_000);
+
+        assert_eq!(vec!["session/b", "session/keep/a", "users/a"], keys(&kvs));
+
+        advance_clock(9_000);
+
+        assert_eq!(vec!["session/keep/a"], keys(&kvs));
+
+        assert!(KVSOptions::new(&db_dir).prefix_ttl(&key("a/"), Duration::from_secs(1)).prefix_ttl(&key("a/"), Duration::from_secs(2)).validate().is_err());
+
+        clear_clock();
+    }
+
+    #[test]
+    fn range_page() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+
+        let kvs = options.create().unwrap();
+        let key = |i: usize| format!("KEY_{:05}", i).into_bytes();
+
+        // some in SSTables, the rest in the mem_table
+        for i in 0..MAX_MEM_COUNT + 25 {
+            kvs.put(key(i), b"VALUE".to_vec());
+        }
+
+        let mut keys = vec![];
+        let mut cursor = None;
+
+        loop {
+            let page = kvs.range_page(&key(MAX_MEM_COUNT - 5), &key(MAX_MEM_COUNT + 10), 4, cursor.as_ref().map(|c: &Vec<u8>| c.as_slice())).unwrap();
+
+            assert!(page.entries.len() <= 4);
+
+            keys.extend(page.entries.into_iter().map(|(k, _)| k));
+
+            // the listing doesn't see writes made after its first page
+            kvs.put(key(MAX_MEM_COUNT + 1), b"CHANGED".to_vec());
+            kvs.delete(&key(MAX_MEM_COUNT + 2));
+            kvs.put(format!("KEY_{:05}_NEW", MAX_MEM_COUNT).into_bytes(), b"VALUE".to_vec());
+
+            match page.cursor {
+                Some(c) => cursor = Some(c),
+                None => break
+            }
+        }
+
+        assert_eq!((MAX_MEM_COUNT - 5..MAX_MEM_COUNT + 10).map(key).collect::<Vec<_>>(), keys);
+
+        // a new listing sees them
+        let page = kvs.prefix_page(&format!("KEY_{:05}", MAX_MEM_COUNT).into_bytes(), 10, None).unwrap();
+
+        assert_eq!(vec![key(MAX_MEM_COUNT), format!("KEY_{:05}_NEW", MAX_MEM_COUNT).into_bytes()], page.entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        assert!(page.cursor.is_none());
+
+        // an empty prefix lists every key, from the start
+        let page = kvs.prefix_page(&vec![], 3, None).unwrap();
+
+        assert_eq!((0..3).map(key).collect::<Vec<_>>(), page.entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+
+        assert_eq!(ErrorKind::InvalidInput, kvs.range_page(&key(0), &key(10), 4, Some(b"NOT A CURSOR")).unwrap_err().kind());
+
+        // a cursor can't be moved out of its range, or used with another one
+        let page = kvs.prefix_page(&b"KEY_0000".to_vec(), 4, None).unwrap();
+        let cursor = page.cursor.unwrap();
+        let mut moved = cursor.clone();
+
+        moved.truncate(1 + 8);
+        moved.extend_from_slice(b"KEY_1");
+
+        assert_eq!(ErrorKind::InvalidInput, kvs.prefix_page(&b"KEY_0000".to_vec(), 4, Some(&moved)).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, kvs.prefix_page(&b"KEY_1".to_vec(), 4, Some(&cursor)).unwrap_err().kind());
+        assert_eq!(ErrorKind::NotFound, kvs.prefix_page(&b"KEY_".to_vec(), 4, Some(&cursor)).unwrap_err().kind());
+        assert_eq!(4, kvs.prefix_page(&b"KEY_0000".to_vec(), 4, Some(&cursor)).unwrap().entries.len());
+        assert_eq!(ErrorKind::InvalidInput, kvs.range_page(&key(0), &key(10), 0, None).unwrap_err().kind());
+    }
+
+    #[test]
+    fn range_page_timeout() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).cursor_timeout(Duration::from_millis(20));
+
+        let kvs = options.create().unwrap();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        let page = kvs.prefix_page(&b"KEY_".to_vec(), 5, None).unwrap();
+
+        assert_eq!(5, page.entries.len());
+
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(ErrorKind::NotFound, kvs.prefix_page(&b"KEY_".to_vec(), 5, page.cursor.as_ref().map(|c| c.as_slice())).unwrap_err().kind());
+        assert!(kvs.core.cursors.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn range_page_max_cursors() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.max_cursors(2);
+
+        let kvs = options.create().unwrap();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        // a listing in one page keeps no view
+        assert_eq!(None, kvs.prefix_page(&b"KEY_".to_vec(), 10, None).unwrap().cursor);
+        assert!(kvs.core.cursors.lock().unwrap().is_empty());
+
+        let cursors = ["KEY_", "KE", "KEY"].iter().map(|prefix| {
+            thread::sleep(Duration::from_millis(2)); // so they're read at different instants
+
+            kvs.prefix_page(&prefix.as_bytes().to_vec(), 1, None).unwrap().cursor.unwrap()
+        }).collect::<Vec<_>>();
+
+        // the third listing dropped the view of the first
+        assert_eq!(2, kvs.core.cursors.lock().unwrap().len());
+        assert_eq!(ErrorKind::NotFound, kvs.prefix_page(&b"KEY_".to_vec(), 1, Some(&cursors[0])).unwrap_err().kind());
+        assert_eq!(b"KEY_1".to_vec(), kvs.prefix_page(&b"KE".to_vec(), 1, Some(&cursors[1])).unwrap().entries[0].0);
+        assert_eq!(1, kvs.prefix_page(&b"KEY".to_vec(), 1, Some(&cursors[2])).unwrap().entries.len());
+    }
+
+    #[test]
+    fn hot_keys() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.hot_keys(2);
+
+        let kvs = options.clone().create().unwrap();
+
+        for i in 0..10u8 {
+            kvs.put(vec![i], vec![i]);
+
+            // key i is read i times
+            for _ in 0..i {
+                kvs.get(&vec![i]);
+            }
+        }
+
+        let mut txn = kvs.begin();
+
+        txn.get(&vec![8]);
+        txn.get(&vec![8]);
+
+        assert_eq!(vec![(vec![8], 10), (vec![9], 9)], kvs.hot_keys(10));
+        assert_eq!(vec![(vec![8], 10)], kvs.stats().hot_keys[..1].to_vec());
+
+        // off by default, and writes aren't counted
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        kvs.put(vec![1], vec![1]);
+        kvs.get(&vec![1]);
+
+        assert!(kvs.hot_keys(10).is_empty());
+        assert!(options.hot_keys(10_001).validate().is_err());
+    }
+
+    #[test]
+    fn pin_metadata() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).meta_cache_size(1 << 16).pin_metadata(true);
+
+        let pinned_bytes = {
+            let kvs = options.clone().create().unwrap();
+
+            for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT * 2 {
+                kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
+            }
+
+            // compacted at least once
+            kvs.wait_for_flushes();
+
+            assert!(kvs.get(&b"KEY_00001".to_vec()).is_some());
+
+            let stats = kvs.stats();
+
+            assert!(stats.pinned_bytes > 0);
+            assert!(stats.meta_cache.hits > 0);
+
+            stats.pinned_bytes
+        };
+
+        // the metadata of the tables removed by the compaction isn't kept
+        let kvs = options.create().unwrap();
+
+        assert_eq!(pinned_bytes, kvs.stats().pinned_bytes);
+    }
+
+    #[test]
+    fn row_cache() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.row_cache_size(10);
+
+        let kvs = options.create().unwrap();
+        let key = b"KEY".to_vec();
+
+        kvs.put(key.clone(), b"VALUE_1".to_vec());
+        kvs.core.flush(false);
+        kvs.wait_for_flushes();
+
+        assert_eq!(Some(b"VALUE_1".to_vec()), kvs.get(&key));
+        assert_eq!(Some(b"VALUE_1".to_vec()), kvs.get(&key));
+
+        let stats = kvs.stats();
+
+        assert_eq!((1, 1, 1), (stats.row_cache.hits, stats.row_cache.misses, stats.cached_rows));
+
+        // a write removes the key, so the old record isn't read once the new one is flushed
+        kvs.put(key.clone(), b"VALUE_2".to_vec());
+
+        assert_eq!(0, kvs.stats().cached_rows);
+
+        kvs.core.flush(false);
+        kvs.wait_for_flushes();
+
+        assert_eq!(Some(b"VALUE_2".to_vec()), kvs.get(&key));
+        assert_eq!(Some(b"VALUE_2".to_vec()), kvs.get(&key));
+
+        kvs.delete_range(&b"A".to_vec(), &b"Z".to_vec());
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        assert_eq!(None, kvs.get(&key));
+        assert_eq!(0, kvs.stats().cached_rows);
+    }
+
+    #[test]
+    fn compaction_fadvise() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).compaction_drop_inputs(true).compaction_prefetch_indexes(true);
+
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT * 2 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+
+        // the hints don't change what's read
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT * 2 {
+            assert_eq!(Some(b"VALUE".to_vec()), kvs.get(&format!("KEY_{:05}", i).into_bytes()));
+        }
+
+        if cfg!(target_os = "linux") {
+            assert!(kvs.stats().readahead_bytes > 0);
+        }
+    }
+
+    #[test]
+    fn deadline() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.core.flush(false);
+        kvs.wait_for_flushes();
+        kvs.put(b"MEM".to_vec(), b"VALUE".to_vec());
+
+        let mut options = ReadOptions::new();
+
+        options.deadline(Duration::from_secs(60));
+
+        assert_eq!(Some(b"VALUE".to_vec()), kvs.try_get(&b"KEY_1".to_vec(), &options).unwrap());
+        assert_eq!(10, kvs.try_range(&b"KEY_".to_vec(), &b"KEY_Z".to_vec(), &options).unwrap().len());
+
+        // no time left, so only the mem_tables are read
+        options.deadline(Duration::from_secs(0));
+
+        assert_eq!(Some(b"VALUE".to_vec()), kvs.try_get(&b"MEM".to_vec(), &options).unwrap());
+        match kvs.try_get(&b"KEY_1".to_vec(), &options) {
+            Err(ReadError::DeadlineExceeded(e)) => assert!(e.entries.is_empty()),
+            other => panic!("Not past the deadline: {:?}", other)
+        }
+
+        let mut it = kvs.range_with_options(&b"KEY_".to_vec(), &b"KEY_Z".to_vec(), &options);
+
+        assert_eq!(None, it.next());
+        assert!(it.deadline_exceeded());
+        assert!(kvs.try_range(&b"KEY_".to_vec(), &b"KEY_Z".to_vec(), &options).is_err());
     }
 
-    pub fn delete(&mut self, key: &Vec<u8>) {
-        debug!("Called delete: {:?}", key);
+    #[test]
+    fn read_error() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.cache_size(2).cache_policy(CachePolicyKind::Lru);
+
+        let kvs = options.create().unwrap();
+
+        // the records written after it push it out of the cache
+        kvs.put(b"KEY".to_vec(), b"A_VALUE_TO_CORRUPT".to_vec());
+
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        kvs.core.flush(false);
+        kvs.wait_for_flushes();
+
+        // flip a byte of the value in the SSTable
+        let path = kvs.export_live_files().files[0].path.clone();
+        let mut bytes = fs::read(&path).unwrap();
+        let offset = bytes.windows(18).position(|w| w == b"A_VALUE_TO_CORRUPT").unwrap();
+
+        bytes[offset] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        // not cached, as the flush on close reads it again
+        let mut read_options = ReadOptions::new();
 
-        // create a record, and call insert
-        let rec = Record::new(key.to_vec(), None);
+        read_options.fill_cache(false);
 
-        self.insert(rec)
+        match kvs.try_get(&b"KEY".to_vec(), &read_options) {
+            Err(ReadError::IO(e)) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+            other => panic!("Read a corrupt record: {:?}", other)
+        }
+
+        // fixed, for the flush on close
+        bytes[offset] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
     }
 
-    /// Returns an upper bound on the number of records
-    /// To get an exact count, we'd need to read all the records in searching for deletes
-    pub fn count_estimate(&self) -> u64 {
-        let mut sum = self.mem_table.len() as u64;
+    #[test]
+    fn background_error() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
 
-        debug!("mem_table count: {}", sum);
+        options.mem_count(MAX_MEM_COUNT).io_retries(2).io_retry_delay(Duration::from_millis(1));
 
-        sum += self.cur_sstable.record_count();
+        assert!(options.clone().io_retries(100).validate().is_err());
 
-        debug!("cur_sstable count: {}", self.cur_sstable.record_count());
+        let mut invalid = options.clone();
 
-        for sstable in self.sstables.iter() {
-            debug!("{:?} count: {}", sstable, sstable.record_count());
-            sum += sstable.record_count();
+        invalid.io_retries(100);
+
+        assert_eq!(ErrorKind::InvalidInput, invalid.create().err().unwrap().kind());
+
+        let kvs = options.create().unwrap();
+
+        // directories where the next SSTables would go, so creating them fails
+        let block = |count: u64| {
+            let first = kvs.core.lock_manifest().new_file_number();
+
+            (first..first + count).map(|n| {
+                let path = db_dir.join(format!("{:06}.sst", n));
+
+                fs::create_dir(&path).unwrap();
+                path
+            }).collect::<Vec<_>>()
+        };
+
+        for i in 0..50 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
         }
 
-        debug!("SUM: {}", sum);
+        // the flush's new WAL takes the second number, then its first try fails on the third
+        let mut blocked = block(3);
 
-        return sum;
-    }
+        kvs.core.flush(false);
 
-}
+        assert_eq!(None, kvs.background_error());
+        assert_eq!(db_dir.join(format!("{:06}.sst", kvs.core.lock_manifest().current_number() - 1)), blocked[2]); // tried again
 
-impl Drop for KVS {
-    fn drop(&mut self) {
-        debug!("KVS Drop");
-        // call flush without checking the size
-        self.flush(false);
-    }
-}
+        // more failures than retries
+        blocked.extend(block(10));
 
+        for i in 50..60 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
 
-#[cfg(test)]
-mod tests {
-    use kvs::{KVSOptions, KVS};
-    use std::path::PathBuf;
-    use rand::{thread_rng, Rng};
-    use std::fs::create_dir;
-    use simple_logger;
-    use ::LOGGER_INIT;
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| kvs.core.flush(false))).is_err());
+        assert!(kvs.background_error().is_some());
 
-    const MAX_MEM_COUNT: usize = 100;
-    const MAX_FILE_COUNT: usize = 6;
+        // still readable, but not writable
+        for i in 0..60 {
+            assert_eq!(Some(b"VALUE".to_vec()), kvs.get(&format!("KEY_{}", i).into_bytes()));
+        }
 
-    fn gen_dir() -> PathBuf {
-        LOGGER_INIT.call_once(|| simple_logger::init().unwrap()); // this will panic on error
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| kvs.put(b"KEY".to_vec(), b"VALUE".to_vec()))).is_err());
+        assert_eq!(Err(WriteError::ReadOnly(kvs.background_error().unwrap())), kvs.try_put(b"KEY".to_vec(), b"VALUE".to_vec()));
+        assert!(kvs.compact_range(&b"A".to_vec(), &b"Z".to_vec(), 1).is_err());
+        assert!(kvs.close(true).is_err());
 
-        let tmp_dir: String = thread_rng().gen_ascii_chars().take(6).collect();
-        let ret_dir = PathBuf::from("/tmp").join(format!("kvs_{}", tmp_dir));
+        for path in blocked {
+            fs::remove_dir(path).unwrap();
+        }
 
-        debug!("CREATING TMP DIR: {:?}", ret_dir);
+        // the WALs are replayed, and flushed
+        let kvs = KVS::open(&db_dir).unwrap();
 
-        create_dir(&ret_dir).unwrap();
+        for i in 0..60 {
+            assert_eq!(Some(b"VALUE".to_vec()), kvs.get(&format!("KEY_{}", i).into_bytes()));
+        }
 
-        return ret_dir;
+        kvs.put(b"KEY".to_vec(), b"VALUE".to_vec());
     }
 
     #[test]
-    fn new() {
+    fn wal_error() {
         let db_dir = gen_dir();
-        let _kvs = KVSOptions::new(&PathBuf::from(db_dir)).create().unwrap();
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT);
+
+        let kvs = options.create().unwrap();
+
+        // a directory where the next WAL would go, so the flush can't start one
+        let blocked = db_dir.join(format!("{:06}.wal", kvs.core.lock_manifest().next_file_number()));
+
+        fs::create_dir(&blocked).unwrap();
+
+        // the put that fills the mem_table is in the old WAL
+        for i in 0..MAX_MEM_COUNT {
+            assert_eq!(Ok( () ), kvs.try_put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec()));
+        }
+
+        let error = kvs.background_error().expect("The store is read-only");
+
+        assert_eq!(Err(WriteError::ReadOnly(error)), kvs.try_put(b"KEY".to_vec(), b"VALUE".to_vec()));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| kvs.delete_range(&b"A".to_vec(), &b"Z".to_vec()))).is_err());
+        assert!(kvs.delete_prefix(&b"KEY".to_vec(), false).is_err());
+        assert!(kvs.close(true).is_err());
+
+        fs::remove_dir(blocked).unwrap();
+
+        let kvs = KVS::open(&db_dir).unwrap();
+
+        for i in 0..MAX_MEM_COUNT {
+            assert_eq!(Some(b"VALUE".to_vec()), kvs.get(&format!("KEY_{}", i).into_bytes()));
+        }
+
+        assert_eq!(None, kvs.get(&b"KEY".to_vec()));
     }
 
     #[test]
-    fn put_flush_get() {
+    fn health() {
         let db_dir = gen_dir();
-        let mut kvs = KVSOptions::new(&PathBuf::from(db_dir)).create().unwrap();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
 
-        let key = "KEY".as_bytes();
-        let value = "VALUE".as_bytes();
+        for i in 0..10 {
+            kvs.put(format!("KEY_{}", i).into_bytes(), b"VALUE".to_vec());
+        }
 
-        kvs.put(key.to_vec(), value.to_vec());
+        let health = kvs.health();
 
-        assert_eq!(kvs.count_estimate(), 1);
+        assert!(health.is_healthy());
+        assert_eq!(0, health.immutables);
+        assert!(health.wal_bytes > 0);
 
-        kvs.flush(false);
+        if cfg!(target_os = "linux") {
+            assert!(health.disk_free_bytes.is_some());
+        }
 
-        assert_eq!(kvs.count_estimate(), 1);
+        kvs.core.set_background_error(&IOError::new(ErrorKind::Other, "No space left on device"));
 
-        let ret = kvs.get(&key.to_vec());
+        let health = kvs.health();
 
-        assert_eq!(value, ret.unwrap().as_slice());
+        assert!(!health.is_healthy());
+        assert_eq!(Some("No space left on device".to_string()), health.background_error);
     }
 
     #[test]
-    fn auto_flush() {
+    fn min_free_space() {
+        struct SpaceListener(Mutex<Vec<DiskSpace>>);
+
+        impl EventListener for SpaceListener {
+            fn on_disk_space(&self, space: &DiskSpace) {
+                self.0.lock().unwrap().push(*space);
+            }
+        }
+
         let db_dir = gen_dir();
-        let mut kvs = KVSOptions::new(&PathBuf::from(db_dir)).create().unwrap();
+        let listener = Arc::new(SpaceListener(Mutex::new(vec![])));
+        let mut options = KVSOptions::new(&db_dir);
 
-        for _i in 0..MAX_MEM_COUNT+1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        // more than any disk has
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).min_free_space(u64::max_value()).event_listener(listener.clone());
 
-            kvs.put(key, value);
+        let kvs = options.create().unwrap();
+
+        // only the try writes are limited
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT * 2 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
         }
 
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT+1) as u64);
+        kvs.wait_for_flushes();
+
+        if cfg!(target_os = "linux") {
+            match kvs.try_put(b"KEY".to_vec(), b"VALUE".to_vec()) {
+                Err(WriteError::OutOfSpace(e)) => assert!(e.free_bytes < e.min_free_bytes),
+                other => panic!("Not out of space: {:?}", other)
+            }
+
+            // deletes free space
+            let mut batch = WriteBatch::new();
+
+            batch.delete(&b"KEY_00000".to_vec());
+            kvs.try_write(batch, &WriteOptions::new()).unwrap();
+
+            assert!(kvs.core.needs_compaction());
+            assert!(!kvs.core.run_compactions(&[CompactionReason::Size]).unwrap());
+
+            let events = listener.0.lock().unwrap();
+
+            assert_eq!(1, events.len());
+            assert!(events[0].low);
+        }
     }
 
     #[test]
-    fn compact() {
+    fn check() {
         let db_dir = gen_dir();
-        let mut kvs = KVSOptions::new(&PathBuf::from(db_dir)).create().unwrap();
+        let mut options = KVSOptions::new(&db_dir);
 
-        for _i in 0..MAX_MEM_COUNT*MAX_FILE_COUNT + 1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).wal_retention(2).check_on_open(true);
 
-            kvs.put(key, value);
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT * 2 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
         }
 
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
 
-        kvs.compact();
+        let report = kvs.check();
 
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+        assert!(report.is_consistent(), "{}", report);
+        assert!(report.tables > 1, "{}", report);
+        assert_eq!(3, report.wals);
+
+        // a retained WAL lost, and a table number handed out twice
+        let (retained, tables) = {
+            let manifest = kvs.core.lock_manifest();
+
+            (manifest.wal_file(manifest.retained_wal_numbers()[0]), manifest.table_paths())
+        };
+
+        fs::remove_file(&retained).unwrap();
+
+        let report = kvs.check();
+
+        assert!(report.problems.contains(&Inconsistency::MissingFile(retained.clone())), "{}", report);
+
+        kvs.close(false).unwrap();
+
+        match KVS::open(&db_dir) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => assert!(e.to_string().contains("missing"), "{}", e),
+            Err(e) => panic!("Not an inconsistency: {}", e),
+            Ok(_) => panic!("Opened an inconsistent store")
+        }
+
+        // without the option it opens, overlapping tables and all
+        let mut options = KVSOptions::new(&db_dir);
+
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).wal_retention(2);
+
+        let kvs = options.create().unwrap();
+
+        {
+            let mut manifest = kvs.core.lock_manifest();
+            let mut numbers = manifest.table_numbers().to_vec();
+
+            numbers.push(numbers[0]);
+            manifest.set_tables(numbers);
+        }
+
+        let report = kvs.check();
+
+        assert!(report.problems.iter().any(|p| match *p { Inconsistency::Overlap { ref first, ref second, .. } => first == second && *first == tables[0], _ => false }), "{}", report);
     }
 
     #[test]
-    fn compact2() {
+    fn max_wal_bytes() {
         let db_dir = gen_dir();
 
         {
-            let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
+            let mut options = KVSOptions::new(&db_dir);
+            options.mem_count(1_000_000).max_wal_bytes(8192);
+            let kvs = options.create().unwrap();
 
-            for _i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-                let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-                let key = format!("KEY_{}", rnd).as_bytes().to_vec();
-                let value = rnd.as_bytes().to_vec();
+            for i in 0..1000 {
+                kvs.put(format!("KEY_{:05}", i).as_bytes().to_vec(), format!("VALUE_{}", i).as_bytes().to_vec());
 
-                kvs.put(key, value);
+                // the full WALs are under the limit when they're swapped, and the active one reaches it at most
+                assert!(kvs.stats().wal_bytes < 2 * 8192 + 100, "{}", kvs.stats().wal_bytes);
             }
 
-            assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+            kvs.wait_for_flushes();
 
-            kvs.compact();
+            // flushed without ever filling a mem_table
+            let stats = kvs.stats();
 
-            assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+            assert_eq!(0, stats.immutables);
+            assert!(stats.mem_records < 1000);
+            assert!(stats.wal_bytes < 8192 + 100);
         }
 
-        let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
 
-        for _i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", rnd).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        for i in 0..1000 {
+            assert_eq!(Some(format!("VALUE_{}", i).into_bytes()), kvs.get(&format!("KEY_{:05}", i).into_bytes()));
+        }
+    }
 
-            kvs.put(key, value);
+    #[test]
+    fn compare_and_swap() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = Arc::new(options.create().unwrap());
+        let key = b"COUNTER".to_vec();
+
+        // claimed only while it isn't there
+        assert_eq!(Ok( () ), kvs.compare_and_swap(&key, None, Some(b"0")));
+        assert_eq!(Err(CompareFailed { current: Some(b"0".to_vec()) }), kvs.compare_and_swap(&key, None, Some(b"1")));
+
+        // every thread adds to the counter, retrying with the value it found
+        let threads = (0..4).map(|_| {
+            let (kvs, key) = (kvs.clone(), key.clone());
+
+            thread::spawn(move || {
+                let mut current = kvs.get(&key).unwrap();
+
+                for _ in 0..MAX_MEM_COUNT {
+                    loop {
+                        let count = String::from_utf8(current.clone()).unwrap().parse::<u64>().unwrap();
+                        let next = (count + 1).to_string().into_bytes();
+
+                        match kvs.compare_and_swap(&key, Some(&current), Some(&next)) {
+                            Ok( () ) => { current = next; break; },
+                            Err(CompareFailed { current: found }) => current = found.unwrap()
+                        }
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
         }
 
-        assert_eq!(kvs.count_estimate(), ((MAX_MEM_COUNT*MAX_FILE_COUNT+1)*2) as u64);
+        assert_eq!((4 * MAX_MEM_COUNT).to_string().into_bytes(), kvs.get(&key).unwrap());
 
-        kvs.compact();
+        // a delete, once the value is as expected
+        let last = kvs.get(&key).unwrap();
 
-        assert_eq!(kvs.count_estimate(), ((MAX_MEM_COUNT*MAX_FILE_COUNT+1)*2) as u64);
+        assert!(kvs.compare_and_swap(&key, Some(b"0"), None).is_err());
+        assert_eq!(Ok( () ), kvs.compare_and_swap(&key, Some(&last), None));
+        assert_eq!(None, kvs.get(&key));
     }
 
     #[test]
-    fn delete() {
+    fn increment() {
         let db_dir = gen_dir();
-        let mut kvs = KVSOptions::new(&PathBuf::from(db_dir)).create().unwrap();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = Arc::new(options.create().unwrap());
+        let key = b"COUNTER".to_vec();
 
-        let key = "KEY".as_bytes();
-        let value = "VALUE".as_bytes();
+        assert_eq!(Ok(-2), kvs.increment(&key, -2));
+        assert_eq!(Some(encode_counter(-2)), kvs.get(&key));
 
-        kvs.put(key.to_vec(), value.to_vec());
+        let threads = (0..4).map(|_| {
+            let (kvs, key) = (kvs.clone(), key.clone());
 
-        assert_eq!(kvs.count_estimate(), 1);
+            thread::spawn(move || {
+                for _ in 0..MAX_MEM_COUNT {
+                    kvs.increment(&key, 1).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
 
-        kvs.delete(&key.to_vec());
+        for thread in threads {
+            thread.join().unwrap();
+        }
 
-        // should still be only 1 record
-        assert_eq!(kvs.count_estimate(), 1);
+        assert_eq!(Some(4 * MAX_MEM_COUNT as i64 - 2), decode_counter(&kvs.get(&key).unwrap()));
 
-        assert!(kvs.get(&key.to_vec()).is_none(), "Found key after deleting it!");
+        kvs.put(key.clone(), encode_counter(i64::max_value()));
 
-        kvs.flush(false);
+        assert_eq!(Err(IncrementError::Overflow(i64::max_value())), kvs.increment(&key, 1));
 
-        // should still be only 1 record
-        assert_eq!(kvs.count_estimate(), 1);
+        kvs.put(key.clone(), b"1".to_vec());
 
-        assert!(kvs.get(&key.to_vec()).is_none(), "Found key after deleting it!");
+        assert_eq!(Err(IncrementError::NotACounter(b"1".to_vec())), kvs.increment(&key, 1));
     }
 
     #[test]
-    fn put_close_open_get() {
+    fn keys_only() {
         let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
 
-        {
-            let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
+        // in the SSTables, and the mem_table
+        for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), format!("VALUE_{}", i).into_bytes());
+        }
 
-            // fill half the mem_table, so we're sure we don't flush
-            for i in 0..MAX_MEM_COUNT / 2 {
-                let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-                let key = format!("KEY_{}", i).as_bytes().to_vec();
-                let value = rnd.as_bytes().to_vec();
+        kvs.delete(&format!("KEY_{:05}", 5).into_bytes());
+        kvs.wait_for_flushes();
 
-                kvs.put(key, value);
-            }
-        }
+        let mut read_options = ReadOptions::new();
+        read_options.keys_only(true);
 
-        {
-            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+        let keys = kvs.iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let pairs = kvs.range_with_options(&b"KEY_".to_vec(), &b"KEY_\xff".to_vec(), &read_options).collect::<Vec<_>>();
 
-            for i in 0..MAX_MEM_COUNT / 2 {
-                let key = format!("KEY_{}", i).as_bytes().to_vec();
+        assert_eq!(MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 - 1, pairs.len());
+        assert_eq!(keys, pairs.iter().map(|&(ref k, _)| k.clone()).collect::<Vec<_>>());
+        assert!(pairs.iter().all(|&(_, ref v)| v.is_empty()));
 
-                assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
-            }
+        // gets still read the values
+        assert_eq!(Some(b"VALUE_7".to_vec()), kvs.get_with_options(&format!("KEY_{:05}", 7).into_bytes(), &read_options));
+    }
+
+    #[test]
+    fn count_aggregate_range() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), encode_counter(i as i64));
         }
 
-        {
-            let kvs = KVSOptions::new(&db_dir).create().unwrap();
+        kvs.put(b"KEY_00010".to_vec(), b"TEN".to_vec());
+        kvs.delete(&b"KEY_00011".to_vec());
 
-            for i in 0..MAX_MEM_COUNT / 2 {
-                let key = format!("KEY_{}", i).as_bytes().to_vec();
+        let (start, end) = (b"KEY_00010".to_vec(), b"KEY_00020".to_vec());
 
-                assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
-            }
-        }
+        assert_eq!(9, kvs.count_range(&start, &end));
+        assert_eq!((MAX_MEM_COUNT * 2 + MAX_MEM_COUNT / 2 - 1) as u64, kvs.count_range(&b"KEY_".to_vec(), &b"KEY_\xff".to_vec()));
+        assert_eq!(0, kvs.count_range(&end, &start));
+
+        assert_eq!(Aggregate { count: 8, sum: (12..20).sum(), min: Some(12), max: Some(19), skipped: 1 }, kvs.aggregate_range(&start, &end));
+        assert_eq!(Aggregate::default(), kvs.aggregate_range(&b"OTHER".to_vec(), &b"OTHER\xff".to_vec()));
     }
 
     #[test]
-    fn put_compact_get() {
+    fn sample_keys() {
         let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT).group_count(100);
+        let kvs = options.create().unwrap();
 
-        let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
-
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        assert!(kvs.sample_keys(10).unwrap().is_empty());
 
-            kvs.put(key, value);
+        for i in 0..MAX_MEM_COUNT * 50 {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"VALUE".to_vec());
         }
 
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+        kvs.wait_for_flushes();
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
+        let samples = kvs.sample_keys(10).unwrap();
 
-            assert!(kvs.get(&key).is_some(), "Couldn't find key: {}", i);
+        // about evenly spread over the keys
+        assert!(samples.len() >= 8 && samples.len() <= 10, "{:?}", samples);
+        assert!(samples.windows(2).all(|w| w[0] < w[1]));
+
+        for (i, key) in samples.iter().enumerate() {
+            let n = String::from_utf8(key[4..].to_vec()).unwrap().parse::<usize>().unwrap();
+
+            assert!(n + 1000 >= i * 500 && n <= i * 500 + 1000, "{:?}", samples);
         }
     }
 
     #[test]
-    fn put_compact_update_get() {
-        let db_dir = gen_dir();
+    fn ingest_external() {
+        let (src_dir, dst_dir) = (gen_dir(), gen_dir());
+        let mut options = KVSOptions::new(&src_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let src = options.create().unwrap();
+        let mut options = KVSOptions::new(&dst_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let dst = options.create().unwrap();
+
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            src.put(format!("a/{:05}", i).into_bytes(), format!("VALUE_{}", i).into_bytes());
+            dst.put(format!("b/{:05}", i).into_bytes(), format!("VALUE_{}", i).into_bytes());
+        }
 
-        let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
+        for kvs in [&src, &dst].iter() {
+            kvs.wait_for_flushes();
+            kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
+        }
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        let paths = src.core.state.read().unwrap().sstables.iter().map(|table| table.file_path()).collect::<Vec<_>>();
 
-            kvs.put(key, value);
-        }
+        // a key written since the last compaction would be newer than the table's, even a delete
+        {
+            let other_dir = gen_dir();
+            let other = KVSOptions::new(&other_dir).create().unwrap();
 
-        // compact would have happened here
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+            other.delete(&b"a/00010".to_vec());
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
-            let value = format!("VALUE_{}", i).as_bytes().to_vec();
+            let err = other.ingest_external(&paths, &IngestOptions::new()).unwrap_err();
 
-            kvs.put(key, value); // update our keys
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+            assert!(other.core.state.read().unwrap().sstables.is_empty());
+            assert!(other.check().unreferenced.is_empty());
         }
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
+        assert_eq!((MAX_MEM_COUNT * MAX_FILE_COUNT) as u64, dst.ingest_external(&paths, &IngestOptions::new()).unwrap());
+        assert_eq!(MAX_FILE_COUNT * 2, dst.core.state.read().unwrap().sstables.len());
+        assert_eq!(Some(b"VALUE_10".to_vec()), dst.get(&b"a/00010".to_vec()));
 
-            let ret = kvs.get(&key);
-            assert!(ret.is_some(), "Couldn't find key: {}", i);
-            assert_eq!(ret.unwrap(), format!("VALUE_{}", i).as_bytes().to_vec(), "Didn't get update for key: {}", i);
-        }
+        // they're copied, and now overlap the ones in the store
+        assert!(paths.iter().all(|path| path.exists()));
+        assert_eq!(ErrorKind::InvalidInput, dst.ingest_external(&paths[..1], &IngestOptions::new()).unwrap_err().kind());
+
+        let report = dst.check();
+
+        assert!(report.is_consistent(), "{}", report);
+
+        // a table written on its own is moved in, unless it has deletes
+        let write_table = |name: &str, records: Vec<Record>| {
+            let path = src_dir.join(name);
+
+            let cache = CacheOptions { size: 100, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
+
+            SSTable::new(NewSSTable::new(&path, 1, &dst.core.options.sstable_options(), 4096, cache), &mut records.iter()).unwrap();
+
+            path
+        };
+
+        let deletes = write_table("deletes.sst", vec![Record::new(b"c/1".to_vec(), None), Record::new(b"c/2".to_vec(), Some(b"VALUE".to_vec()))]);
+
+        assert_eq!(ErrorKind::InvalidInput, dst.ingest_external(&[deletes.clone()], &IngestOptions::new()).unwrap_err().kind());
+
+        let puts = write_table("puts.sst", vec![Record::new(b"c/1".to_vec(), Some(b"VALUE".to_vec()))]);
+
+        assert_eq!(1, dst.ingest_external(&[puts.clone()], IngestOptions::new().move_files(true)).unwrap());
+        assert!(!puts.exists());
+
+        // a moved table that can't be added is put back as it was
+        dst.put(b"d/2".to_vec(), b"VALUE".to_vec());
+
+        let hidden = write_table("hidden.sst", vec![Record::new(b"d/1".to_vec(), Some(b"VALUE".to_vec())), Record::new(b"d/3".to_vec(), Some(b"VALUE".to_vec()))]);
+
+        assert_eq!(ErrorKind::InvalidInput, dst.ingest_external(&[hidden.clone()], IngestOptions::new().move_files(true)).unwrap_err().kind());
+        assert_eq!(1, SSTable::open(&hidden, 4096, CacheOptions { size: 100, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None }).unwrap().id());
+
+        dst.close(false).unwrap();
+
+        let dst = KVS::open(&dst_dir).unwrap();
+        let report = dst.check();
+
+        assert!(report.is_consistent(), "{}", report);
+        assert_eq!(Some(b"VALUE_599".to_vec()), dst.get(&b"a/00599".to_vec()));
+        assert_eq!(Some(b"VALUE_0".to_vec()), dst.get(&b"b/00000".to_vec()));
+        assert_eq!(Some(b"VALUE".to_vec()), dst.get(&b"c/1".to_vec()));
     }
 
     #[test]
-    fn put_compact_delete_get() {
+    fn export_live_files() {
         let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(MAX_MEM_COUNT).file_count(MAX_FILE_COUNT);
+        let kvs = options.create().unwrap();
 
-        let mut kvs = KVSOptions::new(&db_dir).create().unwrap();
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), format!("VALUE_{}", i).into_bytes());
+        }
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let rnd: String = thread_rng().gen_ascii_chars().take(6).collect();
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
-            let value = rnd.as_bytes().to_vec();
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
 
-            kvs.put(key, value);
+        // one more flush, into the current SSTable
+        for i in 0..MAX_MEM_COUNT {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"NEWER".to_vec());
         }
 
-        // compact would have happened here
-        assert_eq!(kvs.count_estimate(), (MAX_MEM_COUNT*MAX_FILE_COUNT + 1) as u64);
+        kvs.wait_for_flushes();
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
+        let live = kvs.export_live_files();
 
-            kvs.delete(&key); // delete our key
+        assert_eq!(MAX_FILE_COUNT + 1, live.files.len());
+        assert_eq!(0, live.files[0].level);
+        assert_eq!((MAX_MEM_COUNT * (MAX_FILE_COUNT + 1)) as u64, live.files.iter().map(|f| f.record_count).sum::<u64>());
+        assert!(live.files[1..].windows(2).all(|w| w[0].largest_key < w[1].smallest_key));
+        assert!(live.files.iter().all(|f| f.oldest_ts <= f.newest_ts && f.file_bytes != 0));
+
+        // a compaction replaces the files, but they're kept for the export
+        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT {
+            kvs.put(format!("KEY_{:05}", i).into_bytes(), b"NEWEST".to_vec());
         }
 
-        // compact would happen here
+        kvs.wait_for_flushes();
+        kvs.core.run_compactions(&[CompactionReason::Size]).unwrap();
 
-        for i in 0..MAX_MEM_COUNT * MAX_FILE_COUNT + 1 {
-            let key = format!("KEY_{}", i).as_bytes().to_vec();
+        assert!(live.files.iter().all(|f| f.path.exists()));
 
-            let ret = kvs.get(&key);
-            assert!(ret.is_none(), "Found deleted key: {}", i);
-        }
-    }
+        // what the export had of the level 1 tables
+        let other_dir = gen_dir();
+        let other = KVSOptions::new(&other_dir).create().unwrap();
 
+        assert_eq!((MAX_MEM_COUNT * MAX_FILE_COUNT) as u64, other.ingest_external(&live.ingestable_paths(), &IngestOptions::new()).unwrap());
+        assert_eq!(Some(b"VALUE_0".to_vec()), other.get(&b"KEY_00000".to_vec()));
 
+        let paths = live.files.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+
+        drop(live);
+
+        // the next save of the manifest removes them
+        kvs.put(b"KEY_X".to_vec(), b"VALUE".to_vec());
+        kvs.core.flush(false);
+
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
 }
@@ -4,29 +4,108 @@
 #[macro_use]
 extern crate log;
 
+extern crate bincode;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 extern crate byteorder;
+extern crate crc32fast;
+extern crate crossbeam_skiplist;
 extern crate itertools;
+extern crate libc;
 extern crate lru_cache;
+#[cfg(feature = "parquet")]
+extern crate parquet;
 extern crate positioned_io;
 extern crate regex;
+#[cfg(feature = "tls")]
+extern crate rustls;
 extern crate rmp_serde as rmps;
 extern crate serde;
+extern crate serde_cbor;
+#[cfg(feature = "http")]
+extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+extern crate toml;
+extern crate zstd;
 
 
 // these are for tests
 #[cfg(test)] extern crate simple_logger;
 #[cfg(test)] extern crate rand;
+#[cfg(test)] extern crate proptest;
+#[cfg(test)] extern crate tempfile;
 
 mod record_file;
 mod sstable;
 mod record;
 mod serde_utils;
+mod compression;
+mod bloom;
+mod table_cache;
+mod cache;
+mod meta_cache;
+mod row_cache;
+mod manifest;
+mod mem_table;
+mod lock_manager;
+mod version;
+mod events;
+mod stats;
+mod check;
+mod quota;
+mod merkle;
+mod hot_keys;
+mod compaction_hook;
+mod executor;
+mod clock;
+mod conflict_resolver;
+mod ttl;
+mod codec;
+mod wal_archive;
+mod value_log;
+mod sim;
+#[cfg(test)] mod test_path;
 
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+
+#[cfg(feature = "timeseries")]
+pub mod timeseries;
+
+#[cfg(feature = "http")]
+pub mod http;
+
+#[cfg(feature = "http")]
+pub mod acl;
+
+#[cfg(feature = "http")]
+pub mod client;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(feature = "parquet")]
+mod parquet_io;
+
+pub mod format;
 pub mod kvs;
 
-pub use kvs::{KVSOptions, KVS};
+pub use kvs::{KVSOptions, KVS, ReadOptions, Snapshot, WriteOptions, WriteBatch, Transaction, TransactionOptions, Conflict, CompareFailed, IncrementError, encode_counter, decode_counter, DeadlineExceeded, ReadError, BackgroundError, WriteError, OutOfSpace, ChangeStream, Change, ChangeOp, RestorePoint, Page, Aggregate, IngestOptions, LiveFiles, LiveFile, RawEntry, RawIter};
+pub use events::{EventListener, FlushInfo, CompactionStats, WriteStall, DiskSpace};
+pub use stats::{StoreStats, LevelStats, CacheStats, Health};
+pub use check::{CheckReport, Inconsistency};
+pub use quota::{Quota, QuotaUsage, QuotaExceeded};
+pub use ttl::PrefixTtl;
+pub use merkle::{MerkleTree, MerkleIndex, RepairStats, AntiEntropy, repair, repair_indexed};
+pub use mem_table::MemTableKind;
+pub use cache::{CachePolicy, CachePolicyKind};
+pub use compaction_hook::CompactionHook;
+pub use executor::{Executor, ThreadPool};
+pub use clock::{Clock, SystemClock, ManualClock, HybridLogicalClock};
+pub use conflict_resolver::{ConflictResolver, ReplicaConflict, Resolution};
+pub use codec::CodecKind;
+pub use sstable::{SSTable, SalvageReport};
 
 use std::mem;
 
@@ -0,0 +1,109 @@
+//
+// Per-key locks for pessimistic transactions
+// The keys are spread over stripes, each with its own mutex, so transactions on different keys rarely wait on each other.
+// There's no deadlock detection; a transaction that waits too long for a lock gives up.
+//
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+struct Stripe {
+    owners: Mutex<HashMap<Vec<u8>, u64>>, // the transaction holding the lock on each key
+    released: Condvar
+}
+
+pub struct LockManager {
+    stripes: Vec<Stripe>
+}
+
+impl LockManager {
+    pub fn new(stripe_count: usize) -> LockManager {
+        LockManager { stripes: (0..stripe_count).map(|_| Stripe { owners: Mutex::new(HashMap::new()), released: Condvar::new() }).collect() }
+    }
+
+    fn stripe(&self, key: &[u8]) -> &Stripe {
+        let mut hasher = DefaultHasher::new();
+
+        key.hash(&mut hasher);
+
+        &self.stripes[hasher.finish() as usize % self.stripes.len()]
+    }
+
+    /// Locks the key for the transaction, waiting up to the timeout for another transaction to unlock it
+    /// return: false if it timed out, which is how deadlocks are broken
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> bool {
+        let stripe = self.stripe(key);
+        let deadline = Instant::now() + timeout;
+        let mut owners = stripe.owners.lock().unwrap();
+
+        loop {
+            match owners.get(key) {
+                Some(&owner) if owner != txn_id => (),
+                _ => break
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                debug!("Timed out locking key for transaction {}: {:?}", txn_id, key);
+                return false;
+            }
+
+            owners = stripe.released.wait_timeout(owners, deadline - now).unwrap().0;
+        }
+
+        owners.insert(key.to_vec(), txn_id);
+
+        true
+    }
+
+    /// Unlocks the key, if the transaction holds the lock
+    ///
+    /// Called when a transaction is dropped, so it doesn't panic on a poisoned stripe.
+    pub fn unlock(&self, txn_id: u64, key: &[u8]) {
+        let stripe = self.stripe(key);
+        let mut owners = stripe.owners.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if owners.get(key) == Some(&txn_id) {
+            owners.remove(key);
+            stripe.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lock_manager::LockManager;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn lock_unlock_timeout() {
+        let locks = Arc::new(LockManager::new(4));
+
+        assert!(locks.lock(1, b"KEY_1", Duration::from_millis(10)));
+        assert!(locks.lock(1, b"KEY_1", Duration::from_millis(10))); // already held
+        assert!(locks.lock(2, b"KEY_2", Duration::from_millis(10)));
+        assert!(!locks.lock(2, b"KEY_1", Duration::from_millis(10)));
+
+        locks.unlock(2, b"KEY_1"); // not the owner, so nothing happens
+
+        assert!(!locks.lock(2, b"KEY_1", Duration::from_millis(10)));
+
+        // another thread waits for the lock to be released
+        let waiter = {
+            let locks = locks.clone();
+
+            thread::spawn(move || locks.lock(3, b"KEY_1", Duration::from_secs(10)))
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        locks.unlock(1, b"KEY_1");
+
+        assert!(waiter.join().unwrap());
+    }
+}
@@ -0,0 +1,362 @@
+//
+// The manifest is the source of truth for which files make up the store
+// Every file gets a unique, increasing, number from the manifest. Files are only removed after
+// a new version of the manifest no longer references them, so a crash at any point leaves
+// the store pointing at a complete set of files.
+//
+
+use regex::Regex;
+use rmps::encode::to_vec;
+use rmps::decode::from_slice;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use record_file::RecordFile;
+use sim::{self, CrashPoint};
+
+const MANIFEST_HEADER: &[u8; 8] = b"MANI\x01\x00\x00\x00";
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_NEW_FILE: &str = "MANIFEST-new";
+
+// the manifest is small, and only read once
+const BUFFER_SIZE: usize = 4096;
+const CACHE_SIZE: usize = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ManifestState {
+    next_file_number: u64,
+    wal_number: u64,      // the WAL for the mem_table
+    current_number: u64,  // the current SSTable, with the merges from the mem_table
+    table_numbers: Vec<u64>, // the SSTables without overlapping ranges
+    #[serde(default)]
+    immutable_wal_numbers: Vec<u64>, // the WALs of mem_tables still being flushed, oldest first
+    #[serde(default)]
+    retained_wal_numbers: Vec<u64>,  // flushed WALs kept for change streams, oldest first
+    #[serde(default)]
+    wal_first_seqs: BTreeMap<u64, u64>, // the sequence number of the first record of each WAL
+    #[serde(default)]
+    value_log_numbers: Vec<u64>      // the files of the value log, the newest is appended to
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    db_dir: PathBuf,
+    state: ManifestState
+}
+
+impl Manifest {
+    /// Opens the manifest in the directory, or creates a new one if there isn't one
+    pub fn open(db_dir: &PathBuf) -> Result<Manifest, IOError> {
+        let path = db_dir.join(MANIFEST_FILE);
+
+        if path.exists() {
+            let rec_file = RecordFile::new(&path, MANIFEST_HEADER, BUFFER_SIZE, CACHE_SIZE)?;
+            let state :ManifestState = from_slice(&rec_file.last_record()?).expect("Error decoding manifest");
+
+            debug!("Opened manifest: {:?}", state);
+
+            return Ok(Manifest { db_dir: db_dir.to_path_buf(), state: state });
+        }
+
+        let manifest = Manifest {
+            db_dir: db_dir.to_path_buf(),
+            state: ManifestState { next_file_number: 3, wal_number: 1, current_number: 2, table_numbers: vec![], immutable_wal_numbers: vec![], retained_wal_numbers: vec![], wal_first_seqs: BTreeMap::new(), value_log_numbers: vec![] }
+        };
+
+        manifest.save()?;
+
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a new file, then renames it over the old one
+    pub fn save(&self) -> Result<(), IOError> {
+        let new_path = self.db_dir.join(MANIFEST_NEW_FILE);
+
+        // left over from a crash during a save
+        if new_path.exists() {
+            fs::remove_file(&new_path)?;
+        }
+
+        {
+            let mut rec_file = RecordFile::new(&new_path, MANIFEST_HEADER, BUFFER_SIZE, CACHE_SIZE)?;
+
+            rec_file.append(&to_vec(&self.state).expect("Error serializing manifest"))?;
+            rec_file.sync()?;
+        }
+
+        sim::crash_point(CrashPoint::ManifestWritten);
+
+        let path = self.db_dir.join(MANIFEST_FILE);
+
+        // replaces MANIFEST on Windows too, as long as nothing has it open
+        fs::rename(&new_path, &path)?;
+        sim::on_rename(&new_path, &path);
+        sync_dir(&self.db_dir)?;
+
+        debug!("Saved manifest: {:?}", self.state);
+
+        Ok( () )
+    }
+
+    /// Allocates a new file number; it's persisted with the next save
+    pub fn new_file_number(&mut self) -> u64 {
+        let ret = self.state.next_file_number;
+
+        self.state.next_file_number += 1;
+
+        ret
+    }
+
+    /// The number the next new file will get
+    pub fn next_file_number(&self) -> u64 {
+        self.state.next_file_number
+    }
+
+    pub fn wal_number(&self) -> u64 {
+        self.state.wal_number
+    }
+
+    pub fn immutable_wal_numbers(&self) -> &[u64] {
+        &self.state.immutable_wal_numbers
+    }
+
+    pub fn wal_path(&self) -> PathBuf {
+        Manifest::wal_file_path(&self.db_dir, self.state.wal_number)
+    }
+
+    pub fn current_path(&self) -> PathBuf {
+        Manifest::table_file_path(&self.db_dir, self.state.current_number)
+    }
+
+    pub fn table_paths(&self) -> Vec<PathBuf> {
+        self.state.table_numbers.iter().map(|n| Manifest::table_file_path(&self.db_dir, *n)).collect()
+    }
+
+    /// The path to the SSTable with the given number
+    pub fn table_path(&self, number: u64) -> PathBuf {
+        Manifest::table_file_path(&self.db_dir, number)
+    }
+
+    /// The path to the WAL file with the given number
+    pub fn wal_file(&self, number: u64) -> PathBuf {
+        Manifest::wal_file_path(&self.db_dir, number)
+    }
+
+    /// The path to the value log file with the given number
+    pub fn value_log_path(&self, number: u64) -> PathBuf {
+        self.db_dir.join(format!("{:06}.vlog", number))
+    }
+
+    fn table_file_path(db_dir: &PathBuf, number: u64) -> PathBuf {
+        db_dir.join(format!("{:06}.sst", number))
+    }
+
+    fn wal_file_path(db_dir: &PathBuf, number: u64) -> PathBuf {
+        db_dir.join(format!("{:06}.wal", number))
+    }
+
+    pub fn set_wal(&mut self, number: u64) {
+        self.state.wal_number = number;
+    }
+
+    /// Keeps a WAL after it's replaced, until the mem_table it holds is flushed
+    pub fn add_immutable_wal(&mut self, number: u64) {
+        self.state.immutable_wal_numbers.push(number);
+    }
+
+    pub fn remove_immutable_wal(&mut self, number: u64) {
+        self.state.immutable_wal_numbers.retain(|n| *n != number);
+    }
+
+    /// Keeps a flushed WAL for change streams, dropping the oldest ones past the count
+    /// return: the numbers of the WALs dropped, with their first sequence numbers
+    pub fn retain_wal(&mut self, number: u64, count: usize) -> Vec<(u64, Option<u64>)> {
+        let mut dropped = Vec::new();
+
+        self.state.retained_wal_numbers.push(number);
+
+        while self.state.retained_wal_numbers.len() > count {
+            let oldest = self.state.retained_wal_numbers.remove(0);
+
+            dropped.push( (oldest, self.state.wal_first_seqs.remove(&oldest)) );
+        }
+
+        dropped
+    }
+
+    pub fn retained_wal_numbers(&self) -> &[u64] {
+        &self.state.retained_wal_numbers
+    }
+
+    /// The sequence number of the first record of the WAL, if it's known
+    pub fn wal_first_seq(&self, number: u64) -> Option<u64> {
+        self.state.wal_first_seqs.get(&number).cloned()
+    }
+
+    pub fn set_wal_first_seq(&mut self, number: u64, seq: u64) {
+        self.state.wal_first_seqs.insert(number, seq);
+    }
+
+    pub fn current_number(&self) -> u64 {
+        self.state.current_number
+    }
+
+    pub fn set_current(&mut self, number: u64) {
+        self.state.current_number = number;
+    }
+
+    pub fn table_numbers(&self) -> &[u64] {
+        &self.state.table_numbers
+    }
+
+    pub fn set_tables(&mut self, numbers: Vec<u64>) {
+        self.state.table_numbers = numbers;
+    }
+
+    pub fn value_log_numbers(&self) -> &[u64] {
+        &self.state.value_log_numbers
+    }
+
+    pub fn add_value_log(&mut self, number: u64) {
+        self.state.value_log_numbers.push(number);
+    }
+
+    pub fn remove_value_log(&mut self, number: u64) {
+        self.state.value_log_numbers.retain(|n| *n != number);
+    }
+
+    /// The numbered files in the directory that the manifest doesn't reference
+    pub fn obsolete_files(&self) -> Result<Vec<PathBuf>, IOError> {
+        let re = Regex::new(r"^(\d+)\.(sst|wal|vlog)$").unwrap();
+        let mut live = self.state.table_numbers.iter().cloned().collect::<HashSet<_>>();
+        let mut obsolete = vec![];
+
+        live.insert(self.state.wal_number);
+        live.extend(self.state.immutable_wal_numbers.iter().cloned());
+        live.extend(self.state.retained_wal_numbers.iter().cloned());
+        live.extend(self.state.value_log_numbers.iter().cloned());
+        live.insert(self.state.current_number);
+
+        for entry in fs::read_dir(&self.db_dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                continue
+            }
+
+            let file_name = path.file_name().expect("Error getting file name").to_str().expect("Error getting string for file name").to_owned();
+
+            if let Some(capture) = re.captures(&file_name) {
+                let number = capture.get(1).expect("Error capturing file number").as_str().parse::<u64>().expect("Error parsing number");
+
+                if !live.contains(&number) {
+                    obsolete.push(path);
+                }
+            }
+        }
+
+        Ok(obsolete)
+    }
+
+    /// Removes all the numbered files in the directory that the manifest doesn't reference
+    /// * pinned - files that are still being read, and are left for a later call
+    pub fn remove_obsolete_files(&self, pinned: &HashSet<PathBuf>) -> Result<(), IOError> {
+        for path in self.obsolete_files()? {
+            if !pinned.contains(&path) {
+                debug!("Removing obsolete file: {:?}", path);
+
+                match fs::remove_file(&path) {
+                    Ok(()) => sim::on_remove(&path),
+                    // Windows doesn't remove files that are still open, such as by a reader that's finishing up
+                    Err(ref e) if cfg!(windows) && e.kind() == ErrorKind::PermissionDenied => {
+                        warn!("Leaving {:?} for a later removal, as it's still open", path);
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+/// Syncs a directory, so the files created, renamed, or linked in it survive a crash
+///
+/// Windows can't open a directory as a file to sync it, so there it's left to the file system.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<(), IOError> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<(), IOError> {
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use manifest::Manifest;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use test_path::gen_dir;
+
+    #[test]
+    fn save_open() {
+        let db_dir = gen_dir();
+
+        {
+            let mut manifest = Manifest::open(&db_dir).unwrap();
+
+            let wal = manifest.new_file_number();
+            let table = manifest.new_file_number();
+
+            manifest.add_immutable_wal(1);
+            manifest.retain_wal(2, 1);
+            manifest.set_wal_first_seq(wal, 10);
+            manifest.set_wal(wal);
+            manifest.set_tables(vec![table]);
+            manifest.add_value_log(table + 1);
+            manifest.save().unwrap();
+        }
+
+        let mut manifest = Manifest::open(&db_dir).unwrap();
+
+        assert_eq!(db_dir.join("000003.wal"), manifest.wal_path());
+        assert_eq!(&[1], manifest.immutable_wal_numbers());
+        assert_eq!(&[2], manifest.retained_wal_numbers());
+        assert_eq!(Some(10), manifest.wal_first_seq(3));
+        assert_eq!(vec![db_dir.join("000004.sst")], manifest.table_paths());
+        assert_eq!(&[5], manifest.value_log_numbers());
+        assert_eq!(db_dir.join("000005.vlog"), manifest.value_log_path(5));
+        assert_eq!(5, manifest.new_file_number());
+    }
+
+    #[test]
+    fn remove_obsolete_files() {
+        let db_dir = gen_dir();
+        let manifest = Manifest::open(&db_dir).unwrap();
+
+        for name in ["000001.wal", "000002.sst", "000003.sst", "000004.wal", "000005.vlog", "other.sst"].iter() {
+            File::create(db_dir.join(name)).unwrap();
+        }
+
+        let mut pinned = HashSet::new();
+        pinned.insert(db_dir.join("000003.sst"));
+
+        manifest.remove_obsolete_files(&pinned).unwrap();
+
+        assert!(db_dir.join("000001.wal").exists());
+        assert!(db_dir.join("000002.sst").exists());
+        assert!(db_dir.join("000003.sst").exists());
+        assert!(!db_dir.join("000004.wal").exists());
+        assert!(!db_dir.join("000005.vlog").exists());
+        assert!(db_dir.join("other.sst").exists());
+
+        manifest.remove_obsolete_files(&HashSet::new()).unwrap();
+
+        assert!(!db_dir.join("000003.sst").exists());
+    }
+}
@@ -0,0 +1,441 @@
+//
+// The records written since the last flush, and the WAL they're journaled to
+// The records are kept by one of the MemTable implementations, picked with KVSOptions::mem_table.
+// A snapshot reads a mem_table through a MemTableView, which sees it as it was when the view was made:
+// while there are views, each insert keeps the record it replaced, until no view is old enough to need it.
+//
+
+use crossbeam_skiplist::SkipMap;
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::Bound;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use record::Record;
+
+/// The kinds of mem_tables, see `KVSOptions::mem_table`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemTableKind {
+    SkipList, // reads never wait on writes
+    BTree,    // reads and writes take turns
+    Hash      // fastest gets, but the records are sorted for every flush or scan
+}
+
+/// Keeps the records of a mem_table, by key; range deletes are kept apart by `WalMemTable`
+pub trait MemTable: Send + Sync {
+    /// Adds a record, replacing any record with the same key
+    fn insert(&self, rec: Record);
+
+    fn get(&self, key: &[u8]) -> Option<Record>;
+
+    /// Returns the record with the smallest key that's not before the given one
+    fn get_ge(&self, key: &[u8]) -> Option<Record>;
+
+    /// Returns all the records, in key order
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=Record> + 'a>;
+
+    /// Returns the records from the given key on, in key order
+    fn iter_from<'a>(&'a self, key: &[u8]) -> Box<Iterator<Item=Record> + 'a>;
+
+    fn len(&self) -> usize;
+
+    /// The approximate number of bytes of the records
+    fn approx_size(&self) -> usize;
+}
+
+pub fn new_mem_table(kind: MemTableKind) -> Box<MemTable> {
+    match kind {
+        MemTableKind::SkipList => Box::new(SkipListMemTable::default()),
+        MemTableKind::BTree => Box::new(BTreeMemTable::default()),
+        MemTableKind::Hash => Box::new(HashMemTable::default())
+    }
+}
+
+#[derive(Default)]
+pub struct SkipListMemTable {
+    records: SkipMap<Vec<u8>, Record>,
+    size: AtomicUsize // replaced records are still counted
+}
+
+impl MemTable for SkipListMemTable {
+    fn insert(&self, rec: Record) {
+        self.size.fetch_add(rec.size() as usize, Ordering::Relaxed);
+        self.records.insert(rec.key().to_vec(), rec);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Record> {
+        self.records.get(key).map(|entry| entry.value().to_owned())
+    }
+
+    fn get_ge(&self, key: &[u8]) -> Option<Record> {
+        self.records.lower_bound(Bound::Included(key)).map(|entry| entry.value().to_owned())
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=Record> + 'a> {
+        Box::new(self.records.iter().map(|entry| entry.value().to_owned()))
+    }
+
+    fn iter_from<'a>(&'a self, key: &[u8]) -> Box<Iterator<Item=Record> + 'a> {
+        Box::new(self.records.range(key.to_vec()..).map(|entry| entry.value().to_owned()))
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn approx_size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+}
+
+/// Records kept behind a lock with their size, for the map based mem_tables
+#[derive(Default)]
+struct Locked<M> {
+    records: M,
+    size: usize
+}
+
+#[derive(Default)]
+pub struct BTreeMemTable {
+    inner: RwLock<Locked<BTreeMap<Vec<u8>, Record>>>
+}
+
+impl MemTable for BTreeMemTable {
+    fn insert(&self, rec: Record) {
+        let mut inner = self.inner.write().unwrap();
+
+        inner.size += rec.size() as usize;
+
+        if let Some(old) = inner.records.insert(rec.key().to_vec(), rec) {
+            inner.size -= old.size() as usize;
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Record> {
+        self.inner.read().unwrap().records.get(key).cloned()
+    }
+
+    fn get_ge(&self, key: &[u8]) -> Option<Record> {
+        self.inner.read().unwrap().records.range::<[u8], _>((Bound::Included(key), Bound::Unbounded)).next().map(|(_, rec)| rec.clone())
+    }
+
+    /// Copies the records, so writes aren't held up by the iterator
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=Record> + 'a> {
+        let records = self.inner.read().unwrap().records.values().cloned().collect::<Vec<_>>();
+
+        Box::new(records.into_iter())
+    }
+
+    /// Copies the records from the key on, like `iter`
+    fn iter_from<'a>(&'a self, key: &[u8]) -> Box<Iterator<Item=Record> + 'a> {
+        let records = self.inner.read().unwrap().records.range::<[u8], _>((Bound::Included(key), Bound::Unbounded)).map(|(_, rec)| rec.clone()).collect::<Vec<_>>();
+
+        Box::new(records.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().records.len()
+    }
+
+    fn approx_size(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+}
+
+/// Only good at point lookups; the records are sorted every time they're iterated over
+#[derive(Default)]
+pub struct HashMemTable {
+    inner: RwLock<Locked<HashMap<Vec<u8>, Record>>>
+}
+
+impl MemTable for HashMemTable {
+    fn insert(&self, rec: Record) {
+        let mut inner = self.inner.write().unwrap();
+
+        inner.size += rec.size() as usize;
+
+        if let Some(old) = inner.records.insert(rec.key().to_vec(), rec) {
+            inner.size -= old.size() as usize;
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Record> {
+        self.inner.read().unwrap().records.get(key).cloned()
+    }
+
+    /// Looks at every record, though without sorting them
+    fn get_ge(&self, key: &[u8]) -> Option<Record> {
+        self.inner.read().unwrap().records.values().filter(|rec| rec.key() >= key).min_by(|a, b| a.key().cmp(b.key())).cloned()
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=Record> + 'a> {
+        let mut records = self.inner.read().unwrap().records.values().cloned().collect::<Vec<_>>();
+
+        records.sort_by(|a, b| a.key().cmp(&b.key()));
+
+        Box::new(records.into_iter())
+    }
+
+    fn iter_from<'a>(&'a self, key: &[u8]) -> Box<Iterator<Item=Record> + 'a> {
+        let mut records = self.inner.read().unwrap().records.values().filter(|rec| rec.key() >= key).cloned().collect::<Vec<_>>();
+
+        records.sort_by(|a, b| a.key().cmp(&b.key()));
+
+        Box::new(records.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().records.len()
+    }
+
+    fn approx_size(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+}
+
+/// The records replaced by the inserts made while there are views, see `MemTableView`
+#[derive(Default)]
+struct Versions {
+    generation: u64,                                         // the number of inserts so far
+    views: BTreeMap<u64, usize>,                             // the generations of the open views, and how many there are of each
+    replaced: BTreeMap<Vec<u8>, Vec<(u64, Option<Record>)>>  // by key, the generation of each insert a view may need, and the record it replaced
+}
+
+impl Versions {
+    /// The record of the key at the generation, if it has been replaced since
+    fn replaced_at(&self, key: &[u8], generation: u64) -> Option<&Option<Record>> {
+        // the first insert after the generation replaced the record it had
+        self.replaced.get(key).and_then(|inserts| inserts.iter().find(|&&(g, _)| g > generation)).map(|&(_, ref rec)| rec)
+    }
+}
+
+/// A mem_table, with its range deletes, and the WAL its records are journaled to
+pub struct WalMemTable {
+    records: Box<MemTable>,
+    range_tombstones: RwLock<Vec<Record>>, // range deletes, kept apart as they cover many keys
+    versions: Mutex<Versions>,             // for the views of the mem_table
+    wal_number: u64,                       // the WAL with the same records
+    wal_bytes: AtomicU64                   // the size of the WAL, set once the mem_table is immutable
+}
+
+impl WalMemTable {
+    pub fn new(kind: MemTableKind, wal_number: u64) -> WalMemTable {
+        WalMemTable {
+            records: new_mem_table(kind),
+            range_tombstones: RwLock::new(vec![]),
+            versions: Mutex::new(Versions::default()),
+            wal_number: wal_number,
+            wal_bytes: AtomicU64::new(0)
+        }
+    }
+
+    /// Adds a record, replacing any record with the same key
+    ///
+    /// The record replaced is kept while there's a view that may need it.
+    pub fn insert(&self, rec: Record) {
+        if rec.is_range_delete() {
+            self.range_tombstones.write().unwrap().push(rec);
+            return;
+        }
+
+        // the record replaced and the new one are swapped with the versions locked, so a view sees one or the other
+        let mut versions = self.versions.lock().unwrap();
+
+        versions.generation += 1;
+
+        if !versions.views.is_empty() {
+            let generation = versions.generation;
+            let old = self.records.get(rec.key());
+
+            versions.replaced.entry(rec.key().to_vec()).or_insert_with(Vec::new).push( (generation, old) );
+        }
+
+        self.records.insert(rec);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        self.records.get(key)
+    }
+
+    /// Returns the record with the smallest key that's not before the given one, not counting range deletes
+    pub fn get_ge(&self, key: &[u8]) -> Option<Record> {
+        self.records.get_ge(key)
+    }
+
+    /// Returns all the records, in key order
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=Record> + 'a> {
+        self.records.iter()
+    }
+
+    /// The number of records, not counting range deletes
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// The approximate number of bytes of the records, not counting range deletes
+    pub fn approx_size(&self) -> usize {
+        self.records.approx_size()
+    }
+
+    pub fn range_tombstones(&self) -> Vec<Record> {
+        self.range_tombstones.read().unwrap().clone()
+    }
+
+    /// Returns true if the record was deleted by one of the range deletes
+    pub fn is_range_deleted(&self, rec: &Record) -> bool {
+        self.range_tombstones.read().unwrap().iter().any(|t| t.covers(rec))
+    }
+
+    pub fn wal_number(&self) -> u64 {
+        self.wal_number
+    }
+
+    /// The size of the WAL once it's no longer written to, 0 before
+    pub fn wal_bytes(&self) -> u64 {
+        self.wal_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn set_wal_bytes(&self, bytes: u64) {
+        self.wal_bytes.store(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A mem_table as it was when the view was made, for a snapshot; the inserts after that aren't seen
+///
+/// Range deletes aren't part of the view, the snapshot keeps its own.
+pub struct MemTableView {
+    mem_table: Arc<WalMemTable>,
+    generation: u64
+}
+
+impl MemTableView {
+    pub fn new(mem_table: &Arc<WalMemTable>) -> MemTableView {
+        let mut versions = mem_table.versions.lock().unwrap();
+        let generation = versions.generation;
+
+        *versions.views.entry(generation).or_insert(0) += 1;
+
+        MemTableView { mem_table: mem_table.clone(), generation: generation }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        let versions = self.mem_table.versions.lock().unwrap();
+
+        match versions.replaced_at(key, self.generation) {
+            Some(rec) => rec.clone(),
+            None => self.mem_table.records.get(key)
+        }
+    }
+
+    /// Returns the records from the given key on, in key order
+    pub fn iter_from<'a>(&'a self, key: &[u8]) -> Box<Iterator<Item=Record> + 'a> {
+        // keys are never removed, so every record of the view has a key in the mem_table now
+        Box::new(self.mem_table.records.iter_from(key).filter_map(move |rec| {
+            match self.mem_table.versions.lock().unwrap().replaced_at(rec.key(), self.generation) {
+                Some(old) => old.clone(),
+                None => Some(rec)
+            }
+        }))
+    }
+}
+
+impl Drop for MemTableView {
+    /// Drops the replaced records that no view needs anymore
+    fn drop(&mut self) {
+        let mut versions = self.mem_table.versions.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let last = match versions.views.get_mut(&self.generation) {
+            Some(count) => { *count -= 1; *count == 0 },
+            None => false
+        };
+
+        if !last {
+            return;
+        }
+
+        versions.views.remove(&self.generation);
+
+        // only the inserts after the oldest view are needed
+        match versions.views.keys().next().cloned() {
+            None => versions.replaced.clear(),
+            Some(oldest) if oldest > self.generation => {
+                versions.replaced.retain(|_, inserts| {
+                    inserts.retain(|&(g, _)| g > oldest);
+                    !inserts.is_empty()
+                });
+            },
+            Some(_) => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mem_table::{WalMemTable, MemTableView, MemTableKind};
+    use record::Record;
+    use std::sync::Arc;
+
+    #[test]
+    fn insert_get() {
+        for &kind in [MemTableKind::SkipList, MemTableKind::BTree, MemTableKind::Hash].iter() {
+            let mem_table = WalMemTable::new(kind, 1);
+
+            mem_table.insert(Record::new(b"KEY_2".to_vec(), Some(b"VALUE_2".to_vec())));
+            mem_table.insert(Record::new(b"KEY_1".to_vec(), Some(b"VALUE_1".to_vec())));
+            mem_table.insert(Record::new(b"KEY_1".to_vec(), Some(b"NEW_VALUE".to_vec())));
+            mem_table.insert(Record::new_range_delete(b"KEY_3".to_vec(), b"KEY_5".to_vec(), 1));
+
+            assert_eq!(2, mem_table.len(), "{:?}", kind);
+            assert_eq!(b"NEW_VALUE".to_vec(), mem_table.get(b"KEY_1").unwrap().value());
+            assert!(mem_table.get(b"KEY_3").is_none());
+            assert_eq!(b"KEY_2".to_vec(), mem_table.get_ge(b"KEY_10").unwrap().key().to_vec(), "{:?}", kind);
+            assert_eq!(b"KEY_1".to_vec(), mem_table.get_ge(b"KEY_1").unwrap().key().to_vec(), "{:?}", kind);
+            assert!(mem_table.get_ge(b"KEY_3").is_none());
+            assert_eq!(vec![b"KEY_1".to_vec(), b"KEY_2".to_vec()], mem_table.iter().map(|r| r.key().to_vec()).collect::<Vec<_>>(), "{:?}", kind);
+            assert_eq!(1, mem_table.range_tombstones().len());
+            assert!(mem_table.approx_size() > 0);
+        }
+    }
+
+    #[test]
+    fn view() {
+        let rec = |k: &str, v: &str| Record::new(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        let keys_values = |records: Vec<Record>| records.into_iter().map(|r| (r.key().to_vec(), r.value().to_vec())).collect::<Vec<_>>();
+
+        for &kind in [MemTableKind::SkipList, MemTableKind::BTree, MemTableKind::Hash].iter() {
+            let mem_table = Arc::new(WalMemTable::new(kind, 1));
+
+            mem_table.insert(rec("KEY_1", "VALUE_1"));
+            mem_table.insert(rec("KEY_3", "VALUE_3"));
+
+            let view = MemTableView::new(&mem_table);
+
+            mem_table.insert(rec("KEY_1", "NEW_VALUE"));
+            mem_table.insert(rec("KEY_1", "NEWER_VALUE"));
+            mem_table.insert(rec("KEY_2", "VALUE_2"));
+
+            let newer = MemTableView::new(&mem_table);
+
+            mem_table.insert(rec("KEY_3", "NEW_VALUE"));
+
+            // each view sees the mem_table as it was
+            assert_eq!(b"VALUE_1".to_vec(), view.get(b"KEY_1").unwrap().value(), "{:?}", kind);
+            assert!(view.get(b"KEY_2").is_none());
+            assert_eq!(keys_values(vec![rec("KEY_1", "VALUE_1"), rec("KEY_3", "VALUE_3")]), keys_values(view.iter_from(b"KEY_0").collect()), "{:?}", kind);
+            assert_eq!(keys_values(vec![rec("KEY_2", "VALUE_2"), rec("KEY_3", "VALUE_3")]), keys_values(newer.iter_from(b"KEY_2").collect()), "{:?}", kind);
+            assert_eq!(b"NEWER_VALUE".to_vec(), newer.get(b"KEY_1").unwrap().value());
+
+            // the records only the older view needed are dropped with it
+            drop(view);
+
+            assert_eq!(vec![b"KEY_3".to_vec()], mem_table.versions.lock().unwrap().replaced.keys().cloned().collect::<Vec<_>>());
+
+            drop(newer);
+
+            assert!(mem_table.versions.lock().unwrap().replaced.is_empty());
+            assert_eq!(b"NEW_VALUE".to_vec(), mem_table.get(b"KEY_3").unwrap().value());
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//
+// Merkle trees over key/value pairs, so two copies of a store can find where they differ, and repair it
+// The pairs go into 2^depth buckets by the hash of their key, so a key is in the same bucket on every copy.
+// A bucket's hash is the sum of the hashes of its pairs, and each node above hashes its two children.
+// Comparing two trees only descends into the nodes that differ, so the copies only need to exchange
+// the nodes along the way, then the pairs of the buckets that differ.
+// A MerkleIndex keeps a tree up to date with the writes to a store, so AntiEntropy can repair a replica
+// in the background without reading both stores every time.
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bloom::hash_key;
+use kvs::{KVS, WriteBatch, WriteOptions, Change, ChangeOp};
+
+/// The deepest tree, with about a million buckets
+pub const MAX_DEPTH: u8 = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u8,
+    nodes: Vec<u64> // node i has children 2i and 2i + 1; the root is 1, and the buckets are the last 2^depth
+}
+
+/// What `repair` changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairStats {
+    pub buckets: usize,  // the buckets that differed
+    pub puts: usize,     // pairs missing from, or different in, the target
+    pub deletes: usize   // pairs only in the target
+}
+
+fn hash_pair(key: &[u8], value: &[u8]) -> u64 {
+    let mut bytes = Vec::with_capacity(4 + key.len() + value.len());
+
+    // the length, so the boundary between the key and value counts
+    bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(value);
+
+    hash_key(&bytes)
+}
+
+impl RepairStats {
+    fn add(&mut self, other: &RepairStats) {
+        self.buckets += other.buckets;
+        self.puts += other.puts;
+        self.deletes += other.deletes;
+    }
+}
+
+impl MerkleTree {
+    /// Builds a tree of the pairs, like those of `KVS::iter` or `KVS::range`
+    ///
+    /// # Panics
+    /// If the depth is more than `MAX_DEPTH`.
+    pub fn build<I>(pairs: I, depth: u8) -> MerkleTree where I: Iterator<Item=(Vec<u8>, Vec<u8>)> {
+        MerkleTree::with_hashes(pairs.map(|(key, value)| { let hash = hash_pair(&key, &value); (key, hash) }), depth)
+    }
+
+    /// Builds a tree of the keys and the hashes of their pairs
+    fn with_hashes<K, I>(hashes: I, depth: u8) -> MerkleTree where K: AsRef<[u8]>, I: Iterator<Item=(K, u64)> {
+        assert!(depth <= MAX_DEPTH, "A Merkle tree can't be deeper than {}: {}", MAX_DEPTH, depth);
+
+        let leaves = 1usize << depth;
+        let mut tree = MerkleTree { depth: depth, nodes: vec![0u64; leaves * 2] };
+
+        // summed, so the order of the pairs doesn't matter
+        for (key, hash) in hashes {
+            let i = leaves + bucket(key.as_ref(), depth);
+
+            tree.nodes[i] = tree.nodes[i].wrapping_add(hash);
+        }
+
+        for i in (1..leaves).rev() {
+            tree.hash_children(i);
+        }
+
+        tree
+    }
+
+    fn hash_children(&mut self, i: usize) {
+        let mut children = [0u8; 16];
+
+        children[..8].copy_from_slice(&self.nodes[2 * i].to_be_bytes());
+        children[8..].copy_from_slice(&self.nodes[2 * i + 1].to_be_bytes());
+
+        self.nodes[i] = hash_key(&children);
+    }
+
+    /// Takes a pair's hash out of its bucket, and puts another in, hashing the nodes above it again
+    fn replace(&mut self, key: &[u8], old: Option<u64>, new: Option<u64>) {
+        let mut i = (1usize << self.depth) + bucket(key, self.depth);
+
+        self.nodes[i] = self.nodes[i].wrapping_sub(old.unwrap_or(0)).wrapping_add(new.unwrap_or(0));
+
+        while i > 1 {
+            i /= 2;
+            self.hash_children(i);
+        }
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The hash of the whole tree; two copies with the same pairs have the same root
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// The hash of a node, by its index: 1 is the root, and node i has children 2i and 2i + 1
+    pub fn node(&self, index: usize) -> Option<u64> {
+        if index == 0 { None } else { self.nodes.get(index).cloned() }
+    }
+
+    /// The buckets whose pairs differ from those of the other tree, in order
+    ///
+    /// # Panics
+    /// If the trees have different depths.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        assert_eq!(self.depth, other.depth, "Merkle trees of different depths can't be compared");
+
+        let leaves = 1usize << self.depth;
+        let mut buckets = Vec::new();
+        let mut stack = vec![1];
+
+        while let Some(i) = stack.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+
+            if i >= leaves {
+                buckets.push(i - leaves);
+            } else {
+                // right first, so the left is popped first, and the buckets come out in order
+                stack.push(2 * i + 1);
+                stack.push(2 * i);
+            }
+        }
+
+        buckets
+    }
+}
+
+/// The bucket of a key, in a tree of the depth
+pub fn bucket(key: &[u8], depth: u8) -> usize {
+    if depth == 0 { 0 } else { (hash_key(key) >> (64 - depth as u32)) as usize }
+}
+
+/// Makes the pairs of the target with keys in [start, end) match those of the source
+///
+/// Both stores are read twice, so this is for a repair now and then; to repair again and again, keep a
+/// `MerkleIndex` of each, or have an `AntiEntropy` do it in the background. Trees of both are compared, then the pairs of the buckets that differ are read from both, and the
+/// target gets the source's puts, and deletes of the keys only it has, in one batch. The source wins
+/// every difference, so this repairs a replica from its primary. Writes to either store while it runs
+/// may be missed, to be found by the next repair.
+pub fn repair(source: &KVS, target: &KVS, start: &Vec<u8>, end: &Vec<u8>, depth: u8) -> RepairStats {
+    let buckets = MerkleTree::build(source.range(start, end), depth).diff(&MerkleTree::build(target.range(start, end), depth));
+
+    if buckets.is_empty() {
+        return RepairStats::default();
+    }
+
+    let in_buckets = |key: &Vec<u8>| buckets.binary_search(&bucket(key, depth)).is_ok();
+    let wanted = source.range(start, end).filter(|pair| in_buckets(&pair.0)).collect::<BTreeMap<_, _>>();
+    let mut stats = RepairStats { buckets: buckets.len(), puts: 0, deletes: 0 };
+    let mut batch = WriteBatch::new();
+
+    for (key, _) in target.range(start, end).filter(|pair| in_buckets(&pair.0)) {
+        // the keys both have are put below, when their values differ
+        if !wanted.contains_key(&key) {
+            batch.delete(&key);
+            stats.deletes += 1;
+        }
+    }
+
+    for (key, value) in wanted.into_iter() {
+        if target.get(&key).as_ref() != Some(&value) {
+            batch.put(key, value);
+            stats.puts += 1;
+        }
+    }
+
+    target.write(batch, &WriteOptions::new());
+
+    stats
+}
+
+/// A Merkle tree of the pairs of a store with keys in a range, kept up to date with its writes
+///
+/// The index has the hash of every pair in the range, and watches the store, see `KVS::watch`. Each
+/// `update` takes in the writes since the last one, hashing again only the buckets they changed,
+/// and the nodes above them. Writes with `WriteOptions::disable_wal` aren't watched, and records
+/// that expire aren't seen to go, so they're only taken in by a `rebuild`.
+pub struct MerkleIndex {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    tree: MerkleTree,
+    hashes: BTreeMap<Vec<u8>, u64>, // the hash of each pair, by key
+    changes: Receiver<Change>       // the writes to the store, not yet taken in
+}
+
+impl MerkleIndex {
+    /// Reads the pairs of the store with keys in [start, end), and watches it for writes
+    ///
+    /// # Panics
+    /// If the depth is more than `MAX_DEPTH`.
+    pub fn new(kvs: &KVS, start: &Vec<u8>, end: &Vec<u8>, depth: u8) -> MerkleIndex {
+        let changes = watch_range(kvs, start, end);
+        let hashes = read_hashes(kvs, start, end);
+
+        MerkleIndex {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            tree: MerkleTree::with_hashes(hashes.iter().map(|(key, &hash)| (key, hash)), depth),
+            hashes: hashes,
+            changes: changes
+        }
+    }
+
+    /// Reads the pairs of the store again, dropping the writes that weren't taken in
+    pub fn rebuild(&mut self, kvs: &KVS) {
+        self.changes = watch_range(kvs, &self.start, &self.end);
+        self.hashes = read_hashes(kvs, &self.start, &self.end);
+        self.tree = MerkleTree::with_hashes(self.hashes.iter().map(|(key, &hash)| (key, hash)), self.tree.depth);
+    }
+
+    /// Takes in the writes made to the store since the last update, returning how many there were
+    ///
+    /// A write can be taken in before readers of the store see it, as watchers are sent the writes
+    /// when they're added to the WAL.
+    pub fn update(&mut self) -> usize {
+        let mut count = 0;
+
+        while let Ok(change) = self.changes.try_recv() {
+            count += 1;
+
+            match change.op {
+                ChangeOp::Put => if self.in_range(&change.key) {
+                    let hash = hash_pair(&change.key, change.value.as_ref().expect("Put without a value"));
+
+                    self.set(change.key, Some(hash));
+                },
+                ChangeOp::Delete => if self.in_range(&change.key) {
+                    self.set(change.key, None);
+                },
+                ChangeOp::DeleteRange => {
+                    let end = change.value.expect("DeleteRange without an end");
+
+                    if change.key < end {
+                        let deleted = self.hashes.range(change.key..end).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+                        for key in deleted.into_iter() {
+                            self.set(key, None);
+                        }
+                    }
+                }
+            }
+        }
+
+        count
+    }
+
+    pub fn tree(&self) -> &MerkleTree {
+        &self.tree
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    fn set(&mut self, key: Vec<u8>, hash: Option<u64>) {
+        let old = match hash {
+            Some(hash) => self.hashes.insert(key.clone(), hash),
+            None => self.hashes.remove(&key)
+        };
+
+        if old != hash {
+            self.tree.replace(&key, old, hash);
+        }
+    }
+
+    /// The keys in the buckets, which must be in order
+    fn keys_in(&self, buckets: &[usize]) -> BTreeSet<&Vec<u8>> {
+        self.hashes.keys().filter(|key| buckets.binary_search(&bucket(key, self.tree.depth)).is_ok()).collect()
+    }
+}
+
+/// Watches the keys with the prefix that every key in [start, end) has
+fn watch_range(kvs: &KVS, start: &[u8], end: &[u8]) -> Receiver<Change> {
+    let prefix = start.iter().zip(end.iter()).take_while(|&(a, b)| a == b).map(|(a, _)| *a).collect();
+
+    kvs.watch(&prefix)
+}
+
+fn read_hashes(kvs: &KVS, start: &Vec<u8>, end: &Vec<u8>) -> BTreeMap<Vec<u8>, u64> {
+    kvs.range(start, end).map(|(key, value)| { let hash = hash_pair(&key, &value); (key, hash) }).collect()
+}
+
+/// Makes the pairs of the target match those of the source, like `repair`, with an index of each
+///
+/// The indexes are updated, then only the pairs of the buckets that differ are read from the source,
+/// by key; nothing is read from the target.
+///
+/// # Panics
+/// If the indexes are of different ranges, or depths.
+pub fn repair_indexed(source: &KVS, source_index: &mut MerkleIndex, target: &KVS, target_index: &mut MerkleIndex) -> RepairStats {
+    assert!(source_index.start == target_index.start && source_index.end == target_index.end, "The Merkle indexes are of different ranges");
+
+    source_index.update();
+    target_index.update();
+
+    let buckets = source_index.tree.diff(&target_index.tree);
+
+    if buckets.is_empty() {
+        return RepairStats::default();
+    }
+
+    let mut stats = RepairStats { buckets: buckets.len(), puts: 0, deletes: 0 };
+    let mut batch = WriteBatch::new();
+    let wanted = source_index.keys_in(&buckets);
+
+    for key in target_index.keys_in(&buckets).into_iter() {
+        if !wanted.contains(key) {
+            batch.delete(key);
+            stats.deletes += 1;
+        }
+    }
+
+    for key in wanted.into_iter() {
+        if source_index.hashes.get(key) == target_index.hashes.get(key) {
+            continue;
+        }
+
+        // a key deleted from the source since its index was updated is left for the next repair
+        if let Some(value) = source.get(key) {
+            batch.put(key.to_vec(), value);
+            stats.puts += 1;
+        }
+    }
+
+    target.write(batch, &WriteOptions::new());
+
+    stats
+}
+
+/// Repairs a replica from its primary on a thread of its own, every interval, until it's dropped
+///
+/// The thread keeps a `MerkleIndex` of each store, built when it starts, and calls `repair_indexed`.
+pub struct AntiEntropy {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<RepairStats>> // summed over every repair
+}
+
+impl AntiEntropy {
+    /// Repairs the pairs of the target with keys in [start, end) from the source, first thing and then every interval
+    ///
+    /// # Panics
+    /// On the thread, if the depth is more than `MAX_DEPTH`.
+    pub fn start(source: Arc<KVS>, target: Arc<KVS>, start: &Vec<u8>, end: &Vec<u8>, depth: u8, interval: Duration) -> Result<AntiEntropy, IOError> {
+        let (stop, stopped) = channel::<()>();
+        let stats = Arc::new(Mutex::new(RepairStats::default()));
+        let thread_stats = stats.clone();
+        let (start, end) = (start.to_vec(), end.to_vec());
+
+        let thread = thread::Builder::new().name("kvs-anti-entropy".to_string()).spawn(move || {
+            let mut source_index = MerkleIndex::new(&source, &start, &end, depth);
+            let mut target_index = MerkleIndex::new(&target, &start, &end, depth);
+
+            loop {
+                let repaired = repair_indexed(&source, &mut source_index, &target, &mut target_index);
+
+                if repaired != RepairStats::default() {
+                    debug!("Anti-entropy repair: {:?}", repaired);
+                }
+
+                thread_stats.lock().unwrap().add(&repaired);
+
+                // sent nothing, until it's dropped
+                if stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                    break;
+                }
+            }
+        })?;
+
+        Ok(AntiEntropy { stop: Some(stop), thread: Some(thread), stats: stats })
+    }
+
+    /// What the repairs have changed so far
+    pub fn stats(&self) -> RepairStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+impl Drop for AntiEntropy {
+    /// Waits for a repair that's running to finish
+    fn drop(&mut self) {
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use merkle::{MerkleTree, MerkleIndex, RepairStats, AntiEntropy, bucket, repair, repair_indexed};
+    use kvs::{KVS, KVSOptions};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use test_path::gen_dir;
+
+    fn pairs(kvs: &KVS) -> Vec<(Vec<u8>, Vec<u8>)> {
+        kvs.iter().collect()
+    }
+
+    #[test]
+    fn tree() {
+        let pairs = (0..1000).map(|i| (format!("KEY_{:04}", i).into_bytes(), vec![i as u8])).collect::<Vec<_>>();
+        let tree = MerkleTree::build(pairs.clone().into_iter(), 6);
+
+        // the order of the pairs doesn't matter
+        assert_eq!(tree, MerkleTree::build(pairs.iter().rev().cloned(), 6));
+        assert!(tree.diff(&tree).is_empty());
+        assert_eq!(Some(tree.root()), tree.node(1));
+        assert_eq!(None, tree.node(128));
+
+        let mut changed = pairs.clone();
+
+        changed[10].1 = vec![0xFF];
+        changed.remove(20);
+
+        let mut expected = vec![bucket(&pairs[10].0, 6), bucket(&pairs[20].0, 6)];
+
+        expected.sort();
+        expected.dedup();
+
+        assert_eq!(expected, tree.diff(&MerkleTree::build(changed.into_iter(), 6)));
+
+        // a pair moved from the key to the value is a different pair
+        let a = MerkleTree::build(vec![(b"ab".to_vec(), b"c".to_vec())].into_iter(), 0);
+        let b = MerkleTree::build(vec![(b"a".to_vec(), b"bc".to_vec())].into_iter(), 0);
+
+        assert_ne!(a.root(), b.root());
+        assert_eq!(vec![0], a.diff(&b));
+    }
+
+    #[test]
+    fn repair_replica() {
+        let (source_dir, target_dir) = (gen_dir(), gen_dir());
+        let source = KVSOptions::new(&source_dir).create().unwrap();
+        let target = KVSOptions::new(&target_dir).create().unwrap();
+
+        for i in 0..500 {
+            let key = format!("KEY_{:04}", i).into_bytes();
+
+            source.put(key.clone(), b"VALUE".to_vec());
+
+            if i % 100 != 0 {
+                target.put(key, if i % 50 == 0 { b"OLD".to_vec() } else { b"VALUE".to_vec() });
+            }
+        }
+
+        target.put(b"KEY_EXTRA".to_vec(), b"VALUE".to_vec());
+        target.put(b"OTHER".to_vec(), b"VALUE".to_vec()); // outside the range, so it's kept
+
+        let stats = repair(&source, &target, &b"KEY_".to_vec(), &b"KEY`".to_vec(), 8);
+
+        assert_eq!((5 + 5, 1), (stats.puts, stats.deletes));
+        assert!(stats.buckets > 0 && stats.buckets <= 11);
+
+        assert_eq!(pairs(&source), pairs(&target).into_iter().filter(|&(ref k, _)| k != b"OTHER").collect::<Vec<_>>());
+        assert_eq!(RepairStats::default(), repair(&source, &target, &b"KEY_".to_vec(), &b"KEY`".to_vec(), 8));
+    }
+
+    #[test]
+    fn index() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+        let (start, end) = (b"KEY_".to_vec(), b"KEY`".to_vec());
+
+        for i in 0..100 {
+            kvs.put(format!("KEY_{:04}", i).into_bytes(), b"VALUE".to_vec());
+        }
+
+        let mut index = MerkleIndex::new(&kvs, &start, &end, 6);
+        let build = |kvs: &KVS| MerkleTree::build(kvs.range(&start, &end), 6);
+
+        assert_eq!(&build(&kvs), index.tree());
+
+        kvs.put(b"KEY_0001".to_vec(), b"NEW".to_vec());
+        kvs.put(b"KEY_0200".to_vec(), b"VALUE".to_vec());
+        kvs.put(b"OTHER".to_vec(), b"VALUE".to_vec()); // not watched
+        kvs.delete(&b"KEY_0002".to_vec());
+        kvs.delete_range(&b"KEY_0010".to_vec(), &b"KEY_0020".to_vec());
+
+        assert_eq!(4, index.update());
+        assert_eq!(&build(&kvs), index.tree());
+
+        // a write that isn't taken in is found by a rebuild
+        let mut stale = MerkleIndex::new(&kvs, &start, &end, 6);
+
+        kvs.put(b"KEY_0300".to_vec(), b"VALUE".to_vec());
+        stale.rebuild(&kvs);
+
+        assert_eq!(0, stale.update());
+        assert_eq!(&build(&kvs), stale.tree());
+    }
+
+    #[test]
+    fn repair_indexes() {
+        let (source_dir, target_dir) = (gen_dir(), gen_dir());
+        let source = KVSOptions::new(&source_dir).create().unwrap();
+        let target = KVSOptions::new(&target_dir).create().unwrap();
+        let (start, end) = (b"KEY_".to_vec(), b"KEY`".to_vec());
+        let mut source_index = MerkleIndex::new(&source, &start, &end, 8);
+        let mut target_index = MerkleIndex::new(&target, &start, &end, 8);
+
+        for i in 0..500 {
+            let key = format!("KEY_{:04}", i).into_bytes();
+
+            source.put(key.clone(), b"VALUE".to_vec());
+
+            if i % 100 != 0 {
+                target.put(key, if i % 50 == 0 { b"OLD".to_vec() } else { b"VALUE".to_vec() });
+            }
+        }
+
+        target.put(b"KEY_EXTRA".to_vec(), b"VALUE".to_vec());
+
+        let stats = repair_indexed(&source, &mut source_index, &target, &mut target_index);
+
+        assert_eq!((5 + 5, 1), (stats.puts, stats.deletes));
+        assert_eq!(pairs(&source), pairs(&target));
+
+        // the repair's writes are taken in by the target's index
+        assert_eq!(RepairStats::default(), repair_indexed(&source, &mut source_index, &target, &mut target_index));
+    }
+
+    #[test]
+    fn anti_entropy() {
+        let (source_dir, target_dir) = (gen_dir(), gen_dir());
+        let source = Arc::new(KVSOptions::new(&source_dir).create().unwrap());
+        let target = Arc::new(KVSOptions::new(&target_dir).create().unwrap());
+
+        source.put(b"KEY_1".to_vec(), b"VALUE".to_vec());
+
+        let anti_entropy = AntiEntropy::start(source.clone(), target.clone(), &b"KEY_".to_vec(), &b"KEY`".to_vec(), 4, Duration::from_millis(10)).unwrap();
+
+        source.put(b"KEY_2".to_vec(), b"VALUE".to_vec());
+        target.put(b"KEY_3".to_vec(), b"VALUE".to_vec());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while (anti_entropy.stats().puts, anti_entropy.stats().deletes) != (2, 1) {
+            assert!(Instant::now() < deadline, "The target wasn't repaired: {:?}", anti_entropy.stats());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(pairs(&source), pairs(&target));
+
+        drop(anti_entropy);
+
+        assert_eq!(1, Arc::strong_count(&source));
+    }
+}
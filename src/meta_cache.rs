@@ -0,0 +1,218 @@
+//
+// A cache of the bloom filters and index blocks of the SSTables, apart from the records, see KVSOptions::meta_cache_size
+// Entries are kept decoded, by the table's number and their offset in it, in an LRU bounded by bytes, so
+// a burst of record reads can't push out the metadata every lookup needs. Pinned entries, the filter and
+// index block of every partition with KVSOptions::pin_metadata, are kept apart and never evicted; they're
+// dropped with their tables, once a saved manifest no longer references them.
+//
+
+use lru_cache::LruCache;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bloom::BloomFilter;
+use U64_SIZE;
+
+#[derive(Clone)]
+enum Meta {
+    Filter(Arc<BloomFilter>),
+    Index(Arc<Vec<u64>>) // the offsets of a partition's group indices, or a group's records
+}
+
+impl Meta {
+    fn size(&self) -> usize {
+        match *self {
+            Meta::Filter(ref filter) => filter.size(),
+            Meta::Index(ref index) => index.len() * U64_SIZE
+        }
+    }
+}
+
+type Key = (u64, u64); // the table's number, and the offset in it
+
+struct Entries {
+    lru: LruCache<Key, Meta>, // unbounded, trimmed to the capacity in bytes
+    lru_bytes: usize,
+    pinned: HashMap<Key, Meta>,
+    pinned_bytes: usize
+}
+
+/// The cached metadata of all the SSTables of a store
+pub struct MetaCache {
+    capacity: usize, // the bytes of unpinned entries
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl MetaCache {
+    pub fn new(capacity: usize) -> MetaCache {
+        MetaCache {
+            capacity: capacity,
+            entries: Mutex::new(Entries { lru: LruCache::new(usize::max_value()), lru_bytes: 0, pinned: HashMap::new(), pinned_bytes: 0 }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// Gets a bloom filter, loading it on a miss, and caching it if `fill` or `pin` is set
+    pub fn filter<F>(&self, table: u64, offset: u64, pin: bool, fill: bool, load: F) -> Result<Arc<BloomFilter>, IOError>
+        where F: FnOnce() -> Result<BloomFilter, IOError>
+    {
+        if let Some(Meta::Filter(filter)) = self.get(&(table, offset)) {
+            return Ok(filter);
+        }
+
+        let filter = Arc::new(load()?);
+
+        if fill || pin {
+            self.insert((table, offset), Meta::Filter(filter.clone()), pin);
+        }
+
+        Ok(filter)
+    }
+
+    /// Gets an index block or group index, loading it on a miss, and caching it if `fill` or `pin` is set
+    pub fn index<F>(&self, table: u64, offset: u64, pin: bool, fill: bool, load: F) -> Result<Arc<Vec<u64>>, IOError>
+        where F: FnOnce() -> Result<Vec<u64>, IOError>
+    {
+        if let Some(Meta::Index(index)) = self.get(&(table, offset)) {
+            return Ok(index);
+        }
+
+        let index = Arc::new(load()?);
+
+        if fill || pin {
+            self.insert((table, offset), Meta::Index(index.clone()), pin);
+        }
+
+        Ok(index)
+    }
+
+    fn get(&self, key: &Key) -> Option<Meta> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let found = match entries.pinned.get(key) {
+            Some(meta) => Some(meta.clone()),
+            None => entries.lru.get_mut(key).map(|meta| meta.clone())
+        };
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        found
+    }
+
+    fn insert(&self, key: Key, meta: Meta, pin: bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let size = meta.size();
+
+        if pin {
+            if let Some(old) = entries.lru.remove(&key) {
+                entries.lru_bytes -= old.size();
+            }
+
+            if entries.pinned.insert(key, meta).is_none() {
+                entries.pinned_bytes += size;
+            }
+
+            return;
+        }
+
+        if entries.pinned.contains_key(&key) {
+            return;
+        }
+
+        if let Some(old) = entries.lru.insert(key, meta) {
+            entries.lru_bytes -= old.size();
+        }
+
+        entries.lru_bytes += size;
+
+        while entries.lru_bytes > self.capacity {
+            match entries.lru.remove_lru() {
+                Some((_, old)) => entries.lru_bytes -= old.size(),
+                None => break
+            }
+        }
+    }
+
+    /// Drops the entries of the tables that aren't live, after they're removed from the manifest
+    pub fn retain_tables(&self, live: &HashSet<u64>) {
+        let mut entries = self.entries.lock().unwrap();
+        let dead = entries.lru.iter().map(|(key, _)| *key).filter(|key| !live.contains(&key.0)).collect::<Vec<_>>();
+
+        for key in dead {
+            if let Some(old) = entries.lru.remove(&key) {
+                entries.lru_bytes -= old.size();
+            }
+        }
+
+        entries.pinned.retain(|key, _| live.contains(&key.0));
+        entries.pinned_bytes = entries.pinned.values().map(|meta| meta.size()).sum();
+    }
+
+    /// The lookups that were, and weren't, found in the cache
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    /// The bytes of all the entries, and of those pinned
+    pub fn bytes(&self) -> (u64, u64) {
+        let entries = self.entries.lock().unwrap();
+
+        ((entries.lru_bytes + entries.pinned_bytes) as u64, entries.pinned_bytes as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meta_cache::MetaCache;
+    use std::collections::HashSet;
+    use std::io::{Error as IOError, ErrorKind};
+
+    #[test]
+    fn evict_and_pin() {
+        let cache = MetaCache::new(64); // 8 offsets
+        let loaded = |n: usize| Ok(vec![0u64; n]);
+
+        cache.index(1, 0, true, true, || loaded(16)).unwrap();
+        cache.index(1, 100, false, true, || loaded(4)).unwrap();
+        cache.index(1, 200, false, true, || loaded(4)).unwrap();
+
+        assert_eq!((16 * 8 + 8 * 8, 16 * 8), cache.bytes());
+
+        // the pinned and recently used entries are hits, without loading
+        let fail = || Err(IOError::new(ErrorKind::Other, "loaded"));
+
+        assert_eq!(16, cache.index(1, 0, true, true, fail).unwrap().len());
+        assert_eq!(4, cache.index(1, 100, false, true, fail).unwrap().len());
+
+        // pushes out the least recently used entry, but never the pinned one
+        cache.index(2, 0, false, true, || loaded(4)).unwrap();
+
+        assert!(cache.index(1, 200, false, true, fail).is_err());
+        assert!(cache.index(1, 0, true, true, fail).is_ok());
+
+        // not filled, so the next read loads it again
+        cache.index(3, 0, false, false, || loaded(1)).unwrap();
+        assert!(cache.index(3, 0, false, false, fail).is_err());
+
+        assert_eq!((3, 7), cache.cache_stats());
+
+        cache.retain_tables(&vec![2].into_iter().collect::<HashSet<_>>());
+
+        assert_eq!((4 * 8, 0), cache.bytes());
+        assert!(cache.index(1, 0, true, true, fail).is_err());
+
+        // too big to cache
+        cache.index(4, 0, false, true, || loaded(9)).unwrap();
+        assert_eq!((0, 0), cache.bytes());
+    }
+}
@@ -0,0 +1,213 @@
+//
+// Copies the records of SSTables to and from Apache Parquet files, for analytics tools to read and write
+// An exported file has a binary key column, and a binary value column that's null for a delete. An
+// imported file can have any columns; the key and value ones are picked by name.
+//
+
+use parquet::data_type::{ByteArray, ByteArrayType};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::Field;
+use parquet::schema::parser::parse_message_type;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Error as IOError, ErrorKind};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cache::{CacheOptions, CachePolicyKind};
+use codec::CodecKind;
+use record::Record;
+use sstable::{SSTable, SSTableOptions, NewSSTable};
+
+/// The schema of an exported file
+const PARQUET_SCHEMA: &str = "message kvs_records { required binary key; optional binary value; }";
+
+/// The most records in a row group of an exported file
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// The buffer and record cache sizes of the table written by `SSTable::from_parquet`
+const IMPORT_BUFFER_SIZE: usize = 64 * 1024;
+const IMPORT_CACHE_SIZE: usize = 100;
+
+fn parquet_error(e: ParquetError) -> IOError {
+    IOError::new(ErrorKind::InvalidData, format!("Parquet error: {}", e))
+}
+
+impl SSTable {
+    /// Writes the records of the table to a new Parquet file, with `key` and `value` columns
+    ///
+    /// A delete has a null value. Range deletes, and the timestamps and TTLs of the records, aren't
+    /// written. Returns the number of rows written.
+    pub fn to_parquet(&self, path: &PathBuf) -> Result<u64, IOError> {
+        if path.exists() {
+            return Err(IOError::new(ErrorKind::AlreadyExists, format!("The Parquet file {:?} already exists", path)));
+        }
+
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).map_err(parquet_error)?);
+        let mut writer = SerializedFileWriter::new(File::create(path)?, schema, Arc::new(WriterProperties::builder().build())).map_err(parquet_error)?;
+        let mut records = self.iter().peekable();
+        let mut row_count = 0;
+
+        while records.peek().is_some() {
+            let (mut keys, mut values, mut def_levels) = (vec![], vec![], vec![]);
+
+            for rec in records.by_ref().take(ROW_GROUP_SIZE) {
+                let (key, value) = rec.into_parts();
+
+                keys.push(ByteArray::from(key));
+
+                match value {
+                    Some(value) => { values.push(ByteArray::from(value)); def_levels.push(1) },
+                    None => def_levels.push(0)
+                }
+            }
+
+            let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+
+            for &(column, def_levels) in [(&keys, None), (&values, Some(def_levels.as_slice()))].iter() {
+                let mut column_writer = row_group.next_column().map_err(parquet_error)?.expect("The schema has a key and value column");
+
+                column_writer.typed::<ByteArrayType>().write_batch(column, def_levels, None).map_err(parquet_error)?;
+                column_writer.close().map_err(parquet_error)?;
+            }
+
+            row_group.close().map_err(parquet_error)?;
+            row_count += keys.len() as u64;
+        }
+
+        writer.close().map_err(parquet_error)?;
+
+        Ok(row_count)
+    }
+
+    /// Creates an SSTable of the rows of a Parquet file, taking the key and value of each from the named columns
+    ///
+    /// The columns can be binary, or strings. A row with a null key is an error; a null value is a delete.
+    /// The rows are held in memory and sorted, and of the rows with the same key, the last is kept. The
+    /// SSTable is written without a dictionary, and its id is its file's number, like a store's tables. Its
+    /// records are encoded with `codec`, which has to be the codec of the store that will read them.
+    pub fn from_parquet(src: &PathBuf, key_column: &str, value_column: &str, dst: &PathBuf, codec: CodecKind) -> Result<SSTable, IOError> {
+        let reader = SerializedFileReader::new(File::open(src)?).map_err(parquet_error)?;
+
+        let column_index = |name: &str| {
+            let fields = reader.metadata().file_metadata().schema_descr().root_schema().get_fields().iter().position(|field| field.name() == name);
+
+            fields.ok_or_else(|| IOError::new(ErrorKind::InvalidInput, format!("{:?} has no column {:?}", src, name)))
+        };
+
+        let (key_index, value_index) = (column_index(key_column)?, column_index(value_column)?);
+        let mut records = BTreeMap::new();
+
+        for row in reader.get_row_iter(None).map_err(parquet_error)? {
+            let mut columns = row.map_err(parquet_error)?.into_columns();
+            let key = column_bytes(mem::replace(&mut columns[key_index].1, Field::Null), key_column)?;
+            let value = column_bytes(mem::replace(&mut columns[value_index].1, Field::Null), value_column)?;
+
+            match key {
+                Some(key) => records.insert(key, value),
+                None => return Err(IOError::new(ErrorKind::InvalidData, format!("{:?} has a row with a null {:?}", src, key_column)))
+            };
+        }
+
+        let id = dst.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()).unwrap_or(0);
+        let options = SSTableOptions { group_count: None, target_block_bytes: 4096, group_by_size: false, dict_size: 0, codec: codec, alignment: 0, preallocate: 0 };
+        let cache = CacheOptions { size: IMPORT_CACHE_SIZE, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
+        let mut records = records.into_iter().map(|(key, value)| Record::new(key, value));
+
+        SSTable::new(NewSSTable::new(dst, id, &options, IMPORT_BUFFER_SIZE, cache), &mut records)
+    }
+}
+
+/// The bytes of a binary or string field, or None for a null one
+fn column_bytes(field: Field, column: &str) -> Result<Option<Vec<u8>>, IOError> {
+    match field {
+        Field::Bytes(bytes) => Ok(Some(bytes.data().to_vec())),
+        Field::Str(s) => Ok(Some(s.into_bytes())),
+        Field::Null => Ok(None),
+        other => Err(IOError::new(ErrorKind::InvalidData, format!("The column {:?} isn't binary or a string: {}", column, other)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    use cache::{CacheOptions, CachePolicyKind};
+    use codec::CodecKind;
+    use record::Record;
+    use sstable::{SSTable, SSTableOptions, NewSSTable};
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use test_path::gen_dir;
+
+    fn cache() -> CacheOptions {
+        CacheOptions { size: 100, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = gen_dir();
+        let options = SSTableOptions { group_count: Some(10), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+        let mut records = (0..1000).map(|i| Record::new(format!("KEY_{:04}", i).into_bytes(), if i % 10 == 0 { None } else { Some(format!("VALUE_{}", i).into_bytes()) })).collect::<Vec<_>>();
+        let sstable = SSTable::new(NewSSTable::new(&dir.join("000001.sst"), 1, &options, 4096, cache()), &mut records.iter()).unwrap();
+
+        assert_eq!(1000, sstable.to_parquet(&dir.join("records.parquet")).unwrap());
+        assert_eq!(ErrorKind::AlreadyExists, sstable.to_parquet(&dir.join("records.parquet")).unwrap_err().kind());
+
+        let imported = SSTable::from_parquet(&dir.join("records.parquet"), "key", "value", &dir.join("000002.sst"), CodecKind::MsgPack).unwrap();
+
+        assert_eq!(2, imported.id());
+        assert_eq!(100, imported.tombstone_count());
+        assert_eq!(records.drain(..).map(|rec| rec.into_parts()).collect::<Vec<_>>(), imported.iter().map(|rec| rec.into_parts()).collect::<Vec<_>>());
+
+        let bincode = SSTable::from_parquet(&dir.join("records.parquet"), "key", "value", &dir.join("000003.sst"), CodecKind::Bincode).unwrap();
+
+        assert_eq!(CodecKind::Bincode, bincode.codec());
+        assert_eq!(1000, bincode.iter().count());
+        assert_eq!(ErrorKind::InvalidInput, SSTable::from_parquet(&dir.join("records.parquet"), "key", "missing", &dir.join("000004.sst"), CodecKind::MsgPack).unwrap_err().kind());
+    }
+
+    #[test]
+    fn import() {
+        let dir = gen_dir();
+        let path = dir.join("rows.parquet");
+        let schema = Arc::new(parse_message_type("message rows { required int64 id; required binary name (UTF8); optional binary data; }").unwrap());
+        let mut writer = SerializedFileWriter::new(File::create(&path).unwrap(), schema, Arc::new(WriterProperties::builder().build())).unwrap();
+
+        // out of order, with a key written twice
+        let names = ["b", "a", "b"].iter().map(|&name| ByteArray::from(name)).collect::<Vec<_>>();
+        let data = vec![ByteArray::from(vec![1]), ByteArray::from(vec![3])];
+        let mut row_group = writer.next_row_group().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<Int64Type>().write_batch(&[1, 2, 3], None, None).unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<ByteArrayType>().write_batch(&names, None, None).unwrap();
+        column.close().unwrap();
+
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column.typed::<ByteArrayType>().write_batch(&data, Some(&[1, 0, 1]), None).unwrap();
+        column.close().unwrap();
+
+        row_group.close().unwrap();
+        writer.close().unwrap();
+
+        let sstable = SSTable::from_parquet(&path, "name", "data", &dir.join("000001.sst"), CodecKind::MsgPack).unwrap();
+
+        assert_eq!(vec![(b"a".to_vec(), None), (b"b".to_vec(), Some(vec![3]))], sstable.iter().map(|rec| rec.into_parts()).collect::<Vec<_>>());
+
+        // the key has to be binary, or a string
+        assert_eq!(ErrorKind::InvalidData, SSTable::from_parquet(&path, "id", "data", &dir.join("000002.sst"), CodecKind::MsgPack).unwrap_err().kind());
+    }
+}
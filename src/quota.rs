@@ -0,0 +1,208 @@
+//
+// Limits on the keys and bytes under key prefixes, for stores shared by tenants; see `KVSOptions::quota`
+// The usage of each prefix is counted when the store is opened, then kept up to date by every write,
+// which looks up the old value of each key under a prefix with a quota.
+//
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use record::Record;
+
+/// A limit on the keys starting with a prefix; None is no limit, so a quota without limits only counts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub prefix: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,    // of the keys and values
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<u64>
+}
+
+/// The keys, and the bytes of their keys and values, under a quota's prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    pub keys: u64,
+    pub bytes: u64
+}
+
+/// The error of a write that would take a prefix over its quota
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub prefix: Vec<u8>,
+    pub keys: u64,    // the usage the write would have led to
+    pub bytes: u64
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quota exceeded for prefix {:?}: {} keys, {} bytes", self.prefix, self.keys, self.bytes)
+    }
+}
+
+impl Error for QuotaExceeded { }
+
+impl Quota {
+    fn over(&self, keys: u64, bytes: u64) -> bool {
+        self.max_keys.map_or(false, |max| keys > max) || self.max_bytes.map_or(false, |max| bytes > max)
+    }
+}
+
+/// The usage of all the quotas of a store
+pub struct Quotas {
+    usage: Mutex<Vec<QuotaUsage>>
+}
+
+/// The usage of the quotas after a write, see `Quotas::charge`
+pub struct Charge {
+    usage: Vec<QuotaUsage>,
+    pub exceeded: Option<QuotaExceeded> // the first quota the write takes, or keeps, over a limit while growing
+}
+
+fn size(key: &[u8], value: Option<&[u8]>) -> Option<u64> {
+    value.map(|v| (key.len() + v.len()) as u64)
+}
+
+impl Quotas {
+    pub fn new(quotas: &[Quota]) -> Quotas {
+        Quotas { usage: Mutex::new(quotas.iter().map(|q| QuotaUsage { quota: q.clone(), keys: 0, bytes: 0 }).collect()) }
+    }
+
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Works out the usage after the records are written, with `get` reading the current value of a key.
+    /// It must be called, and the charge applied, with the WAL locked, so no other write comes in between.
+    pub fn charge<F>(&self, records: &[Record], get: F) -> Charge where F: Fn(&[u8]) -> Option<Vec<u8>> {
+        let mut usage = self.usage();
+
+        if usage.is_empty() {
+            return Charge { usage: usage, exceeded: None };
+        }
+
+        // the sizes of the keys written so far in the batch, as a later record of a key replaces an earlier one
+        let mut written = Vec::<(&[u8], Option<u64>)>::new();
+
+        for rec in records.iter().filter(|rec| !rec.is_range_delete()) {
+            let key = rec.key();
+
+            if !usage.iter().any(|u| key.starts_with(&u.quota.prefix)) {
+                continue;
+            }
+
+            let old = match written.iter().rev().find(|&&(k, _)| k == key) {
+                Some(&(_, old)) => old,
+                None => size(key, get(key).as_ref().map(|v| v.as_slice()))
+            };
+
+            let new = size(key, if rec.is_delete() { None } else { Some(rec.value()) });
+
+            for u in usage.iter_mut().filter(|u| key.starts_with(&u.quota.prefix)) {
+                // saturating, so a usage that was counted low can't wrap around
+                u.keys = (u.keys + new.is_some() as u64).saturating_sub(old.is_some() as u64);
+                u.bytes = (u.bytes + new.unwrap_or(0)).saturating_sub(old.unwrap_or(0));
+            }
+
+            written.push( (key, new) );
+        }
+
+        let before = self.usage.lock().unwrap();
+
+        let exceeded = usage.iter().zip(before.iter()).find(|&(after, before)| {
+            after.quota.over(after.keys, after.bytes) && (after.keys > before.keys || after.bytes > before.bytes)
+        }).map(|(after, _)| QuotaExceeded { prefix: after.quota.prefix.clone(), keys: after.keys, bytes: after.bytes });
+
+        Charge { usage: usage, exceeded: exceeded }
+    }
+
+    pub fn apply(&self, charge: Charge) {
+        if !charge.usage.is_empty() {
+            *self.usage.lock().unwrap() = charge.usage;
+        }
+    }
+
+    /// The prefixes of the quotas with keys in the range [start, end)
+    pub fn overlapping(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        self.usage.lock().unwrap().iter()
+            .filter(|u| u.quota.prefix.as_slice() < end && (start <= u.quota.prefix.as_slice() || start.starts_with(&u.quota.prefix)))
+            .map(|u| u.quota.prefix.clone())
+            .collect()
+    }
+
+    /// Sets the usage of the quota with the prefix, after counting it
+    pub fn set(&self, prefix: &[u8], keys: u64, bytes: u64) {
+        for u in self.usage.lock().unwrap().iter_mut().filter(|u| u.quota.prefix.as_slice() == prefix) {
+            u.keys = keys;
+            u.bytes = bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quota::{Quota, Quotas, QuotaExceeded};
+    use record::Record;
+    use std::collections::HashMap;
+
+    #[test]
+    fn charge() {
+        let quotas = Quotas::new(&[
+            Quota { prefix: b"a/".to_vec(), max_bytes: None, max_keys: Some(2) },
+            Quota { prefix: b"a/b/".to_vec(), max_bytes: Some(10), max_keys: None }
+        ]);
+
+        let mut store = HashMap::<Vec<u8>, Vec<u8>>::new();
+
+        // writes the records, if they don't exceed a quota
+        let mut write = |records: Vec<Record>| {
+            let charge = quotas.charge(&records, |k| store.get(k).cloned());
+
+            if let Some(e) = charge.exceeded {
+                return Err(e);
+            }
+
+            quotas.apply(charge);
+
+            for rec in records {
+                match rec.into_parts() {
+                    (key, Some(value)) => { store.insert(key, value); },
+                    (key, None) => { store.remove(&key); }
+                }
+            }
+
+            Ok( () )
+        };
+
+        assert!(write(vec![Record::new(b"a/1".to_vec(), Some(b"1".to_vec())), Record::new(b"b/1".to_vec(), Some(b"1".to_vec()))]).is_ok());
+        assert_eq!((1, 4), (quotas.usage()[0].keys, quotas.usage()[0].bytes));
+
+        // overwriting a key changes its bytes, not the keys
+        assert!(write(vec![Record::new(b"a/1".to_vec(), Some(b"123".to_vec()))]).is_ok());
+        assert_eq!((1, 6), (quotas.usage()[0].keys, quotas.usage()[0].bytes));
+
+        // a key under both prefixes counts for both
+        assert!(write(vec![Record::new(b"a/b/1".to_vec(), Some(b"12".to_vec()))]).is_ok());
+        assert_eq!((2, 13), (quotas.usage()[0].keys, quotas.usage()[0].bytes));
+        assert_eq!((1, 7), (quotas.usage()[1].keys, quotas.usage()[1].bytes));
+
+        assert_eq!(Err(QuotaExceeded { prefix: b"a/".to_vec(), keys: 3, bytes: 17 }), write(vec![Record::new(b"a/2".to_vec(), Some(b"1".to_vec()))]));
+        assert_eq!(Err(QuotaExceeded { prefix: b"a/b/".to_vec(), keys: 1, bytes: 11 }), write(vec![Record::new(b"a/b/1".to_vec(), Some(b"123456".to_vec()))]));
+
+        // a delete in the same batch makes room
+        assert!(write(vec![Record::new(b"a/1".to_vec(), None), Record::new(b"a/2".to_vec(), Some(b"1".to_vec()))]).is_ok());
+        assert_eq!((2, 11), (quotas.usage()[0].keys, quotas.usage()[0].bytes));
+
+        // a key written twice in a batch counts once
+        assert!(write(vec![Record::new(b"a/3".to_vec(), None), Record::new(b"a/2".to_vec(), Some(b"12".to_vec())), Record::new(b"a/2".to_vec(), Some(b"1".to_vec()))]).is_ok());
+        assert_eq!((2, 11), (quotas.usage()[0].keys, quotas.usage()[0].bytes));
+
+        assert_eq!(vec![b"a/".to_vec(), b"a/b/".to_vec()], quotas.overlapping(b"a/a", b"a/c"));
+        assert_eq!(vec![b"a/".to_vec(), b"a/b/".to_vec()], quotas.overlapping(b"a/b/1", b"a/b/2"));
+        assert_eq!(vec![b"a/".to_vec()], quotas.overlapping(b"a/c", b"b"));
+        assert!(quotas.overlapping(b"b", b"c").is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::io::{Cursor, Error as IOError, Write, Read};
+use std::io::{Cursor, Error as IOError, ErrorKind, Write};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 
 use U32_SIZE;
@@ -11,13 +11,30 @@ use record_file::buf2string;
 use kvs::get_timestamp;
 
 pub const VALUE_SENTINEL: u64 = 0xFF_FF_FF_FF_FF_FF_FF_FF;
+pub const RANGE_SENTINEL: u64 = 0xFF_FF_FF_FF_FF_FF_FF_FE; // followed by the length & end key of a range delete
+pub const POINTER_SENTINEL: u64 = 0xFF_FF_FF_FF_FF_FF_FF_FD; // followed by the length & pointer to a value in the value log
+pub const VERSION_SENTINEL: u64 = 0xFF_FF_FF_FF_FF_FF_FF_FC; // in place of the key length, followed by the version & the record with fields
+
+// The version of the encoding of a record with fields. A record without fields is written as it was before there
+// were versions, so files written before stay readable, and are written the same. A versioned record is followed
+// by the length of its fields, each a 1-byte tag, the length, and the bytes; a reader skips the tags it doesn't
+// know, so fields can be added without a new version. Only a change a reader can't skip bumps the version.
+pub const RECORD_VERSION: u8 = 1;
+
+// The tags of the fields
+pub const BATCH_TAG: u8 = 1; // in a WAL, the number of records of the same batch written after this one, as a u64
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Record {
     key: Vec<u8>,
     value: Option<Vec<u8>>, // if None, means we're deleting this key
+    range_end: Option<Vec<u8>>, // if Some, means we're deleting all keys in [key, range_end)
     created: u64, // timestamp of when the record was created
-    ttl: u64 // timestamp when this record should be deleted
+    ttl: u64, // timestamp when this record should be deleted
+    #[serde(skip)]
+    value_pointer: bool, // if true, the value is where the real one is in the value log
+    #[serde(skip)]
+    fields: Vec<u8> // the encoded fields, kept as they are so the tags this version doesn't know are written back
 }
 
 impl Record {
@@ -29,8 +46,24 @@ impl Record {
         Record {
             key: key,
             value: value,
+            range_end: None,
             created: get_timestamp(),
-            ttl: ttl
+            ttl: ttl,
+            value_pointer: false,
+            fields: vec![]
+        }
+    }
+
+    /// Creates a range tombstone that deletes all keys in [start, end) created before it
+    pub fn new_range_delete(start: Vec<u8>, end: Vec<u8>, created: u64) -> Record {
+        Record {
+            key: start,
+            value: None,
+            range_end: Some(end),
+            created: created,
+            ttl: u64::max_value(),
+            value_pointer: false,
+            fields: vec![]
         }
     }
 
@@ -38,8 +71,11 @@ impl Record {
     pub fn size(&self) -> u32 {
         (U64_SIZE + self.key.len() + // size of the key
             U64_SIZE + if self.value.is_some() { self.value.to_owned().unwrap().len() } else { 0 } + // size of the value
+            if let Some(ref end) = self.range_end { U64_SIZE + end.len() } else { 0 } + // size of the range end
+            if self.value_pointer { U64_SIZE } else { 0 } + // the sentinel before a pointer's length
             U64_SIZE + // size of created
-            U64_SIZE) as u32 // size of ttl
+            U64_SIZE + // size of ttl
+            if self.fields.is_empty() { 0 } else { U64_SIZE + 1 + U64_SIZE + self.fields.len() }) as u32 // the version, and the fields
     }
 
     /// Serializes the record into a write, appending first the total size of the record
@@ -47,15 +83,34 @@ impl Record {
     pub fn serialize<W>(&self, writer: &mut W) -> Result<u32, IOError> where W: Write {
         writer.write_u32::<LE>(self.size())?; // write out the total size of this serialization
 
-        // the serialization
+        self.serialize_body(writer)?;
+
+        return Ok(U32_SIZE as u32 + self.size());
+    }
+
+    /// Serializes the record into a writer without the size, the inverse of `deserialize`
+    pub fn serialize_body<W>(&self, writer: &mut W) -> Result<(), IOError> where W: Write {
+        if !self.fields.is_empty() {
+            writer.write_u64::<LE>(VERSION_SENTINEL)?; // sentinel value for a versioned record
+            writer.write_u8(RECORD_VERSION)?;
+        }
+
         writer.write_u64::<LE>(self.key.len() as u64)?; // length of key
         writer.write_all(&self.key)?; // write the actual key's data
 
         if self.value.is_some() {
             let value = self.value.to_owned().unwrap();
 
+            if self.value_pointer {
+                writer.write_u64::<LE>(POINTER_SENTINEL)?; // sentinel value for a pointer into the value log
+            }
+
             writer.write_u64::<LE>(value.len() as u64)?; // write the size of the value
             writer.write_all(&value)?;
+        } else if let Some(ref end) = self.range_end {
+            writer.write_u64::<LE>(RANGE_SENTINEL)?; // sentinel value for a range delete
+            writer.write_u64::<LE>(end.len() as u64)?;
+            writer.write_all(end)?;
         } else {
             writer.write_u64::<LE>(VALUE_SENTINEL)?; // sentinel value for no value
         }
@@ -63,57 +118,305 @@ impl Record {
         writer.write_u64::<LE>(self.created)?;
         writer.write_u64::<LE>(self.ttl)?;
 
-        return Ok(U32_SIZE as u32 + self.size());
+        if !self.fields.is_empty() {
+            writer.write_u64::<LE>(self.fields.len() as u64)?;
+            writer.write_all(&self.fields)?;
+        }
+
+        Ok( () )
     }
 
     pub fn deserialize(bytes: Vec<u8>) -> Record {
+        Record::try_deserialize(&bytes).expect("Error deserializing record")
+    }
+
+    /// Deserializes a record, returning an error instead of panicking if the bytes aren't a valid record
+    pub fn try_deserialize(bytes: &[u8]) -> Result<Record, IOError> {
+        RecordRef::parse(bytes).map(|rec| rec.to_record())
+    }
+
+    pub fn is_expired(&self, ts: u64) -> bool {
+        self.ttl <= ts
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.value.is_none()
+    }
+
+    pub fn is_range_delete(&self) -> bool {
+        self.range_end.is_some()
+    }
+
+    /// Returns true if the value is a pointer to the real one in the value log
+    pub fn is_value_pointer(&self) -> bool {
+        self.value_pointer
+    }
+
+    /// Returns true if this is a range delete covering the record: it's in the range, and older
+    pub fn covers(&self, rec: &Record) -> bool {
+        match self.range_end {
+            Some(ref end) => self.key <= rec.key && rec.key < *end && rec.created < self.created,
+            None => false
+        }
+    }
+
+    /// Returns true if this record of a key wins over the other by last-write-wins
+    ///
+    /// The later timestamp wins; on a tie the larger value does, with any value larger than a delete,
+    /// so replicas merging records written at the same time by different writers keep the same one.
+    pub fn is_newer_than(&self, other: &Record) -> bool {
+        (self.created, &self.value) > (other.created, &other.value)
+    }
+
+    /// Returns true if this is a range delete containing every key in [smallest, largest]
+    pub fn contains_range(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        match self.range_end {
+            Some(ref end) => self.key.as_slice() <= smallest && largest < end.as_slice(),
+            None => false
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The end of the range, for a range delete
+    pub fn range_end(&self) -> Option<&[u8]> {
+        self.range_end.as_ref().map(|end| end.as_slice())
+    }
+
+    pub fn value(&self) -> &[u8] {
+        self.value.as_ref().expect("Tried to get value of delete record")
+    }
+
+    /// Copies the record, with an empty value in place of its value, for scans of keys
+    pub fn to_key_record(&self) -> Record {
+        Record {
+            key: self.key.clone(),
+            value: self.value.as_ref().map(|_| vec![]),
+            range_end: self.range_end.clone(),
+            created: self.created,
+            ttl: self.ttl,
+            value_pointer: false,
+            fields: self.fields.to_vec()
+        }
+    }
+
+    /// Takes the key and value, None for a delete, without copying them
+    pub fn into_parts(self) -> (Vec<u8>, Option<Vec<u8>>) {
+        (self.key, self.value)
+    }
+
+    /// Sets when the record expires, in ms since the epoch
+    pub fn set_ttl(&mut self, ttl: u64) {
+        self.ttl = ttl;
+    }
+
+    /// Moves the created timestamp of a record
+    pub fn set_created(&mut self, created: u64) {
+        self.created = created;
+    }
+
+    /// Replaces the key of a record, leaving the timestamps alone
+    pub fn set_key(&mut self, key: Vec<u8>) {
+        self.key = key;
+    }
+
+    /// Replaces the value of a record, leaving the timestamps alone
+    pub fn set_value(&mut self, value: Vec<u8>) {
+        assert!(self.value.is_some(), "Tried to set value of delete record");
+
+        self.value = Some(value);
+        self.value_pointer = false;
+    }
+
+    /// Replaces the value of a record with a pointer to where it is in the value log
+    pub fn set_value_pointer(&mut self, pointer: Vec<u8>) {
+        self.set_value(pointer);
+        self.value_pointer = true;
+    }
+
+    /// The bytes of the field with the tag, if the record has it
+    pub fn field(&self, tag: u8) -> Option<&[u8]> {
+        parse_fields(&self.fields).expect("The fields were checked when set or parsed").into_iter().find(|&(t, _)| t == tag).map(|(_, bytes)| bytes)
+    }
+
+    /// Sets the field with the tag, replacing any the record has; the record is then written with a version
+    pub fn set_field(&mut self, tag: u8, bytes: &[u8]) {
+        let mut fields = Vec::with_capacity(self.fields.len() + 1 + U64_SIZE + bytes.len());
+
+        for (t, b) in parse_fields(&self.fields).expect("The fields were checked when set or parsed").into_iter().filter(|&(t, _)| t != tag) {
+            write_field(&mut fields, t, b);
+        }
+
+        write_field(&mut fields, tag, bytes);
+
+        self.fields = fields;
+    }
+
+    /// Removes the field with the tag, returning its bytes if the record had it
+    pub fn take_field(&mut self, tag: u8) -> Option<Vec<u8>> {
+        let mut fields = Vec::with_capacity(self.fields.len());
+        let mut taken = None;
+
+        for (t, b) in parse_fields(&self.fields).expect("The fields were checked when set or parsed").into_iter() {
+            if t == tag { taken = Some(b.to_vec()) } else { write_field(&mut fields, t, b) }
+        }
+
+        self.fields = fields;
+        taken
+    }
+}
+
+fn write_field(buff: &mut Vec<u8>, tag: u8, bytes: &[u8]) {
+    buff.push(tag);
+    buff.write_u64::<LE>(bytes.len() as u64).expect("Error writing to a Vec");
+    buff.extend_from_slice(bytes);
+}
+
+/// Splits the fields of a versioned record into their tags and bytes, in the order they were written
+fn parse_fields(bytes: &[u8]) -> Result<Vec<(u8, &[u8])>, IOError> {
+    let mut cursor = Cursor::new(bytes);
+    let mut fields = vec![];
+
+    while (cursor.position() as usize) < bytes.len() {
+        let tag = cursor.read_u8()?;
+        let len = cursor.read_u64::<LE>()?;
+
+        fields.push( (tag, RecordRef::read_bytes(&mut cursor, len)?) );
+    }
+
+    Ok(fields)
+}
+
+/// A record borrowing its key and value from the buffer it was serialized into
+///
+/// Parsing one doesn't allocate, so a record can be looked at in place, e.g. to compare its key,
+/// and only copied with `to_record` when it's needed.
+pub struct RecordRef<'a> {
+    key: &'a [u8],
+    value: Option<&'a [u8]>,
+    range_end: Option<&'a [u8]>,
+    created: u64,
+    ttl: u64,
+    value_pointer: bool,
+    fields: &'a [u8]
+}
+
+impl<'a> RecordRef<'a> {
+    /// Parses a record serialized without its size, like `Record::try_deserialize`
+    pub fn parse(bytes: &'a [u8]) -> Result<RecordRef<'a>, IOError> {
         let mut cursor = Cursor::new(bytes);
 
-        let key_len = cursor.read_u64::<LE>().expect("Error reading key length");
-        let mut key = vec![0x00; key_len as usize];
+        let mut key_len = cursor.read_u64::<LE>()?;
+        let versioned = key_len == VERSION_SENTINEL;
+
+        if versioned {
+            let version = cursor.read_u8()?;
+
+            if version > RECORD_VERSION {
+                return Err(IOError::new(ErrorKind::InvalidData, format!("Record version {} is newer than this one, {}", version, RECORD_VERSION)));
+            }
+
+            key_len = cursor.read_u64::<LE>()?;
+        }
 
-        cursor.read_exact(&mut key).expect("Error reading key");
+        let key = RecordRef::read_bytes(&mut cursor, key_len)?;
+        let value_len = cursor.read_u64::<LE>()?;
 
-        let value_len = cursor.read_u64::<LE>().expect("Error reading value length");
+        let mut range_end = None;
+        let mut value_pointer = false;
 
         let value = if value_len == VALUE_SENTINEL {
             None
-        } else {
-            let mut val_buff = vec![0x00; value_len as usize];
+        } else if value_len == POINTER_SENTINEL {
+            let pointer_len = cursor.read_u64::<LE>()?;
+
+            value_pointer = true;
+
+            Some(RecordRef::read_bytes(&mut cursor, pointer_len)?)
+        } else if value_len == RANGE_SENTINEL {
+            let end_len = cursor.read_u64::<LE>()?;
 
-            cursor.read_exact(&mut val_buff).expect("Error reading value");
+            range_end = Some(RecordRef::read_bytes(&mut cursor, end_len)?);
 
-            Some(val_buff)
+            None
+        } else {
+            Some(RecordRef::read_bytes(&mut cursor, value_len)?)
         };
 
-        let created = cursor.read_u64::<LE>().expect("Error reading created");
-        let ttl = cursor.read_u64::<LE>().expect("Error reading ttl");
+        let created = cursor.read_u64::<LE>()?;
+        let ttl = cursor.read_u64::<LE>()?;
 
-        Record{ key, value, created, ttl }
-    }
+        let fields = if versioned {
+            let fields_len = cursor.read_u64::<LE>()?;
+            let fields = RecordRef::read_bytes(&mut cursor, fields_len)?;
 
-    pub fn is_expired(&self, ts: u64) -> bool {
-        self.ttl <= ts
+            parse_fields(fields)?;
+
+            fields
+        } else {
+            &[]
+        };
+
+        Ok(RecordRef{ key, value, range_end, created, ttl, value_pointer, fields })
     }
 
-    pub fn created(&self) -> u64 {
-        self.created
+    /// Borrows the next len bytes, checking the length first so a bad one is an error
+    fn read_bytes(cursor: &mut Cursor<&'a [u8]>, len: u64) -> Result<&'a [u8], IOError> {
+        let bytes = *cursor.get_ref();
+        let start = cursor.position();
+
+        if len > bytes.len() as u64 - start {
+            return Err(IOError::new(ErrorKind::UnexpectedEof, format!("Record length is past the end of the buffer: {} > {}", len, bytes.len() as u64 - start)));
+        }
+
+        cursor.set_position(start + len);
+
+        Ok(&bytes[start as usize..(start + len) as usize])
     }
 
-    pub fn ttl(&self) -> u64 {
-        self.ttl
+    pub fn key(&self) -> &'a [u8] {
+        self.key
     }
 
-    pub fn is_delete(&self) -> bool {
-        self.value.is_none()
+    /// The value, None for a delete
+    pub fn value(&self) -> Option<&'a [u8]> {
+        self.value
     }
 
-    pub fn key(&self) -> Vec<u8> {
-        self.key.to_owned()
+    /// Copies the record out of the buffer
+    pub fn to_record(&self) -> Record {
+        Record {
+            key: self.key.to_vec(),
+            value: self.value.map(|v| v.to_vec()),
+            range_end: self.range_end.map(|e| e.to_vec()),
+            created: self.created,
+            ttl: self.ttl,
+            value_pointer: self.value_pointer,
+            fields: self.fields.to_vec()
+        }
     }
 
-    pub fn value(&self) -> Vec<u8> {
-        self.value.to_owned().expect("Tried to get value of delete record")
+    /// Copies the record out of the buffer, with an empty value in place of its value
+    pub fn to_key_record(&self) -> Record {
+        Record {
+            key: self.key.to_vec(),
+            value: self.value.map(|_| vec![]),
+            range_end: self.range_end.map(|e| e.to_vec()),
+            created: self.created,
+            ttl: self.ttl,
+            value_pointer: false,
+            fields: self.fields.to_vec()
+        }
     }
 }
 
@@ -147,6 +450,7 @@ impl Debug for Record {
         formatter.debug_struct("Record")
             .field("key", &buf2string(&self.key))
             .field("value", &match &self.value { &None => String::from("None"), &Some(ref v) => buf2string(&v) })
+            .field("range_end", &self.range_end.as_ref().map(|e| buf2string(e)))
             .field("created", &self.created)
             .field("ttl", &self.ttl)
             .finish()
@@ -155,13 +459,14 @@ impl Debug for Record {
 
 #[cfg(test)]
 mod tests {
-    use record::Record;
+    use record::{Record, RecordRef, VERSION_SENTINEL, RECORD_VERSION};
+    use byteorder::{ByteOrder, LE};
     use std::io::Cursor;
     use ::U32_SIZE;
 
     #[test]
     fn serialize_value() {
-        let rec = Record{ key: vec![123; 8], value: Some(vec![21; 12]), created: 1234, ttl: 6789 };
+        let rec = Record{ key: vec![123; 8], value: Some(vec![21; 12]), range_end: None, created: 1234, ttl: 6789, value_pointer: false, fields: vec![] };
 
         let buff = vec![0x00 as u8; rec.size() as usize + U32_SIZE];
         let mut cursor = Cursor::new(buff);
@@ -181,7 +486,7 @@ mod tests {
 
     #[test]
     fn serialize_no_value() {
-        let rec = Record{ key: vec![123; 8], value: None, created: 1234, ttl: 6789 };
+        let rec = Record{ key: vec![123; 8], value: None, range_end: None, created: 1234, ttl: 6789, value_pointer: false, fields: vec![] };
 
         let buff = vec![0x00 as u8; rec.size() as usize + U32_SIZE];
         let mut cursor = Cursor::new(buff);
@@ -199,4 +504,142 @@ mod tests {
         assert_eq!(rec.ttl, rec_d.ttl);
     }
 
+    #[test]
+    fn serialize_range_delete() {
+        let rec = Record::new_range_delete(vec![123; 8], vec![124; 8], 1234);
+
+        let buff = vec![0x00 as u8; rec.size() as usize + U32_SIZE];
+        let mut cursor = Cursor::new(buff);
+
+        rec.serialize(&mut cursor).unwrap();
+        let buff_d = cursor.into_inner();
+
+        let rec_d = Record::deserialize(buff_d[4..].to_vec());
+
+        assert_eq!(rec.key, rec_d.key);
+        assert_eq!(rec.range_end, rec_d.range_end);
+        assert!(rec_d.is_delete());
+        assert!(rec_d.is_range_delete());
+        assert_eq!(rec.created, rec_d.created);
+    }
+
+    #[test]
+    fn serialize_value_pointer() {
+        let mut rec = Record::new(vec![123; 8], Some(vec![21; 1000]));
+
+        rec.set_value_pointer(vec![7; 16]);
+
+        let mut buff = vec![];
+
+        assert_eq!(rec.serialize(&mut buff).unwrap(), buff.len() as u32);
+
+        let rec_d = Record::deserialize(buff[4..].to_vec());
+
+        assert!(rec_d.is_value_pointer());
+        assert_eq!(&[7; 16], rec_d.value());
+        assert!(!rec_d.to_key_record().is_value_pointer());
+
+        // a value read back in place of the pointer is a value again
+        let mut resolved = rec_d.clone();
+
+        resolved.set_value(vec![21; 1000]);
+
+        assert!(!resolved.is_value_pointer());
+    }
+
+    #[test]
+    fn serialize_fields() {
+        let mut rec = Record{ key: vec![123; 8], value: Some(vec![21; 12]), range_end: None, created: 1234, ttl: 6789, value_pointer: false, fields: vec![] };
+        let mut legacy = vec![];
+
+        // a record without fields is written as before there were versions
+        rec.serialize_body(&mut legacy).unwrap();
+
+        assert_eq!(&[8, 0, 0, 0, 0, 0, 0, 0], &legacy[..8]);
+
+        rec.set_field(1, b"ONE");
+        rec.set_field(200, b"UNKNOWN");
+        rec.set_field(1, b"UNO");
+
+        let mut buff = vec![];
+
+        assert_eq!(rec.serialize(&mut buff).unwrap(), buff.len() as u32);
+        assert_eq!(VERSION_SENTINEL, LE::read_u64(&buff[4..12]));
+
+        let rec_d = Record::deserialize(buff[4..].to_vec());
+
+        assert_eq!(rec.key, rec_d.key);
+        assert_eq!(rec.value, rec_d.value);
+        assert_eq!((1234, 6789), (rec_d.created, rec_d.ttl));
+        assert_eq!(Some(&b"UNO"[..]), rec_d.field(1));
+        assert_eq!(Some(&b"UNKNOWN"[..]), rec_d.field(200));
+        assert_eq!(None, rec_d.field(2));
+
+        // the fields are written back as they were read, with the tags this version doesn't know
+        let mut rewritten = vec![];
+
+        rec_d.serialize_body(&mut rewritten).unwrap();
+
+        assert_eq!(&buff[4..], &rewritten[..]);
+
+        // a field taken off isn't written
+        let mut taken = Record::deserialize(buff[4..].to_vec());
+
+        assert_eq!(Some(b"UNO".to_vec()), taken.take_field(1));
+        assert_eq!(None, taken.take_field(1));
+        assert_eq!(Some(&b"UNKNOWN"[..]), taken.field(200));
+
+        // a newer version can't be read, nor fields past the end of the record
+        let mut newer = rewritten.clone();
+
+        newer[8] = RECORD_VERSION + 1;
+
+        assert!(RecordRef::parse(&newer).is_err());
+
+        let mut bad_len = rewritten.clone();
+        let fields_end = bad_len.len();
+
+        bad_len[fields_end - 4] = 0xFF; // the high byte of the last field's length
+
+        assert!(RecordRef::parse(&bad_len).is_err());
+    }
+
+    #[test]
+    fn range_delete_covers() {
+        let tombstone = Record::new_range_delete(b"B".to_vec(), b"D".to_vec(), 1000);
+
+        assert!(tombstone.covers(&Record{ key: b"B".to_vec(), value: None, range_end: None, created: 999, ttl: 0, value_pointer: false, fields: vec![] }));
+        assert!(tombstone.covers(&Record{ key: b"C".to_vec(), value: None, range_end: None, created: 999, ttl: 0, value_pointer: false, fields: vec![] }));
+        assert!(!tombstone.covers(&Record{ key: b"C".to_vec(), value: None, range_end: None, created: 1000, ttl: 0, value_pointer: false, fields: vec![] }));
+        assert!(!tombstone.covers(&Record{ key: b"A".to_vec(), value: None, range_end: None, created: 999, ttl: 0, value_pointer: false, fields: vec![] }));
+        assert!(!tombstone.covers(&Record{ key: b"D".to_vec(), value: None, range_end: None, created: 999, ttl: 0, value_pointer: false, fields: vec![] }));
+
+        assert!(tombstone.contains_range(b"B", b"CZZ"));
+        assert!(!tombstone.contains_range(b"A", b"C"));
+        assert!(!tombstone.contains_range(b"B", b"D"));
+    }
+
+    #[test]
+    fn borrowed_parts() {
+        let rec = Record{ key: vec![123; 8], value: Some(vec![21; 12]), range_end: None, created: 1234, ttl: 6789, value_pointer: false, fields: vec![] };
+
+        let mut buff = vec![];
+
+        rec.serialize_body(&mut buff).unwrap();
+
+        // the key and value point into the buffer
+        let rec_ref = RecordRef::parse(&buff).unwrap();
+
+        assert_eq!(&buff[8..16], rec_ref.key());
+        assert_eq!(rec_ref.key().as_ptr(), buff[8..].as_ptr());
+        assert_eq!(Some(&[21; 12][..]), rec_ref.value());
+        assert_eq!(1234, rec_ref.to_record().created());
+
+        assert!(RecordRef::parse(&buff[..buff.len() - 1]).is_err());
+
+        let (key, value) = rec.into_parts();
+
+        assert_eq!(vec![123; 8], key);
+        assert_eq!(Some(vec![21; 12]), value);
+    }
 }
@@ -1,14 +1,23 @@
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use lru_cache::LruCache;
+#[cfg(target_os = "linux")] use libc;
 use positioned_io::{ReadAt, WriteAt, WriteBytesExt as PositionedWriteBytesExt, ReadBytesExt as PositionedReadBytesExt};
 
 use std::cell::RefCell;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::fs::{File, OpenOptions};
 use std::io::{Error as IOError, ErrorKind, Read, Seek, SeekFrom, Write, BufWriter};
+#[cfg(target_os = "linux")] use std::ffi::CString;
+#[cfg(target_os = "linux")] use std::mem;
+#[cfg(target_os = "linux")] use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")] use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use record::{Record, VALUE_SENTINEL};
+use cache::{CachePolicy, CachePolicyKind, new_cache};
+use record::Record;
+use format::{self, file_prefix_len, encode_file_prefix, decode_file_prefix, aligned_offset, BAD_COUNT};
+use sim;
 
 use U32_SIZE;
 use U64_SIZE;
@@ -27,19 +36,28 @@ use U64_SIZE;
 /// |---------------------------|
 /// | ...                       |
 /// |---------------------------|
+/// With an alignment set, a record that would cross a block boundary starts at the next one instead,
+/// after zeros. Records larger than a block start at a boundary. As no record crosses a boundary from
+/// the middle of a block, a reader finding fewer than 4 bytes, or a size of 0, before the next boundary
+/// knows it's padding.
 
-pub const BAD_COUNT: u32 = 0xFFFFFFFF;
 
 
 /// Record file
 pub struct RecordFile {
     fd: File,           // actual file
-    writer: RefCell<BufWriter<File>>,  // buffered writer
+    writer: Mutex<BufWriter<File>>,  // buffered writer
     file_path: PathBuf, // location of the file on disk
     record_count: u32,  // number of records in the file
     header_len: usize,  // length of the header
     last_record: u64,   // the start of the last record
-    record_cache: RefCell<LruCache<u64, Vec<u8>>>
+    file_len: AtomicU64, // the length of the file, as written through this handle, or last looked up
+    alignment: u64,     // the block size records are aligned to, 0 for none
+    padding_bytes: u64, // the bytes of padding appended through this handle
+    record_cache: Mutex<Box<CachePolicy>>,
+    cache_hits: AtomicU64,  // reads found in the record_cache
+    cache_misses: AtomicU64, // reads that went to the file
+    readahead_bytes: AtomicU64 // asked to be read ahead by `readahead`
 }
 
 pub fn buf2string(buf: &[u8]) -> String {
@@ -52,6 +70,46 @@ pub fn buf2string(buf: &[u8]) -> String {
     return ret;
 }
 
+/// Has the OS drop the pages of a file from its page cache, as they won't be read again
+///
+/// Only pages that were written to disk are dropped. Only Linux drops them, elsewhere it does nothing.
+#[cfg(target_os = "linux")]
+pub fn drop_page_cache(file_path: &PathBuf) -> Result<(), IOError> {
+    let fd = File::open(file_path)?;
+    let ret = unsafe { libc::posix_fadvise(fd.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+
+    if ret != 0 {
+        return Err(IOError::from_raw_os_error(ret));
+    }
+
+    Ok( () )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_page_cache(_file_path: &PathBuf) -> Result<(), IOError> {
+    Ok( () )
+}
+
+/// The bytes free for unprivileged users on the file system holding the directory
+///
+/// Only Linux knows it, elsewhere it's None.
+#[cfg(target_os = "linux")]
+pub fn free_space(dir: &PathBuf) -> Result<Option<u64>, IOError> {
+    let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| IOError::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(IOError::last_os_error());
+    }
+
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn free_space(_dir: &PathBuf) -> Result<Option<u64>, IOError> {
+    Ok(None)
+}
+
 fn rec_to_string(size: u32, rec: &[u8]) -> String {
     let mut dbg_buf = String::new();
 
@@ -63,6 +121,11 @@ fn rec_to_string(size: u32, rec: &[u8]) -> String {
 
 impl RecordFile {
     pub fn new(file_path: &PathBuf, header: &[u8], buffer_size: usize, cache_size: usize) -> Result<RecordFile, IOError> {
+        RecordFile::with_cache(file_path, header, buffer_size, new_cache(CachePolicyKind::Lru, cache_size))
+    }
+
+    /// Opens or creates a file, caching the records it reads and appends in the cache
+    pub fn with_cache(file_path: &PathBuf, header: &[u8], buffer_size: usize, cache: Box<CachePolicy>) -> Result<RecordFile, IOError> {
         debug!("Attempting to open file: {}", file_path.display());
 
         let mut fd = OpenOptions::new()
@@ -71,15 +134,13 @@ impl RecordFile {
             .create(true)
             .open(&file_path)?;
         let mut record_count = 0;
-        let mut last_record = (header.len() + U32_SIZE + U64_SIZE) as u64;
+        let mut last_record = file_prefix_len(header) as u64;
 
         fd.seek(SeekFrom::Start(0))?;
 
         // check to see if we're opening a new/blank file or not
         if fd.metadata()?.len() == 0 {
-            fd.write(header)?;
-            fd.write_u32::<LE>(BAD_COUNT)?; // record count
-            fd.write_u64::<LE>(last_record)?;
+            fd.write_all(&encode_file_prefix(header, BAD_COUNT, last_record))?;
 
             debug!(
                 "Created new RecordFile {} with count {} and last record {}",
@@ -88,25 +149,14 @@ impl RecordFile {
                 last_record
             );
         } else {
-            let mut header_buff = vec![0; header.len()];
-
-            fd.read_exact(&mut header_buff)?;
+            let mut prefix = vec![0; file_prefix_len(header)];
 
-            if header != header_buff.as_slice() {
-                return Err(IOError::new(
-                    ErrorKind::InvalidData,
-                    format!("Invalid file header for: {}", file_path.display()),
-                ));
-            }
+            fd.read_exact(&mut prefix)?;
 
-            record_count = fd.read_u32::<LE>()?;
-
-            if record_count == BAD_COUNT {
-                //TODO: Add a check in here
-                panic!("Opened a bad record file; record_count == BAD_COUNT");
-            }
+            let (count, last) = decode_file_prefix(&prefix, header).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("{}: {}", e, file_path.display())))?;
 
-            last_record = fd.read_u64::<LE>()?;
+            record_count = count;
+            last_record = last;
 
             fd.seek(SeekFrom::End(0))?; // go to the end of the file
 
@@ -118,7 +168,8 @@ impl RecordFile {
             );
         }
 
-        let writer = RefCell::new(BufWriter::with_capacity(buffer_size, fd.try_clone().expect("Unable to create RecordFile writer")));
+        let file_len = fd.metadata()?.len();
+        let writer = Mutex::new(BufWriter::with_capacity(buffer_size, fd.try_clone().expect("Unable to create RecordFile writer")));
 
         Ok(RecordFile {
             fd,
@@ -127,7 +178,13 @@ impl RecordFile {
             record_count,
             header_len: header.len(),
             last_record,
-            record_cache: RefCell::new(LruCache::new(cache_size))
+            file_len: AtomicU64::new(file_len),
+            alignment: 0,
+            padding_bytes: 0,
+            record_cache: Mutex::new(cache),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            readahead_bytes: AtomicU64::new(0)
         })
     }
 
@@ -140,84 +197,273 @@ impl RecordFile {
         self.file_path.clone()
     }
 
+    /// The size of the file on disk, padding and preallocated space included
+    pub fn file_len(&self) -> Result<u64, IOError> {
+        Ok(self.fd.metadata()?.len())
+    }
+
     pub fn last_record(&self) -> Result<Vec<u8>, IOError> {
         self.read_at(self.last_record)
     }
 
+    /// The offset of the last record
+    pub fn last_offset(&self) -> u64 {
+        self.last_record
+    }
+
+    /// Aligns the records appended from now on to blocks of the size, see the file format above
+    ///
+    /// Must be set to the same size when reading the file; only the iterators, and `skip_padding`
+    /// step over the padding, as `read_at` is given the offsets of records.
+    pub fn set_alignment(&mut self, block_size: u64) {
+        self.alignment = block_size;
+    }
+
+    /// The bytes of padding appended to align records
+    pub fn padding_bytes(&self) -> u64 {
+        self.padding_bytes
+    }
+
+    /// Pads the end of the file, if needed, so a record of the size doesn't cross a block boundary
+    /// Returns the location to write the record
+    fn align(writer: &mut BufWriter<File>, alignment: u64, padding_bytes: &mut u64, rec_size: usize) -> Result<u64, IOError> {
+        let end = writer.seek(SeekFrom::End(0))?;
+        let rec_loc = aligned_offset(end, alignment, rec_size);
+        let padding = rec_loc - end;
+
+        if padding != 0 {
+            writer.write_all(&vec![0; padding as usize])?;
+            *padding_bytes += padding;
+        }
+
+        Ok(rec_loc)
+    }
+
+    /// Returns the offset of the record at, or after the padding at, the offset
+    pub fn skip_padding(&self, file_offset: u64) -> Result<u64, IOError> {
+        format::skip_padding(file_offset, self.alignment, |offset| {
+            self.writer.lock().unwrap().flush()?; // need to flush any existing writes to disk
+
+            match self.fd.read_u32_at::<LE>(offset) {
+                Ok(size) => Ok(Some(size)),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None), // the end of the file
+                Err(e) => Err(e)
+            }
+        })
+    }
+
+    /// Moves the file's position past any padding, for the iterators reading through it
+    fn seek_past_padding(&mut self) -> Result<(), IOError> {
+        let pos = self.fd.seek(SeekFrom::Current(0))?;
+        let next = self.skip_padding(pos)?;
+
+        if next != pos {
+            self.fd.seek(SeekFrom::Start(next))?;
+        }
+
+        Ok( () )
+    }
+
     /// Appends a record to the end of the file without flushing to disk
     /// Returns the location where the record was written
     pub fn append(&mut self, record: &[u8]) -> Result<u64, IOError> {
-        let writer = self.writer.get_mut();
-        let rec_loc = writer.seek(SeekFrom::End(0))?;
         let rec_size = record.len();
 
+        assert!(self.alignment == 0 || rec_size != 0, "Empty records can't be told from padding");
+
+        let writer = self.writer.get_mut().unwrap();
+        let rec_loc = RecordFile::align(writer, self.alignment, &mut self.padding_bytes, rec_size)?;
+
         writer.write_u32::<LE>(rec_size as u32)?;
         writer.write(record)?;
 
         self.record_count += 1;
         self.last_record = rec_loc;
+        *self.file_len.get_mut() = rec_loc + (U32_SIZE + rec_size) as u64;
 
         // add to our cache
-        self.record_cache.get_mut().insert(rec_loc, record.to_owned());
+        self.record_cache.get_mut().unwrap().insert(rec_loc, record.to_owned());
 
         Ok(rec_loc)
     }
 
     pub fn append_record(&mut self, rec: &Record) -> Result<u64, IOError> {
-        let writer = self.writer.get_mut();
-        let rec_loc = writer.seek(SeekFrom::End(0))?;
+        let writer = self.writer.get_mut().unwrap();
+        let rec_loc = RecordFile::align(writer, self.alignment, &mut self.padding_bytes, rec.size() as usize)?;
 
-        writer.write_u32::<LE>(rec.size())?; // write out the total size of this serialization
+        let written = rec.serialize(writer)?; // writes the total size of the serialization, then the record
 
-        // the serialization
-        writer.write_u64::<LE>(rec.key().len() as u64)?; // length of key
+        self.record_count += 1;
+        self.last_record = rec_loc;
+        *self.file_len.get_mut() = rec_loc + written as u64;
 
-        writer.write_all(&rec.key())?; // write the actual key's data
+        Ok(rec_loc)
+    }
 
-        if !rec.is_delete() {
-            let value = rec.value().to_owned();
+    /// Writes out the buffered records and the count
+    ///
+    /// # Panics
+    /// If the writes fail; `sync` and `close` return the error instead.
+    pub fn flush(&mut self) {
+        self.write_count().expect("Error flushing to disk");
+    }
 
-            writer.write_u64::<LE>(value.len() as u64)?; // write the size of the value
-            writer.write_all(&value)?;
-        } else {
-            writer.write_u64::<LE>(VALUE_SENTINEL)?; // sentinel value for no value
-        }
+    /// Writes out the buffered records, then the count and the end of the file
+    fn write_count(&mut self) -> Result<(), IOError> {
+        // a writer poisoned by a panic is still written out; the count only covers whole appends
+        let writer = match self.writer.get_mut() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner()
+        };
 
-        writer.write_u64::<LE>(rec.created())?;
-        writer.write_u64::<LE>(rec.ttl())?;
+        writer.seek(SeekFrom::Start(self.header_len as u64))?;
+        writer.write_u32::<LE>(self.record_count)?;
+        writer.write_u64::<LE>(self.last_record)?; // write out the end of the file
+        writer.flush()
+    }
 
-        self.record_count += 1;
-        self.last_record = rec_loc;
+    /// Flushes, then waits for the file to reach the disk
+    pub fn sync(&mut self) -> Result<(), IOError> {
+        self.write_count()?;
+        self.fd.sync_data()?;
 
-        Ok(rec_loc)
+        sim::on_sync(&self.file_path);
+
+        Ok( () )
     }
 
-    pub fn flush(&mut self) {
-        let writer = self.writer.get_mut();
-        writer.seek(SeekFrom::Start(self.header_len as u64)).expect("Error seeking");
-        writer.write_u32::<LE>(self.record_count).expect("Error writing record count"); // cannot return an error, so best attempt
-        writer.write_u64::<LE>(self.last_record).expect("Error writing last record");  // write out the end of the file
-        writer.flush().expect("Error flushing to disk");
+    /// Flushes, then returns a handle that can sync the file without holding on to the RecordFile
+    pub fn sync_handle(&mut self) -> Result<File, IOError> {
+        self.write_count()?;
+        self.fd.try_clone()
+    }
+
+    /// Syncs and closes the file, returning the error a drop can only log
+    pub fn close(mut self) -> Result<(), IOError> {
+        self.sync()
     }
 
     /// Read a record from a given offset
     pub fn read_at(&self, file_offset: u64) -> Result<Vec<u8>, IOError> {
-        if let Some(ret) = self.record_cache.borrow_mut().get_mut(&file_offset) {
-            return Ok(ret.to_vec());
+        self.read_at_with(file_offset, true)
+    }
+
+    /// Read a record from a given offset, only adding it to the cache if fill_cache is set
+    pub fn read_at_with(&self, file_offset: u64, fill_cache: bool) -> Result<Vec<u8>, IOError> {
+        let mut rec_buff = Vec::new();
+
+        self.read_into(file_offset, fill_cache, &mut rec_buff)?;
+
+        Ok(rec_buff)
+    }
+
+    /// Reads a record into the buffer, in place of what it held, like `read_at_with`
+    ///
+    /// The buffer's space is reused, so reading many records through one doesn't allocate once it's big enough.
+    pub fn read_into(&self, file_offset: u64, fill_cache: bool, rec_buff: &mut Vec<u8>) -> Result<(), IOError> {
+        rec_buff.clear();
+
+        if let Some(rec) = self.record_cache.lock().unwrap().get(file_offset) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            rec_buff.extend_from_slice(rec);
+            return Ok( () );
         }
 
-        self.writer.borrow_mut().flush()?; // need to flush any existing writes to disk
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        self.writer.lock().unwrap().flush()?; // need to flush any existing writes to disk
         let rec_size = self.fd.read_u32_at::<LE>(file_offset)?;
-        let mut rec_buff = vec![0; rec_size as usize];
+
+        self.check_size(file_offset, rec_size as usize)?;
+
+        rec_buff.resize(rec_size as usize, 0);
 
         debug!("ATTEMPTING TO READ RECORD OF SIZE {} FROM {}", rec_size, file_offset);
 
-        self.fd.read_exact_at(file_offset + U32_SIZE as u64, &mut rec_buff)?;
+        self.fd.read_exact_at(file_offset + U32_SIZE as u64, rec_buff)?;
 
         // add to our cache
-        self.record_cache.borrow_mut().insert(file_offset, rec_buff.to_owned());
+        if fill_cache {
+            self.record_cache.lock().unwrap().insert(file_offset, rec_buff.to_owned());
+        }
 
-        Ok(rec_buff)
+        Ok( () )
+    }
+
+    /// The reads through `read_at` that were, and weren't, found in the cache
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.cache_hits.load(Ordering::Relaxed), self.cache_misses.load(Ordering::Relaxed))
+    }
+
+    /// Has the OS start reading the bytes into its page cache, for reads that are about to come
+    ///
+    /// Only Linux reads ahead, elsewhere it does nothing.
+    #[cfg(target_os = "linux")]
+    pub fn readahead(&self, file_offset: u64, len: u64) -> Result<(), IOError> {
+        // returns the error, instead of setting errno
+        let ret = unsafe { libc::posix_fadvise(self.fd.as_raw_fd(), file_offset as libc::off_t, len as libc::off_t, libc::POSIX_FADV_WILLNEED) };
+
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+
+        self.readahead_bytes.fetch_add(len, Ordering::Relaxed);
+
+        Ok( () )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn readahead(&self, _file_offset: u64, _len: u64) -> Result<(), IOError> {
+        Ok( () )
+    }
+
+    /// The bytes read ahead through `readahead`
+    pub fn readahead_bytes(&self) -> u64 {
+        self.readahead_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The offset of the first record
+    pub fn first_offset(&self) -> u64 {
+        (self.header_len + U32_SIZE + U64_SIZE) as u64
+    }
+
+    /// Reads part of a record from a given offset, without reading or caching the whole record
+    pub fn read_part_at(&self, file_offset: u64, start: usize, len: usize) -> Result<Vec<u8>, IOError> {
+        self.writer.lock().unwrap().flush()?; // need to flush any existing writes to disk
+        let rec_size = self.fd.read_u32_at::<LE>(file_offset)? as usize;
+
+        if start + len > rec_size {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("Read past the end of the record: {} + {} > {}", start, len, rec_size)));
+        }
+
+        self.check_size(file_offset, rec_size)?;
+
+        let mut part_buff = vec![0; len];
+
+        self.fd.read_exact_at(file_offset + (U32_SIZE + start) as u64, &mut part_buff)?;
+
+        Ok(part_buff)
+    }
+
+    /// Returns an error if a record of the size at the offset would go past the end of the file
+    ///
+    /// The file is only looked at for a record past the length kept by the handle, as another handle
+    /// may have appended to it since.
+    fn check_size(&self, file_offset: u64, rec_size: usize) -> Result<(), IOError> {
+        let rec_end = file_offset + (U32_SIZE + rec_size) as u64;
+
+        if rec_end <= self.file_len.load(Ordering::Relaxed) {
+            return Ok( () );
+        }
+
+        let file_len = self.fd.metadata()?.len();
+
+        self.file_len.fetch_max(file_len, Ordering::Relaxed);
+
+        if rec_end > file_len {
+            return Err(IOError::new(ErrorKind::UnexpectedEof, format!("Record at {} of size {} is past the end of the file: {}", file_offset, rec_size, file_len)));
+        }
+
+        Ok( () )
     }
 
     /// Writes a record at a given offset... this is potentially VERY dangerous
@@ -231,32 +477,159 @@ impl RecordFile {
         self.fd.write_u32_at::<LE>(file_offset, record.len() as u32)?;
         self.fd.write_all_at(file_offset + U32_SIZE as u64, &record)?;
 
+        let rec_end = file_offset + (U32_SIZE + record.len()) as u64;
+        let file_len = self.file_len.get_mut();
+
+        *file_len = (*file_len).max(rec_end);
+
         // add to our cache
-        self.record_cache.get_mut().insert(file_offset, record.to_owned());
+        self.record_cache.get_mut().unwrap().insert(file_offset, record.to_owned());
 
         Ok( () )
     }
 
-    pub fn iter(&self) -> Iter {
-        Iter {
-            record_file: self,
-            cur_offset: Some((self.header_len + U32_SIZE + U64_SIZE) as u64)
+    /// Reserves disk space for the file to grow by the bytes, without changing its length
+    ///
+    /// Running out of space then fails here, instead of part way through the records, and the file's
+    /// blocks are less fragmented. Only Linux reserves the space, elsewhere it does nothing.
+    /// Space left over past the end is freed by `trim_preallocated`, or removing the file.
+    #[cfg(target_os = "linux")]
+    pub fn preallocate(&mut self, bytes: u64) -> Result<(), IOError> {
+        if bytes == 0 {
+            return Ok( () );
         }
+
+        self.writer.get_mut().unwrap().flush()?; // so the space is reserved after the writes
+
+        let file_len = self.fd.metadata()?.len();
+        let ret = unsafe { libc::fallocate(self.fd.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, file_len as libc::off_t, bytes as libc::off_t) };
+
+        if ret != 0 {
+            let err = IOError::last_os_error();
+
+            // file systems that can't reserve space just allocate it as the file grows
+            if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                debug!("Can't preallocate {:?}: {}", self.file_path, err);
+                return Ok( () );
+            }
+
+            return Err(err);
+        }
+
+        debug!("Preallocated {} bytes for {:?} at {}", bytes, self.file_path, file_len);
+
+        Ok( () )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn preallocate(&mut self, _bytes: u64) -> Result<(), IOError> {
+        Ok( () )
+    }
+
+    /// Has the file system free the space reserved by `preallocate` that wasn't written to
+    pub fn trim_preallocated(&mut self) -> Result<(), IOError> {
+        self.writer.get_mut().unwrap().flush()?; // need to flush any existing writes to disk
+
+        let file_len = self.fd.metadata()?.len();
+
+        self.fd.set_len(file_len)
+    }
+
+    /// The offset just past the last record, where the records end
+    pub fn ends_at(&self) -> Result<u64, IOError> {
+        if self.record_count == 0 {
+            return Ok(self.first_offset());
+        }
+
+        self.writer.lock().unwrap().flush()?; // need to flush any existing writes to disk
+        let rec_size = self.fd.read_u32_at::<LE>(self.last_record)?;
+
+        Ok(self.last_record + (U32_SIZE as u64) + rec_size as u64)
     }
 
-    /// Creates an iterator from a given offset
+    /// Discards the records from the offset on, such as a partially written tail, and writes out the new count
+    ///
+    /// The offset must be where a record, or the padding before it, starts, or where the records end.
+    pub fn truncate_to(&mut self, file_offset: u64) -> Result<(), IOError> {
+        // replace the writer, as one that failed to write can't be flushed; dropping it writes what it can
+        let capacity = self.writer.get_mut().unwrap().capacity();
+        *self.writer.get_mut().unwrap() = BufWriter::with_capacity(capacity, self.fd.try_clone()?);
+
+        let file_len = self.fd.metadata()?.len();
+
+        if file_offset < self.first_offset() || file_offset > file_len {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("Can't truncate {:?} to {}, of {} bytes", self.file_path, file_offset, file_len)));
+        }
+
+        // count the records before the offset, only reading their sizes
+        let mut record_count = 0;
+        let mut last_record = self.first_offset();
+        let mut records_end = self.first_offset(); // just past the last record counted
+
+        loop {
+            let rec_offset = self.skip_padding(records_end)?;
+
+            if rec_offset >= file_offset {
+                break;
+            }
+
+            let rec_size = self.fd.read_u32_at::<LE>(rec_offset)?;
+
+            record_count += 1;
+            last_record = rec_offset;
+            records_end = rec_offset + (U32_SIZE as u64) + rec_size as u64;
+        }
+
+        if file_offset < records_end {
+            return Err(IOError::new(ErrorKind::InvalidInput, format!("Can't truncate {:?} inside the record at {}", self.file_path, last_record)));
+        }
+
+        debug!("Truncating {:?} to {}: records: {} -> {}", self.file_path, file_offset, self.record_count, record_count);
+
+        self.fd.set_len(file_offset)?;
+        self.record_count = record_count;
+        self.last_record = last_record;
+        *self.file_len.get_mut() = file_offset;
+        self.record_cache.get_mut().unwrap().clear(); // records past the offset can be cached
+
+        self.write_count()
+    }
+
+    /// Iterates over the records, and their offsets
+    pub fn iter(&self) -> Iter {
+        self.iter_from(self.first_offset())
+    }
+
+    /// Iterates over the records, and their offsets, from a given offset
     pub fn iter_from(&self, offset: u64) -> Iter {
-        Iter {
+        Iter { records: self.iter_with_offsets_from(offset).expect("Error reading file") }
+    }
+
+    /// Iterates over the offsets and records, returning read errors instead of panicking
+    ///
+    /// For rebuilding indices that point into the file, and recovering from damaged files.
+    /// The records aren't added to the cache.
+    pub fn iter_with_offsets(&self) -> Result<OffsetIter, IOError> {
+        self.iter_with_offsets_from(self.first_offset())
+    }
+
+    /// Iterates over the offsets and records from a given offset, returning read errors instead of panicking
+    pub fn iter_with_offsets_from(&self, offset: u64) -> Result<OffsetIter, IOError> {
+        Ok(OffsetIter {
             record_file: self,
-            cur_offset: Some(offset)
-        }
+            cur_offset: offset,
+            ends_at: self.ends_at()?
+        })
     }
 
 }
 
 impl Drop for RecordFile {
+    /// Writes out what it can; a failed write is logged, as a drop can't return it, and may be unwinding already
     fn drop(&mut self) {
-        self.flush();
+        if let Err(e) = self.write_count() {
+            warn!("Error writing out {:?} on drop: {}", self.file_path, e);
+        }
 
         debug!("DROP: {:?}: records: {}; last record: {}", self.file_path, self.record_count, self.last_record);
     }
@@ -272,35 +645,60 @@ impl Debug for RecordFile {
     }
 }
 
-pub struct Iter<'a> {
+/// Iterates over the offsets and records of a file, up to where the records ended when it was created
+pub struct OffsetIter<'a> {
     record_file: &'a RecordFile,
-    cur_offset: Option<u64>
+    cur_offset: u64, // the next record, or the padding before it
+    ends_at: u64     // just past the last record
 }
 
-impl<'a> Iterator for Iter<'a> {
-    type Item = Vec<u8>;
+impl<'a> OffsetIter<'a> {
+    /// The offset of the next record, or the padding before it; where to start another iterator to continue
+    pub fn offset(&self) -> u64 {
+        self.cur_offset
+    }
+}
+
+impl<'a> Iterator for OffsetIter<'a> {
+    type Item = Result<(u64, Vec<u8>), IOError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur_offset.is_none() {
+        if self.cur_offset >= self.ends_at {
             return None;
         }
 
-        let rec = match self.record_file.read_at(self.cur_offset.unwrap()) {
-                Err(e) => panic!("Error reading file at {}: {}", self.cur_offset.unwrap(), e.to_string()),
-                Ok(r) => r
-        };
-
-        // update our current record pointer
-        self.cur_offset = Some((self.cur_offset.unwrap() as usize + rec.len() + U32_SIZE) as u64);
+        let rec = self.record_file.skip_padding(self.cur_offset).and_then(|offset| {
+            self.record_file.read_at_with(offset, false).map(|rec| (offset, rec))
+        });
 
-        if self.cur_offset.unwrap() == self.record_file.last_record {
-            self.cur_offset = None;
+        match rec {
+            // the length prefix, then the record
+            Ok((offset, ref rec)) => self.cur_offset = offset + (U32_SIZE + rec.len()) as u64,
+            Err(_) => self.cur_offset = self.ends_at // the rest of the file can't be found
         }
 
         Some(rec)
     }
 }
 
+/// Iterates over the offsets and records of a file, panicking if one can't be read
+pub struct Iter<'a> {
+    records: OffsetIter<'a>
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u64, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.records.offset();
+
+        self.records.next().map(|rec| match rec {
+            Err(e) => panic!("Error reading file at {}: {}", offset, e.to_string()),
+            Ok(r) => r
+        })
+    }
+}
+
 pub struct RecordFileIterator {
     record_file: RefCell<RecordFile>,
     cur_record: u32,
@@ -326,7 +724,7 @@ impl Iterator for RecordFileIterator {
     fn next(&mut self) -> Option<Self::Item> {
         // move to the start of the records if this is the first time through
         if self.cur_record == 0 {
-            self.record_file.get_mut().writer.borrow_mut().flush().expect("Failed to flush writer to disk");
+            self.record_file.get_mut().writer.get_mut().unwrap().flush().expect("Failed to flush writer to disk");
             let offset = (self.record_file.borrow().header_len as usize + U32_SIZE + U64_SIZE) as u64;
             self.record_file.get_mut().fd.seek(SeekFrom::Start(offset)).unwrap();
         }
@@ -336,6 +734,8 @@ impl Iterator for RecordFileIterator {
             return None;
         }
 
+        self.record_file.get_mut().seek_past_padding().expect("Error reading record file");
+
         let rec_size = match self.record_file.get_mut().fd.read_u32::<LE>() {
             Err(e) => {
                 panic!("Error reading record file: {}", e.to_string());
@@ -395,6 +795,8 @@ impl<'a> Iterator for MutRecordFileIterator<'a> {
             return None;
         }
 
+        self.record_file.get_mut().seek_past_padding().expect("Error reading record file");
+
         let rec_size = match self.record_file.get_mut().fd.read_u32::<LE>() {
             Err(e) => {
                 panic!("Error reading record file: {}", e.to_string());
@@ -420,26 +822,15 @@ impl<'a> Iterator for MutRecordFileIterator<'a> {
 mod tests {
     use record_file::RecordFile;
 
-    use simple_logger;
-    use std::path::PathBuf;
-    use std::io::{Seek, SeekFrom, Write};
-    use rand::{thread_rng, Rng};
-    use ::LOGGER_INIT;
+    use std::fs::OpenOptions;
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use test_path::gen_file;
 
     const BUFFER_SIZE: usize = 4069;
     const CACHE_SIZE: usize = 100;
 
-    fn gen_file() -> PathBuf {
-        LOGGER_INIT.call_once(|| simple_logger::init().unwrap()); // this will panic on error
-
-        let tmp_name: String = thread_rng().gen_ascii_chars().take(6).collect();
-        let ret_file = PathBuf::from("/tmp").join(format!("rec_file_{}.data", tmp_name));
-
-        debug!("CREATING TMP FILE: {:?}", ret_file);
-
-        return ret_file;
-    }
-
     #[test]
     fn new() {
         let file = gen_file();
@@ -498,6 +889,67 @@ mod tests {
         assert_eq!(rec, rec_read.as_slice());
     }
 
+    #[test]
+    fn read_into() {
+        let file = gen_file();
+
+        let (long, short) = {
+            let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            (rec_file.append("THE_LONG_RECORD".as_bytes()).unwrap(), rec_file.append("SHORT".as_bytes()).unwrap())
+        };
+
+        let rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let mut buff = Vec::new();
+
+        // once from the file, and again from the cache
+        for _ in 0..2 {
+            rec_file.read_into(long, true, &mut buff).unwrap();
+            assert_eq!("THE_LONG_RECORD".as_bytes(), buff.as_slice());
+
+            rec_file.read_into(short, true, &mut buff).unwrap();
+            assert_eq!("SHORT".as_bytes(), buff.as_slice());
+        }
+
+        assert_eq!((2, 2), rec_file.cache_stats());
+    }
+
+    #[test]
+    fn read_past_len() {
+        let file = gen_file();
+
+        let mut writer = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let first = writer.append("FIRST".as_bytes()).unwrap();
+
+        writer.flush();
+
+        // the reader's length is from when it was opened, so a record appended since looks at the file again
+        let reader = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let second = writer.append("SECOND".as_bytes()).unwrap();
+
+        writer.flush();
+
+        assert_eq!("FIRST".as_bytes(), reader.read_at_with(first, false).unwrap().as_slice());
+        assert_eq!("SECOND".as_bytes(), reader.read_at_with(second, false).unwrap().as_slice());
+        assert_eq!(ErrorKind::UnexpectedEof, reader.read_part_at(second + 4, 0, 1).unwrap_err().kind());
+
+        // truncated through the writer, its length goes back
+        writer.truncate_to(second).unwrap();
+
+        assert_eq!(ErrorKind::UnexpectedEof, writer.read_at_with(second, false).unwrap_err().kind());
+    }
+
+    #[test]
+    fn read_part_at() {
+        let file = gen_file();
+
+        let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let loc = rec_file.append("THE_RECORD".as_bytes()).unwrap();
+
+        assert_eq!("RECORD".as_bytes(), rec_file.read_part_at(loc, 4, 6).unwrap().as_slice());
+        assert!(rec_file.read_part_at(loc, 4, 7).is_err());
+    }
+
     #[test]
     fn iterate() {
         let file = gen_file();
@@ -516,4 +968,187 @@ mod tests {
             assert_eq!("THE_RECORD".as_bytes(), rec.as_slice());
         }
     }
+
+    #[test]
+    fn aligned() {
+        let file = gen_file();
+        let sizes = [40, 10, 44, 10, 100, 3];
+
+        let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+        rec_file.set_alignment(64);
+
+        let locs = sizes.iter().map(|&size| rec_file.append(&vec![0xAB; size]).unwrap()).collect::<Vec<_>>();
+
+        // padding of 4 bytes, then of 2, too few for a size, then before a record larger than a block
+        assert_eq!(vec![16, 64, 78, 128, 192, 296], locs);
+        assert_eq!(4 + 2 + 50, rec_file.padding_bytes());
+
+        assert_eq!(locs.iter().cloned().zip(sizes.iter().map(|&size| vec![0xAB; size])).collect::<Vec<_>>(), rec_file.iter_with_offsets().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>());
+
+        assert_eq!(sizes.iter().map(|&size| vec![0xAB; size]).collect::<Vec<_>>(), rec_file.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_offsets() {
+        for &alignment in [0, 64].iter() {
+            for count in 0..20 {
+                let file = gen_file();
+                let recs = (0..count).map(|i| vec![i as u8; (i * 7 + 1) % 50 + alignment as usize / 64]).collect::<Vec<_>>();
+                let locs;
+
+                {
+                    let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+                    rec_file.set_alignment(alignment);
+                    locs = recs.iter().map(|rec| rec_file.append(rec).unwrap()).collect::<Vec<_>>();
+
+                    // before and after the records reach the disk, and with the last one ending the file
+                    assert_eq!(locs.iter().cloned().zip(recs.iter().cloned()).collect::<Vec<_>>(), rec_file.iter().collect::<Vec<_>>());
+                    rec_file.flush();
+                    assert_eq!(locs.iter().cloned().zip(recs.iter().cloned()).collect::<Vec<_>>(), rec_file.iter().collect::<Vec<_>>());
+                }
+
+                let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+                rec_file.set_alignment(alignment);
+
+                assert_eq!(locs.iter().cloned().zip(recs.iter().cloned()).collect::<Vec<_>>(), rec_file.iter().collect::<Vec<_>>(), "{} {}", alignment, count);
+
+                for start in 0..count {
+                    assert_eq!(count - start, rec_file.iter_from(locs[start]).count());
+                    assert_eq!(Some( (locs[start], recs[start].clone()) ), rec_file.iter_from(locs[start]).next());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn iter_with_offsets_errors() {
+        let file = gen_file();
+        let locs;
+
+        {
+            let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            locs = (0..3).map(|i| rec_file.append(&vec![i as u8; 10]).unwrap()).collect::<Vec<_>>();
+        }
+
+        // the length of the middle record runs past the end of the file
+        {
+            let mut fd = OpenOptions::new().write(true).open(&file).unwrap();
+
+            fd.seek(SeekFrom::Start(locs[1])).unwrap();
+            fd.write_all(&[0xF0, 0xFF, 0xFF, 0x0F]).unwrap();
+        }
+
+        let rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let mut records = rec_file.iter_with_offsets().unwrap();
+
+        assert_eq!((locs[0], vec![0; 10]), records.next().unwrap().unwrap());
+        assert_eq!(locs[1], records.offset());
+        assert!(records.next().unwrap().is_err());
+        assert!(records.next().is_none());
+
+        assert_eq!((locs[2], vec![2; 10]), rec_file.iter_with_offsets_from(locs[2]).unwrap().next().unwrap().unwrap());
+    }
+
+    #[test]
+    fn truncate_to() {
+        for &alignment in [0, 64].iter() {
+            let file = gen_file();
+            let recs = (0..10).map(|i| vec![i as u8 + 1; i * 9 + 1]).collect::<Vec<_>>();
+            let locs;
+
+            {
+                let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+                rec_file.set_alignment(alignment);
+                locs = recs.iter().map(|rec| rec_file.append(rec).unwrap()).collect::<Vec<_>>();
+
+                assert!(rec_file.truncate_to(locs[6] + 1).is_err());
+                assert!(rec_file.truncate_to(rec_file.first_offset() - 1).is_err());
+
+                rec_file.truncate_to(locs[6]).unwrap();
+
+                assert_eq!(6, rec_file.record_count());
+                assert_eq!(recs[5], rec_file.last_record().unwrap());
+
+                // appends follow the records left
+                assert_eq!(locs[6], rec_file.append(&recs[6]).unwrap());
+            }
+
+            let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            rec_file.set_alignment(alignment);
+
+            assert_eq!(recs[..7].to_vec(), rec_file.iter().map(|r| r.1).collect::<Vec<_>>());
+
+            // a tail written after the count was
+            rec_file.flush();
+            rec_file.fd.seek(SeekFrom::End(0)).unwrap();
+            rec_file.fd.write_all(&[0xFF; 7]).unwrap();
+
+            let ends_at = rec_file.ends_at().unwrap();
+
+            rec_file.truncate_to(ends_at).unwrap();
+
+            assert_eq!(ends_at, rec_file.fd.metadata().unwrap().len());
+            assert_eq!(7, rec_file.record_count());
+
+            rec_file.truncate_to(rec_file.first_offset()).unwrap();
+
+            assert_eq!(0, rec_file.record_count());
+            assert_eq!(0, rec_file.iter().count());
+        }
+    }
+
+    #[test]
+    fn preallocate() {
+        let file = gen_file();
+        let mut rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+        let first = rec_file.append(b"FIRST").unwrap();
+
+        rec_file.preallocate(1 << 20).unwrap();
+
+        // the length is where the records end, so appends and reads aren't affected
+        assert_eq!(rec_file.ends_at().unwrap(), rec_file.fd.metadata().unwrap().len());
+
+        let second = rec_file.append(b"SECOND").unwrap();
+
+        rec_file.trim_preallocated().unwrap();
+
+        assert_eq!(vec![(first, b"FIRST".to_vec()), (second, b"SECOND".to_vec())], rec_file.iter().collect::<Vec<_>>());
+        assert_eq!(rec_file.ends_at().unwrap(), rec_file.fd.metadata().unwrap().len());
+    }
+
+    #[test]
+    fn drop_while_panicking() {
+        let file = gen_file();
+        let path = file.clone();
+
+        let result = thread::spawn(move || {
+            let mut rec_file = RecordFile::new(&path, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            rec_file.append("THE_RECORD".as_bytes()).unwrap();
+
+            // poison the writer, as a panic in the middle of a write would
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _writer = rec_file.writer.lock().unwrap();
+
+                panic!("A panic while writing");
+            }));
+
+            panic!("A panic with the file open");
+        }).join();
+
+        // the drop during the unwinding didn't panic again, which would abort
+        assert!(result.is_err());
+
+        let rec_file = RecordFile::new(&file, "ABCD".as_bytes(), BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+        assert_eq!(1, rec_file.record_count());
+
+        rec_file.close().unwrap();
+    }
 }
@@ -0,0 +1,160 @@
+//
+// A cache of the newest record of each key read from the SSTables, see KVSOptions::row_cache_size
+// A hit skips the filters, indexes and record caches of all the tables a lookup would search. Writes remove
+// their keys, and bump the sequence number of the key's stripe; a reader only fills the cache when that
+// number hasn't changed since before it looked in the mem_tables, so a record read before a write can't
+// be cached after it.
+//
+
+use lru_cache::LruCache;
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bloom::hash_key;
+use record::Record;
+
+const STRIPES: usize = 64;
+
+struct Rows {
+    lru: LruCache<Vec<u8>, Record>,
+    seqs: [u64; STRIPES] // bumped by the writes to the keys of each stripe
+}
+
+/// The newest records of the most recently read keys
+pub struct RowCache {
+    rows: Mutex<Rows>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+fn stripe(key: &[u8]) -> usize {
+    hash_key(key) as usize % STRIPES
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> RowCache {
+        RowCache {
+            rows: Mutex::new(Rows { lru: LruCache::new(capacity), seqs: [0; STRIPES] }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// The sequence number to pass to `insert`, taken before looking for the key anywhere else
+    pub fn seq(&self, key: &[u8]) -> u64 {
+        self.rows.lock().unwrap().seqs[stripe(key)]
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        let found = self.rows.lock().unwrap().lru.get_mut(key).map(|rec| rec.clone());
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        found
+    }
+
+    /// Caches the record read for a key, unless one of the keys of its stripe was written since `seq`
+    pub fn insert(&self, rec: &Record, seq: u64) {
+        let mut rows = self.rows.lock().unwrap();
+
+        if rows.seqs[stripe(rec.key())] == seq {
+            rows.lru.insert(rec.key().to_vec(), rec.clone());
+        }
+    }
+
+    /// Removes a key that was just written
+    pub fn invalidate(&self, key: &[u8]) {
+        let mut rows = self.rows.lock().unwrap();
+
+        rows.lru.remove(key);
+        rows.seqs[stripe(key)] += 1;
+    }
+
+    /// Removes the keys in [start, end) that were just range deleted
+    pub fn invalidate_range(&self, start: &[u8], end: &[u8]) {
+        let mut rows = self.rows.lock().unwrap();
+        let keys = rows.lru.iter().map(|(key, _)| key).filter(|key| start <= key.as_slice() && key.as_slice() < end).cloned().collect::<Vec<_>>();
+
+        for key in keys {
+            rows.lru.remove(&key);
+        }
+
+        for seq in rows.seqs.iter_mut() {
+            *seq += 1;
+        }
+    }
+
+    /// Removes every key, after a compaction that may have rewritten any of them
+    pub fn clear(&self) {
+        let mut rows = self.rows.lock().unwrap();
+
+        rows.lru.clear();
+
+        for seq in rows.seqs.iter_mut() {
+            *seq += 1;
+        }
+    }
+
+    /// The number of keys cached
+    pub fn len(&self) -> usize {
+        self.rows.lock().unwrap().lru.len()
+    }
+
+    /// The gets that found the key, and that didn't
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use row_cache::RowCache;
+    use record::Record;
+
+    #[test]
+    fn invalidate() {
+        let cache = RowCache::new(2);
+        let rec = |key: &str| Record::new(key.as_bytes().to_vec(), Some(b"VALUE".to_vec()));
+
+        let seq = cache.seq(b"A");
+        cache.insert(&rec("A"), seq);
+
+        assert!(cache.get(b"A").is_some());
+        assert!(cache.get(b"B").is_none());
+
+        // written while it was read, so the record read may be stale
+        let seq = cache.seq(b"B");
+        cache.invalidate(b"B");
+        cache.insert(&rec("B"), seq);
+
+        assert!(cache.get(b"B").is_none());
+
+        cache.invalidate(b"A");
+
+        assert!(cache.get(b"A").is_none());
+
+        // bounded by keys
+        for key in ["A", "B", "C"].iter() {
+            let seq = cache.seq(key.as_bytes());
+            cache.insert(&rec(key), seq);
+        }
+
+        assert_eq!(2, cache.len());
+        assert!(cache.get(b"A").is_none());
+
+        cache.invalidate_range(b"A", b"C");
+
+        assert!(cache.get(b"B").is_none());
+        assert!(cache.get(b"C").is_some());
+
+        cache.clear();
+
+        assert_eq!(0, cache.len());
+        assert_eq!((2, 5), cache.cache_stats());
+    }
+}
@@ -0,0 +1,58 @@
+//
+// Hooks the store calls where the simulation in `testkit` needs to step in
+// They do nothing unless built for the tests, or with the `testkit` feature.
+//
+
+#[cfg(not(any(test, feature = "testkit")))]
+use std::path::Path;
+
+/// The points in a flush or compaction where a simulated crash can happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    FlushTableWritten,       // the new current SSTable is written, the manifest isn't saved
+    CompactionTablesWritten, // the new SSTables are written, the manifest isn't saved
+    ManifestWritten,         // MANIFEST-new is written, but not renamed over MANIFEST
+    ManifestSaved            // MANIFEST is replaced, the obsolete files aren't removed
+}
+
+#[cfg(any(test, feature = "testkit"))]
+pub use testkit::{crash_point, on_sync, on_rename, on_remove, virtual_time, context, enter};
+
+/// Crashes, if the simulation is set to crash at this point
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn crash_point(_point: CrashPoint) { }
+
+/// A file was synced to disk
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn on_sync(_path: &Path) { }
+
+/// A file was renamed
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn on_rename(_from: &Path, _to: &Path) { }
+
+/// A file was removed
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn on_remove(_path: &Path) { }
+
+/// The time of the virtual clock, if it's set
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn virtual_time() -> Option<u64> { None }
+
+/// What a thread is simulating, for the threads it starts
+#[cfg(not(any(test, feature = "testkit")))]
+pub struct Context;
+
+/// The simulation of the current thread
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn context() -> Context { Context }
+
+/// Runs the current thread in the simulation of another
+#[cfg(not(any(test, feature = "testkit")))]
+#[inline]
+pub fn enter(_context: Context) { }
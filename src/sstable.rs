@@ -1,52 +1,286 @@
-use rmps::encode::to_vec;
-use rmps::decode::from_slice;
+use byteorder::{ByteOrder, BE};
 
 use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::cmp::Ordering::{Less, Equal, Greater};
-use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::io::{Error as IOError, ErrorKind};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs::{self, File};
+use std::io::{Error as IOError, ErrorKind, Read};
 use std::iter::IntoIterator;
+use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use record_file::buf2string;
 use record_file::RecordFile;
 use record::Record;
+use cache::{CacheOptions, CachePolicyKind, new_cache};
+use meta_cache::MetaCache;
+use value_log::{ValueLog, ValuePointer};
+use codec::{Codec, CodecKind};
+use compression::{train_dictionary, ValueCompressor, ValueDecompressor};
+use bloom::{hash_key, BloomFilter, BITS_PER_KEY};
+use format::{sstable_header, parse_sstable_header, encode_record, decode_record, decode_record_key, verify_record, compare_record_key, shared_prefix_len};
+use format::{file_prefix_len, decode_file_prefix, record_at};
 
 use serde_utils::{serialize_u64_exact, deserialize_u64_exact};
 
 use U32_SIZE;
 use U64_SIZE;
 
-const SSTABLE_HEADER: &[u8; 8] = b"DATA\x01\x00\x00\x00";
+/// The number of records sampled from the front of an SSTable to size its groups and train its dictionary
+const SAMPLE_COUNT: usize = 1_000;
 
+/// Bounds on the number of records in a group when it is selected automatically
+const MIN_GROUP_COUNT: u32 = 100;
+const MAX_GROUP_COUNT: u32 = 100_000;
+
+/// The number of groups in a partition of the index, each with its own bloom filter
+const PARTITION_GROUP_COUNT: usize = 128;
+
+/// The most an iterator reads ahead of its records, unless given another size
+pub const DEFAULT_READAHEAD: u64 = 256 * 1024;
+
+/// Iterators read ahead once they've read more than this many records, so short scans don't read more than they need
+const READAHEAD_AFTER: u64 = 16;
+
+/// The first read ahead, doubled each time until it reaches the iterator's max
+const MIN_READAHEAD: u64 = 32 * 1024;
+
+/// The largest buffer a thread keeps for reading the records of its lookups, a larger one is freed after use
+const MAX_READ_BUFFER: usize = 1024 * 1024;
+
+thread_local! {
+    static READ_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// Options used when creating an `SSTable`
+#[derive(Debug, Clone)]
+pub struct SSTableOptions {
+    pub group_count: Option<u32>,  // the number of records in a group, None selects it from target_block_bytes
+    pub target_block_bytes: usize, // the approximate size of a group when selecting the group_count
+    pub group_by_size: bool,       // close each group once it reaches target_block_bytes, instead of grouping by count
+    pub dict_size: usize,          // the max size of the zstd dictionary to train for values, 0 disables compression
+    pub codec: CodecKind,          // how the SSTableInfo is serialized
+    pub alignment: usize,          // the block size records are aligned to, 0 for none
+    pub preallocate: u64           // the bytes of disk space to reserve for the file up front, 0 for none
+}
+
+/// The table `SSTable::new` writes, apart from its records
+pub struct NewSSTable<'a> {
+    pub file_path: &'a PathBuf,          // the path to the SSTable to create
+    pub id: u64,                         // the file number of the SSTable, which orders SSTables with the same smallest key
+    pub options: &'a SSTableOptions,     // how the records are grouped and compressed
+    pub count: Option<u64>,              // the number of records to pull from the iterator, None for all of them
+    pub range_tombstones: Vec<Record>,   // range deletes stored with the SSTable, that cover records in older ones
+    pub buffer_size: usize,              // the write buffer size of the file
+    pub cache: CacheOptions              // how the records read back are cached
+}
+
+impl<'a> NewSSTable<'a> {
+    /// A table of all the records it's given, without range deletes
+    pub fn new(file_path: &'a PathBuf, id: u64, options: &'a SSTableOptions, buffer_size: usize, cache: CacheOptions) -> NewSSTable<'a> {
+        NewSSTable { file_path: file_path, id: id, options: options, count: None, range_tombstones: vec![], buffer_size: buffer_size, cache: cache }
+    }
+}
+
+impl SSTableOptions {
+    /// Selects the number of records in a group, given the average size of a record
+    ///
+    /// Larger groups mean fewer top-level indices to keep in memory, but more reads per lookup.
+    /// Grouping by size, it's the most records a group can have.
+    pub fn select_group_count(&self, avg_record_size: usize) -> u32 {
+        if self.group_by_size {
+            return MAX_GROUP_COUNT;
+        }
+
+        if let Some(count) = self.group_count {
+            return count;
+        }
+
+        let count = self.target_block_bytes / avg_record_size.max(1);
+
+        (count as u32).max(MIN_GROUP_COUNT).min(MAX_GROUP_COUNT)
+    }
+}
+
+/// The buffer and record cache sizes of the table written by `SSTable::salvage`
+const SALVAGE_BUFFER_SIZE: usize = 64 * 1024;
+const SALVAGE_CACHE_SIZE: usize = 100;
+
+/// Returns true if the bytes could be an index block or group index: ascending offsets before the end
+fn is_offsets(buff: &[u8], ends_at: u64) -> bool {
+    if buff.is_empty() || buff.len() % U64_SIZE != 0 {
+        return false;
+    }
+
+    let offsets = deserialize_u64_exact(&buff.to_vec());
+
+    offsets.windows(2).all(|w| w[0] < w[1]) && offsets.iter().all(|&offset| offset < ends_at)
+}
+
+/// Adds a range of lost bytes, joining it to the last one if only padding is between them
+fn add_lost_range(lost_ranges: &mut Vec<(u64, u64)>, file: &[u8], range: (u64, u64)) {
+    if let Some(last) = lost_ranges.last_mut() {
+        if file[last.1 as usize..range.0 as usize].iter().all(|&b| b == 0) {
+            last.1 = range.1;
+            return;
+        }
+    }
+
+    lost_ranges.push(range);
+}
+
+/// Reads the codec from the header of an SSTable, without opening it
+fn read_codec(file_path: &PathBuf) -> Result<CodecKind, IOError> {
+    let mut header = [0; 8];
+
+    File::open(file_path)?.read_exact(&mut header)?;
+
+    parse_sstable_header(&header).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("{}: {}", e, file_path.display())))
+}
+
+// Each group of records is followed by its group index, the offsets of the records in the group:
+// |-----------------------------------|
+// | record 0 ... record N-1           |
+// |-----------------------------------|
+// | group index, N 8-byte offsets     |
+// |-----------------------------------|
+// The group indices are split into partitions of `PARTITION_GROUP_COUNT` groups. After the last group of a
+// partition comes its index block, the offsets of its group indices, and a bloom filter of its keys.
+// The `SSTableInfo` keeps the index of indexes: the first key, index block, and filter of each partition.
+// A lookup only reads the filter, and index block if the filter passes, of the partition with the key.
+// Nothing is rewritten in place.
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IndexPartition {
+    first_key: Vec<u8>, // the first key in the partition
+    index_block: u64,   // the offset of the index block
+    index_count: u64,   // the number of group indices in the index block
+    filter: u64,        // the offset of the bloom filter
+    #[serde(default)]
+    record_count: u64   // the records in the partition, 0 in tables written before it was saved
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 struct SSTableInfo {
     record_count: u64,
     group_count: u32,
-    indices: Vec<u64>,
+    partitions: Vec<IndexPartition>,
     smallest_key: Vec<u8>,
     largest_key: Vec<u8>,
-    oldest_ts: u64
+    oldest_ts: u64,         // the oldest created timestamp of a record
+    newest_ts: u64,         // the newest created timestamp of a record
+    tombstone_count: u64,   // the number of delete records
+    total_value_bytes: u64, // the size of all the values, before compression
+    dictionary: Option<Vec<u8>>, // if Some, values are compressed with this zstd dictionary
+    range_tombstones: Vec<Record>, // range deletes that apply to older SSTables
+    #[serde(default)]
+    expiring_count: u64,    // the number of records with a TTL
+    #[serde(default)]
+    latest_expiry: u64,     // the latest TTL of those records, when they've all expired
+    #[serde(default)]
+    id: u64,                // the file number of the SSTable, unique in a store
+    #[serde(default)]
+    alignment: u64,         // the block size the records are aligned to, 0 for none
+    #[serde(default)]
+    padding_bytes: u64,     // the bytes of padding written to align them
+    #[serde(default)]
+    block_bytes: u64,       // groups are closed once they reach this size, 0 when they're group_count records
+    #[serde(default)]
+    earliest_expiry: u64,   // the earliest TTL of the records with one, 0 for tables from before it was kept
+    #[serde(default)]
+    value_logs: Vec<u64>    // the value log files the records point into
+}
+
+/// Where the last record at or before a key is, see `SSTable::floor`
+struct Floor {
+    partition: usize,
+    group: usize,            // the group in the partition
+    group_indices: Arc<Vec<u64>>, // the offsets of the records in the group
+    group_key: Vec<u8>,      // the key of the first record in the group
+    index: usize,            // the record in the group
+    rec: Record
+}
+
+/// What `SSTable::salvage` got out of a damaged table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SalvageReport {
+    pub recovered: u64,               // the records written to the new table
+    pub expected: Option<u64>,        // the records the table had, None when its info was lost too
+    pub lost_ranges: Vec<(u64, u64)>  // the [start, end) offsets of the bytes that weren't recovered
+}
+
+impl SalvageReport {
+    /// The records that weren't recovered, when the number the table had is known
+    pub fn lost_records(&self) -> Option<u64> {
+        self.expected.map(|expected| expected.saturating_sub(self.recovered))
+    }
+
+    pub fn lost_bytes(&self) -> u64 {
+        self.lost_ranges.iter().map(|&(start, end)| end - start).sum()
+    }
+}
+
+impl Display for SalvageReport {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        match self.expected {
+            Some(expected) => write!(formatter, "recovered {} of {} records", self.recovered, expected)?,
+            None => write!(formatter, "recovered {} records, of an unknown number", self.recovered)?
+        }
+
+        write!(formatter, "; lost {} bytes in {} ranges", self.lost_bytes(), self.lost_ranges.len())?;
+
+        for &(start, end) in self.lost_ranges.iter() {
+            write!(formatter, "\n  [{}, {})", start, end)?;
+        }
+
+        Ok( () )
+    }
 }
 
 pub struct SSTable {
     rec_file: RecordFile,
-    info: SSTableInfo
+    info: SSTableInfo,
+    codec: CodecKind,
+    decompressor: Option<ValueDecompressor>,
+    meta: Option<Arc<MetaCache>>, // where the filters and indexes are cached, apart from the records
+    pin_meta: bool,
+    filters: Mutex<HashMap<u64, Arc<BloomFilter>>>, // the filters read, by offset, kept with the table without a metadata cache
+    value_log: Option<Arc<ValueLog>>
 }
 
 impl SSTable {
-    pub fn open(file_path: &PathBuf, buffer_size: usize, cache_size: usize) -> Result<SSTable, IOError> {
+    pub fn open(file_path: &PathBuf, buffer_size: usize, cache: CacheOptions) -> Result<SSTable, IOError> {
         if !file_path.exists() {
             return Err(IOError::new(ErrorKind::NotFound, format!("The SSTable {:?} was not found", file_path)));
         }
 
-        let rec_file = RecordFile::new(file_path, SSTABLE_HEADER, buffer_size, cache_size)?;
+        let codec = read_codec(file_path)?;
+        let mut rec_file = RecordFile::with_cache(file_path, &sstable_header(codec), buffer_size, new_cache(cache.policy, cache.size))?;
+
+        let mut info :SSTableInfo = codec.decode(&rec_file.last_record()?).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("Error decoding SSTableInfo: {}", e)))?;
+
+        // lookups rely on the first partition starting with the smallest key
+        if info.record_count != 0 && info.partitions.first().map_or(true, |p| p.first_key != info.smallest_key) {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("SSTableInfo has bad partitions: {:?}", file_path)));
+        }
+
+        // SSTables written before the id was saved are named by their number
+        if info.id == 0 {
+            info.id = file_path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()).unwrap_or(0);
+        }
+
+        rec_file.set_alignment(info.alignment);
 
-        let info = from_slice(&rec_file.last_record().expect("Error reading SSTableInfo")).expect("Error decoding SSTableInfo");
+        let decompressor = info.dictionary.as_ref().map(|d| ValueDecompressor::new(d));
 
-        let sstable = SSTable { rec_file: rec_file, info: info };
+        let sstable = SSTable { rec_file: rec_file, info: info, codec: codec, decompressor: decompressor, meta: cache.meta, pin_meta: cache.pin_meta, filters: Mutex::new(HashMap::new()), value_log: cache.value_log };
+
+        sstable.pin_metadata()?;
 
         debug!("Opened SSTable: {:?}", sstable);
 
@@ -54,16 +288,15 @@ impl SSTable {
     }
 
     /// Creates a new `SSTable` that is immutable once returned.
-    /// * file_path - the path to the SSTable to create
+    /// * table - where the SSTable is written, and how
     /// * records - an iterator to records that will be inserted into this `SSTable`
-    /// * group_count - the number of records to group together for each recorded index
-    /// * count - the number of records to pull from the iterator and put into the `SSTable`
-    pub fn new<I, B>(file_path: &PathBuf,  records: &mut I, group_count: u32, count: Option<u64>, buffer_size: usize, cache_size: usize) -> Result<SSTable, IOError>
+    pub fn new<I, B>(table: NewSSTable, records: &mut I) -> Result<SSTable, IOError>
         where I: Iterator<Item=B>, B: Borrow<Record>
     {
-        debug!("New SSTable: {:?} group_count: {} count: {:?}", file_path, group_count, count);
+        let NewSSTable { file_path, id, options, count, range_tombstones, buffer_size, cache } = table;
+
+        debug!("New SSTable: {:?} options: {:?} count: {:?}", file_path, options, count);
 
-        assert_ne!(group_count, 0); // need at least 1 in the group
         if count.is_some() { assert_ne!(count.unwrap(), 0); }
 
         if file_path.exists() {
@@ -71,72 +304,224 @@ impl SSTable {
         }
 
         // create the RecordFile that holds all the data for the SSTable
-        let mut rec_file = RecordFile::new(file_path, SSTABLE_HEADER, buffer_size, cache_size)?;
+        let mut rec_file = RecordFile::with_cache(file_path, &sstable_header(options.codec), buffer_size, new_cache(cache.policy, cache.size))?;
+
+        rec_file.set_alignment(options.alignment as u64);
 
         debug!("Created RecordFile: {:?}", rec_file);
 
+        // a failed write leaves no partial SSTable behind; truncating first frees the space even if it can't be removed
+        let sstable_info = match SSTable::write_records(&mut rec_file, id, records, options, count, range_tombstones) {
+            Ok(info) => info,
+            Err(e) => {
+                let first_offset = rec_file.first_offset();
+
+                if let Err(te) = rec_file.truncate_to(first_offset) {
+                    warn!("Error truncating {:?} after a failed write: {}", file_path, te);
+                }
+
+                drop(rec_file);
+
+                if let Err(re) = fs::remove_file(file_path) {
+                    warn!("Error removing {:?} after a failed write: {}", file_path, re);
+                }
+
+                return Err(e);
+            }
+        };
+
+        // create our SSTable
+        let decompressor = sstable_info.dictionary.as_ref().map(|d| ValueDecompressor::new(d));
+        let sstable = SSTable {
+            rec_file: rec_file,
+            info: sstable_info,
+            codec: options.codec,
+            decompressor: decompressor,
+            meta: cache.meta,
+            pin_meta: cache.pin_meta,
+            filters: Mutex::new(HashMap::new()),
+            value_log: cache.value_log
+        };
+
+        sstable.pin_metadata()?;
+
+        debug!("Created SSTable: {:?}", sstable);
+
+        Ok(sstable)
+    }
+
+    /// Writes the records, indices, and info of a new SSTable, returning the info
+    fn write_records<I, B>(rec_file: &mut RecordFile, id: u64, records: &mut I, options: &SSTableOptions, count: Option<u64>, range_tombstones: Vec<Record>) -> Result<SSTableInfo, IOError>
+        where I: Iterator<Item=B>, B: Borrow<Record>
+    {
+        rec_file.preallocate(options.preallocate)?;
+
+        // pull off a sample of the records to size the groups and train the dictionary, never more than count
+        let mut samples = Vec::new();
+
+        if (options.group_count.is_none() && !options.group_by_size) || options.dict_size != 0 {
+            let sample_count = match count {
+                Some(c) if c < SAMPLE_COUNT as u64 => c as usize,
+                _ => SAMPLE_COUNT
+            };
+
+            while samples.len() < sample_count {
+                match records.next() {
+                    Some(r) => samples.push(r.borrow().to_owned()),
+                    None => break
+                }
+            }
+        }
+
+        let avg_record_size = if samples.is_empty() { 0 } else {
+            samples.iter().map(|r| r.size() as usize).sum::<usize>() / samples.len()
+        };
+
+        let group_count = options.select_group_count(avg_record_size);
+
+        assert_ne!(group_count, 0); // need at least 1 in the group
+
+        debug!("Selected group_count {} for average record size {}", group_count, avg_record_size);
+
         let mut sstable_info = SSTableInfo {
             record_count: 0,
             group_count: group_count,
-            indices: vec!(),
+            partitions: vec!(),
             smallest_key: vec!(),
             largest_key: vec!(),
-            oldest_ts: 0
+            oldest_ts: 0,
+            newest_ts: 0,
+            tombstone_count: 0,
+            total_value_bytes: 0,
+            dictionary: None,
+            range_tombstones: range_tombstones,
+            expiring_count: 0,
+            latest_expiry: 0,
+            id: id,
+            alignment: options.alignment as u64,
+            padding_bytes: 0,
+            block_bytes: if options.group_by_size { options.target_block_bytes.max(1) as u64 } else { 0 },
+            earliest_expiry: 0,
+            value_logs: vec![]
         };
 
-        let mut group_indices = vec![0x00 as u64; group_count as usize];
-        let mut cur_group_indices_offset;
+        if options.dict_size != 0 {
+            sstable_info.dictionary = train_dictionary(&samples, options.dict_size);
+
+            debug!("Trained dictionary from {} samples: {:?}", samples.len(), sstable_info.dictionary.as_ref().map(|d| d.len()));
+        }
+
+        let mut compressor = sstable_info.dictionary.as_ref().map(|d| ValueCompressor::new(d));
+        let mut samples = samples.into_iter();
+
+        let mut group_indices = Vec::with_capacity(group_count.min(MIN_GROUP_COUNT) as usize);
+        let mut group_bytes = 0;
+        let mut indices = Vec::new();
+        let mut partition_key :Vec<u8> = vec![];
+        let mut partition_hashes = Vec::new();
         let mut cur_key :Vec<u8> = vec![];
+        let mut group_key :Vec<u8> = vec![];
         let mut cur_ts ;
 
-        // make space for the record_group_indices
-        let record_group_indices_buff = serialize_u64_exact(&group_indices);
-        cur_group_indices_offset = rec_file.append(&record_group_indices_buff)?;
-
-        // keep fetching from this iterator
-        while let Some(r) = records.next() {
-            let rec :&Record = r.borrow();
+        // keep fetching from the samples, then this iterator
+        loop {
+            let sample;
+            let r;
+            let rec :&Record = if let Some(s) = samples.next() {
+                sample = s;
+                &sample
+            } else if let Some(n) = records.next() {
+                r = n;
+                r.borrow()
+            } else {
+                break;
+            };
 
 //            debug!("GOT REC: {:?}", rec);
 
             // quick sanity check to ensure we're in sorted order
-            if sstable_info.record_count != 0 && rec.key() <= cur_key {
-                panic!("Got records in un-sorted order: {} <= {}", buf2string(&rec.key()), buf2string(&cur_key));
+            if sstable_info.record_count != 0 && rec.key() <= cur_key.as_slice() {
+                panic!("Got records in un-sorted order: {} <= {}", buf2string(rec.key()), buf2string(&cur_key));
             }
 
-            // take care of our group_indices
-            if sstable_info.record_count != 0 && sstable_info.record_count % group_count as u64 == 0 {
-                // write the current record_group_indices to disk
-                let record_group_indices_buff = serialize_u64_exact(&group_indices);
-                rec_file.write_at(cur_group_indices_offset, &record_group_indices_buff, false)?;
-
-                // reset the record_group_indices, and write it to the new location
-                group_indices = vec![0x00 as u64; group_count as usize];
-                let record_group_indices_buff = serialize_u64_exact(&group_indices);
-                cur_group_indices_offset = rec_file.append(&record_group_indices_buff)?;
-            }
+            // the first record of a group is the key the rest are compressed against
+            let shared = if group_indices.is_empty() {
+                group_key = rec.key().to_vec();
 
-            // append the record to the end of the file, without flushing
-            let loc = rec_file.append_record(rec)?;
+                if indices.is_empty() {
+                    partition_key = rec.key().to_vec();
+                }
 
-            // add to our group index
-            group_indices[(sstable_info.record_count % group_count as u64) as usize] = loc;
+                0
+            } else {
+                shared_prefix_len(&group_key, &rec.key())
+            };
 
-            // add to the top-level indices if needed
-            if sstable_info.record_count % group_count as u64 == 0 {
-                sstable_info.indices.push(loc);
+            // append the record to the end of the file, without flushing
+            // a pointer into the value log is kept as it is
+            let rec_buff = match compressor {
+                Some(ref mut c) if !rec.is_delete() && !rec.is_value_pointer() => {
+                    let mut compressed = rec.to_owned();
+                    compressed.set_value(c.compress(&rec.value())?);
+                    encode_record(&compressed, shared)
+                },
+                _ => encode_record(rec, shared)
+            };
+            let loc = rec_file.append(&rec_buff)?;
+
+            // add to our group index, and the partition's filter
+            group_indices.push(loc);
+            group_bytes += (U32_SIZE + rec_buff.len()) as u64;
+            partition_hashes.push(hash_key(&rec.key()));
+
+            // write out the group index after the last record of the group
+            if group_indices.len() == group_count as usize || (sstable_info.block_bytes != 0 && group_bytes >= sstable_info.block_bytes) {
+                let group_indices_buff = serialize_u64_exact(&group_indices);
+                indices.push(rec_file.append(&group_indices_buff)?);
+                group_indices.clear();
+                group_bytes = 0;
+
+                // write out the partition after its last group
+                if indices.len() == PARTITION_GROUP_COUNT {
+                    sstable_info.partitions.push(SSTable::write_partition(rec_file, &partition_key, &indices, &partition_hashes)?);
+                    indices.clear();
+                    partition_hashes.clear();
+                }
             }
 
             // record our current key and ts for use later
-            cur_key = rec.key();
+            cur_key = rec.key().to_vec();
             cur_ts = rec.created();
 
-            // the first time through we set the smallest key, and oldest time
+            // the first time through we set the smallest key, and oldest & newest time
             if sstable_info.record_count == 0 {
                 sstable_info.smallest_key = cur_key.to_vec();
                 sstable_info.oldest_ts = cur_ts;
-            } else if cur_ts > sstable_info.oldest_ts {
+                sstable_info.newest_ts = cur_ts;
+            } else if cur_ts < sstable_info.oldest_ts {
                 sstable_info.oldest_ts = cur_ts;
+            } else if cur_ts > sstable_info.newest_ts {
+                sstable_info.newest_ts = cur_ts;
+            }
+
+            if rec.ttl() != u64::max_value() {
+                sstable_info.earliest_expiry = if sstable_info.expiring_count == 0 { rec.ttl() } else { sstable_info.earliest_expiry.min(rec.ttl()) };
+                sstable_info.expiring_count += 1;
+                sstable_info.latest_expiry = sstable_info.latest_expiry.max(rec.ttl());
+            }
+
+            if rec.is_value_pointer() {
+                let file_number = ValuePointer::decode(rec.value())?.file_number;
+
+                if !sstable_info.value_logs.contains(&file_number) {
+                    sstable_info.value_logs.push(file_number);
+                }
+            }
+
+            if rec.is_delete() {
+                sstable_info.tombstone_count += 1;
+            } else {
+                sstable_info.total_value_bytes += rec.value().len() as u64;
             }
 
             // update our record count
@@ -149,107 +534,661 @@ impl SSTable {
             }
         }
 
-        // write-out our current group_indices
-        let record_group_indices_buff = serialize_u64_exact(&group_indices);
-        rec_file.write_at(cur_group_indices_offset, &record_group_indices_buff, false)?;
+        // write-out the group index of the last, partial, group
+        if !group_indices.is_empty() {
+            let group_indices_buff = serialize_u64_exact(&group_indices);
+            indices.push(rec_file.append(&group_indices_buff)?);
+        }
+
+        // write-out the last, partial, partition
+        if !indices.is_empty() {
+            sstable_info.partitions.push(SSTable::write_partition(rec_file, &partition_key, &indices, &partition_hashes)?);
+        }
 
         // update our largest key
         sstable_info.largest_key = cur_key;
 
-        // append our info as the last record, and flush to disk
-        let info_buff = to_vec(&sstable_info).expect("Error serializing SSTableInfo");
-        rec_file.append(&info_buff).expect("Error writing SSTableInfo");
-        rec_file.flush();
+        // the padding before the info itself isn't counted
+        sstable_info.padding_bytes = rec_file.padding_bytes();
 
-        // create our SSTable
-        let sstable = SSTable {
-            rec_file: rec_file,
-            info: sstable_info
+        // append our info as the last record, and sync to disk, as the manifest will reference it
+        let info_buff = options.codec.encode(&sstable_info).expect("Error serializing SSTableInfo");
+        rec_file.append(&info_buff)?;
+
+        if options.preallocate != 0 {
+            rec_file.trim_preallocated()?;
+        }
+
+        rec_file.sync()?;
+
+        Ok(sstable_info)
+    }
+
+    /// Appends the index block and bloom filter of a partition
+    fn write_partition(rec_file: &mut RecordFile, first_key: &[u8], indices: &Vec<u64>, hashes: &[u64]) -> Result<IndexPartition, IOError> {
+        let index_block = rec_file.append(&serialize_u64_exact(indices))?;
+        let filter = rec_file.append(&BloomFilter::new(hashes, BITS_PER_KEY).serialize())?;
+
+        Ok(IndexPartition {
+            first_key: first_key.to_vec(),
+            index_block: index_block,
+            index_count: indices.len() as u64,
+            filter: filter,
+            record_count: hashes.len() as u64
+        })
+    }
+
+    /// Copies the records that can still be read from a damaged SSTable to a new one
+    ///
+    /// The file is scanned record by record, without trusting its indexes: a record is kept when it
+    /// matches its CRC. Where the sizes before the records are damaged, the scan moves on a byte at a
+    /// time until it finds the next record that matches. The table's info, if it can be read, tells the
+    /// indexes and filters apart from damage, and gives the dictionary, group size, and range deletes;
+    /// without it the filters count as lost bytes, and values are copied as they are. Records after the
+    /// damage in the same group are lost too, as the group's first key they're compressed against may be.
+    /// The records are held in memory, and the new table is written without a dictionary.
+    pub fn salvage(src: &PathBuf, dst: &PathBuf) -> Result<SalvageReport, IOError> {
+        let file = fs::read(src)?;
+        let codec = parse_sstable_header(&file).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("{}: {}", e, src.display())))?;
+        let header = sstable_header(codec);
+
+        // the info is the last record; its offset is only written out when the table is finished
+        let info = decode_file_prefix(&file, &header).ok()
+            .and_then(|(_, last)| record_at(&file, last).ok().map(|buff| (last, buff)))
+            .and_then(|(last, buff)| codec.decode::<SSTableInfo>(buff).ok().map(|info| (last, info)));
+
+        let ends_at = info.as_ref().map_or(file.len() as u64, |&(last, _)| last);
+        let records_file = &file[..ends_at as usize];
+
+        // the offsets of the index blocks, filters, and group indices
+        let mut metadata = HashSet::new();
+
+        if let Some((_, ref info)) = info {
+            for partition in info.partitions.iter() {
+                metadata.insert(partition.index_block);
+                metadata.insert(partition.filter);
+
+                if let Ok(index_block) = record_at(records_file, partition.index_block) {
+                    if is_offsets(index_block, ends_at) {
+                        metadata.extend(deserialize_u64_exact(&index_block.to_vec()));
+                    }
+                }
+            }
+        }
+
+        let decompressor = info.as_ref().and_then(|&(_, ref info)| info.dictionary.as_ref()).map(|d| ValueDecompressor::new(d));
+
+        let mut records: Vec<Record> = vec![];
+        let mut lost_ranges = vec![];
+        let mut lost_start = None;  // the start of the bytes skipped since the last record or metadata
+        let mut group_key = None;   // the first key of the current group, None after damage
+        let mut offset = file_prefix_len(&header) as u64;
+
+        while offset < ends_at {
+            let frame = record_at(records_file, offset).ok().and_then(|buff| {
+                if metadata.contains(&offset) || (info.is_none() && is_offsets(buff, ends_at)) {
+                    Some((buff, None))
+                } else {
+                    verify_record(buff).ok().map(|shared| (buff, Some(shared)))
+                }
+            });
+
+            let (buff, shared) = match frame {
+                Some(frame) => frame,
+                None => {
+                    lost_start = lost_start.or(Some(offset));
+                    offset += 1;
+                    continue;
+                }
+            };
+
+            // the bytes skipped are damage, unless they're the zeros padding an aligned record
+            if let Some(start) = lost_start.take() {
+                if file[start as usize..offset as usize].iter().any(|&b| b != 0) {
+                    add_lost_range(&mut lost_ranges, &file, (start, offset));
+                    group_key = None;
+                }
+            }
+
+            let frame = (offset, offset + (U32_SIZE + buff.len()) as u64);
+
+            offset = frame.1;
+
+            let shared = match shared {
+                Some(shared) => shared,
+                None => continue // an index or filter
+            };
+
+            let rec = if shared == 0 {
+                decode_record(buff, &[], false).ok()
+            } else {
+                group_key.as_ref().and_then(|key: &Vec<u8>| decode_record(buff, key, false).ok())
+            };
+
+            if shared == 0 {
+                group_key = rec.as_ref().map(|rec| rec.key().to_vec());
+            }
+
+            let rec = rec.and_then(|mut rec| {
+                if let Some(ref d) = decompressor {
+                    if !rec.is_delete() && !rec.is_value_pointer() {
+                        let value = d.decompress(&rec.value()).ok()?;
+                        rec.set_value(value);
+                    }
+                }
+
+                Some(rec)
+            });
+
+            match rec {
+                Some(rec) if records.last().map_or(true, |last: &Record| last.key() < rec.key()) => records.push(rec),
+                _ => add_lost_range(&mut lost_ranges, &file, frame) // its group's first key was lost, or it's out of order
+            }
+        }
+
+        if let Some(start) = lost_start {
+            if file[start as usize..ends_at as usize].iter().any(|&b| b != 0) {
+                add_lost_range(&mut lost_ranges, &file, (start, ends_at));
+            }
+        }
+
+        let (id, options, range_tombstones, expected) = match info {
+            Some((_, info)) => {
+                let options = SSTableOptions { group_count: Some(info.group_count), target_block_bytes: info.block_bytes as usize, group_by_size: info.block_bytes != 0, dict_size: 0, codec: codec, alignment: info.alignment as usize, preallocate: 0 };
+
+                (info.id, options, info.range_tombstones, Some(info.record_count))
+            },
+            None => {
+                let id = src.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()).unwrap_or(0);
+                let options = SSTableOptions { group_count: Some(MIN_GROUP_COUNT), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: codec, alignment: 0, preallocate: 0 };
+
+                (id, options, vec![], None)
+            }
         };
 
-        debug!("Created SSTable: {:?}", sstable);
+        let cache = CacheOptions { size: SALVAGE_CACHE_SIZE, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
 
-        Ok(sstable)
+        SSTable::new(NewSSTable { range_tombstones: range_tombstones, ..NewSSTable::new(dst, id, &options, SALVAGE_BUFFER_SIZE, cache) }, &mut records.iter())?;
+
+        let report = SalvageReport { recovered: records.len() as u64, expected: expected, lost_ranges: lost_ranges };
+
+        info!("Salvaged {:?} to {:?}: {}", src, dst, report);
+
+        Ok(report)
     }
 
-    fn binary_search_by<'a, T, F>(array: &'a [T], mut f: F) -> Result<usize, usize>
-        where F: FnMut(&'a T) -> Ordering
+    /// Binary searches the positions 0..len, so the items don't need to be in memory
+    fn binary_search_by<F>(len: usize, mut f: F) -> Result<usize, usize>
+        where F: FnMut(usize) -> Ordering
     {
         let mut base = 0usize;
-        let mut s = array;
+        let mut size = len;
 
         loop {
-            let (head, tail) = s.split_at(s.len() >> 1);
-            if tail.is_empty() {
+            let head = size >> 1;
+            if size == head {
                 return Err(base);
             }
-            match f(&tail[0]) {
+            match f(base + head) {
                 Less => {
-                    base += head.len() + 1;
-                    s = &tail[1..];
+                    base += head + 1;
+                    size -= head + 1;
                 }
-                Greater => s = head,
-                Equal => return Ok(base + head.len()),
+                Greater => size = head,
+                Equal => return Ok(base + head),
+            }
+        }
+    }
+
+    /// Reads the offset of a group index from the index block of a partition
+    fn group_index_offset(&self, partition: &IndexPartition, i: usize) -> Result<u64, IOError> {
+        if let Some(ref meta) = self.meta {
+            let index_block = meta.index(self.info.id, partition.index_block, self.pin_meta, true, || self.read_index(partition.index_block, false))?;
+
+            return index_block.get(i).cloned().ok_or_else(|| IOError::new(ErrorKind::InvalidData, format!("Bad index block at {}: no group {}", partition.index_block, i)));
+        }
+
+        let buff = self.rec_file.read_part_at(partition.index_block, i * U64_SIZE, U64_SIZE)?;
+
+        Ok(BE::read_u64(&buff))
+    }
+
+    /// Reads the bloom filter of a partition, through the metadata cache if there is one
+    ///
+    /// Without one, the filter is kept with the table once read, as every lookup in the partition needs it;
+    /// that's about 1.25 bytes per record, like pinning it.
+    fn filter(&self, partition: &IndexPartition, fill_cache: bool) -> Result<Arc<BloomFilter>, IOError> {
+        let load = || BloomFilter::deserialize(self.rec_file.read_at_with(partition.filter, false)?);
+
+        if let Some(ref meta) = self.meta {
+            return meta.filter(self.info.id, partition.filter, self.pin_meta, fill_cache, load);
+        }
+
+        if let Some(filter) = self.filters.lock().unwrap().get(&partition.filter) {
+            return Ok(filter.clone());
+        }
+
+        let filter = Arc::new(load()?);
+
+        self.filters.lock().unwrap().insert(partition.filter, filter.clone());
+
+        Ok(filter)
+    }
+
+    /// Has the OS read the index block and filter of every partition into its page cache, for the lookups to come
+    pub fn prefetch_metadata(&self) -> Result<(), IOError> {
+        for (p, partition) in self.info.partitions.iter().enumerate() {
+            // the filter follows the index block, and has a bit count set by the records of the partition
+            let keys = self.partition_record_count(p);
+            let end = partition.filter + (U32_SIZE + BloomFilter::serialized_len(keys as usize, BITS_PER_KEY)) as u64;
+
+            self.rec_file.readahead(partition.index_block, end - partition.index_block)?;
+        }
+
+        Ok( () )
+    }
+
+    /// Checks every record against its CRC, and that there are as many as the table's info says
+    ///
+    /// The index blocks and filters are told apart from the records by the info, as they have no CRC.
+    pub fn verify(&self) -> Result<(), IOError> {
+        let mut metadata = HashSet::new();
+
+        for partition in self.info.partitions.iter() {
+            metadata.insert(partition.index_block);
+            metadata.insert(partition.filter);
+            metadata.extend(self.read_index(partition.index_block, false)?);
+        }
+
+        let info_offset = self.rec_file.last_offset();
+        let mut record_count = 0;
+
+        for rec in self.rec_file.iter_with_offsets()? {
+            let (offset, buff) = rec?;
+
+            if offset == info_offset || metadata.contains(&offset) {
+                continue;
             }
+
+            verify_record(&buff).map_err(|e| IOError::new(ErrorKind::InvalidData, format!("Bad record at {} in {:?}: {}", offset, self.file_path(), e)))?;
+            record_count += 1;
+        }
+
+        if record_count != self.info.record_count {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Found {} records in {:?}, expected {}", record_count, self.file_path(), self.info.record_count)));
+        }
+
+        Ok( () )
+    }
+
+    /// Gives the table another id, like one taken from another store, replacing only its info
+    ///
+    /// The info is the last record, so the records, indexes, and filters stay where they are. Anything
+    /// cached in a metadata cache under the old id isn't found again.
+    pub fn set_id(&mut self, id: u64) -> Result<(), IOError> {
+        let info_offset = self.rec_file.last_offset();
+
+        self.info.id = id;
+
+        let info_buff = self.codec.encode(&self.info).expect("Error serializing SSTableInfo");
+
+        self.rec_file.truncate_to(info_offset)?;
+        self.rec_file.append(&info_buff)?;
+        self.rec_file.sync()
+    }
+
+    /// Loads the filter and index block of every partition into the metadata cache, if they're pinned
+    fn pin_metadata(&self) -> Result<(), IOError> {
+        if self.meta.is_none() || !self.pin_meta {
+            return Ok( () );
+        }
+
+        for partition in self.info.partitions.iter() {
+            self.filter(partition, true)?;
+            self.group_index_offset(partition, 0)?;
         }
+
+        Ok( () )
     }
 
     pub fn get(&self, key: Vec<u8>) -> Result<Option<Record>, IOError> {
+        self.get_with(key, true, true)
+    }
+
+    /// Looks up a key
+    /// * fill_cache - add the records read to the cache
+    /// * verify_checksums - return an error if a record read doesn't match its checksum
+    pub fn get_with(&self, key: Vec<u8>, fill_cache: bool, verify_checksums: bool) -> Result<Option<Record>, IOError> {
         // check if the key is in the range of this SSTable
         if self.info.record_count == 0 || key < self.info.smallest_key || self.info.largest_key < key {
             return Ok(None);
         }
 
-        // binary search using the indices
-        let top_index_res = SSTable::binary_search_by(&self.info.indices, |index| {
-            let rec_buff = self.rec_file.read_at(*index).expect("Error reading SSTable");
-            let rec = Record::deserialize(rec_buff);
+        // find the partition that would have the key
+        let partition = &self.info.partitions[match self.info.partitions.binary_search_by(|p| p.first_key.cmp(&key)) {
+            Ok(i) => i,
+            Err(i) => i-1
+        }];
+
+        // check the filter before reading any of the partition's indices
+        if !self.filter(partition, fill_cache)?.may_contain(&key) {
+            debug!("Key not in filter: {:?}", partition);
+            return Ok(None);
+        }
+
+        // the searches can't return errors, so the first one is saved and checked after
+        let mut error = None;
 
-            rec.key().cmp(&key)
+        // binary search using the indices, the first record in a group has the whole key
+        let top_index_res = SSTable::binary_search_by(partition.index_count as usize, |i| {
+            match self.group_index_offset(partition, i).and_then(|offset| self.compare_group_head(offset, &key, fill_cache, verify_checksums)) {
+                Ok(ord) => ord,
+                Err(e) => { if error.is_none() { error = Some(e); } Greater }
+            }
         });
 
-        let start_offset = self.info.indices[match top_index_res {
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        let group_indices_offset = self.group_index_offset(partition, match top_index_res {
             Ok(i) => i,
+            Err(0) => return Ok(None), // only when the partition doesn't start with its first key
             Err(i) => i-1
-        }];
+        })?;
 
-        debug!("Top-level binary search: {:?} -> {}", top_index_res, start_offset);
+        debug!("Top-level binary search: {:?} -> {}", top_index_res, group_indices_offset);
 
-        // need to fetch the group indices array from rec_file
-        let group_indices_offset = start_offset - ((self.info.group_count as usize * U64_SIZE) + U32_SIZE) as u64;
-        let group_indices_buff = self.rec_file.read_at(group_indices_offset)?;
-        let mut group_indices = deserialize_u64_exact(&group_indices_buff);
+        // fetch the group indices array from rec_file
+        let group_indices = self.group_indices(group_indices_offset, fill_cache)?;
 
-        // chop the array when we find our first zero offset
-        group_indices = group_indices.into_iter().take_while(|i| *i != 0x00 as u64).collect::<Vec<_>>();
+        // the rest of the keys in the group are compressed against the first
+        let group_key = self.with_record(group_indices[0], fill_cache, |buff| Ok(decode_record_key(buff, &[], verify_checksums)?.key().to_vec()))?;
 
-        // save the record so we don't need to re-read it
-        let mut rec :Record = Record::new(Vec::<u8>::new(), Some(Vec::<u8>::new()));
+        // only the matching record is decoded, the others are compared in place and never copied
+        let mut found = None;
 
         // binary search through the group indices
-        let group_index_res = SSTable::binary_search_by(&group_indices, |index| {
-            let rec_buff = self.rec_file.read_at(*index).expect("Error reading SSTable");
-            rec = Record::deserialize(rec_buff);
+        let group_index_res = SSTable::binary_search_by(group_indices.len(), |i| {
+            let res = self.with_record(group_indices[i], fill_cache, |buff| {
+                let ord = compare_record_key(buff, &group_key, &key, verify_checksums)?;
+
+                if ord == Equal {
+                    found = Some(decode_record(buff, &group_key, false)?);
+                }
+
+                Ok(ord)
+            });
 
-            rec.key().cmp(&key)
+            match res {
+                Ok(ord) => ord,
+                Err(e) => { if error.is_none() { error = Some(e); } Greater }
+            }
         });
 
+        if let Some(e) = error {
+            return Err(e);
+        }
+
         debug!("Group binary search: {:?}", group_index_res);
 
         // convert from binary_search result to actual result
-        let ret = match group_index_res {
-            Ok(_) => Some(rec),
-            Err(_) => None
+        let ret = match (group_index_res, found) {
+            (Ok(_), Some(rec)) => Some(self.load_value(rec)?),
+            _ => None
         };
 
         Ok(ret)
     }
 
-    pub fn iter(&self) -> Iter {
+    /// Returns the record with the largest key at or before the key
+    pub fn get_le(&self, key: Vec<u8>) -> Result<Option<Record>, IOError> {
+        match self.floor(&key)? {
+            Some(floor) => Ok(Some(self.load_value(floor.rec)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the record with the smallest key at or after the key
+    pub fn get_ge(&self, key: Vec<u8>) -> Result<Option<Record>, IOError> {
+        if self.info.record_count == 0 || self.info.largest_key < key {
+            return Ok(None);
+        }
+
+        let floor = match self.floor(&key)? {
+            Some(floor) => floor,
+            None => return Ok(Some(self.load_value(self.group_head(self.group_index_offset(&self.info.partitions[0], 0)?, true, true)?)?)) // before the first key
+        };
+
+        if floor.rec.key() == key {
+            return Ok(Some(self.load_value(floor.rec)?));
+        }
+
+        // the next record is in the same group, the next group, or the next partition; there is one, as the key is before the largest
+        let partition = &self.info.partitions[floor.partition];
+
+        let rec = if floor.index + 1 < floor.group_indices.len() {
+            self.group_record(&floor.group_indices, &floor.group_key, floor.index + 1, true)?
+        } else if floor.group + 1 < partition.index_count as usize {
+            self.group_head(self.group_index_offset(partition, floor.group + 1)?, true, true)?
+        } else {
+            self.group_head(self.group_index_offset(&self.info.partitions[floor.partition + 1], 0)?, true, true)?
+        };
+
+        Ok(Some(self.load_value(rec)?))
+    }
+
+    /// Finds the last record at or before the key, with the same two-level search as `get_with`
+    /// The record's value is still compressed.
+    fn floor(&self, key: &Vec<u8>) -> Result<Option<Floor>, IOError> {
+        if self.info.record_count == 0 || *key < self.info.smallest_key {
+            return Ok(None);
+        }
+
+        // the last partition starting at or before the key
+        let partition_index = match self.info.partitions.binary_search_by(|p| p.first_key.cmp(key)) {
+            Ok(i) => i,
+            Err(i) => i-1
+        };
+        let partition = &self.info.partitions[partition_index];
+
+        let mut error = None;
+
+        let top_index_res = SSTable::binary_search_by(partition.index_count as usize, |i| {
+            match self.group_index_offset(partition, i).and_then(|offset| self.compare_group_head(offset, key, true, true)) {
+                Ok(ord) => ord,
+                Err(e) => { if error.is_none() { error = Some(e); } Greater }
+            }
+        });
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        let group = match top_index_res {
+            Ok(i) => i,
+            Err(0) => return Ok(None), // only when the partition doesn't start with its first key
+            Err(i) => i-1
+        };
+
+        let group_indices = self.group_indices(self.group_index_offset(partition, group)?, true)?;
+        let group_key = self.with_record(group_indices[0], true, |buff| Ok(decode_record_key(buff, &[], true)?.key().to_vec()))?;
+
+        // the keys are compared in place, only the record found is decoded
+        let group_index_res = SSTable::binary_search_by(group_indices.len(), |i| {
+            match self.with_record(group_indices[i], true, |buff| Ok(compare_record_key(buff, &group_key, key, true)?)) {
+                Ok(ord) => ord,
+                Err(e) => { if error.is_none() { error = Some(e); } Greater }
+            }
+        });
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        // the group starts at or before the key, so there's always a record at or before it
+        let index = match group_index_res {
+            Ok(i) => i,
+            Err(i) => i-1
+        };
+
+        let rec = self.group_record(&group_indices, &group_key, index, true)?;
+
+        Ok(Some(Floor { partition: partition_index, group: group, group_indices: group_indices, group_key: group_key, index: index, rec: rec }))
+    }
+
+    /// Reads a record of a group, whose keys are compressed against the group's first key
+    fn group_record(&self, group_indices: &[u64], group_key: &[u8], i: usize, verify_checksum: bool) -> Result<Record, IOError> {
+        self.with_record(group_indices[i], true, |buff| Ok(decode_record(buff, group_key, verify_checksum)?))
+    }
+
+    /// Reads a record into this thread's buffer, and passes it to `f`, so a lookup doesn't allocate for each record it reads
+    fn with_record<T, F>(&self, offset: u64, fill_cache: bool, f: F) -> Result<T, IOError> where F: FnOnce(&[u8]) -> Result<T, IOError> {
+        READ_BUFFER.with(|cell| {
+            match cell.try_borrow_mut() {
+                Ok(mut buff) => {
+                    self.rec_file.read_into(offset, fill_cache, &mut buff)?;
+
+                    let ret = f(&buff);
+
+                    // don't hold on to the space of an outsized record
+                    if buff.capacity() > MAX_READ_BUFFER {
+                        *buff = Vec::new();
+                    }
+
+                    ret
+                },
+                // already lent out further up this thread's stack
+                Err(_) => f(&self.rec_file.read_at_with(offset, fill_cache)?)
+            }
+        })
+    }
+
+    /// The number of records in a partition
+    fn partition_record_count(&self, p: usize) -> u64 {
+        if self.info.partitions[p].record_count != 0 {
+            return self.info.partitions[p].record_count;
+        }
+
+        // older tables have full groups, and full partitions but for the last
+        let partition_records = self.info.group_count as u64 * PARTITION_GROUP_COUNT as u64;
+
+        partition_records.min(self.info.record_count - p as u64 * partition_records)
+    }
+
+    /// The number of records in a group, given the number of its first record
+    fn group_len(&self, partition: &IndexPartition, group: usize, first_record: u64, fill_cache: bool) -> Result<u64, IOError> {
+        if self.info.block_bytes == 0 {
+            return Ok((self.info.record_count - first_record).min(self.info.group_count as u64));
+        }
+
+        Ok(self.group_indices(self.group_index_offset(partition, group)?, fill_cache)?.len() as u64)
+    }
+
+    /// Returns the offset of the first record in a partition
+    fn partition_start(&self, p: usize) -> Result<u64, IOError> {
+        let group_indices_offset = self.group_index_offset(&self.info.partitions[p], 0)?;
+
+        Ok(self.group_indices(group_indices_offset, true)?[0])
+    }
+
+    /// Reads a group index, the offsets of the records in a group, through the metadata cache if there is one
+    fn group_indices(&self, group_indices_offset: u64, fill_cache: bool) -> Result<Arc<Vec<u64>>, IOError> {
+        match self.meta {
+            Some(ref meta) => meta.index(self.info.id, group_indices_offset, false, fill_cache, || self.read_index(group_indices_offset, false)),
+            None => self.read_index(group_indices_offset, fill_cache).map(Arc::new)
+        }
+    }
+
+    /// Reads an index block or group index from the file
+    fn read_index(&self, offset: u64, fill_cache: bool) -> Result<Vec<u64>, IOError> {
+        let buff = self.rec_file.read_at_with(offset, fill_cache)?;
+
+        if buff.is_empty() || buff.len() % U64_SIZE != 0 {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Bad group index at {}: {} bytes", offset, buff.len())));
+        }
+
+        Ok(deserialize_u64_exact(&buff))
+    }
+
+    /// Reads the first record of a group, given the offset of its group index
+    fn group_head(&self, group_indices_offset: u64, fill_cache: bool, verify_checksum: bool) -> Result<Record, IOError> {
+        let group_indices = self.group_indices(group_indices_offset, fill_cache)?;
+
+        self.with_record(group_indices[0], fill_cache, |buff| Ok(decode_record(buff, &[], verify_checksum)?))
+    }
+
+    /// Compares the first key of a group to the key, in place, without decoding the record
+    fn compare_group_head(&self, group_indices_offset: u64, key: &[u8], fill_cache: bool, verify_checksum: bool) -> Result<Ordering, IOError> {
+        let group_indices = self.group_indices(group_indices_offset, fill_cache)?;
+
+        self.with_record(group_indices[0], fill_cache, |buff| Ok(compare_record_key(buff, &[], key, verify_checksum)?))
+    }
+
+    /// Decompresses the value of a record read from disk, if this table uses a dictionary
+    fn decompress(&self, mut rec: Record) -> Result<Record, IOError> {
+        if let Some(ref d) = self.decompressor {
+            if !rec.is_delete() && !rec.is_value_pointer() {
+                let value = d.decompress(&rec.value())?;
+                rec.set_value(value);
+            }
+        }
+
+        Ok(rec)
+    }
+
+    /// Decompresses the value of a record read from disk, then reads it from the value log if the record points there
+    fn load_value(&self, rec: Record) -> Result<Record, IOError> {
+        let rec = self.decompress(rec)?;
+
+        match self.value_log {
+            Some(ref value_log) => value_log.resolve(rec),
+            None if rec.is_value_pointer() => Err(IOError::new(ErrorKind::NotFound, format!("{:?} points into a value log, but there isn't one", self.file_path()))),
+            None => Ok(rec)
+        }
+    }
+
+    pub fn iter(&self) -> Iter<&SSTable> {
+        SSTable::iter_from(self, true, true, DEFAULT_READAHEAD, false)
+    }
+
+    /// Creates an iterator that leaves the values in the value log, with the records pointing to them; for compactions
+    pub fn iter_raw(&self) -> Iter<&SSTable> {
+        let mut it = SSTable::iter_from(self, true, true, DEFAULT_READAHEAD, false);
+
+        it.resolve_values = false;
+        it
+    }
+
+    /// Creates an iterator that owns a reference to the table, so it isn't tied to a borrow
+    /// * fill_cache - add the records read to the cache
+    /// * verify_checksums - panic if a record read doesn't match its checksum
+    /// * readahead - the most bytes to read ahead of the records, 0 for none
+    /// * keys_only - leave the values empty, without copying or decompressing them
+    pub fn iter_shared(sstable: Arc<SSTable>, fill_cache: bool, verify_checksums: bool, readahead: u64, keys_only: bool) -> Iter<Arc<SSTable>> {
+        SSTable::iter_from(sstable, fill_cache, verify_checksums, readahead, keys_only)
+    }
+
+    fn iter_from<S>(sstable: S, fill_cache: bool, verify_checksums: bool, readahead: u64, keys_only: bool) -> Iter<S> where S: Deref<Target=SSTable> {
+        let cur_offset = if sstable.info.record_count == 0 { 0 } else { sstable.partition_start(0).expect("Error reading SSTable") };
+
         return Iter {
-            sstable: self,
+            sstable: sstable,
             cur_record: 0,
-            cur_offset: if self.info.record_count == 0 { 0 } else { self.info.indices[0] }
+            cur_offset: cur_offset,
+            partition: 0,
+            group: 0,
+            group_len: 0,
+            group_left: 0,
+            group_key: vec![],
+            buff: vec![],
+            fill_cache: fill_cache,
+            verify_checksums: verify_checksums,
+            keys_only: keys_only,
+            resolve_values: true,
+            max_readahead: readahead,
+            readahead_size: 0,
+            readahead_end: 0
         }
     }
 
@@ -257,15 +1196,131 @@ impl SSTable {
         self.info.oldest_ts
     }
 
+    pub fn newest_ts(&self) -> u64 {
+        self.info.newest_ts
+    }
+
+    pub fn tombstone_count(&self) -> u64 {
+        self.info.tombstone_count
+    }
+
+    pub fn total_value_bytes(&self) -> u64 {
+        self.info.total_value_bytes
+    }
+
+    pub fn expiring_count(&self) -> u64 {
+        self.info.expiring_count
+    }
+
+    pub fn latest_expiry(&self) -> u64 {
+        self.info.latest_expiry
+    }
+
+    pub fn earliest_expiry(&self) -> u64 {
+        self.info.earliest_expiry
+    }
+
+    /// The bytes of padding written to align the records
+    pub fn padding_bytes(&self) -> u64 {
+        self.info.padding_bytes
+    }
+
+    /// The bytes of the bloom filters, which are read through the record cache
+    pub fn bloom_bytes(&self) -> u64 {
+        (0..self.info.partitions.len()).map(|p| BloomFilter::serialized_len(self.partition_record_count(p) as usize, BITS_PER_KEY) as u64).sum()
+    }
+
+    /// The size groups are closed at, 0 when they're `group_count` records
+    pub fn block_bytes(&self) -> u64 {
+        self.info.block_bytes
+    }
+
+    /// Up to n of the first keys of the groups, evenly spaced, in key order; from the indexes, without reading the other records
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<Vec<u8>>, IOError> {
+        let group_count = self.info.partitions.iter().map(|p| p.index_count as usize).sum::<usize>();
+        let mut keys = vec![];
+
+        if n == 0 || group_count == 0 {
+            return Ok(keys);
+        }
+
+        let mut last = None;
+
+        for k in 0..n.min(group_count) {
+            let group = k * group_count / n.min(group_count);
+
+            if last == Some(group) {
+                continue;
+            }
+
+            last = Some(group);
+
+            // every partition but the last is full
+            let partition = &self.info.partitions[group / PARTITION_GROUP_COUNT];
+            let i = group % PARTITION_GROUP_COUNT;
+
+            if i == 0 {
+                keys.push(partition.first_key.clone());
+            } else {
+                let group_indices_offset = self.group_index_offset(partition, i)?;
+
+                keys.push(self.group_head(group_indices_offset, false, true)?.key().to_vec());
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// The reads of the SSTable that were, and weren't, found in the record cache
+    pub fn cache_stats(&self) -> (u64, u64) {
+        self.rec_file.cache_stats()
+    }
+
+    /// The bytes its iterators have read ahead
+    pub fn readahead_bytes(&self) -> u64 {
+        self.rec_file.readahead_bytes()
+    }
+
+    pub fn id(&self) -> u64 { self.info.id }
+
+    /// The codec the SSTableInfo is written with
+    pub fn codec(&self) -> CodecKind { self.codec }
+
     pub fn record_count(&self) -> u64 { self.info.record_count }
 
+    pub fn range_tombstones(&self) -> &[Record] { &self.info.range_tombstones }
+
+    /// The numbers of the value log files the records point into
+    pub fn value_logs(&self) -> &[u64] { &self.info.value_logs }
+
+    /// The paths to the value log files the records point into, that are still open
+    pub fn value_log_paths(&self) -> Vec<PathBuf> {
+        match self.value_log {
+            Some(ref value_log) => self.info.value_logs.iter().filter_map(|&number| value_log.file_path(number)).collect(),
+            None => vec![]
+        }
+    }
+
+    pub fn smallest_key(&self) -> &[u8] { &self.info.smallest_key }
+
+    pub fn largest_key(&self) -> &[u8] { &self.info.largest_key }
+
+    /// The smallest and largest keys, None for an empty table
+    pub fn key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.info.record_count == 0 { None } else { Some((self.info.smallest_key.clone(), self.info.largest_key.clone())) }
+    }
+
     pub fn file_path(&self) -> PathBuf { self.rec_file.file_path() }
+
+    /// The bytes of the file, through the handle it was opened with
+    pub fn file_size(&self) -> Result<u64, IOError> { self.rec_file.file_len() }
 }
 
 impl Debug for SSTable {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         formatter.debug_struct("SSTable")
             .field("record_file", &self.rec_file)
+            .field("codec", &self.codec)
             .field("info", &self.info)
             .finish()
     }
@@ -274,24 +1329,103 @@ impl Debug for SSTable {
 impl Debug for SSTableInfo {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         formatter.debug_struct("SSTableInfo")
+            .field("id", &self.id)
             .field("record_count", &self.record_count)
             .field("group_count", &self.group_count)
             .field("smallest_key", &buf2string(&self.smallest_key))
             .field("largest_key", &buf2string(&self.largest_key))
             .field("oldest_ts", &self.oldest_ts)
-            .field("indices", &self.indices)
+            .field("newest_ts", &self.newest_ts)
+            .field("tombstone_count", &self.tombstone_count)
+            .field("total_value_bytes", &self.total_value_bytes)
+            .field("expiring_count", &self.expiring_count)
+            .field("latest_expiry", &self.latest_expiry)
+            .field("earliest_expiry", &self.earliest_expiry)
+            .field("alignment", &self.alignment)
+            .field("padding_bytes", &self.padding_bytes)
+            .field("dictionary", &self.dictionary.as_ref().map(|d| d.len()))
+            .field("range_tombstones", &self.range_tombstones)
+            .field("partitions", &self.partitions.len())
             .finish()
     }
 }
 
 
-pub struct Iter<'a> {
-    sstable: &'a SSTable,
+/// Reads the records of an SSTable in order
+///
+/// Once it has read a few, it reads ahead of them, starting small and doubling up to `max_readahead`,
+/// so a scan isn't waiting on the disk for each record.
+pub struct Iter<S> where S: Deref<Target=SSTable> {
+    sstable: S,
     cur_record: u64,
-    cur_offset: u64
+    cur_offset: u64,
+    partition: usize, // the partition and group of the current record
+    group: usize,
+    group_len: u64,
+    group_left: u64,  // the records of the group not yet read
+    group_key: Vec<u8>,
+    buff: Vec<u8>,    // each record is read into, reusing its space
+    fill_cache: bool,
+    verify_checksums: bool,
+    keys_only: bool,
+    resolve_values: bool, // read the values out of the value log, instead of leaving the pointers to them
+    max_readahead: u64,
+    readahead_size: u64, // of the last read ahead
+    readahead_end: u64   // the end of the bytes read ahead so far
 }
 
-impl<'a> Iterator for Iter<'a> {
+impl<S> Iter<S> where S: Deref<Target=SSTable> {
+    /// Moves to the start of the group holding the key, so a scan from the key doesn't read the records before it
+    ///
+    /// The records of the group before the key are still returned; a key before the first leaves the iterator at the start.
+    /// Called before the first `next`.
+    pub fn seek(&mut self, key: &[u8]) -> Result<(), IOError> {
+        let floor = match self.sstable.floor(&key.to_vec())? {
+            Some(floor) => floor,
+            None => return Ok( () )
+        };
+
+        // the records before the group, counted by partition, then by group in its partition
+        let mut first_record = (0..floor.partition).map(|p| self.sstable.partition_record_count(p)).sum::<u64>();
+        let partition = &self.sstable.info.partitions[floor.partition];
+
+        for group in 0..floor.group {
+            first_record += self.sstable.group_len(partition, group, first_record, self.fill_cache)?;
+        }
+
+        self.cur_record = first_record;
+        self.cur_offset = floor.group_indices[0];
+        self.partition = floor.partition;
+        self.group = floor.group;
+        self.group_left = 0;
+
+        Ok( () )
+    }
+
+    /// Reads ahead of the next record, once half of the bytes last read ahead have been read
+    fn readahead(&mut self) {
+        if self.max_readahead == 0 || self.cur_record <= READAHEAD_AFTER || self.cur_record == self.sstable.info.record_count {
+            return;
+        }
+
+        if self.cur_offset + self.readahead_size / 2 < self.readahead_end {
+            return;
+        }
+
+        self.readahead_size = (self.readahead_size * 2).max(MIN_READAHEAD).min(self.max_readahead);
+
+        let start = self.readahead_end.max(self.cur_offset);
+
+        // only a hint, so the scan goes on without it
+        if let Err(e) = self.sstable.rec_file.readahead(start, self.readahead_size) {
+            debug!("Error reading ahead {:?} at {}: {}", self.sstable.file_path(), start, e);
+        }
+
+        self.readahead_end = start + self.readahead_size;
+    }
+}
+
+impl<S> Iterator for Iter<S> where S: Deref<Target=SSTable> {
     type Item = Record;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -299,18 +1433,57 @@ impl<'a> Iterator for Iter<'a> {
             return None;
         }
 
-        let rec_buff = self.sstable.rec_file.read_at(self.cur_offset).expect("Error reading SSTable");
-        let rec_buff_len = rec_buff.len();
-        let rec = Record::deserialize(rec_buff);
+        // groups are counted out, as they can have any number of records when they're closed by size
+        if self.group_left == 0 {
+            let group_len = self.sstable.group_len(&self.sstable.info.partitions[self.partition], self.group, self.cur_record, self.fill_cache).expect("Error reading SSTable");
 
-        self.cur_record += 1;
-        self.cur_offset += (rec_buff_len + U32_SIZE) as u64;
+            self.group_len = group_len;
+            self.group_left = group_len;
+        }
+
+        let rec_offset = self.sstable.rec_file.skip_padding(self.cur_offset).expect("Error reading SSTable");
+        self.sstable.rec_file.read_into(rec_offset, self.fill_cache, &mut self.buff).expect("Error reading SSTable");
+        let rec_buff_len = self.buff.len();
+        let rec = if self.keys_only {
+            decode_record_key(&self.buff, &self.group_key, self.verify_checksums)
+        } else {
+            decode_record(&self.buff, &self.group_key, self.verify_checksums)
+        }.expect("Error decoding record");
+
+        // the first record in a group is the key the rest are compressed against
+        if self.group_left == self.group_len {
+            self.group_key = rec.key().to_vec();
+        }
+
+        // an empty value needs no decompressing
+        let rec = if self.keys_only {
+            rec
+        } else if self.resolve_values {
+            self.sstable.load_value(rec).expect("Error loading value")
+        } else {
+            self.sstable.decompress(rec).expect("Error decompressing record")
+        };
 
-        // need to skip over the group index records
-        if self.cur_record % self.sstable.info.group_count as u64 == 0 {
-            self.cur_offset += ((self.sstable.info.group_count as usize * U64_SIZE) + U32_SIZE) as u64;
+        self.cur_record += 1;
+        self.cur_offset = rec_offset + (rec_buff_len + U32_SIZE) as u64;
+        self.group_left -= 1;
+
+        // need to skip over the group index records, and the index block & filter after a partition
+        if self.group_left == 0 && self.cur_record != self.sstable.info.record_count {
+            self.group += 1;
+
+            if self.group as u64 == self.sstable.info.partitions[self.partition].index_count {
+                self.partition += 1;
+                self.group = 0;
+                self.cur_offset = self.sstable.partition_start(self.partition).expect("Error reading SSTable");
+            } else {
+                let index_offset = self.sstable.rec_file.skip_padding(self.cur_offset).expect("Error reading SSTable");
+                self.cur_offset = index_offset + ((self.group_len as usize * U64_SIZE) + U32_SIZE) as u64;
+            }
         }
 
+        self.readahead();
+
         Some(rec)
     }
 
@@ -328,15 +1501,13 @@ impl PartialOrd for SSTable {
 
 impl Ord for SSTable {
     fn cmp(&self, other: &SSTable) -> Ordering {
-        self.info.smallest_key.cmp(&other.info.smallest_key)
+        (&self.info.smallest_key, self.info.id).cmp(&(&other.info.smallest_key, other.info.id))
     }
 }
 
 impl PartialEq for SSTable {
     fn eq(&self, other: &SSTable) -> bool {
-        self.info.smallest_key == other.info.smallest_key &&
-        self.info.largest_key  == other.info.largest_key &&
-        self.info.record_count == other.info.record_count
+        self.info.id == other.info.id
     }
 }
 
@@ -345,34 +1516,31 @@ impl Eq for SSTable { }
 
 #[cfg(test)]
 mod tests {
-    use sstable::SSTable;
+    use cache::{CacheOptions, CachePolicyKind};
+    use meta_cache::MetaCache;
+    use codec::CodecKind;
+    use sstable::{SSTable, SSTableOptions, NewSSTable};
+    use format::{encode_record, decode_record, shared_prefix_len};
     use record::Record;
-    use std::path::PathBuf;
+    use std::sync::Arc;
     use std::iter;
-    use rand::{thread_rng, Rng};
-    use std::fs::create_dir;
-    use simple_logger;
-    use serde_utils::serialize_u64_exact;
-    use ::LOGGER_INIT;
+    use serde_utils::{serialize_u64_exact, deserialize_u64_exact};
+    use format::{records as file_records, sstable_header};
+    use sstable::SalvageReport;
+    use std::fs;
+    use std::io::ErrorKind;
+    use test_path::{gen_dir, TestPath};
+    use {U32_SIZE, U64_SIZE};
 
     const BUFFER_SIZE: usize = 4069;
-    const CACHE_SIZE: usize = 100;
-
-
-    fn gen_dir() -> PathBuf {
-        LOGGER_INIT.call_once(|| simple_logger::init().unwrap()); // this will panic on error
-
-        let tmp_dir: String = thread_rng().gen_ascii_chars().take(6).collect();
-        let ret_dir = PathBuf::from("/tmp").join(format!("kvs_{}", tmp_dir));
-
-        debug!("CREATING TMP DIR: {:?}", ret_dir);
+    const CACHE: CacheOptions = CacheOptions { size: 100, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
 
-        create_dir(&ret_dir).unwrap();
-
-        return ret_dir;
+    fn options(group_size: u32) -> SSTableOptions {
+        SSTableOptions { group_count: Some(group_size), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 }
     }
 
-    fn new_open(num_records: usize, group_size: u32, use_size: bool) -> SSTable {
+    /// Creates and opens an SSTable, returning it with its directory
+    fn new_open(num_records: usize, group_size: u32, use_size: bool) -> (TestPath, SSTable) {
         let db_dir = gen_dir();
         let mut records = vec![];
 
@@ -383,53 +1551,55 @@ mod tests {
         }
 
         {
-            SSTable::new(&db_dir.join("test.data"), &mut records.iter(), group_size, if use_size { Some(num_records as u64) } else { None }, BUFFER_SIZE, CACHE_SIZE).unwrap();
+            SSTable::new(NewSSTable { count: if use_size { Some(num_records as u64) } else { None }, ..NewSSTable::new(&db_dir.join("test.data"), 1, &options(group_size), BUFFER_SIZE, CACHE) }, &mut records.iter()).unwrap();
         }
 
-        SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE_SIZE).unwrap()
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        (db_dir, sstable)
     }
 
     #[test]
     fn test_new_empty() {
         let db_dir = gen_dir();
 
-        SSTable::new(&db_dir.join("test.data"), &mut iter::empty::<Record>(), 10, None, BUFFER_SIZE, CACHE_SIZE).unwrap();
+        SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(10), BUFFER_SIZE, CACHE), &mut iter::empty::<Record>()).unwrap();
     }
 
     #[test]
     fn test_new_100_2() {
-        let f1 = new_open(100, 2, false);
-        let f2 = new_open(100, 2, true);
+        let (_dir1, f1) = new_open(100, 2, false);
+        let (_dir2, f2) = new_open(100, 2, true);
 
         assert_eq!(f1.record_count(), f2.record_count());
     }
 
     #[test]
     fn test_new_10000_10() {
-        let f1 = new_open(10000, 10, false);
-        let f2 = new_open(10000, 10, true);
+        let (_dir1, f1) = new_open(10000, 10, false);
+        let (_dir2, f2) = new_open(10000, 10, true);
 
         assert_eq!(f1.record_count(), f2.record_count());
     }
 
     #[test]
     fn test_new_1_10() {
-        let f1 = new_open(1, 10, false);
-        let f2 = new_open(1, 10, true);
+        let (_dir1, f1) = new_open(1, 10, false);
+        let (_dir2, f2) = new_open(1, 10, true);
 
         assert_eq!(f1.record_count(), f2.record_count());
     }
 
     #[test]
     fn test_new_1_1() {
-        let f1 = new_open(1, 1, false);
-        let f2 = new_open(1, 1, true);
+        let (_dir1, f1) = new_open(1, 1, false);
+        let (_dir2, f2) = new_open(1, 1, true);
 
         assert_eq!(f1.record_count(), f2.record_count());
     }
 
     fn get(num_records: usize, group_size: u32) {
-        let sstable = new_open(num_records, group_size, false);
+        let (_dir, sstable) = new_open(num_records, group_size, false);
 
         debug!("GET TEST SSTABLE: {:?}", sstable);
 
@@ -464,7 +1634,7 @@ mod tests {
     }
 
     fn iterate(num_records: usize, group_size: u32) {
-        let sstable = new_open(num_records, group_size, false);
+        let (_dir, sstable) = new_open(num_records, group_size, false);
 
         debug!("ITER TEST SSTABLE: {:?}", sstable);
 
@@ -502,4 +1672,495 @@ mod tests {
     fn test_iter_1_1() {
         iterate(1, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_dictionary() {
+        let db_dir = gen_dir();
+        let mut records = vec![];
+
+        for i in 0..5000 {
+            let key = serialize_u64_exact(&vec![i as u64]);
+            let value = format!("{{\"id\": {}, \"name\": \"user_{}\", \"active\": true}}", i, i).into_bytes();
+
+            records.push(Record::new(key, Some(value)));
+        }
+
+        {
+            let options = SSTableOptions { group_count: Some(10), target_block_bytes: 0, group_by_size: false, dict_size: 4096, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+            SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert!(sstable.info.dictionary.is_some());
+
+        for rec in records.iter() {
+            let ret = sstable.get(rec.key().to_vec()).unwrap().unwrap();
+
+            assert_eq!(rec.value(), ret.value());
+        }
+
+        for (rec, ret) in records.iter().zip(sstable.iter()) {
+            assert_eq!(rec.value(), ret.value());
+        }
+
+        // keys only scans skip the values, compressed or not
+        let sstable = Arc::new(sstable);
+
+        for (rec, ret) in records.iter().zip(SSTable::iter_shared(sstable, true, true, 0, true)) {
+            assert_eq!(rec.key(), ret.key());
+            assert!(!ret.is_delete() && ret.value().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_prefix_encoding() {
+        let group_key = b"KEY_1000".to_vec();
+        let rec = Record::new(b"KEY_1001".to_vec(), Some(b"VALUE".to_vec()));
+        let shared = shared_prefix_len(&group_key, &rec.key());
+
+        assert_eq!(7, shared);
+
+        let buff = encode_record(&rec, shared);
+
+        assert!(buff.len() < encode_record(&rec, 0).len());
+
+        let rec_d = decode_record(&buff, &group_key, true).unwrap();
+
+        assert_eq!(rec.key(), rec_d.key());
+        assert_eq!(rec.value(), rec_d.value());
+        assert_eq!(rec.created(), rec_d.created());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let rec = Record::new(b"KEY_1001".to_vec(), Some(b"VALUE".to_vec()));
+        let mut buff = encode_record(&rec, 0);
+
+        let value_offset = buff.len() - U32_SIZE - 1; // the last byte of the value
+
+        buff[value_offset] ^= 0xFF;
+
+        assert!(decode_record(&buff, &[], true).is_err());
+        assert!(decode_record(&buff, &[], false).is_ok());
+    }
+
+    #[test]
+    fn test_select_group_count() {
+        let mut options = SSTableOptions { group_count: None, target_block_bytes: 64 * 1024, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+        assert_eq!(1024, options.select_group_count(64));
+        assert_eq!(100, options.select_group_count(64 * 1024));
+
+        options.target_block_bytes = 1024 * 1024 * 1024;
+
+        assert_eq!(100_000, options.select_group_count(64));
+
+        options.group_count = Some(10);
+
+        assert_eq!(10, options.select_group_count(64));
+    }
+
+    #[test]
+    fn test_auto_group_count() {
+        let db_dir = gen_dir();
+        let records = (0..1000).map(|i| Record::new(serialize_u64_exact(&vec![i as u64]), Some(vec![0xAB; 200]))).collect::<Vec<_>>();
+        let options = SSTableOptions { group_count: None, target_block_bytes: 64 * 1024, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+        let sstable = SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+        assert_eq!(options.select_group_count(records[0].size() as usize), sstable.info.group_count);
+
+        for rec in records.iter() {
+            assert!(sstable.get(rec.key().to_vec()).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_group_by_size() {
+        let db_dir = gen_dir();
+        let records = (0..3000u64).map(|i| Record::new(serialize_u64_exact(&vec![i * 2]), Some(vec![i as u8; (i as usize * 7919) % 2000]))).collect::<Vec<_>>();
+        let options = SSTableOptions { group_count: None, target_block_bytes: 4096, group_by_size: true, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+        {
+            SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert_eq!(4096, sstable.block_bytes());
+        assert!(sstable.info.partitions.len() > 1);
+
+        // every group but the last is closed by the record that took it to the target size
+        let mut groups = vec![];
+
+        for (p, partition) in sstable.info.partitions.iter().enumerate() {
+            for g in 0..partition.index_count as usize {
+                let group_indices = sstable.group_indices(sstable.group_index_offset(partition, g).unwrap(), false).unwrap();
+                let sizes = group_indices.iter().map(|&offset| (U32_SIZE + sstable.rec_file.read_at_with(offset, false).unwrap().len()) as u64).collect::<Vec<_>>();
+
+                groups.push(sizes);
+            }
+
+            assert_eq!(partition.record_count, sstable.partition_record_count(p));
+        }
+
+        let last = groups.pop().unwrap();
+
+        assert!(last.iter().sum::<u64>() < 4096 + 2100);
+
+        for sizes in groups.iter() {
+            assert!(sizes.iter().sum::<u64>() >= 4096);
+            assert!(sizes.iter().sum::<u64>() - sizes.last().unwrap() < 4096);
+        }
+
+        assert_eq!(3000, groups.iter().map(|g| g.len()).sum::<usize>() + last.len());
+
+        assert!(sstable.iter().eq(records.iter().cloned()));
+
+        for (i, rec) in records.iter().enumerate() {
+            assert_eq!(Some(rec.clone()), sstable.get(rec.key().to_vec()).unwrap());
+            assert_eq!(records.get(i + 1).cloned(), sstable.get_ge(serialize_u64_exact(&vec![i as u64 * 2 + 1])).unwrap());
+        }
+
+        // salvaging keeps the grouping
+        let report = SSTable::salvage(&db_dir.join("test.data"), &db_dir.join("salvaged.data")).unwrap();
+        let salvaged = SSTable::open(&db_dir.join("salvaged.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert_eq!(3000, report.recovered);
+        assert_eq!(4096, salvaged.block_bytes());
+        assert!(salvaged.iter().eq(records.iter().cloned()));
+    }
+
+    #[test]
+    fn test_info_stats() {
+        let db_dir = gen_dir();
+        let mut records = vec![];
+
+        for i in 0..100 {
+            let key = serialize_u64_exact(&vec![i as u64]);
+            let value = if i % 4 == 0 { None } else { Some(vec![0xAB; 10]) };
+
+            records.push(if i % 4 == 1 { Record::new_with_ttl(key, value, 5_000 + i) } else { Record::new(key, value) });
+        }
+
+        {
+            SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(10), BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert_eq!(25, sstable.tombstone_count());
+        assert_eq!(750, sstable.total_value_bytes());
+        assert_eq!(records.iter().map(|r| r.created()).min().unwrap(), sstable.oldest_ts());
+        assert_eq!(records.iter().map(|r| r.created()).max().unwrap(), sstable.newest_ts());
+        assert_eq!(25, sstable.expiring_count());
+        assert_eq!(5_097, sstable.latest_expiry());
+        assert_eq!(5_001, sstable.earliest_expiry());
+        assert_eq!(Some((records[0].key().to_vec(), records[99].key().to_vec())), sstable.key_range());
+        assert_eq!(fs::metadata(db_dir.join("test.data")).unwrap().len(), sstable.file_size().unwrap());
+
+        let empty = SSTable::new(NewSSTable::new(&db_dir.join("empty.data"), 2, &options(10), BUFFER_SIZE, CACHE), &mut iter::empty::<Record>()).unwrap();
+
+        assert_eq!(None, empty.key_range());
+        assert_eq!(0, empty.record_count());
+    }
+
+    #[test]
+    fn test_range_tombstones() {
+        let db_dir = gen_dir();
+        let records = (0..10).map(|i| Record::new(serialize_u64_exact(&vec![i as u64]), Some(vec![0xAB; 10]))).collect::<Vec<_>>();
+        let tombstone = Record::new_range_delete(b"A".to_vec(), b"B".to_vec(), 1234);
+
+        {
+            SSTable::new(NewSSTable { range_tombstones: vec![tombstone.clone()], ..NewSSTable::new(&db_dir.join("test.data"), 1, &options(10), BUFFER_SIZE, CACHE) }, &mut records.iter()).unwrap();
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert_eq!(1, sstable.range_tombstones().len());
+        assert_eq!(tombstone.key(), sstable.range_tombstones()[0].key());
+        assert!(sstable.range_tombstones()[0].is_range_delete());
+        assert_eq!(records.len(), sstable.iter().count());
+    }
+
+    #[test]
+    fn test_partitions() {
+        let db_dir = gen_dir();
+
+        // enough groups for a few partitions, with gaps between the keys
+        let records = (0..5000).map(|i| Record::new(serialize_u64_exact(&vec![i * 2 as u64]), Some(vec![0xAB; 10]))).collect::<Vec<_>>();
+        let sstable = SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(10), BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+        assert_eq!(4, sstable.info.partitions.len());
+
+        for i in 0..5000 {
+            assert!(sstable.get(serialize_u64_exact(&vec![i * 2 as u64])).unwrap().is_some());
+            assert!(sstable.get(serialize_u64_exact(&vec![i * 2 + 1 as u64])).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_get_le_ge() {
+        let db_dir = gen_dir();
+
+        // the even numbers, in partitions of 256 records
+        let records = (0..1000u64).map(|i| Record::new(serialize_u64_exact(&vec![i * 2]), Some(vec![0xAB; 10]))).collect::<Vec<_>>();
+
+        {
+            SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(2), BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+        let key = |i: u64| serialize_u64_exact(&vec![i]);
+
+        for i in 0..1000u64 {
+            assert_eq!(key(i * 2), sstable.get_le(key(i * 2)).unwrap().unwrap().key());
+            assert_eq!(key(i * 2), sstable.get_le(key(i * 2 + 1)).unwrap().unwrap().key());
+            assert_eq!(key(i * 2), sstable.get_ge(key(i * 2)).unwrap().unwrap().key());
+
+            if i < 999 {
+                assert_eq!(key(i * 2 + 2), sstable.get_ge(key(i * 2 + 1)).unwrap().unwrap().key());
+            }
+        }
+
+        assert_eq!(vec![0xAB; 10], sstable.get_le(key(11)).unwrap().unwrap().value());
+        assert!(sstable.get_ge(key(1999)).unwrap().is_none());
+        assert!(sstable.get_le(vec![]).unwrap().is_none());
+        assert_eq!(key(0), sstable.get_ge(vec![]).unwrap().unwrap().key());
+    }
+
+    #[test]
+    fn iter_seek() {
+        let db_dir = gen_dir();
+
+        // the even numbers, in a few partitions
+        let records = (0..1000u64).map(|i| Record::new(serialize_u64_exact(&vec![i * 2]), Some(vec![0xAB; 10]))).collect::<Vec<_>>();
+        let sstable = SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(2), BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+        let key = |i: u64| serialize_u64_exact(&vec![i]);
+
+        for &i in &[0u64, 1, 2, 511, 512, 513, 1000, 1998, 1999] {
+            let mut it = sstable.iter();
+
+            it.seek(&key(i)).unwrap();
+
+            let keys = it.map(|rec| rec.key().to_vec()).skip_while(|k| *k < key(i)).collect::<Vec<_>>();
+
+            assert_eq!((0..1000u64).map(|j| key(j * 2)).filter(|k| *k >= key(i)).collect::<Vec<_>>(), keys);
+        }
+
+        // the records before the key are in its group at most
+        let mut it = sstable.iter();
+
+        it.seek(&key(1000)).unwrap();
+
+        assert!(it.next().unwrap().key() >= key(1000 - 2 * 2).as_slice());
+
+        let mut it = sstable.iter();
+
+        it.seek(&vec![]).unwrap();
+
+        assert_eq!(1000, it.count());
+    }
+
+    #[test]
+    fn aligned_records() {
+        let db_dir = gen_dir();
+        let records = (0..1000u64).map(|i| Record::new(serialize_u64_exact(&vec![i]), Some(vec![0xAB; 100 + (i as usize % 300)]))).collect::<Vec<_>>();
+        let mut options = options(10);
+
+        options.alignment = 4096;
+
+        {
+            let sstable = SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+            assert_ne!(0, sstable.padding_bytes());
+        }
+
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert_eq!(records.iter().map(|r| r.clone().into_parts()).collect::<Vec<_>>(), sstable.iter().map(|r| r.into_parts()).collect::<Vec<_>>());
+
+        for rec in records.iter() {
+            assert_eq!(rec.value(), sstable.get(rec.key().to_vec()).unwrap().unwrap().value());
+        }
+    }
+
+    #[test]
+    fn meta_cache() {
+        let db_dir = gen_dir();
+        let records = (0..5000).map(|i| Record::new(serialize_u64_exact(&vec![i * 2 as u64]), Some(vec![0xAB; 10]))).collect::<Vec<_>>();
+
+        // pinned, the filters and index blocks are loaded as the table is created
+        let meta = Arc::new(MetaCache::new(4096));
+        let cache = CacheOptions { meta: Some(meta.clone()), pin_meta: true, ..CACHE };
+        let sstable = SSTable::new(NewSSTable::new(&db_dir.join("test.data"), 1, &options(10), BUFFER_SIZE, cache.clone()), &mut records.iter()).unwrap();
+
+        let index_bytes = sstable.info.partitions.iter().map(|p| p.index_count * U64_SIZE as u64).sum::<u64>();
+
+        assert_eq!((sstable.bloom_bytes() + index_bytes, sstable.bloom_bytes() + index_bytes), meta.bytes());
+
+        for i in 0..5000 {
+            assert!(sstable.get(serialize_u64_exact(&vec![i * 2 as u64])).unwrap().is_some());
+            assert!(sstable.get(serialize_u64_exact(&vec![i * 2 + 1 as u64])).unwrap().is_none());
+        }
+
+        assert_eq!(Some(serialize_u64_exact(&vec![12])), sstable.get_ge(serialize_u64_exact(&vec![11])).unwrap().map(|r| r.key().to_vec()));
+        assert_eq!(Some(serialize_u64_exact(&vec![10])), sstable.get_le(serialize_u64_exact(&vec![11])).unwrap().map(|r| r.key().to_vec()));
+
+        // the group indices are cached too, up to the size
+        let (bytes, pinned) = meta.bytes();
+
+        assert!(bytes > pinned && bytes <= pinned + 4096);
+
+        // reopened, the pinned entries are found
+        let (_, misses) = meta.cache_stats();
+
+        drop(sstable);
+        SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, cache).unwrap();
+
+        assert_eq!(misses, meta.cache_stats().1);
+
+        // without a metadata cache, the filters are kept with the table once read
+        let sstable = SSTable::open(&db_dir.join("test.data"), BUFFER_SIZE, CACHE).unwrap();
+
+        assert!(sstable.filters.lock().unwrap().is_empty());
+
+        for i in 0..5000 {
+            assert!(sstable.get(serialize_u64_exact(&vec![i * 2 + 1 as u64])).unwrap().is_none());
+        }
+
+        let partition = &sstable.info.partitions[0];
+
+        assert_eq!(sstable.info.partitions.len(), sstable.filters.lock().unwrap().len());
+        assert!(Arc::ptr_eq(&sstable.filter(partition, false).unwrap(), &sstable.filter(partition, false).unwrap()));
+    }
+
+    #[test]
+    fn readahead() {
+        let (_dir, sstable) = new_open(10_000, 10, false);
+        let sstable = Arc::new(sstable);
+
+        // short scans, and those without it, don't read ahead
+        assert_eq!(16, SSTable::iter_shared(sstable.clone(), true, true, 1 << 16, false).take(16).count());
+        assert_eq!(10_000, SSTable::iter_shared(sstable.clone(), true, true, 0, false).count());
+        assert_eq!(0, sstable.readahead_bytes());
+
+        assert_eq!(10_000, SSTable::iter_shared(sstable.clone(), false, true, 1 << 16, false).count());
+
+        if cfg!(target_os = "linux") {
+            assert!(sstable.readahead_bytes() >= sstable.file_path().metadata().unwrap().len() / 2, "{}", sstable.readahead_bytes());
+        }
+    }
+
+    #[test]
+    fn salvage() {
+        let db_dir = gen_dir();
+        let records = (0..1000u64).map(|i| Record::new(serialize_u64_exact(&vec![i]), Some(vec![i as u8; 100]))).collect::<Vec<_>>();
+        let src = db_dir.join("000001.sst");
+        let salvaged = |name: &str| {
+            let dst = db_dir.join(name);
+            let report = SSTable::salvage(&src, &dst).unwrap();
+            let sstable = SSTable::open(&dst, BUFFER_SIZE, CACHE).unwrap();
+
+            // whatever was recovered is the original records, in order
+            for rec in sstable.iter() {
+                assert_eq!(records[deserialize_u64_exact(&rec.key().to_vec())[0] as usize].value(), rec.value());
+            }
+
+            assert_eq!(report.recovered, sstable.record_count());
+
+            report
+        };
+
+        // an intact table is copied whole, aligned or not
+        for &alignment in [0, 4096].iter() {
+            let _ = fs::remove_file(&src);
+            SSTable::new(NewSSTable::new(&src, 1, &SSTableOptions { alignment: alignment, ..options(10) }, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+            let report = salvaged(&format!("intact-{}.sst", alignment));
+
+            assert_eq!(SalvageReport { recovered: 1000, expected: Some(1000), lost_ranges: vec![] }, report);
+        }
+
+        let file = fs::read(&src).unwrap();
+        let offsets = file_records(&file, &sstable_header(CodecKind::MsgPack), 4096).unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+
+        // a bit flipped in a record loses it, and the rest of its group
+        let mut damaged = file.clone();
+        damaged[offsets[500] as usize + U32_SIZE + 10] ^= 0x01;
+        fs::write(&src, &damaged).unwrap();
+
+        let report = salvaged("flipped.sst");
+
+        assert!(report.recovered < 1000 && report.recovered >= 990, "{}", report);
+        assert_eq!(Some(1000 - report.recovered), report.lost_records());
+        assert_eq!(1, report.lost_ranges.len(), "{}", report);
+
+        // a bad size is skipped over, to the next record that matches its CRC
+        let mut damaged = file.clone();
+        damaged[offsets[300] as usize..offsets[300] as usize + U32_SIZE].copy_from_slice(&[0xFF; 4]);
+        fs::write(&src, &damaged).unwrap();
+
+        let report = salvaged("bad_size.sst");
+
+        assert!(report.recovered < 1000 && report.recovered >= 990, "{}", report);
+        assert_eq!(offsets[300], report.lost_ranges[0].0);
+
+        // a torn write loses the info, and the records past the tear
+        fs::write(&src, &file[..file.len() / 2]).unwrap();
+
+        let report = salvaged("torn.sst");
+
+        assert_eq!(None, report.expected);
+        assert!(report.recovered > 400 && report.recovered < 600, "{}", report);
+        assert_eq!(Some(file.len() as u64 / 2), report.lost_ranges.last().map(|r| r.1));
+    }
+
+    #[test]
+    fn sample_keys() {
+        let (_dir, sstable) = new_open(10_000, 10, false);
+
+        // groups 0, 100, .. 900, across the partitions
+        assert_eq!((0..10).map(|i| serialize_u64_exact(&vec![i * 1000])).collect::<Vec<_>>(), sstable.sample_keys(10).unwrap());
+        assert_eq!(1_000, sstable.sample_keys(5_000).unwrap().len());
+        assert!(sstable.sample_keys(0).unwrap().is_empty());
+        assert!(new_open(0, 10, false).1.sample_keys(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify_set_id() {
+        let db_dir = gen_dir();
+        let records = (0..1000u64).map(|i| Record::new(serialize_u64_exact(&vec![i]), Some(vec![i as u8; 100]))).collect::<Vec<_>>();
+        let path = db_dir.join("000001.sst");
+
+        for &alignment in [0, 4096].iter() {
+            let _ = fs::remove_file(&path);
+            SSTable::new(NewSSTable::new(&path, 1, &SSTableOptions { alignment: alignment, ..options(10) }, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+            let mut sstable = SSTable::open(&path, BUFFER_SIZE, CACHE).unwrap();
+
+            sstable.verify().unwrap();
+            sstable.set_id(42).unwrap();
+
+            // only the info changed, so the records are all still there
+            let sstable = SSTable::open(&path, BUFFER_SIZE, CACHE).unwrap();
+
+            assert_eq!(42, sstable.id());
+            assert_eq!(records, sstable.iter().collect::<Vec<_>>());
+            sstable.verify().unwrap();
+        }
+
+        // a bit flipped in a record is found
+        let mut file = fs::read(&path).unwrap();
+        let offsets = file_records(&file, &sstable_header(CodecKind::MsgPack), 4096).unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+
+        file[offsets[500] as usize + U32_SIZE + 10] ^= 0x01;
+        fs::write(&path, &file).unwrap();
+
+        let err = SSTable::open(&path, BUFFER_SIZE, CACHE).unwrap().verify().unwrap_err();
+
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+}
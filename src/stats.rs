@@ -0,0 +1,271 @@
+//
+// A snapshot of the shape of a store, for `KVS::stats` and the `kvs stats` command, and of its health,
+// for `KVS::health` and the HTTP server's /healthz
+//
+
+use std::fmt::{self, Display, Formatter};
+
+use quota::QuotaUsage;
+
+/// The mem_tables, SSTables, and caches of a store; see `KVS::stats`
+#[derive(Debug, Clone)]
+pub struct StoreStats {
+    pub mem_records: u64,            // records in the active mem_table
+    pub immutables: usize,           // full mem_tables waiting to be flushed
+    pub immutable_records: u64,
+    pub wal_bytes: u64,              // the WALs of the mem_tables that aren't flushed, see `KVSOptions::max_wal_bytes`
+    pub levels: Vec<LevelStats>,     // level 0 is the current SSTable, level 1 the SSTables it's compacted into
+    pub open_tables: usize,          // SSTables in the table cache, see `KVSOptions::max_open_tables`
+    pub table_cache: CacheStats,     // gets of SSTables from the table cache
+    pub record_cache: CacheStats,    // reads of the open SSTables from their record caches
+    pub readahead_bytes: u64,        // read ahead in the open SSTables, see `ReadOptions::readahead` and `KVSOptions::compaction_prefetch_indexes`
+    pub meta_cache: CacheStats,      // reads of filters and indexes from the metadata cache, see `KVSOptions::meta_cache_size`
+    pub meta_bytes: u64,             // the filters and indexes in the metadata cache
+    pub pinned_bytes: u64,           // those of them pinned, see `KVSOptions::pin_metadata`
+    pub row_cache: CacheStats,       // gets that looked in the row cache, see `KVSOptions::row_cache_size`
+    pub cached_rows: u64,            // the keys in the row cache
+    pub compaction_pending: bool,    // the current SSTable is big enough to be compacted
+    pub pending_compaction_bytes: u64, // the bytes the pending compaction reads, 0 if none is pending
+    pub expired_records: u64,        // records known to have expired in level 1, see `KVSOptions::ttl_compaction_percent`
+    pub quotas: Vec<QuotaUsage>,     // see `KVSOptions::quota`
+    pub hot_keys: Vec<(Vec<u8>, u64)> // the most read keys, and about how often, see `KVSOptions::hot_keys`
+}
+
+/// Whether a store is keeping up with its writes; see `KVS::health`
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub immutables: usize,             // full mem_tables waiting to be flushed
+    pub max_immutables: usize,         // writers wait once there are this many, see `KVSOptions::max_immutables`
+    pub wal_bytes: u64,                // the WALs of the mem_tables that aren't flushed
+    pub disk_free_bytes: Option<u64>,  // on the store's file system, where it's known
+    pub background_error: Option<String> // the error that made the store read-only, see `KVS::background_error`
+}
+
+/// The SSTables of a level
+#[derive(Debug, Clone)]
+pub struct LevelStats {
+    pub level: usize,
+    pub table_count: usize,
+    pub file_bytes: u64,
+    pub record_count: u64,
+    pub smallest_key: Option<Vec<u8>>, // None when the level has no records
+    pub largest_key: Option<Vec<u8>>,
+    pub bloom_bytes: u64             // the bloom filters, which are read through the record caches
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64
+}
+
+impl CacheStats {
+    /// The fraction of lookups that were hits, 0 with no lookups
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 { 0.0 } else { self.hits as f64 / (self.hits + self.misses) as f64 }
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{\"hits\":{},\"misses\":{},\"hit_rate\":{:.4}}}", self.hits, self.misses, self.hit_rate())
+    }
+}
+
+impl LevelStats {
+    fn to_json(&self) -> String {
+        let key = |key: &Option<Vec<u8>>| key.as_ref().map_or("null".to_string(), |k| format!("\"{}\"", to_hex(k)));
+
+        format!("{{\"level\":{},\"table_count\":{},\"file_bytes\":{},\"record_count\":{},\"smallest_key\":{},\"largest_key\":{},\"bloom_bytes\":{}}}",
+                self.level, self.table_count, self.file_bytes, self.record_count, key(&self.smallest_key), key(&self.largest_key), self.bloom_bytes)
+    }
+}
+
+fn quota_json(usage: &QuotaUsage) -> String {
+    let max = |max: Option<u64>| max.map_or("null".to_string(), |m| m.to_string());
+
+    format!("{{\"prefix\":\"{}\",\"max_bytes\":{},\"max_keys\":{},\"bytes\":{},\"keys\":{}}}",
+            to_hex(&usage.quota.prefix), max(usage.quota.max_bytes), max(usage.quota.max_keys), usage.bytes, usage.keys)
+}
+
+impl StoreStats {
+    /// The stats as a JSON object, for scripts; keys are hex strings
+    pub fn to_json(&self) -> String {
+        let levels = self.levels.iter().map(|l| l.to_json()).collect::<Vec<_>>().join(",");
+        let quotas = self.quotas.iter().map(quota_json).collect::<Vec<_>>().join(",");
+        let hot_keys = self.hot_keys.iter().map(|&(ref k, c)| format!("{{\"key\":\"{}\",\"reads\":{}}}", to_hex(k), c)).collect::<Vec<_>>().join(",");
+
+        format!("{{\"mem_records\":{},\"immutables\":{},\"immutable_records\":{},\"wal_bytes\":{},\"levels\":[{}],\"open_tables\":{},\"table_cache\":{},\"record_cache\":{},\"readahead_bytes\":{},\"meta_cache\":{},\"meta_bytes\":{},\"pinned_bytes\":{},\"row_cache\":{},\"cached_rows\":{},\"compaction_pending\":{},\"pending_compaction_bytes\":{},\"expired_records\":{},\"quotas\":[{}],\"hot_keys\":[{}]}}",
+                self.mem_records, self.immutables, self.immutable_records, self.wal_bytes, levels, self.open_tables, self.table_cache.to_json(),
+                self.record_cache.to_json(), self.readahead_bytes, self.meta_cache.to_json(), self.meta_bytes, self.pinned_bytes, self.row_cache.to_json(), self.cached_rows, self.compaction_pending, self.pending_compaction_bytes, self.expired_records, quotas, hot_keys)
+    }
+}
+
+impl Health {
+    /// Writers are waiting for a flush
+    pub fn write_stalled(&self) -> bool {
+        self.immutables >= self.max_immutables
+    }
+
+    /// Writes aren't stalled, and the store isn't read-only
+    pub fn is_healthy(&self) -> bool {
+        !self.write_stalled() && self.background_error.is_none()
+    }
+
+    /// The health as a JSON object, for load balancers and monitoring
+    pub fn to_json(&self) -> String {
+        format!("{{\"healthy\":{},\"write_stalled\":{},\"immutables\":{},\"max_immutables\":{},\"wal_bytes\":{},\"disk_free_bytes\":{},\"background_error\":{}}}",
+                self.is_healthy(), self.write_stalled(), self.immutables, self.max_immutables, self.wal_bytes,
+                self.disk_free_bytes.map_or("null".to_string(), |b| b.to_string()), self.background_error.as_ref().map_or("null".to_string(), |e| json_string(e)))
+    }
+}
+
+/// Quotes and escapes a string for JSON
+pub(crate) fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+
+    ret.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c)
+        }
+    }
+
+    ret.push('"');
+    ret
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Shows a key as text, with the bytes that aren't printable ASCII escaped
+fn escape(key: &Option<Vec<u8>>) -> String {
+    match *key {
+        Some(ref key) => String::from_utf8(key.iter().flat_map(|&b| ::std::ascii::escape_default(b)).collect()).expect("Escaped bytes are ASCII"),
+        None => "-".to_string()
+    }
+}
+
+/// Prints the stats as a table, for people
+impl Display for StoreStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "mem_table: {} records, {} immutables with {} records, {} WAL bytes", self.mem_records, self.immutables, self.immutable_records, self.wal_bytes)?;
+        writeln!(f)?;
+        writeln!(f, "{:>5} {:>6} {:>12} {:>12} {:>10}  {}", "level", "tables", "bytes", "records", "bloom", "keys")?;
+
+        for level in self.levels.iter() {
+            writeln!(f, "{:>5} {:>6} {:>12} {:>12} {:>10}  {} .. {}", level.level, level.table_count, level.file_bytes,
+                     level.record_count, level.bloom_bytes, escape(&level.smallest_key), escape(&level.largest_key))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "table cache: {} open, {} hits, {} misses, {:.1}% hit rate", self.open_tables, self.table_cache.hits,
+                 self.table_cache.misses, self.table_cache.hit_rate() * 100.0)?;
+        writeln!(f, "record cache: {} hits, {} misses, {:.1}% hit rate, {} bytes read ahead", self.record_cache.hits, self.record_cache.misses,
+                 self.record_cache.hit_rate() * 100.0, self.readahead_bytes)?;
+        writeln!(f, "meta cache: {} bytes, {} pinned, {} hits, {} misses, {:.1}% hit rate", self.meta_bytes, self.pinned_bytes,
+                 self.meta_cache.hits, self.meta_cache.misses, self.meta_cache.hit_rate() * 100.0)?;
+        writeln!(f, "row cache: {} rows, {} hits, {} misses, {:.1}% hit rate", self.cached_rows, self.row_cache.hits,
+                 self.row_cache.misses, self.row_cache.hit_rate() * 100.0)?;
+
+        if self.compaction_pending {
+            writeln!(f, "compaction: pending, reading {} bytes", self.pending_compaction_bytes)?;
+        } else {
+            writeln!(f, "compaction: none pending")?;
+        }
+
+        write!(f, "expired records: {}", self.expired_records)?;
+
+        for usage in self.quotas.iter() {
+            let max = |max: Option<u64>| max.map_or("-".to_string(), |m| m.to_string());
+
+            write!(f, "\nquota {}: {} of {} keys, {} of {} bytes", escape(&Some(usage.quota.prefix.clone())), usage.keys,
+                   max(usage.quota.max_keys), usage.bytes, max(usage.quota.max_bytes))?;
+        }
+
+        for &(ref key, reads) in self.hot_keys.iter() {
+            write!(f, "\nhot key {}: about {} reads", escape(&Some(key.clone())), reads)?;
+        }
+
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stats::{StoreStats, LevelStats, CacheStats, Health};
+    use quota::{Quota, QuotaUsage};
+
+    #[test]
+    fn json() {
+        let stats = StoreStats {
+            mem_records: 3,
+            immutables: 0,
+            immutable_records: 0,
+            wal_bytes: 512,
+            levels: vec![
+                LevelStats { level: 0, table_count: 1, file_bytes: 100, record_count: 2, smallest_key: Some(b"A\x00".to_vec()), largest_key: Some(b"B".to_vec()), bloom_bytes: 9 },
+                LevelStats { level: 1, table_count: 0, file_bytes: 0, record_count: 0, smallest_key: None, largest_key: None, bloom_bytes: 0 }
+            ],
+            open_tables: 1,
+            table_cache: CacheStats { hits: 3, misses: 1 },
+            record_cache: CacheStats::default(),
+            readahead_bytes: 0,
+            meta_cache: CacheStats { hits: 1, misses: 1 },
+            meta_bytes: 200,
+            pinned_bytes: 100,
+            row_cache: CacheStats { hits: 1, misses: 3 },
+            cached_rows: 2,
+            compaction_pending: false,
+            pending_compaction_bytes: 0,
+            expired_records: 0,
+            quotas: vec![QuotaUsage { quota: Quota { prefix: b"a/".to_vec(), max_bytes: None, max_keys: Some(10) }, keys: 2, bytes: 30 }],
+            hot_keys: vec![(b"a/1".to_vec(), 42)]
+        };
+
+        assert_eq!(0.75, stats.table_cache.hit_rate());
+        assert_eq!(0.0, stats.record_cache.hit_rate());
+
+        assert_eq!("{\"mem_records\":3,\"immutables\":0,\"immutable_records\":0,\"wal_bytes\":512,\"levels\":[\
+                    {\"level\":0,\"table_count\":1,\"file_bytes\":100,\"record_count\":2,\"smallest_key\":\"4100\",\"largest_key\":\"42\",\"bloom_bytes\":9},\
+                    {\"level\":1,\"table_count\":0,\"file_bytes\":0,\"record_count\":0,\"smallest_key\":null,\"largest_key\":null,\"bloom_bytes\":0}],\
+                    \"open_tables\":1,\"table_cache\":{\"hits\":3,\"misses\":1,\"hit_rate\":0.7500},\
+                    \"record_cache\":{\"hits\":0,\"misses\":0,\"hit_rate\":0.0000},\"readahead_bytes\":0,\
+                    \"meta_cache\":{\"hits\":1,\"misses\":1,\"hit_rate\":0.5000},\"meta_bytes\":200,\"pinned_bytes\":100,\
+                    \"row_cache\":{\"hits\":1,\"misses\":3,\"hit_rate\":0.2500},\"cached_rows\":2,\
+                    \"compaction_pending\":false,\"pending_compaction_bytes\":0,\"expired_records\":0,\
+                    \"quotas\":[{\"prefix\":\"612f\",\"max_bytes\":null,\"max_keys\":10,\"bytes\":30,\"keys\":2}],\
+                    \"hot_keys\":[{\"key\":\"612f31\",\"reads\":42}]}", stats.to_json());
+
+        assert!(stats.to_string().starts_with("mem_table: 3 records, 0 immutables with 0 records, 512 WAL bytes\n"));
+        assert!(stats.to_string().contains("    0      1          100            2          9  A\\x00 .. B"));
+        assert!(stats.to_string().contains("\nmeta cache: 200 bytes, 100 pinned, 1 hits, 1 misses, 50.0% hit rate\n"));
+        assert!(stats.to_string().contains("\nrow cache: 2 rows, 1 hits, 3 misses, 25.0% hit rate\n"));
+        assert!(stats.to_string().contains("\nquota a/: 2 of 10 keys, 30 of - bytes\n"));
+        assert!(stats.to_string().ends_with("hot key a/1: about 42 reads"));
+    }
+
+    #[test]
+    fn health() {
+        let mut health = Health { immutables: 1, max_immutables: 2, wal_bytes: 4096, disk_free_bytes: None, background_error: None };
+
+        assert!(health.is_healthy());
+        assert_eq!("{\"healthy\":true,\"write_stalled\":false,\"immutables\":1,\"max_immutables\":2,\"wal_bytes\":4096,\"disk_free_bytes\":null,\"background_error\":null}", health.to_json());
+
+        health.immutables = 2;
+
+        assert!(!health.is_healthy());
+
+        health.immutables = 0;
+        health.background_error = Some("No \"space\" left".to_string());
+
+        assert!(!health.is_healthy());
+        assert!(health.to_json().ends_with(",\"background_error\":\"No \\\"space\\\" left\"}"), "{}", health.to_json());
+    }
+}
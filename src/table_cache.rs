@@ -0,0 +1,289 @@
+//
+// Keeps a bounded number of SSTables open, so a store with many tables doesn't run out of file handles
+//
+
+use lru_cache::LruCache;
+
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Error as IOError;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use cache::CacheOptions;
+use record_file::buf2string;
+use sstable::SSTable;
+
+/// What the store needs to know about an SSTable without keeping it open
+#[derive(Clone)]
+pub struct TableMeta {
+    file_path: PathBuf,
+    id: u64,
+    smallest_key: Vec<u8>,
+    largest_key: Vec<u8>,
+    record_count: u64,
+    tombstone_count: u64,
+    newest_ts: u64,
+    expiring_count: u64,
+    latest_expiry: u64,
+    earliest_expiry: u64,
+    bloom_bytes: u64,
+    value_logs: Vec<u64>
+}
+
+impl TableMeta {
+    pub fn new(sstable: &SSTable) -> TableMeta {
+        TableMeta {
+            file_path: sstable.file_path(),
+            id: sstable.id(),
+            smallest_key: sstable.smallest_key().to_vec(),
+            largest_key: sstable.largest_key().to_vec(),
+            record_count: sstable.record_count(),
+            tombstone_count: sstable.tombstone_count(),
+            newest_ts: sstable.newest_ts(),
+            expiring_count: sstable.expiring_count(),
+            latest_expiry: sstable.latest_expiry(),
+            earliest_expiry: sstable.earliest_expiry(),
+            bloom_bytes: sstable.bloom_bytes(),
+            value_logs: sstable.value_logs().to_vec()
+        }
+    }
+
+    /// Returns true if the key is in the range of the table
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.record_count != 0 && self.smallest_key.as_slice() <= key && key <= self.largest_key.as_slice()
+    }
+
+    pub fn file_path(&self) -> PathBuf { self.file_path.clone() }
+
+    pub fn id(&self) -> u64 { self.id }
+
+    pub fn smallest_key(&self) -> &[u8] { &self.smallest_key }
+
+    pub fn largest_key(&self) -> &[u8] { &self.largest_key }
+
+    pub fn record_count(&self) -> u64 { self.record_count }
+
+    pub fn tombstone_count(&self) -> u64 { self.tombstone_count }
+
+    pub fn newest_ts(&self) -> u64 { self.newest_ts }
+
+    pub fn bloom_bytes(&self) -> u64 { self.bloom_bytes }
+
+    /// The numbers of the value log files the records point into
+    pub fn value_logs(&self) -> &[u64] { &self.value_logs }
+
+    /// The number of records known to have expired by the time; none until all the records with a TTL have
+    pub fn expired_count(&self, ts: u64) -> u64 {
+        if self.expiring_count != 0 && self.latest_expiry <= ts { self.expiring_count } else { 0 }
+    }
+
+    /// An estimate of the records expired by the time, taking their expiries to be spread evenly from the earliest to the latest
+    pub fn expired_estimate(&self, ts: u64) -> u64 {
+        if !self.has_expired(ts) {
+            return 0;
+        }
+
+        if self.latest_expiry <= ts {
+            return self.expiring_count;
+        }
+
+        let elapsed = (ts - self.earliest_expiry + 1) as f64 / (self.latest_expiry - self.earliest_expiry + 1) as f64;
+
+        (self.expiring_count as f64 * elapsed) as u64
+    }
+
+    /// Returns true if some of the records may have expired by the time
+    pub fn has_expired(&self, ts: u64) -> bool {
+        self.expiring_count != 0 && self.earliest_expiry <= ts
+    }
+}
+
+impl PartialOrd for TableMeta {
+    fn partial_cmp(&self, other: &TableMeta) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TableMeta {
+    fn cmp(&self, other: &TableMeta) -> Ordering {
+        (&self.smallest_key, self.id).cmp(&(&other.smallest_key, other.id))
+    }
+}
+
+impl PartialEq for TableMeta {
+    fn eq(&self, other: &TableMeta) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for TableMeta { }
+
+impl Debug for TableMeta {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        formatter.debug_struct("TableMeta")
+            .field("file_path", &self.file_path)
+            .field("id", &self.id)
+            .field("smallest_key", &buf2string(&self.smallest_key))
+            .field("largest_key", &buf2string(&self.largest_key))
+            .field("record_count", &self.record_count)
+            .finish()
+    }
+}
+
+/// An LRU cache of open SSTables
+///
+/// Tables are opened on demand, and the least recently used table is closed when there are too many open.
+/// A table handed out stays open until it is dropped, even if it is evicted from the cache.
+pub struct TableCache {
+    tables: Mutex<LruCache<PathBuf, Arc<SSTable>>>,
+    buffer_size: usize,
+    cache: CacheOptions,
+    hits: AtomicU64,  // gets of a table that was open
+    misses: AtomicU64 // gets that opened the table
+}
+
+impl TableCache {
+    pub fn new(max_open_tables: usize, buffer_size: usize, cache: CacheOptions) -> TableCache {
+        TableCache {
+            tables: Mutex::new(LruCache::new(max_open_tables)),
+            buffer_size: buffer_size,
+            cache: cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// Returns the open SSTable, opening it if needed
+    pub fn get(&self, file_path: &PathBuf) -> Result<Arc<SSTable>, IOError> {
+        if let Some(sstable) = self.tables.lock().unwrap().get_mut(file_path) {
+            self.hits.fetch_add(1, AtomicOrdering::Relaxed);
+            return Ok(sstable.clone());
+        }
+
+        self.misses.fetch_add(1, AtomicOrdering::Relaxed);
+
+        debug!("Opening SSTable for cache: {:?}", file_path);
+
+        let sstable = Arc::new(SSTable::open(file_path, self.buffer_size, self.cache.clone())?);
+
+        self.tables.lock().unwrap().insert(file_path.clone(), sstable.clone());
+
+        Ok(sstable)
+    }
+
+    /// Adds a table that was just created, returning its metadata
+    pub fn insert(&self, sstable: SSTable) -> TableMeta {
+        let meta = TableMeta::new(&sstable);
+
+        self.tables.lock().unwrap().insert(sstable.file_path(), Arc::new(sstable));
+
+        meta
+    }
+
+    /// Closes the table, if it's open, so its file can be removed
+    pub fn evict(&self, file_path: &PathBuf) {
+        self.tables.lock().unwrap().remove(file_path);
+    }
+
+    /// The number of tables currently open
+    pub fn len(&self) -> usize {
+        self.tables.lock().unwrap().len()
+    }
+
+    /// The gets that found the table open, and that had to open it
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (self.hits.load(AtomicOrdering::Relaxed), self.misses.load(AtomicOrdering::Relaxed))
+    }
+
+    /// The tables currently open
+    pub fn open_tables(&self) -> Vec<Arc<SSTable>> {
+        self.tables.lock().unwrap().iter().map(|(_, sstable)| sstable.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use table_cache::{TableCache, TableMeta};
+    use cache::{CacheOptions, CachePolicyKind};
+    use codec::CodecKind;
+    use sstable::{SSTable, SSTableOptions, NewSSTable};
+    use record::Record;
+    use std::collections::BTreeSet;
+    use test_path::gen_dir;
+
+    const BUFFER_SIZE: usize = 4069;
+    const CACHE: CacheOptions = CacheOptions { size: 100, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
+
+    #[test]
+    fn max_open_tables() {
+        let db_dir = gen_dir();
+        let cache = TableCache::new(2, BUFFER_SIZE, CACHE);
+        let options = SSTableOptions { group_count: Some(10), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+        let mut metas = vec![];
+
+        for i in 0..5 {
+            let records = vec![Record::new(format!("KEY_{}", i).into_bytes(), Some(b"VALUE".to_vec()))];
+            let sstable = SSTable::new(NewSSTable::new(&db_dir.join(format!("table-{}.data", i)), i, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+            metas.push(cache.insert(sstable));
+
+            assert!(cache.len() <= 2);
+        }
+
+        for (i, meta) in metas.iter().enumerate() {
+            assert!(meta.contains_key(format!("KEY_{}", i).as_bytes()));
+
+            let sstable = cache.get(&meta.file_path()).unwrap();
+
+            assert!(sstable.get(format!("KEY_{}", i).into_bytes()).unwrap().is_some());
+            assert!(cache.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn same_smallest_key() {
+        let db_dir = gen_dir();
+        let cache = TableCache::new(2, BUFFER_SIZE, CACHE);
+        let options = SSTableOptions { group_count: Some(10), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+        let mut metas = BTreeSet::new();
+
+        // the newer table is written first, it must not replace or be replaced by the older one
+        for &(id, count) in [(7, 2), (3, 1)].iter() {
+            let records = (0..count).map(|i| Record::new(format!("KEY_{}", i).into_bytes(), Some(b"VALUE".to_vec()))).collect::<Vec<_>>();
+            let sstable = SSTable::new(NewSSTable::new(&db_dir.join(format!("table-{}.sst", id)), id, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+            metas.insert(cache.insert(sstable));
+        }
+
+        assert_eq!(vec![3, 7], metas.iter().map(|m| m.id()).collect::<Vec<_>>());
+
+        // the id is saved with the table
+        assert_eq!(7, TableMeta::new(&SSTable::open(&db_dir.join("table-7.sst"), BUFFER_SIZE, CACHE).unwrap()).id());
+    }
+
+    #[test]
+    fn expired_estimate() {
+        let db_dir = gen_dir();
+        let options = SSTableOptions { group_count: Some(10), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+        // half the records expire, at 1_000 through 1_099, and a delete
+        let mut records = (0..200).map(|i| {
+            let key = format!("KEY_{:03}", i).into_bytes();
+
+            if i % 2 == 0 { Record::new_with_ttl(key, Some(b"VALUE".to_vec()), 1_000 + i / 2) } else { Record::new(key, Some(b"VALUE".to_vec())) }
+        }).collect::<Vec<_>>();
+
+        records.push(Record::new(b"KEY_200".to_vec(), None));
+
+        let meta = TableMeta::new(&SSTable::new(NewSSTable::new(&db_dir.join("table-1.sst"), 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap());
+
+        assert_eq!(1, meta.tombstone_count());
+        assert_eq!(0, meta.expired_estimate(999));
+        assert_eq!(1, meta.expired_estimate(1_000));
+        assert_eq!(50, meta.expired_estimate(1_049));
+        assert_eq!(100, meta.expired_estimate(1_099));
+        assert_eq!(100, meta.expired_estimate(u64::max_value()));
+    }
+}
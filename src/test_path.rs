@@ -0,0 +1,77 @@
+//
+// Temporary files and directories for the tests
+// They're removed once the test that made them passes, and kept after a failure, to look at.
+//
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use simple_logger;
+use tempfile;
+use ::LOGGER_INIT;
+
+/// A temporary file or directory, removed when dropped unless the thread is panicking
+pub struct TestPath {
+    path: PathBuf,
+    is_dir: bool
+}
+
+impl Deref for TestPath {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl Debug for TestPath {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.path.fmt(f)
+    }
+}
+
+impl AsRef<Path> for TestPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("KEEPING TMP {}: {:?}", if self.is_dir { "DIR" } else { "FILE" }, self.path);
+            return;
+        }
+
+        let removed = if self.is_dir { fs::remove_dir_all(&self.path) } else { fs::remove_file(&self.path) };
+
+        if let Err(e) = removed {
+            warn!("Error removing {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Creates an empty directory
+pub fn gen_dir() -> TestPath {
+    LOGGER_INIT.call_once(|| simple_logger::init().unwrap()); // this will panic on error
+
+    let ret_dir = tempfile::Builder::new().prefix("kvs_").tempdir().unwrap().keep(); // removed by the TestPath
+
+    debug!("CREATING TMP DIR: {:?}", ret_dir);
+
+    TestPath { path: ret_dir, is_dir: true }
+}
+
+/// Creates an empty file
+pub fn gen_file() -> TestPath {
+    LOGGER_INIT.call_once(|| simple_logger::init().unwrap()); // this will panic on error
+
+    let ret_file = tempfile::Builder::new().prefix("rec_file_").suffix(".data").tempfile().unwrap().into_temp_path().keep().unwrap();
+
+    debug!("CREATING TMP FILE: {:?}", ret_file);
+
+    TestPath { path: ret_file, is_dir: false }
+}
@@ -0,0 +1,495 @@
+//
+// Utilities for damaging the files of a store, to test how damaged data is handled,
+// and for simulating crashes and the passing of time
+// Always built for the tests, and built for applications with the `testkit` feature.
+//
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{Error as IOError, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub use sim::CrashPoint;
+
+/// Flips one bit of the byte at the offset
+pub fn flip_bit(path: &Path, offset: u64, bit: u8) -> Result<(), IOError> {
+    let mut fd = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut byte = [0u8; 1];
+
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.read_exact(&mut byte)?;
+
+    byte[0] ^= 1 << (bit % 8);
+
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.write_all(&byte)
+}
+
+/// Overwrites the bytes starting at the offset, extending the file if needed
+pub fn overwrite(path: &Path, offset: u64, bytes: &[u8]) -> Result<(), IOError> {
+    let mut fd = OpenOptions::new().write(true).open(path)?;
+
+    fd.seek(SeekFrom::Start(offset))?;
+    fd.write_all(bytes)
+}
+
+/// Cuts the file off after len bytes, like writes lost in a crash
+pub fn truncate(path: &Path, len: u64) -> Result<(), IOError> {
+    OpenOptions::new().write(true).open(path)?.set_len(len)
+}
+
+/// The simulation and clock of a thread, shared with the background threads of its stores
+#[derive(Default)]
+struct SimState {
+    simulation: Option<Simulation>,
+    clock: Option<u64>
+}
+
+thread_local! {
+    static STATE: RefCell<Arc<Mutex<SimState>>> = RefCell::new(Arc::new(Mutex::new(SimState::default())));
+}
+
+fn with_state<F, T>(f: F) -> T where F: FnOnce(&mut SimState) -> T {
+    STATE.with(|s| f(&mut s.borrow().lock().unwrap()))
+}
+
+/// The simulation and clock of the thread that created a store, for its background thread
+#[doc(hidden)]
+pub struct Context(Arc<Mutex<SimState>>);
+
+#[doc(hidden)]
+pub fn context() -> Context {
+    Context(STATE.with(|s| s.borrow().clone()))
+}
+
+#[doc(hidden)]
+pub fn enter(context: Context) {
+    STATE.with(|s| *s.borrow_mut() = context.0);
+}
+
+/// Panic payload of a simulated crash
+struct SimulatedCrash;
+
+struct Simulation {
+    db_dir: PathBuf,
+    crash_at: Option<CrashPoint>,
+    sync_delay: usize,
+    pending: VecDeque<(PathBuf, Vec<u8>)>, // synced, but not on disk yet
+    durable: HashMap<PathBuf, Vec<u8>>,    // what's on disk as of the last sync
+    random: Option<u64>,                   // xorshift state for partial writes
+    crashed: bool                          // crashed at a crash point, so nothing more reaches the disk
+}
+
+impl Simulation {
+    fn tracks(&self, path: &Path) -> bool {
+        path.parent() == Some(self.db_dir.as_path())
+    }
+
+    fn next_random(&mut self) -> Option<u64> {
+        self.random.as_mut().map(|x| {
+            *x ^= *x << 13;
+            *x ^= *x >> 7;
+            *x ^= *x << 17;
+            *x
+        })
+    }
+}
+
+/// Simulates the disk under a store's directory, so crashes can be tested deterministically
+///
+/// Only what's synced survives a `crash`, and the store can be made to crash at any `CrashPoint`.
+/// The simulation covers the stores created on the current thread, and their background threads,
+/// until it is dropped.
+pub struct SimulatedStorage {
+    db_dir: PathBuf
+}
+
+impl SimulatedStorage {
+    /// Starts simulating; the files already in the directory are treated as being on disk
+    ///
+    /// # Panics
+    /// If there is already a simulation on this thread.
+    pub fn new(db_dir: &Path) -> Result<SimulatedStorage, IOError> {
+        let mut durable = HashMap::new();
+
+        for entry in fs::read_dir(db_dir)? {
+            let path = entry?.path();
+
+            if path.is_file() {
+                let contents = fs::read(&path)?;
+                durable.insert(path, contents);
+            }
+        }
+
+        if with_state(|s| s.simulation.is_some()) {
+            panic!("A simulation is already running on this thread");
+        }
+
+        with_state(|s| {
+            s.simulation = Some(Simulation {
+                db_dir: db_dir.to_path_buf(),
+                crash_at: None,
+                sync_delay: 0,
+                pending: VecDeque::new(),
+                durable: durable,
+                random: None,
+                crashed: false
+            });
+        });
+
+        Ok(SimulatedStorage { db_dir: db_dir.to_path_buf() })
+    }
+
+    /// Crash the next time the store gets to the point
+    pub fn crash_at(&self, point: CrashPoint) {
+        SimulatedStorage::with(|sim| sim.crash_at = Some(point));
+    }
+
+    /// Syncs only reach the disk after `count` more syncs, so the last `count` syncs are lost in a crash
+    pub fn delay_syncs(&self, count: usize) {
+        SimulatedStorage::with(|sim| sim.sync_delay = count);
+    }
+
+    /// In a crash, files keep a random part of what was appended since their last sync, instead of none of it
+    pub fn partial_writes(&self, seed: u64) {
+        SimulatedStorage::with(|sim| sim.random = Some(seed | 1));
+    }
+
+    /// Runs the function, returning true if it stopped at a simulated crash
+    ///
+    /// The store must be created in the function, so it's dropped by the crash. A crash in the store's
+    /// background thread stops the function at the next write or flush that waits on that thread.
+    /// Other panics are passed on.
+    pub fn run<F: FnOnce()>(&self, f: F) -> bool {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(()) => false,
+            Err(e) => if e.is::<SimulatedCrash>() { true } else { panic::resume_unwind(e) }
+        }
+    }
+
+    /// Puts the directory back to what's on disk
+    ///
+    /// Files are cut back to their last sync, and files that were never synced are removed.
+    pub fn crash(&self) -> Result<(), IOError> {
+        SimulatedStorage::with(|sim| {
+            debug!("Simulating a crash of: {:?}", sim.db_dir);
+
+            sim.pending.clear(); // never made it to disk
+            sim.crashed = false;
+
+            for entry in fs::read_dir(&sim.db_dir)? {
+                let path = entry?.path();
+
+                if !path.is_file() {
+                    continue;
+                }
+
+                let mut contents = match sim.durable.get(&path) {
+                    Some(contents) => contents.clone(),
+                    None => { fs::remove_file(&path)?; continue; }
+                };
+
+                let current = fs::read(&path)?;
+
+                if current.len() > contents.len() {
+                    let tail_len = (current.len() - contents.len()) as u64;
+                    let keep = sim.next_random().map_or(0, |r| r % (tail_len + 1)) as usize;
+                    let start = contents.len();
+
+                    contents.extend_from_slice(&current[start..start + keep]);
+                }
+
+                fs::write(&path, &contents)?;
+            }
+
+            // files removed after the crash point are still on disk
+            for (path, contents) in sim.durable.iter() {
+                if !path.exists() {
+                    fs::write(path, contents)?;
+                }
+            }
+
+            Ok( () )
+        })
+    }
+
+    fn with<F, T>(f: F) -> T where F: FnOnce(&mut Simulation) -> T {
+        with_state(|s| f(s.simulation.as_mut().expect("No simulation is running")))
+    }
+}
+
+impl Drop for SimulatedStorage {
+    fn drop(&mut self) {
+        debug!("Ending simulation of: {:?}", self.db_dir);
+
+        with_state(|s| s.simulation = None);
+    }
+}
+
+#[doc(hidden)]
+pub fn crash_point(point: CrashPoint) {
+    let crash = with_state(|s| match s.simulation {
+        Some(ref mut sim) if sim.crash_at == Some(point) => { sim.crash_at = None; sim.crashed = true; true },
+        _ => false
+    });
+
+    if crash {
+        debug!("Simulated crash at: {:?}", point);
+
+        // skips the panic hook, so there's no message
+        panic::resume_unwind(Box::new(SimulatedCrash));
+    }
+}
+
+#[doc(hidden)]
+pub fn on_sync(path: &Path) {
+    with_state(|s| {
+        if let Some(ref mut sim) = s.simulation {
+            if sim.crashed || !sim.tracks(path) {
+                return;
+            }
+
+            let contents = fs::read(path).expect("Error reading synced file");
+
+            sim.pending.push_back( (path.to_path_buf(), contents) );
+
+            while sim.pending.len() > sim.sync_delay {
+                let (path, contents) = sim.pending.pop_front().unwrap();
+                sim.durable.insert(path, contents);
+            }
+        }
+    });
+}
+
+#[doc(hidden)]
+pub fn on_rename(from: &Path, to: &Path) {
+    with_state(|s| {
+        if let Some(ref mut sim) = s.simulation {
+            if sim.crashed {
+                return;
+            }
+
+            // a rename of a file that was never synced leaves a file that isn't on disk either
+            let contents = sim.durable.remove(from);
+
+            sim.durable.remove(to);
+
+            if let Some(contents) = contents {
+                sim.durable.insert(to.to_path_buf(), contents);
+            }
+
+            for &mut (ref mut path, _) in sim.pending.iter_mut() {
+                if path == from {
+                    *path = to.to_path_buf();
+                }
+            }
+        }
+    });
+}
+
+#[doc(hidden)]
+pub fn on_remove(path: &Path) {
+    with_state(|s| {
+        if let Some(ref mut sim) = s.simulation {
+            if sim.crashed {
+                return;
+            }
+
+            sim.durable.remove(path);
+            sim.pending.retain(|&(ref p, _)| p != path);
+        }
+    });
+}
+
+#[doc(hidden)]
+pub fn virtual_time() -> Option<u64> {
+    with_state(|s| s.clock)
+}
+
+/// Sets the time, in ms since the epoch, given to records created on this thread, and by the stores it creates
+pub fn set_clock(ts: u64) {
+    with_state(|s| s.clock = Some(ts));
+}
+
+/// Moves the clock set with `set_clock` forward
+///
+/// # Panics
+/// If the clock isn't set.
+pub fn advance_clock(ms: u64) {
+    with_state(|s| s.clock = Some(s.clock.expect("The clock isn't set") + ms));
+}
+
+/// Goes back to the system clock
+pub fn clear_clock() {
+    with_state(|s| s.clock = None);
+}
+
+#[cfg(test)]
+mod tests {
+    use testkit::{flip_bit, overwrite, truncate, SimulatedStorage, CrashPoint, set_clock, advance_clock, clear_clock, crash_point};
+    use kvs::get_timestamp;
+    use record::Record;
+    use record_file::RecordFile;
+    use codec::CodecKind;
+    use sstable::{SSTable, SSTableOptions, NewSSTable};
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+    use cache::{CacheOptions, CachePolicyKind};
+    use std::fs::{self, metadata, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use test_path::{gen_dir, TestPath};
+
+    const BUFFER_SIZE: usize = 4096;
+    const CACHE_SIZE: usize = 100;
+    const CACHE: CacheOptions = CacheOptions { size: CACHE_SIZE, policy: CachePolicyKind::Lru, meta: None, pin_meta: false, value_log: None };
+    const HEADER: &[u8; 8] = b"TEST\x01\x00\x00\x00";
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("KEY_{:05}", i).into_bytes()
+    }
+
+    /// Creates an SSTable with a few partitions, returning its directory and path
+    fn gen_sstable() -> (TestPath, PathBuf) {
+        let dir = gen_dir();
+        let path = dir.join("test.sst");
+        let records = (0..300).map(|i| Record::new(key(i), Some(format!("VALUE_{}", i).into_bytes()))).collect::<Vec<_>>();
+        let options = SSTableOptions { group_count: Some(2), target_block_bytes: 0, group_by_size: false, dict_size: 0, codec: CodecKind::MsgPack, alignment: 0, preallocate: 0 };
+
+        SSTable::new(NewSSTable::new(&path, 1, &options, BUFFER_SIZE, CACHE), &mut records.iter()).unwrap();
+
+        (dir, path)
+    }
+
+    /// Opens the SSTable and looks up every key, which must return errors, not panic
+    fn open_and_get(path: &PathBuf) {
+        if let Ok(sstable) = SSTable::open(path, BUFFER_SIZE, CACHE) {
+            for i in 0..310 {
+                let _ = sstable.get(key(i));
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_crash() {
+        let db_dir = gen_dir();
+        let storage = SimulatedStorage::new(&db_dir).unwrap();
+
+        {
+            let mut synced = RecordFile::new(&db_dir.join("synced.data"), HEADER, BUFFER_SIZE, CACHE_SIZE).unwrap();
+            let mut unsynced = RecordFile::new(&db_dir.join("unsynced.data"), HEADER, BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            synced.append(b"SYNCED").unwrap();
+            synced.sync().unwrap();
+            synced.append(b"LOST").unwrap(); // written out when dropped, but not synced
+            unsynced.append(b"LOST").unwrap();
+        }
+
+        fs::rename(db_dir.join("synced.data"), db_dir.join("renamed.data")).unwrap();
+        ::sim::on_rename(&db_dir.join("synced.data"), &db_dir.join("renamed.data"));
+
+        assert!(!storage.run(|| crash_point(CrashPoint::ManifestSaved))); // not armed
+
+        storage.crash_at(CrashPoint::ManifestSaved);
+
+        assert!(storage.run(|| crash_point(CrashPoint::ManifestSaved)));
+
+        storage.crash().unwrap();
+
+        assert!(!db_dir.join("unsynced.data").exists());
+
+        let rec_file = RecordFile::new(&db_dir.join("renamed.data"), HEADER, BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+        assert_eq!(1, rec_file.record_count());
+        assert_eq!(b"SYNCED".to_vec(), rec_file.last_record().unwrap());
+    }
+
+    #[test]
+    fn delayed_syncs() {
+        let db_dir = gen_dir();
+        let storage = SimulatedStorage::new(&db_dir).unwrap();
+        let path = db_dir.join("test.data");
+
+        storage.delay_syncs(1);
+
+        {
+            let mut rec_file = RecordFile::new(&path, HEADER, BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+            rec_file.append(b"FIRST").unwrap();
+            rec_file.sync().unwrap();
+            rec_file.append(b"SECOND").unwrap();
+            rec_file.sync().unwrap(); // still pending at the crash
+        }
+
+        storage.crash().unwrap();
+
+        let rec_file = RecordFile::new(&path, HEADER, BUFFER_SIZE, CACHE_SIZE).unwrap();
+
+        assert_eq!(b"FIRST".to_vec(), rec_file.last_record().unwrap());
+    }
+
+    #[test]
+    fn virtual_clock() {
+        set_clock(1_000);
+
+        let rec = Record::new_with_ttl(b"KEY".to_vec(), Some(b"VALUE".to_vec()), 1_500);
+
+        assert_eq!(1_000, rec.created());
+        assert!(!rec.is_expired(get_timestamp()));
+
+        advance_clock(500);
+
+        assert!(rec.is_expired(get_timestamp()));
+
+        clear_clock();
+
+        assert!(get_timestamp() > 1_500);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn record_garbage(bytes in vec(any::<u8>(), 0..256)) {
+            let _ = Record::try_deserialize(&bytes);
+        }
+
+        #[test]
+        fn record_file_garbage(bytes in vec(any::<u8>(), 0..256), offsets in vec(any::<u16>(), 1..10)) {
+            let dir = gen_dir();
+            let path = dir.join("test.data");
+
+            File::create(&path).unwrap().write_all(&bytes).unwrap();
+            overwrite(&path, 0, HEADER).unwrap(); // so the garbage gets past the header check
+
+            if let Ok(rec_file) = RecordFile::new(&path, HEADER, BUFFER_SIZE, CACHE_SIZE) {
+                let _ = rec_file.last_record();
+
+                for offset in offsets {
+                    let _ = rec_file.read_at(offset as u64);
+                }
+            }
+        }
+
+        #[test]
+        fn sstable_bit_flip(position in 0.0..1.0f64, bit in 0u8..8) {
+            let (_dir, path) = gen_sstable();
+            let offset = (metadata(&path).unwrap().len() as f64 * position) as u64;
+
+            flip_bit(&path, offset, bit).unwrap();
+            open_and_get(&path);
+        }
+
+        #[test]
+        fn sstable_truncate(position in 0.0..1.0f64) {
+            let (_dir, path) = gen_sstable();
+            let len = (metadata(&path).unwrap().len() as f64 * position) as u64;
+
+            truncate(&path, len).unwrap();
+            open_and_get(&path);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//
+// Time series on top of the store: values keyed by a series and a timestamp
+// Keys are the length of the series name, the name, then the timestamp, all big-endian, so the points
+// of a series are next to each other in timestamp order, and a query is a single range scan.
+// Old points can be rolled up into buckets by compactions, with `Rollup`. A rolled up bucket is keyed by
+// the key of a point at its start with a tag byte after it, so it sorts after that point, and a point
+// written to the start of the bucket later doesn't replace it.
+//
+
+use byteorder::{ByteOrder, BE};
+
+use compaction_hook::CompactionHook;
+use kvs::{Iter, KVS};
+use {U32_SIZE, U64_SIZE};
+
+const ROLLUP_TAG: u8 = 0x00; // after the key of the point at the start of a rolled up bucket
+
+/// Encodes the key of a point, which sorts by series, then timestamp
+pub fn encode_key(series: &[u8], ts: u64) -> Vec<u8> {
+    let mut key = vec![0; U32_SIZE + series.len() + U64_SIZE];
+
+    BE::write_u32(&mut key[..U32_SIZE], series.len() as u32);
+    key[U32_SIZE..U32_SIZE + series.len()].copy_from_slice(series);
+    BE::write_u64(&mut key[U32_SIZE + series.len()..], ts);
+
+    key
+}
+
+/// The key of a bucket rolled up by `Rollup`, which sorts right after the point at the start of the bucket
+fn encode_rollup_key(series: &[u8], bucket_start: u64) -> Vec<u8> {
+    let mut key = encode_key(series, bucket_start);
+
+    key.push(ROLLUP_TAG);
+
+    key
+}
+
+fn is_rollup_key(key: &[u8]) -> bool {
+    key.len() >= U32_SIZE && key.len() == U32_SIZE + BE::read_u32(&key[..U32_SIZE]) as usize + U64_SIZE + 1 && key[key.len() - 1] == ROLLUP_TAG
+}
+
+/// Decodes the series and timestamp of a key, if it was made by `encode_key`, or is a rolled up bucket
+pub fn decode_key(key: &[u8]) -> Option<(Vec<u8>, u64)> {
+    if key.len() < U32_SIZE + U64_SIZE {
+        return None;
+    }
+
+    let series_len = BE::read_u32(&key[..U32_SIZE]) as usize;
+    let key = if is_rollup_key(key) { &key[..key.len() - 1] } else { key };
+
+    if key.len() != U32_SIZE + series_len + U64_SIZE {
+        return None;
+    }
+
+    Some( (key[U32_SIZE..U32_SIZE + series_len].to_vec(), BE::read_u64(&key[U32_SIZE + series_len..])) )
+}
+
+/// Appends and queries the points of time series kept in a `KVS`
+///
+/// The keys of the points can be mixed with other keys in the store, but keys made some other way
+/// could look like points, so it's best to keep time series in a store of their own.
+pub struct TimeSeries<'a> {
+    kvs: &'a KVS
+}
+
+impl<'a> TimeSeries<'a> {
+    pub fn new(kvs: &'a KVS) -> TimeSeries<'a> {
+        TimeSeries { kvs: kvs }
+    }
+
+    /// Adds a point to the series, replacing any point with the same timestamp
+    pub fn append(&self, series: &[u8], ts: u64, value: Vec<u8>) {
+        self.kvs.put(encode_key(series, ts), value);
+    }
+
+    /// Returns the points of the series with timestamps in [t0, t1), in timestamp order
+    pub fn query(&self, series: &[u8], t0: u64, t1: u64) -> Points {
+        Points { records: self.kvs.range(&encode_key(series, t0), &encode_key(series, t1)) }
+    }
+}
+
+/// An iterator over the (timestamp, value) points of a series
+pub struct Points {
+    records: Iter
+}
+
+impl Iterator for Points {
+    type Item = (u64, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next().map(|(key, value)| (decode_key(&key).map_or(0, |(_, ts)| ts), value))
+    }
+}
+
+/// Encodes a numeric value, the kind `Rollup` can aggregate
+pub fn encode_value(value: f64) -> Vec<u8> {
+    let mut buff = vec![0; U64_SIZE];
+
+    BE::write_f64(&mut buff, value);
+
+    buff
+}
+
+/// The points of a bucket rolled up by `Rollup`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregate {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u64
+}
+
+impl Aggregate {
+    /// Decodes the value of a point, either written with `encode_value` or rolled up
+    pub fn decode(value: &[u8]) -> Option<Aggregate> {
+        match value.len() {
+            8 => {
+                let v = BE::read_f64(value);
+
+                Some(Aggregate { min: v, max: v, sum: v, count: 1 })
+            },
+            32 => Some(Aggregate { min: BE::read_f64(&value[..8]), max: BE::read_f64(&value[8..16]), sum: BE::read_f64(&value[16..24]), count: BE::read_u64(&value[24..]) }),
+            _ => None
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = vec![0; 4 * U64_SIZE];
+
+        BE::write_f64(&mut buff[..8], self.min);
+        BE::write_f64(&mut buff[8..16], self.max);
+        BE::write_f64(&mut buff[16..24], self.sum);
+        BE::write_u64(&mut buff[24..], self.count);
+
+        buff
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    fn merge(&self, other: &Aggregate) -> Aggregate {
+        Aggregate { min: self.min.min(other.min), max: self.max.max(other.max), sum: self.sum + other.sum, count: self.count + other.count }
+    }
+}
+
+/// A compaction hook that rolls the points of a series older than an age into fixed size buckets
+///
+/// Each bucket becomes one point, at the start of the bucket, with an `Aggregate` value. Points written
+/// to a bucket after it's rolled up, even at its start, are merged into it by a later compaction. Only whole buckets older
+/// than the age are rolled up, and only the points with values from `encode_value`, or already rolled up.
+/// The timestamps of the points must be in ms since the epoch, like the store's clock.
+pub struct Rollup {
+    age: u64,   // ms
+    bucket: u64 // ms
+}
+
+impl Rollup {
+    pub fn new(age_ms: u64, bucket_ms: u64) -> Rollup {
+        assert_ne!(bucket_ms, 0);
+
+        Rollup { age: age_ms, bucket: bucket_ms }
+    }
+}
+
+impl CompactionHook for Rollup {
+    fn run_of(&self, key: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (series, ts) = decode_key(key)?;
+        let bucket_start = ts - ts % self.bucket;
+
+        if bucket_start.saturating_add(self.bucket) <= now.saturating_sub(self.age) {
+            Some(encode_key(&series, bucket_start))
+        } else {
+            None
+        }
+    }
+
+    fn rewrite(&self, run: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        // a bucket rolled up before, and nothing new
+        if run.len() == 1 && is_rollup_key(&run[0].0) {
+            return None;
+        }
+
+        let mut aggregate = Aggregate::decode(&run[0].1)?;
+
+        for &(_, ref value) in run[1..].iter() {
+            aggregate = aggregate.merge(&Aggregate::decode(value)?);
+        }
+
+        let (series, ts) = decode_key(&run[0].0)?;
+
+        Some(vec![ (encode_rollup_key(&series, ts - ts % self.bucket), aggregate.encode()) ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvs::KVSOptions;
+    use compaction_hook::CompactionHook;
+    use timeseries::{encode_key, encode_rollup_key, decode_key, encode_value, Aggregate, Rollup, TimeSeries};
+    use testkit::{set_clock, clear_clock};
+    use std::sync::Arc;
+    use test_path::gen_dir;
+
+    #[test]
+    fn encode_decode() {
+        assert_eq!(Some( (b"cpu".to_vec(), 1234) ), decode_key(&encode_key(b"cpu", 1234)));
+        assert_eq!(None, decode_key(b"cpu"));
+
+        // a longer series with the same start doesn't sort inside the shorter one
+        assert!(encode_key(b"cpu", u64::max_value()) < encode_key(b"cpu0", 0));
+        assert!(encode_key(b"cpu", 255) < encode_key(b"cpu", 256));
+    }
+
+    #[test]
+    fn append_query() {
+        let db_dir = gen_dir();
+        let kvs = KVSOptions::new(&db_dir).create().unwrap();
+        let series = TimeSeries::new(&kvs);
+
+        for ts in (0..1000).rev() {
+            series.append(b"cpu", ts * 10, format!("{}", ts).into_bytes());
+            series.append(b"mem", ts * 10, vec![]);
+        }
+
+        let points = series.query(b"cpu", 100, 200).collect::<Vec<_>>();
+
+        assert_eq!((100..200).step_by(10).collect::<Vec<_>>(), points.iter().map(|p| p.0).collect::<Vec<_>>());
+        assert_eq!(b"10".to_vec(), points[0].1);
+        assert_eq!(1000, series.query(b"cpu", 0, u64::max_value()).count());
+        assert_eq!(0, series.query(b"disk", 0, u64::max_value()).count());
+    }
+
+    #[test]
+    fn rollup() {
+        let db_dir = gen_dir();
+        let mut options = KVSOptions::new(&db_dir);
+        options.mem_count(100).file_count(6).compaction_hook(Arc::new(Rollup::new(100_000, 1_000)));
+
+        set_clock(1_000_000);
+
+        let kvs = options.create().unwrap();
+        let series = TimeSeries::new(&kvs);
+
+        // 6 buckets of 100 points; the compaction after the 6th flush rolls them up
+        for i in 0..600 {
+            series.append(b"cpu", i * 10, encode_value(i as f64));
+        }
+
+        kvs.wait_for_flushes();
+
+        let points = series.query(b"cpu", 0, u64::max_value()).collect::<Vec<_>>();
+
+        assert_eq!(vec![0, 1_000, 2_000, 3_000, 4_000, 5_000], points.iter().map(|p| p.0).collect::<Vec<_>>());
+        assert_eq!(Some(Aggregate { min: 100.0, max: 199.0, sum: 14_950.0, count: 100 }), Aggregate::decode(&points[1].1));
+
+        // a point written to a rolled up bucket is merged into it
+        let rollup = Rollup::new(100_000, 1_000);
+        let late = (encode_key(b"cpu", 1_500), encode_value(1_000.0));
+
+        assert!(rollup.rewrite(&[(encode_rollup_key(b"cpu", 1_000), points[1].1.clone())]).is_none());
+
+        let merged = rollup.rewrite(&[(encode_rollup_key(b"cpu", 1_000), points[1].1.clone()), late]).unwrap();
+
+        assert_eq!(encode_rollup_key(b"cpu", 1_000), merged[0].0);
+        assert_eq!(Some(Aggregate { min: 100.0, max: 1_000.0, sum: 15_950.0, count: 101 }), Aggregate::decode(&merged[0].1));
+
+        // a point written later at the start of a rolled up bucket doesn't replace it, and is merged into it
+        series.append(b"cpu", 2_000, encode_value(1_000.0));
+
+        let points = series.query(b"cpu", 2_000, 3_000).collect::<Vec<_>>();
+
+        assert_eq!(vec![2_000, 2_000], points.iter().map(|p| p.0).collect::<Vec<_>>());
+        assert_eq!(Some(Aggregate { min: 200.0, max: 299.0, sum: 24_950.0, count: 100 }), Aggregate::decode(&points[1].1));
+
+        let merged = rollup.rewrite(&[(encode_key(b"cpu", 2_000), points[0].1.clone()), (encode_rollup_key(b"cpu", 2_000), points[1].1.clone())]).unwrap();
+
+        assert_eq!(vec![(encode_rollup_key(b"cpu", 2_000), Aggregate { min: 200.0, max: 1_000.0, sum: 25_950.0, count: 101 }.encode())], merged);
+        assert_eq!(Some( (b"cpu".to_vec(), 2_000) ), decode_key(&merged[0].0));
+
+        // recent points aren't rolled up
+        assert!(rollup.run_of(&encode_key(b"cpu", 899_999), 1_000_000).is_some());
+        assert!(rollup.run_of(&encode_key(b"cpu", 900_000), 1_000_000).is_none());
+
+        clear_clock();
+    }
+}
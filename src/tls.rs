@@ -0,0 +1,106 @@
+//
+// TLS for the HTTP server, with rustls, built with the `tls` feature
+// The certificates and keys are read from PEM files. With a client CA, clients must present a certificate
+// the CA signed, otherwise any client can connect.
+//
+
+use std::io::{Error as IOError, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+
+/// The certificate and key a server presents, and the CA its clients' certificates must be signed by
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    config: Arc<ServerConfig>
+}
+
+fn invalid<E: ::std::fmt::Display>(path: &PathBuf, e: E) -> IOError {
+    IOError::new(ErrorKind::InvalidData, format!("Error reading {}: {}", path.display(), e))
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, IOError> {
+    let certs = CertificateDer::pem_file_iter(path).map_err(|e| invalid(path, e))?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| invalid(path, e))?;
+
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates"));
+    }
+
+    Ok(certs)
+}
+
+impl TlsConfig {
+    /// Reads the server's certificate chain and private key, and the CA for client certificates, if any, from PEM files
+    pub fn from_pem(cert: &PathBuf, key: &PathBuf, client_ca: Option<&PathBuf>) -> Result<TlsConfig, IOError> {
+        let provider = Arc::new(ring::default_provider());
+        let certs = read_certs(cert)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, e))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions().map_err(|e| IOError::new(ErrorKind::Other, e.to_string()))?;
+
+        let builder = match client_ca {
+            None => builder.with_no_client_auth(),
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(|e| invalid(path, e))?;
+                }
+
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build().map_err(|e| invalid(path, e))?;
+
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+
+        let config = builder.with_single_cert(certs, key).map_err(|e| IOError::new(ErrorKind::InvalidInput, format!("Error with {}: {}", cert.display(), e)))?;
+
+        Ok(TlsConfig { config: Arc::new(config) })
+    }
+
+    /// Starts a TLS session on an accepted connection; the handshake happens on the first read
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<TlsStream, IOError> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(|e| IOError::new(ErrorKind::Other, e.to_string()))?;
+
+        Ok(TlsStream(StreamOwned::new(conn, stream)))
+    }
+}
+
+/// A TLS session over a connection, which is closed cleanly when dropped
+pub(crate) struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // without a close_notify, the client can't tell the response wasn't truncated
+        self.0.conn.send_close_notify();
+
+        if let Err(e) = self.0.flush() {
+            debug!("Error closing a TLS session: {}", e);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//
+// The TTLs given to the puts that don't set their own, for the whole store or the keys under a prefix
+// See `KVSOptions::default_ttl` and `KVSOptions::prefix_ttl`, for keyspaces like sessions and caches.
+//
+
+/// The TTL of the puts of keys starting with a prefix, that don't set their own; 0 is none
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixTtl {
+    pub prefix: Vec<u8>,
+    pub ttl_ms: u64
+}
+
+/// The TTL, in ms, of a put of the key that doesn't set its own: that of the longest prefix it has, or the default
+pub fn ttl_for(key: &[u8], default_ms: u64, prefixes: &[PrefixTtl]) -> u64 {
+    prefixes.iter()
+        .filter(|p| key.starts_with(&p.prefix))
+        .max_by_key(|p| p.prefix.len())
+        .map_or(default_ms, |p| p.ttl_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use ttl::{ttl_for, PrefixTtl};
+
+    #[test]
+    fn longest_prefix() {
+        let prefixes = vec![
+            PrefixTtl { prefix: b"cache/".to_vec(), ttl_ms: 1_000 },
+            PrefixTtl { prefix: b"cache/pinned/".to_vec(), ttl_ms: 0 },
+            PrefixTtl { prefix: b"session/".to_vec(), ttl_ms: 60_000 }
+        ];
+
+        assert_eq!(1_000, ttl_for(b"cache/a", 5, &prefixes));
+        assert_eq!(0, ttl_for(b"cache/pinned/a", 5, &prefixes));
+        assert_eq!(60_000, ttl_for(b"session/a", 5, &prefixes));
+        assert_eq!(5, ttl_for(b"users/a", 5, &prefixes));
+        assert_eq!(0, ttl_for(b"users/a", 0, &[]));
+    }
+}
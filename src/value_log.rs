@@ -0,0 +1,194 @@
+//
+// Large values can be kept apart from their keys, WiscKey-style, see `KVSOptions::value_log_threshold`
+// A value at or over the threshold is appended to the value log when its mem_table is flushed, and
+// the SSTables only hold a pointer to it, so compactions copy the pointer instead of the value.
+// The log is a set of numbered files, the newest of which is appended to. Records are never removed
+// from a file; the live ones are moved to the newest file, and the old file is dropped, by `KVS::collect_value_log`.
+//
+
+use byteorder::{ByteOrder, LE};
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error as IOError, ErrorKind};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use record::Record;
+use record_file::{RecordFile, buf2string};
+use sim;
+
+use U64_SIZE;
+
+const VALUE_LOG_HEADER: &[u8; 8] = b"VLOG\x01\x00\x00\x00";
+
+// the values are large, and each is read once per lookup, so few are cached
+const CACHE_SIZE: usize = 16;
+
+/// Where a value is in the value log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub file_number: u64,
+    pub offset: u64
+}
+
+impl ValuePointer {
+    /// The bytes stored as the value of a record in place of the value
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buff = vec![0; U64_SIZE * 2];
+
+        LE::write_u64(&mut buff[..U64_SIZE], self.file_number);
+        LE::write_u64(&mut buff[U64_SIZE..], self.offset);
+
+        buff
+    }
+
+    pub fn decode(buff: &[u8]) -> Result<ValuePointer, IOError> {
+        if buff.len() != U64_SIZE * 2 {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("Bad value pointer: {}", buf2string(buff))));
+        }
+
+        Ok(ValuePointer { file_number: LE::read_u64(&buff[..U64_SIZE]), offset: LE::read_u64(&buff[U64_SIZE..]) })
+    }
+}
+
+/// The files of the value log, by number
+///
+/// Appends go to the file with the highest number. They aren't synced until `sync` is called,
+/// which must be before the SSTables pointing at them are in the manifest.
+pub struct ValueLog {
+    files: RwLock<BTreeMap<u64, RecordFile>>,
+    buffer_size: usize
+}
+
+impl ValueLog {
+    pub fn new(buffer_size: usize) -> ValueLog {
+        ValueLog { files: RwLock::new(BTreeMap::new()), buffer_size: buffer_size }
+    }
+
+    /// Opens a file of the log, creating it if it doesn't exist; a file with a higher number than the rest is appended to
+    pub fn open_file(&self, number: u64, file_path: &PathBuf) -> Result<(), IOError> {
+        let mut rec_file = RecordFile::new(file_path, VALUE_LOG_HEADER, self.buffer_size, CACHE_SIZE)?;
+
+        rec_file.sync()?; // so the header of a new file is on disk
+
+        self.files.write().unwrap().insert(number, rec_file);
+
+        Ok( () )
+    }
+
+    /// Closes the files that aren't in the manifest, or still read by the pinned SSTables
+    pub fn retain(&self, numbers: &[u64], pinned: &HashSet<PathBuf>) {
+        self.files.write().unwrap().retain(|number, rec_file| numbers.contains(number) || pinned.contains(&rec_file.file_path()));
+    }
+
+    /// The numbers of the open files, oldest first
+    pub fn numbers(&self) -> Vec<u64> {
+        self.files.read().unwrap().keys().cloned().collect()
+    }
+
+    /// The path to the file with the number, if it's open
+    pub fn file_path(&self, number: u64) -> Option<PathBuf> {
+        self.files.read().unwrap().get(&number).map(|rec_file| rec_file.file_path())
+    }
+
+    /// The size of the file being appended to
+    pub fn head_len(&self) -> Result<u64, IOError> {
+        match self.files.read().unwrap().values().next_back() {
+            Some(rec_file) => rec_file.file_len(),
+            None => Ok(0)
+        }
+    }
+
+    /// Appends the record to the newest file, returning where it is
+    pub fn append(&self, rec: &Record) -> Result<ValuePointer, IOError> {
+        let mut files = self.files.write().unwrap();
+        let (&number, rec_file) = files.iter_mut().next_back().ok_or_else(|| IOError::new(ErrorKind::NotFound, "The value log has no files"))?;
+        let offset = rec_file.append_record(rec)?;
+
+        Ok(ValuePointer { file_number: number, offset: offset })
+    }
+
+    /// Waits for the records appended to reach the disk
+    pub fn sync(&self) -> Result<(), IOError> {
+        let (fd, path) = match self.files.write().unwrap().values_mut().next_back() {
+            Some(rec_file) => (rec_file.sync_handle()?, rec_file.file_path()),
+            None => return Ok( () )
+        };
+
+        // sync without the files locked, so the values can be read meanwhile
+        fd.sync_data()?;
+        sim::on_sync(&path);
+
+        Ok( () )
+    }
+
+    /// Reads the value of the key at the pointer
+    pub fn read(&self, key: &[u8], pointer: &ValuePointer) -> Result<Vec<u8>, IOError> {
+        let files = self.files.read().unwrap();
+        let rec_file = files.get(&pointer.file_number).ok_or_else(|| IOError::new(ErrorKind::NotFound, format!("Value log {} is not open", pointer.file_number)))?;
+        let rec = Record::try_deserialize(&rec_file.read_at(pointer.offset)?)?;
+
+        if rec.key() != key || rec.is_delete() {
+            return Err(IOError::new(ErrorKind::InvalidData, format!("The value log has no value of {} at {:?}", buf2string(key), pointer)));
+        }
+
+        Ok(rec.into_parts().1.unwrap())
+    }
+
+    /// Reads the value a record points to in place of the pointer; other records are left as they are
+    pub fn resolve(&self, mut rec: Record) -> Result<Record, IOError> {
+        if rec.is_value_pointer() {
+            let value = self.read(rec.key(), &ValuePointer::decode(rec.value())?)?;
+
+            rec.set_value(value);
+        }
+
+        Ok(rec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use value_log::{ValueLog, ValuePointer};
+    use record::Record;
+    use std::collections::HashSet;
+    use test_path::gen_dir;
+
+    #[test]
+    fn append_resolve() {
+        let db_dir = gen_dir();
+        let value_log = ValueLog::new(4096);
+
+        value_log.open_file(1, &db_dir.join("000001.vlog")).unwrap();
+
+        let rec = Record::new(b"KEY".to_vec(), Some(vec![7; 1000]));
+        let pointer = value_log.append(&rec).unwrap();
+
+        assert_eq!(pointer, ValuePointer::decode(&pointer.encode()).unwrap());
+        assert_eq!(1, pointer.file_number);
+
+        value_log.sync().unwrap();
+
+        // appends go to the newest file
+        value_log.open_file(2, &db_dir.join("000002.vlog")).unwrap();
+
+        assert_eq!(2, value_log.append(&rec).unwrap().file_number);
+
+        let mut ptr_rec = rec.clone();
+
+        ptr_rec.set_value_pointer(pointer.encode());
+
+        assert_eq!(vec![7; 1000], value_log.resolve(ptr_rec).unwrap().value());
+        assert!(value_log.read(b"OTHER", &pointer).is_err());
+
+        // a closed file can't be read, until it's opened again
+        value_log.retain(&[2], &HashSet::new());
+
+        assert_eq!(vec![2], value_log.numbers());
+        assert!(value_log.read(b"KEY", &pointer).is_err());
+
+        value_log.open_file(1, &db_dir.join("000001.vlog")).unwrap();
+
+        assert_eq!(vec![7; 1000], value_log.read(b"KEY", &pointer).unwrap());
+    }
+}
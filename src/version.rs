@@ -0,0 +1,56 @@
+//
+// A version is the set of SSTables that made up the store at a point in time
+// Iterators hold a reference to the version they started with, and the files of a version
+// are not removed while any references to it remain.
+//
+
+use std::collections::HashSet;
+use std::iter;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+
+use sstable::SSTable;
+
+pub struct Version {
+    tables: Vec<Arc<SSTable>>
+}
+
+impl Version {
+    pub fn new(tables: Vec<Arc<SSTable>>) -> Version {
+        Version { tables }
+    }
+
+    pub fn tables(&self) -> &[Arc<SSTable>] {
+        &self.tables
+    }
+}
+
+/// Tracks the versions handed out, without keeping them alive
+pub struct VersionSet {
+    versions: Vec<Weak<Version>>
+}
+
+impl VersionSet {
+    pub fn new() -> VersionSet {
+        VersionSet { versions: vec![] }
+    }
+
+    /// Creates a new version, and tracks it until all references to it are dropped
+    pub fn add(&mut self, tables: Vec<Arc<SSTable>>) -> Arc<Version> {
+        let version = Arc::new(Version::new(tables));
+
+        self.versions.push(Arc::downgrade(&version));
+
+        version
+    }
+
+    /// Returns the files of all the versions that are still referenced, with the value logs their tables point into
+    pub fn pinned_files(&mut self) -> HashSet<PathBuf> {
+        self.versions.retain(|v| v.upgrade().is_some());
+
+        self.versions.iter()
+            .filter_map(|v| v.upgrade())
+            .flat_map(|v| v.tables().iter().flat_map(|t| iter::once(t.file_path()).chain(t.value_log_paths())).collect::<Vec<_>>())
+            .collect()
+    }
+}
@@ -0,0 +1,100 @@
+//
+// Flushed WALs can be kept in an archive directory, see `KVSOptions::wal_archive_dir`
+// An archived segment is named by the sequence numbers of its first record, and the one after its last,
+// so the segments holding a range of changes are found from their names alone.
+//
+
+use regex::Regex;
+
+use std::fs::{self, File};
+use std::io::Error as IOError;
+use std::path::PathBuf;
+
+use manifest::sync_dir;
+
+/// The name of the archived segment holding the changes [first_seq, end_seq)
+pub fn segment_name(first_seq: u64, end_seq: u64) -> String {
+    format!("{:020}-{:020}.wal", first_seq, end_seq)
+}
+
+/// The sequence numbers in the name of an archived segment, if it's one
+pub fn parse_segment_name(name: &str) -> Option<(u64, u64)> {
+    let re = Regex::new(r"^(\d{20})-(\d{20})\.wal$").unwrap();
+    let capture = re.captures(name)?;
+
+    Some( (capture[1].parse().ok()?, capture[2].parse().ok()?) )
+}
+
+/// Copies the WAL into the archive as the segment holding the changes [first_seq, end_seq)
+///
+/// The WAL is linked into the archive when it's on the same file system, and copied otherwise.
+/// A segment archived before a crash is replaced.
+pub fn archive(wal_path: &PathBuf, archive_dir: &PathBuf, first_seq: u64, end_seq: u64) -> Result<PathBuf, IOError> {
+    let segment_path = archive_dir.join(segment_name(first_seq, end_seq));
+
+    if segment_path.exists() {
+        fs::remove_file(&segment_path)?;
+    }
+
+    if fs::hard_link(wal_path, &segment_path).is_err() {
+        fs::copy(wal_path, &segment_path)?;
+        File::open(&segment_path)?.sync_all()?;
+    }
+
+    sync_dir(archive_dir)?;
+
+    debug!("Archived WAL {:?} as {:?}", wal_path, segment_path);
+
+    Ok(segment_path)
+}
+
+/// The archived segments, as (first_seq, end_seq, path), in sequence order
+pub fn segments(archive_dir: &PathBuf) -> Result<Vec<(u64, u64, PathBuf)>, IOError> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(archive_dir)? {
+        let path = entry?.path();
+        let range = path.file_name().and_then(|n| n.to_str()).and_then(parse_segment_name);
+
+        if let Some((first_seq, end_seq)) = range {
+            segments.push( (first_seq, end_seq, path) );
+        }
+    }
+
+    segments.sort();
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use wal_archive::{archive, parse_segment_name, segment_name, segments};
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+    use test_path::gen_dir;
+
+    #[test]
+    fn names() {
+        assert_eq!(Some( (10, 1234) ), parse_segment_name(&segment_name(10, 1234)));
+        assert!(segment_name(9, 10) < segment_name(10, 11));
+        assert_eq!(None, parse_segment_name("000001.wal"));
+        assert_eq!(None, parse_segment_name(&format!("{}.tmp", segment_name(1, 2))));
+    }
+
+    #[test]
+    fn archive_segments() {
+        let db_dir = gen_dir();
+        let archive_dir = db_dir.join("archive");
+        let wal_path = db_dir.join("000001.wal");
+
+        create_dir(&archive_dir).unwrap();
+        File::create(&wal_path).unwrap().write_all(b"WAL").unwrap();
+        File::create(archive_dir.join("other")).unwrap();
+
+        archive(&wal_path, &archive_dir, 100, 200).unwrap();
+        archive(&wal_path, &archive_dir, 0, 100).unwrap();
+        archive(&wal_path, &archive_dir, 0, 100).unwrap(); // again, after a crash
+
+        assert_eq!(vec![(0, 100, archive_dir.join(segment_name(0, 100))), (100, 200, archive_dir.join(segment_name(100, 200)))], segments(&archive_dir).unwrap());
+    }
+}
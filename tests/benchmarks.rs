@@ -2,14 +2,11 @@ extern crate kvs;
 use kvs::{KVSOptions, KVS};
 
 extern crate elapsed;
-extern crate rand;
 extern crate simple_logger;
 extern crate log;
+extern crate tempfile;
 
 use elapsed::measure_time;
-use std::path::PathBuf;
-use rand::{thread_rng, Rng};
-use std::fs::create_dir;
 use simple_logger as sl;
 use log::Level;
 
@@ -65,14 +62,11 @@ fn benchmarks() {
     sl::init_with_level(Level::Info).unwrap();
 //    sl::init_with_level(Level::Debug).unwrap();
 
-    let tmp_dir: String = thread_rng().gen_ascii_chars().take(6).collect();
-    let ret_dir = PathBuf::from("/tmp").join(format!("kvs_{}", tmp_dir));
+    let ret_dir = tempfile::Builder::new().prefix("kvs_").tempdir().unwrap(); // removed once the store is dropped
 
-    println!("CREATING TMP DIR: {:?}", ret_dir);
+    println!("CREATING TMP DIR: {:?}", ret_dir.path());
 
-    create_dir(&ret_dir).unwrap();
-
-    let mut kvs = KVSOptions::new(&PathBuf::from(ret_dir)).create().expect("Error creating KVS");
+    let mut kvs = KVSOptions::new(&ret_dir.path().to_path_buf()).create().expect("Error creating KVS");
 
     let num: u64 = 1_000;
 